@@ -0,0 +1,148 @@
+use chrono::prelude::*;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::mails::MailError;
+use crate::storage::KeyStore;
+
+/// Prefix of keys, so leaked keys are easy to recognize
+const KEY_PREFIX: &str = "dmk_";
+const ID_LENGTH: usize = 8;
+
+/// What a key is allowed to do
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Scope {
+    /// Create addresses
+    Create,
+    /// List addresses and read their emails
+    Read,
+    /// Delete emails
+    Delete,
+}
+
+impl std::str::FromStr for Scope {
+    type Err = MailError;
+
+    fn from_str(scope: &str) -> Result<Self, Self::Err> {
+        match scope {
+            "create" => Ok(Scope::Create),
+            "read" => Ok(Scope::Read),
+            "delete" => Ok(Scope::Delete),
+            _ => Err(MailError::ApiKeyError(format!(
+                "unknown scope `{scope}`. Available scopes: create, read, delete"
+            ))),
+        }
+    }
+}
+
+impl std::fmt::Display for Scope {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Scope::Create => "create",
+            Scope::Read => "read",
+            Scope::Delete => "delete",
+        })
+    }
+}
+
+/// Key of HTTP server. Only hash of its secret is stored,
+/// the whole key is shown once when it is created
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ApiKey {
+    pub id: String,
+    pub name: String,
+    pub secret_hash: String,
+    pub scopes: Vec<Scope>,
+    /// Profiles whose addresses the key can access
+    pub profiles: Vec<String>,
+    #[serde(with = "bson::serde_helpers::chrono_datetime_as_bson_datetime")]
+    pub created_at: DateTime<Utc>,
+}
+
+impl ApiKey {
+    /// New key with the secret to give to its user
+    pub fn generate(name: &str, scopes: Vec<Scope>, profiles: Vec<String>) -> (ApiKey, String) {
+        let mut rng = rand::thread_rng();
+
+        let id: String = (0..ID_LENGTH)
+            .map(|_| char::from_digit(rng.gen_range(0..36), 36).unwrap_or('0'))
+            .collect();
+        let secret = base64::encode_config(rng.gen::<[u8; 32]>(), base64::URL_SAFE_NO_PAD);
+
+        let key = ApiKey {
+            secret_hash: hash(&secret),
+            id: id.clone(),
+            name: name.to_string(),
+            scopes,
+            profiles,
+            created_at: Utc::now(),
+        };
+
+        (key, format!("{KEY_PREFIX}{id}_{secret}"))
+    }
+
+    pub fn allows(&self, scope: Scope, profile: &str) -> bool {
+        self.scopes.contains(&scope) && self.profiles.iter().any(|p| p == profile)
+    }
+}
+
+fn hash(secret: &str) -> String {
+    format!("{:x}", Sha256::digest(secret.as_bytes()))
+}
+
+/// Stored key matching `token`, None if the token is not a valid key
+pub async fn authenticate(keys: &dyn KeyStore, token: &str) -> Result<Option<ApiKey>, MailError> {
+    let (id, secret) = match token
+        .strip_prefix(KEY_PREFIX)
+        .and_then(|token| token.split_once('_'))
+    {
+        Some(parts) => parts,
+        None => return Ok(None),
+    };
+
+    Ok(keys
+        .find_api_key(id)
+        .await?
+        .filter(|key| key.secret_hash == hash(secret)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::storage::MemoryProfiles;
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_authenticate() -> Result<(), MailError> {
+        let keys = MemoryProfiles::default();
+        let (key, token) = ApiKey::generate("ci", vec![Scope::Read], vec!["qa".to_string()]);
+        keys.save_api_key(&key).await?;
+
+        assert!(!token.contains(&key.secret_hash));
+        assert_eq!(authenticate(&keys, &token).await?, Some(key.clone()));
+        assert!(key.allows(Scope::Read, "qa"));
+        assert!(!key.allows(Scope::Delete, "qa"));
+        assert!(!key.allows(Scope::Read, "default"));
+
+        let forged = format!("{KEY_PREFIX}{}_{}", key.id, "guessed");
+        assert_eq!(authenticate(&keys, &forged).await?, None);
+        assert_eq!(authenticate(&keys, "not a key").await?, None);
+
+        assert!(keys.revoke_api_key(&key.id).await?);
+        assert_eq!(authenticate(&keys, &token).await?, None);
+        assert!(!keys.revoke_api_key(&key.id).await?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_scope() {
+        assert_eq!("delete".parse::<Scope>().unwrap(), Scope::Delete);
+        assert!(matches!(
+            "admin".parse::<Scope>(),
+            Err(MailError::ApiKeyError(_))
+        ));
+    }
+}
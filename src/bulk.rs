@@ -0,0 +1,149 @@
+use futures::stream::{self, StreamExt};
+use serde::Serialize;
+
+use crate::cli;
+use crate::context::Context;
+use crate::mails;
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct CreatedEmail {
+    pub provider: String,
+    pub email_addr: String,
+}
+
+#[derive(Debug)]
+pub struct FailedEmail {
+    pub provider: String,
+    pub error: mails::MailError,
+}
+
+/// Assigns providers round-robin
+fn schedule(providers: &[&str], count: usize) -> Vec<String> {
+    (0..count)
+        .map(|index| providers[index % providers.len()].to_string())
+        .collect()
+}
+
+/// Creates `count` email addresses with at most `concurrency` requests in flight.
+/// Addresses are spread across `providers`, or with `failover` each one is created
+/// by the first of `providers` that succeeds.
+/// Provider rate limits are kept by the shared HTTP client of every provider.
+/// Creation continues after a failure, so caller gets every success and every failure.
+/// Every created address is tagged with `tags`
+pub async fn create_emails(
+    ctx: &Context,
+    providers: &[&str],
+    count: usize,
+    concurrency: usize,
+    tags: &[String],
+    failover: bool,
+) -> Result<(Vec<CreatedEmail>, Vec<FailedEmail>), mails::MailError> {
+    for provider in providers {
+        for provider in ctx.failover_order(provider) {
+            ctx.provider(&provider)?;
+        }
+    }
+
+    // Provider reported on failure and the providers tried in order
+    let jobs: Vec<(String, Vec<String>)> = if failover {
+        let order: Vec<String> = providers.iter().map(|p| p.to_string()).collect();
+        vec![(providers.join(","), order); count]
+    } else {
+        schedule(providers, count)
+            .into_iter()
+            .map(|provider| {
+                let order = ctx.failover_order(&provider);
+                (provider, order)
+            })
+            .collect()
+    };
+
+    let results: Vec<Result<CreatedEmail, FailedEmail>> = stream::iter(jobs)
+        .map(|(provider, providers)| async move {
+            match cli::store_email_with_failover(ctx, &providers, None, tags).await {
+                Ok(account) => Ok(CreatedEmail {
+                    provider: account.provider,
+                    email_addr: account.email_addr,
+                }),
+                Err(error) => Err(FailedEmail { provider, error }),
+            }
+        })
+        .buffer_unordered(concurrency.max(1))
+        .collect()
+        .await;
+
+    let mut created = Vec::new();
+    let mut failed = Vec::new();
+
+    for result in results {
+        match result {
+            Ok(email) => created.push(email),
+            Err(failure) => failed.push(failure),
+        }
+    }
+
+    Ok((created, failed))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_schedule_spreads_across_providers() {
+        let schedule = schedule(&["guerrillamail", "example"], 5);
+
+        assert_eq!(
+            schedule,
+            vec![
+                "guerrillamail",
+                "example",
+                "guerrillamail",
+                "example",
+                "guerrillamail"
+            ]
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_create_emails_with_unknown_provider() {
+        let ctx = Context::in_memory(mails::MemoryProvider::default());
+
+        let result = create_emails(&ctx, &["memory", "example"], 2, 2, &[], false).await;
+
+        assert!(matches!(
+            result,
+            Err(mails::MailError::ProviderNotAvailableError(provider)) if provider == "example"
+        ));
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_create_emails() -> Result<(), mails::MailError> {
+        let ctx = Context::in_memory(mails::MemoryProvider::default());
+
+        let tags = vec!["signup".to_string()];
+        let (created, failed) = create_emails(&ctx, &["memory"], 3, 2, &tags, false).await?;
+
+        assert_eq!(created.len(), 3);
+        assert!(failed.is_empty());
+        assert_eq!(ctx.storage.list_addresses().await?.len(), 3);
+        assert_eq!(ctx.storage.find_addresses(Some("signup")).await?.len(), 3);
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_create_emails_with_failover() -> Result<(), mails::MailError> {
+        let ctx = Context::in_memory(mails::MemoryProvider::default());
+
+        let (spread, _) = create_emails(&ctx, &["local", "memory"], 3, 1, &[], false).await?;
+        assert_eq!(spread.iter().filter(|e| e.provider == "memory").count(), 1);
+
+        // First provider succeeds, so it creates every address
+        let (created, failed) = create_emails(&ctx, &["local", "memory"], 3, 1, &[], true).await?;
+        assert!(failed.is_empty());
+        assert!(created.iter().all(|email| email.provider == "local"));
+
+        Ok(())
+    }
+}
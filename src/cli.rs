@@ -1,41 +1,65 @@
-use bson;
-use chrono::prelude::*;
-use clap::{arg, Command};
+use clap::{arg, ArgMatches, Command};
 use comfy_table::Table;
-use mongodb;
 use owo_colors::colors::*;
 use owo_colors::OwoColorize;
-use serde_json;
-use tokio;
 
 use std::fs;
+use std::io::{IsTerminal, Read};
+use std::path::Path;
+use std::sync::Arc;
 use std::time::Duration;
 
-use crate::db;
+use crate::api_keys;
+use crate::bulk;
+use crate::config;
+use crate::context::Context;
+use crate::doctor;
+use crate::events;
+use crate::export;
+use crate::failover;
+use crate::http;
+use crate::imap_server;
 use crate::mails;
-use crate::mails::GuerrillaUser;
-
-const FILENAME: &str = "providers.txt";
-const URL: &str = "mongodb://localhost";
-const PORT: &str = "27017";
+use crate::mock;
+use crate::notify;
+use crate::search;
+use crate::secrets;
+use crate::server;
+use crate::smtp;
+use crate::storage;
+use crate::stream;
+use crate::tui;
 
 pub fn cli() -> Command<'static> {
     Command::new("disposable_mail")
         .about("Tool for generating disposable emails from different email providers")
         .subcommand_required(true)
         .arg_required_else_help(true)
+        .arg(arg!(--"config" <PATH> "Config file. Defaults to DISPOSABLE_MAIL_CONFIG or disposable_mail.toml").required(false).global(true))
+        .arg(arg!(--"provider" <PROVIDER> "Default email provider. `memory` keeps addresses and emails in memory, without network and MongoDB").required(false).global(true))
+        .arg(arg!(--"profile" <PROFILE> "Profile whose addresses and emails are used. Defaults to the one in config file or `default`").required(false).global(true))
         .subcommand(Command::new("list").about("List available email providers"))
         .subcommand(Command::new("guerrillamails").about("List unexpired guerillamails from database"))
+        .subcommand(
+            Command::new("addresses")
+                .about("Lists unexpired addresses with their labels and tags")
+                .arg(arg!(--"tag" <TAG> "Only addresses with the tag").required(false))
+                .arg(arg!(--"json" "Print addresses as JSON")),
+        )
         .subcommand(
             Command::new("create")
                 .about("Creates new email address")
-                .arg(arg!(<PROVIDER> "Email provider"))
-                .arg_required_else_help(true),
+                .arg(arg!([PROVIDER] "Email provider, default provider if omitted. `auto` picks a healthy provider. Comma separated list spreads addresses across providers"))
+                .arg(arg!(--"failover" "Tries comma separated providers in order until one creates the address, for each address with --count").required(false))
+                .arg(arg!(-'n' --"count" <COUNT> "Number of addresses to create. Prints created addresses as JSON").required(false))
+                .arg(arg!(--"concurrency" <CONCURRENCY> "Maximum number of addresses created at once").required(false).default_value("4"))
+                .arg(arg!(--"name" <LABEL> "Unique label, usable instead of the address with -e").required(false))
+                .arg(arg!(--"tag" <TAG> "Tag of created addresses, can be repeated").required(false).multiple_occurrences(true)),
         )
         .subcommand(
             Command::new("get")
                 .about("Fetches available emails")
-                .arg(arg!(-'e' --"email" <EMAIL> "Email address"))
+                .arg(arg!(-'e' --"email" <EMAIL> "Email address or its label"))
                 .arg_required_else_help(true)
                 .arg(arg!(-'o' --"offset" <OFFSET> "How many emails to start from. Ex: Offset of 0 will fetch a list of the first 10 emails"))
                 .arg_required_else_help(true),
@@ -43,33 +67,159 @@ pub fn cli() -> Command<'static> {
         .subcommand(
             Command::new("check")
                 .about("Checks for new email")
-                .arg(arg!(-'e' --"email" <EMAIL> "Email address"))
+                .arg(arg!(-'e' --"email" <EMAIL> "Email address or its label"))
                 .arg_required_else_help(true)
                 .arg(arg!(-'c' --"count" <COUNT> "The sequence number (id) of the oldest email"))
+                .arg(arg!(--"notify" <SINK> "Announces new email: desktop, bell or title. Can be repeated").required(false).multiple_occurrences(true))
+                .arg(arg!(--"notify-command" <COMMAND> "Runs shell command with each new email as JSON on stdin").required(false))
                 .arg_required_else_help(true),
         )
         .subcommand(
             Command::new("fetch")
                 .about("Fetches email information")
-                .arg(arg!(-'e' --"email" <EMAIL> "Email address"))
+                .arg(arg!(-'e' --"email" <EMAIL> "Email address or its label"))
                 .arg_required_else_help(true)
                 .arg(arg!(--"id" <ID> "Id of the received email from inbox"))
                 .arg_required_else_help(true)
         )
+        .subcommand(
+            Command::new("reply")
+                .about("Replies to the sender of a received email, if the provider of the address can send email")
+                .arg(arg!(-'e' --"email" <EMAIL> "Email address or its label"))
+                .arg(arg!(--"id" <ID> "Id of the received email from inbox"))
+                .arg(arg!(--"body" <TEXT> "Text of the reply. Read from stdin if it is piped, otherwise written in $EDITOR").required(false))
+                .arg_required_else_help(true),
+        )
+        .subcommand(
+            Command::new("forward")
+                .about("Forwards a received email, if the provider of the address can send email")
+                .arg(arg!(-'e' --"email" <EMAIL> "Email address or its label"))
+                .arg(arg!(--"id" <ID> "Id of the received email from inbox"))
+                .arg(arg!(--"to" <ADDRESS> "Address the email is forwarded to"))
+                .arg(arg!(--"body" <TEXT> "Note above the forwarded email").required(false))
+                .arg_required_else_help(true),
+        )
+        .subcommand(
+            Command::new("stream")
+                .about("Prints events of addresses as JSON lines: created addresses, received emails, expired addresses and provider errors")
+                .arg(arg!(-'e' --"email" <EMAIL> "Email address or its label to follow, every stored address if omitted. Can be repeated").required(false).multiple_occurrences(true))
+                .arg(arg!(--"create" "Creates address with the default provider and follows it"))
+                .arg(arg!(--"interval" <SECONDS> "Seconds between looking for new and expired addresses. Emails are polled as set in [polling] section of config file").required(false).default_value("2")),
+        )
+        .subcommand(
+            Command::new("search")
+                .about("Searches received emails of all stored email addresses")
+                .arg(arg!([QUERY] "Text to find in sender, subject or body"))
+                .arg(arg!(-'e' --"email" <EMAIL> "Only emails received by address or label").required(false))
+                .arg(arg!(-'f' --"from" <FROM> "Text to find in sender").required(false))
+                .arg(arg!(-'s' --"subject" <SUBJECT> "Text to find in subject").required(false))
+                .arg(arg!(-'b' --"body" <BODY> "Text to find in body").required(false))
+                .arg(arg!(--"since" <DATE> "Only emails received on or after date (YYYY-MM-DD or RFC 3339)").required(false))
+                .arg(arg!(--"until" <DATE> "Only emails received on or before date (YYYY-MM-DD or RFC 3339)").required(false))
+                .arg(arg!(-'r' --"refresh" "Fetch new emails from providers before searching"))
+                .arg(arg!(--"json" "Print results as JSON")),
+        )
+        .subcommand(
+            Command::new("export")
+                .about("Exports emails to a file")
+                .arg(arg!(-'e' --"email" <EMAIL> "Email address or its label"))
+                .arg(arg!(--"id" <ID> "Id of the email to export. Whole inbox is exported if omitted").required(false))
+                .arg(arg!(--"format" <FORMAT> "Export format: eml, mbox or json"))
+                .arg(arg!(-'o' --"output" <PATH> "Output file. Directory of eml files when exporting to eml without --id"))
+                .arg_required_else_help(true),
+        )
+        .subcommand(Command::new("tui").about("Opens interactive inbox browser"))
+        .subcommand(
+            Command::new("mock-server")
+                .about("Runs local server emulating Guerrilla Mail API for offline tests")
+                .arg(arg!(--"addr" <ADDR> "Address to listen on").required(false).default_value("127.0.0.1:8025"))
+                .arg(arg!(--"inbox" <FILE> "JSON array of emails delivered to every created address").required(false)),
+        )
+        .subcommand(
+            Command::new("smtp-server")
+                .about("Runs SMTP server receiving emails of local provider")
+                .arg(arg!(--"addr" <ADDR> "Address to listen on").required(false).default_value("127.0.0.1:2525")),
+        )
+        .subcommand(
+            Command::new("rekey")
+                .about("Encrypts session tokens stored in MongoDB with a new key")
+                .arg(arg!(--"new-key" <KEY> "New key, 32 bytes encoded in base64. Generated if not set").required(false)),
+        )
+        .subcommand(
+            Command::new("imap-server")
+                .about("Runs read-only IMAP server exposing every stored address as a mailbox")
+                .arg(arg!(--"addr" <ADDR> "Address to listen on").required(false).default_value("127.0.0.1:1143")),
+        )
+        .subcommand(
+            Command::new("doctor")
+                .about("Checks config file, MongoDB and reachability of every provider")
+                .arg(arg!(--"json" "Prints the report as JSON")),
+        )
+        .subcommand(Command::new("profiles").about("Lists profiles that have stored addresses or emails"))
+        .subcommand(
+            Command::new("delete-profile")
+                .about("Deletes every address and email stored in a profile")
+                .arg(arg!(<PROFILE> "Profile to delete")),
+        )
+        .subcommand(
+            Command::new("server")
+                .about("Runs HTTP API serving addresses and emails of profiles to API keys")
+                .arg(arg!(--"addr" <ADDR> "Address to listen on").required(false).default_value("127.0.0.1:8080"))
+                .arg(arg!(--"no-auth" "Serves every request without API key. Only for servers nobody else can reach"))
+                .arg(arg!(--"poll-interval" <SECONDS> "Seconds between looking for new and expired addresses of streamed profiles").required(false).default_value("2")),
+        )
+        .subcommand(
+            Command::new("api-key")
+                .about("Manages API keys of HTTP server")
+                .arg_required_else_help(true)
+                .subcommand(
+                    Command::new("create")
+                        .about("Creates API key and prints it once")
+                        .arg(arg!(--"name" <NAME> "Who or what uses the key"))
+                        .arg(arg!(--"scope" <SCOPE> "Allowed operation: create, read or delete. Can be repeated").multiple_occurrences(true))
+                        .arg(arg!(--"namespace" <PROFILE> "Profile the key can access, active profile if omitted. Can be repeated").required(false).multiple_occurrences(true)),
+                )
+                .subcommand(Command::new("list").about("Lists API keys"))
+                .subcommand(
+                    Command::new("revoke")
+                        .about("Revokes API key")
+                        .arg(arg!(<ID> "Id of the key, as printed by list")),
+                ),
+        )
 }
 
 pub async fn menu() -> Result<(), mails::MailError> {
     let args = cli().get_matches();
 
-    let mongodb_client = db::connect(URL, PORT).await?;
-    let db = mongodb_client.database("disposable_mail_db");
+    // Doctor reports invalid config file instead of failing on it
+    if let Some(("doctor", sub_args)) = args.subcommand() {
+        let report = doctor::run(
+            args.value_of("config"),
+            args.value_of("provider"),
+            args.value_of("profile"),
+        )
+        .await;
+
+        if sub_args.is_present("json") {
+            println!("{}", serde_json::to_string_pretty(&report)?);
+        } else {
+            doctor::print_report(&report);
+        }
+
+        return report.error().map_or(Ok(()), Err);
+    }
+
+    let config = config::Config::load(args.value_of("config"))?;
+    http::init(&config);
+
+    let ctx = Context::new(&config, args.value_of("provider"), args.value_of("profile")).await?;
 
     match args.subcommand() {
         Some(("list", _)) => {
-            println!("{}", list_providers(FILENAME)?);
+            print!("{}", list_providers(&ctx));
         }
         Some(("guerrillamails", _)) => {
-            let emails = mails::get_unexpired_guerrillamails_from_db(&db).await?;
+            let emails = ctx.storage.list_addresses().await?;
 
             if emails.is_empty() {
                 println!("There is not available guerrillamails");
@@ -80,339 +230,1072 @@ pub async fn menu() -> Result<(), mails::MailError> {
             }
         }
         Some(("create", sub_args)) => {
-            let provider = sub_args.value_of("PROVIDER").expect("required");
+            let provider = sub_args
+                .value_of("PROVIDER")
+                .unwrap_or_else(|| ctx.default_provider());
+            let providers: Vec<&str> = provider.split(',').map(str::trim).collect();
+            let label = sub_args.value_of("name");
+            let tags: Vec<String> = sub_args
+                .values_of("tag")
+                .map(|tags| tags.map(str::to_string).collect())
+                .unwrap_or_default();
+
+            let failover = sub_args.is_present("failover");
+
+            if sub_args.value_of("count").is_none() && (providers.len() == 1 || failover) {
+                let providers = match providers.as_slice() {
+                    [provider] => ctx.failover_order(provider),
+                    providers => providers.iter().map(|p| p.to_string()).collect(),
+                };
+                let account = store_email_with_failover(&ctx, &providers, label, &tags).await?;
+
+                println!(
+                    "Your {} temp email: {}",
+                    account.provider, account.email_addr
+                );
+                println!("{}", "Emails expire after 60 minutes".fg::<BrightYellow>());
+
+                return Ok(());
+            }
 
-            let provider_struct = create_email_from_provider(provider).await?;
+            let count: usize = match sub_args.value_of("count") {
+                Some(count) => count.parse()?,
+                None => providers.len(),
+            };
+            let concurrency: usize = sub_args.value_of("concurrency").expect("default").parse()?;
 
-            let email_users = db.collection::<bson::Document>("email_users");
+            if let Some(label) = label {
+                return Err(mails::MailError::LabelError(format!(
+                    "`{label}` can name only one address, so --name cannot create more"
+                )));
+            }
 
-            db::create_index(&email_users).await?;
+            let (created, mut failed) =
+                bulk::create_emails(&ctx, &providers, count, concurrency, &tags, failover).await?;
 
-            match provider_struct {
-                mails::MailEnum::Guerrilla(guerrilla_user) => {
-                    let serialized_guerrilla_mail = bson::to_bson(&guerrilla_user)?;
+            println!("{}", serde_json::to_string_pretty(&created)?);
 
-                    // It is safe to call unwrap on this result because
-                    // serializing a struct to BSON (above function) creates a BSON document type.
-                    let document = serialized_guerrilla_mail.as_document().unwrap();
+            for failure in &failed {
+                eprintln!(
+                    "Failed to create {} email: {}",
+                    failure.provider, failure.error
+                );
+            }
+            eprintln!("Created {} of {} email addresses", created.len(), count);
 
-                    email_users.insert_one(document.to_owned(), None).await?;
+            if created.is_empty() && !failed.is_empty() {
+                return Err(failed.remove(0).error);
+            }
+        }
+        Some(("addresses", sub_args)) => {
+            let addresses = ctx.storage.find_addresses(sub_args.value_of("tag")).await?;
 
-                    println!(
-                        "Your guerrilla temp email: {}",
-                        guerrilla_user.mails[0].email_addr
-                    );
-                    println!("{}", "Emails expire after 60 minutes".fg::<BrightYellow>());
-                }
-                _ => panic!("Unexpected struct"),
+            if sub_args.is_present("json") {
+                println!("{}", serde_json::to_string_pretty(&addresses)?);
+            } else {
+                print_addresses(&addresses);
             }
         }
         Some(("get", sub_args)) => {
-            let email = sub_args.value_of("email").expect("required");
+            let email =
+                &resolve_address(&ctx, sub_args.value_of("email").expect("required")).await?;
             let seq = sub_args.value_of("offset").expect("required");
 
             let seq: u32 = seq.parse()?;
 
-            let response = check_available_emails_from_provider(&db, "get", email, seq).await?;
+            let messages = get_emails_from_provider(&ctx, email, seq).await?;
 
-            pretty_print_json(response);
+            print_email_list(&messages);
         }
         Some(("check", sub_args)) => {
-            let email = sub_args.value_of("email").expect("required");
+            let email =
+                &resolve_address(&ctx, sub_args.value_of("email").expect("required")).await?;
             let seq = sub_args.value_of("count").expect("required");
 
             let seq: u32 = seq.parse()?;
 
-            let response = check_available_emails_from_provider(&db, "check", email, seq).await?;
+            let mut notifier = notify::Notifier::from_config(&config.notify);
+            for sink in sub_args.values_of("notify").into_iter().flatten() {
+                notifier.add(sink.parse()?);
+            }
+            if let Some(command) = sub_args.value_of("notify-command") {
+                notifier.add(notify::Sink::Command(command.to_string()));
+            }
+
+            let messages = check_new_emails_from_provider(&ctx, email, seq, &notifier).await?;
 
-            pretty_print_json(response);
+            print_email_list(&messages);
         }
         Some(("fetch", sub_args)) => {
-            let email = sub_args.value_of("email").expect("required");
+            let email =
+                &resolve_address(&ctx, sub_args.value_of("email").expect("required")).await?;
             let email_id = sub_args.value_of("id").expect("required");
 
-            let response = fetch_email_from_provider(&db, email, email_id).await?;
+            let fetched = fetch_email_from_provider(&ctx, email, email_id).await?;
+
+            print_fetched_email(fetched.as_ref().map(|fetched| &fetched.message));
+        }
+        Some(("reply", sub_args)) => {
+            let email =
+                &resolve_address(&ctx, sub_args.value_of("email").expect("required")).await?;
+            let email_id = sub_args.value_of("id").expect("required");
+
+            let reply = reply_to_email(&ctx, email, email_id, |original| {
+                reply_body(sub_args.value_of("body"), original)
+            })
+            .await?;
+
+            println!("Sent reply to {} from {email}", reply.to);
+        }
+        Some(("forward", sub_args)) => {
+            let email =
+                &resolve_address(&ctx, sub_args.value_of("email").expect("required")).await?;
+            let email_id = sub_args.value_of("id").expect("required");
+            let to = sub_args.value_of("to").expect("required");
+
+            forward_email(
+                &ctx,
+                email,
+                email_id,
+                to,
+                sub_args.value_of("body").unwrap_or_default(),
+            )
+            .await?;
+
+            println!("Forwarded email {email_id} to {to} from {email}");
+        }
+        Some(("stream", sub_args)) => {
+            let mut emails = Vec::new();
+            for email in sub_args.values_of("email").into_iter().flatten() {
+                emails.push(resolve_address(&ctx, email).await?);
+            }
+            let interval =
+                Duration::from_secs(sub_args.value_of("interval").unwrap_or("2").parse()?);
+
+            let mut stream = stream::Stream::new(&ctx, emails);
+            let mut stdout = std::io::stdout();
+
+            if sub_args.is_present("create") {
+                let provider = ctx.default_provider();
+
+                match store_email_from_provider(&ctx, provider).await {
+                    Ok(account) => stream::write(&mut stdout, &stream.created(account))?,
+                    Err(e) => {
+                        let event = events::Event::provider_error(&ctx.profile, provider, None, &e);
+                        stream::write(&mut stdout, &event)?;
+
+                        return Err(e);
+                    }
+                }
+            }
+
+            stream::run(&mut stream, interval, &mut stdout).await?;
+        }
+        Some(("search", sub_args)) => {
+            let email_addr = match sub_args.value_of("email") {
+                Some(email) => Some(resolve_address(&ctx, email).await?),
+                None => None,
+            };
+            let query = search::SearchQuery {
+                email_addr,
+                text: sub_args.value_of("QUERY").map(str::to_string),
+                from: sub_args.value_of("from").map(str::to_string),
+                subject: sub_args.value_of("subject").map(str::to_string),
+                body: sub_args.value_of("body").map(str::to_string),
+                since: sub_args
+                    .value_of("since")
+                    .map(|date| search::parse_date(date, false))
+                    .transpose()?,
+                until: sub_args
+                    .value_of("until")
+                    .map(|date| search::parse_date(date, true))
+                    .transpose()?,
+            };
+
+            if sub_args.is_present("refresh") {
+                refresh_cached_messages(&ctx).await?;
+            }
+
+            let messages = ctx.storage.find_messages(&query).await?;
+
+            if sub_args.is_present("json") {
+                println!("{}", serde_json::to_string_pretty(&messages)?);
+            } else {
+                print_search_results(&messages);
+            }
+        }
+        Some(("export", sub_args)) => {
+            let email =
+                &resolve_address(&ctx, sub_args.value_of("email").expect("required")).await?;
+            let format: export::ExportFormat =
+                sub_args.value_of("format").expect("required").parse()?;
+            let output = sub_args.value_of("output").expect("required");
+
+            let messages =
+                export_emails_from_provider(&ctx, email, sub_args.value_of("id")).await?;
+
+            let single = sub_args.is_present("id");
+            export::write_messages(format, &messages, Path::new(output), single)?;
+
+            println!("Exported {} emails to {}", messages.len(), output);
+        }
+        Some(("tui", _)) => {
+            tui::run(&ctx).await?;
+        }
+        Some(("mock-server", sub_args)) => {
+            let addr = listen_addr(sub_args)?;
+
+            let inbox = match sub_args.value_of("inbox") {
+                Some(inbox) => mock::load_inbox(Path::new(inbox))?,
+                None => Vec::new(),
+            };
+
+            println!("Mock Guerrilla Mail listening on http://{addr}");
+            println!(
+                "Set base_url = \"http://{addr}\" in [providers.guerrillamail] section of config file"
+            );
+
+            mock::serve(addr, inbox).await?;
+        }
+        Some(("smtp-server", sub_args)) => {
+            let addr = listen_addr(sub_args)?;
+
+            println!(
+                "Accepting emails for *@{} on smtp://{addr}",
+                ctx.local.domain()
+            );
+            println!("Read them with `get`, `check` and `fetch` like emails of other providers");
+
+            smtp::serve(addr, ctx.local.clone()).await?;
+        }
+        Some(("rekey", sub_args)) => {
+            let new_key = match sub_args.value_of("new-key") {
+                Some(key) => secrets::SecretKey::from_base64(key)?,
+                None => secrets::SecretKey::generate(),
+            };
+
+            let count = ctx.profiles.rekey(&new_key).await?;
+            println!("Encrypted {count} session tokens of every profile with the new key");
+
+            if secrets::uses_keyring(&config) {
+                if let Err(e) = secrets::save_key_to_keyring(&new_key).await {
+                    println!("New key: {}", new_key.to_base64());
+                    return Err(e);
+                }
+
+                println!("New key saved to OS keyring");
+            } else {
+                println!("New key: {}", new_key.to_base64());
+                println!(
+                    "Set it as {} or `key` in [secrets] section of config file",
+                    secrets::KEY_ENV
+                );
+            }
+        }
+        Some(("imap-server", sub_args)) => {
+            let addr = listen_addr(sub_args)?;
+
+            println!("Serving stored addresses as read-only mailboxes on imap://{addr}");
+            println!("Log in with any username and password");
+
+            imap_server::serve(addr, Arc::new(ctx)).await?;
+        }
+        Some(("profiles", _)) => {
+            for profile in ctx.profiles.list().await? {
+                let active = if profile == ctx.profile { "*" } else { " " };
+
+                println!("{active} {profile}");
+            }
+        }
+        Some(("delete-profile", sub_args)) => {
+            let profile = sub_args.value_of("PROFILE").expect("required");
+            storage::check_profile_name(profile)?;
+
+            ctx.profiles.delete(profile).await?;
+
+            println!("Deleted addresses and emails of profile {profile}");
+        }
+        Some(("server", sub_args)) => {
+            let addr = listen_addr(sub_args)?;
+
+            let auth = !sub_args.is_present("no-auth");
+
+            println!("HTTP API listening on http://{addr}");
+            if !auth {
+                println!(
+                    "{}",
+                    "Requests are not authenticated, anyone reaching the server reads all email"
+                        .fg::<BrightYellow>()
+                );
+            }
+
+            let poll_interval =
+                Duration::from_secs(sub_args.value_of("poll-interval").unwrap_or("2").parse()?);
 
-            print_fetched_email(response);
+            server::serve(addr, Arc::new(ctx), auth, poll_interval).await?;
         }
+        Some(("api-key", sub_args)) => match sub_args.subcommand() {
+            Some(("create", create_args)) => {
+                let name = create_args.value_of("name").expect("required");
+                let scopes = create_args
+                    .values_of("scope")
+                    .expect("required")
+                    .map(str::parse)
+                    .collect::<Result<Vec<api_keys::Scope>, _>>()?;
+                let profiles: Vec<String> = match create_args.values_of("namespace") {
+                    Some(profiles) => profiles.map(str::to_string).collect(),
+                    None => vec![ctx.profile.clone()],
+                };
+                for profile in &profiles {
+                    storage::check_profile_name(profile)?;
+                }
+
+                let (key, token) = api_keys::ApiKey::generate(name, scopes, profiles);
+                ctx.keys.save_api_key(&key).await?;
+
+                println!("Created API key {}: {token}", key.id);
+                println!("Send it as `Authorization: Bearer <key>`. It is not shown again");
+            }
+            Some(("list", _)) => {
+                print_api_keys(&ctx.keys.list_api_keys().await?);
+            }
+            Some(("revoke", revoke_args)) => {
+                let id = revoke_args.value_of("ID").expect("required");
+
+                if !ctx.keys.revoke_api_key(id).await? {
+                    return Err(mails::MailError::ApiKeyError(format!(
+                        "no API key with id `{id}`"
+                    )));
+                }
+
+                println!("Revoked API key {id}");
+            }
+            _ => println!("No such argument"),
+        },
         _ => println!("No such argument"),
     }
 
     Ok(())
 }
 
-fn list_providers(filename: &str) -> Result<String, mails::MailError> {
-    let providers = fs::read_to_string(filename)?;
-    Ok(providers)
+/// Providers registered for this run, one per line, the default one marked
+fn list_providers(ctx: &Context) -> String {
+    ctx.provider_names()
+        .into_iter()
+        .map(|name| {
+            if name == ctx.default_provider() {
+                format!("{name} (default)\n")
+            } else {
+                format!("{name}\n")
+            }
+        })
+        .collect()
 }
 
-async fn create_email_from_provider(provider: &str) -> Result<mails::MailEnum, mails::MailError> {
-    match provider {
-        "guerrillamail" => {
-            let guerrilla_email = mails::GuerrillaMail::create_new_email().await?;
-
-            // Using unwrap is safe here, because unix timestamp
-            // does not gonna exceed i64 soon
-            let mail_creation_date = chrono::DateTime::from_utc(
-                NaiveDateTime::from_timestamp(
-                    guerrilla_email.email_timestamp.try_into().unwrap(),
-                    100_000_000,
-                ),
-                Utc,
-            );
+/// Creates email address and saves it in storage. `auto` provider fails over
+/// to another provider. Returns the account, with the provider that created it
+pub(crate) async fn store_email_from_provider(
+    ctx: &Context,
+    provider: &str,
+) -> Result<mails::Account, mails::MailError> {
+    store_email_with_failover(ctx, &ctx.failover_order(provider), None, &[]).await
+}
 
-            let mut guerrilla_user = mails::GuerrillaUser::new(mail_creation_date);
+/// Creates email address with the first of `providers` that succeeds
+/// and saves it in storage, together with its label and tags
+pub(crate) async fn store_email_with_failover(
+    ctx: &Context,
+    providers: &[String],
+    label: Option<&str>,
+    tags: &[String],
+) -> Result<mails::Account, mails::MailError> {
+    // Label is checked first, so no address is created for nothing
+    if let Some(label) = label {
+        check_label(ctx, label).await?;
+    }
 
-            guerrilla_user.email(guerrilla_email);
+    let mut account = failover::create_address(ctx, providers).await?;
+    account.label = label.map(str::to_string);
+    account.tags = tags.to_vec();
 
-            Ok(mails::MailEnum::Guerrilla(guerrilla_user))
-        }
-        _ => Ok(mails::MailEnum::NotAvailabe(
-            "Email provider not available".to_string(),
-        )),
+    ctx.storage.save_account(&account).await?;
+
+    Ok(account)
+}
+
+/// Fails if `label` could be mistaken for an address or another address has it
+async fn check_label(ctx: &Context, label: &str) -> Result<(), mails::MailError> {
+    if label.trim().is_empty() || label.contains('@') {
+        return Err(mails::MailError::LabelError(format!(
+            "`{label}` must not be empty or contain @"
+        )));
+    }
+
+    match ctx.storage.find_account_by_label(label).await? {
+        Some(account) => Err(mails::MailError::LabelError(format!(
+            "`{label}` is already used by {}",
+            account.email_addr
+        ))),
+        None => Ok(()),
     }
 }
 
-/// Searches database to find the correct values
-/// and deserialize them to correct struct
-async fn check_available_emails_from_provider(
-    db: &mongodb::Database,
-    call_function: &str,
+/// Address given with -e, which is either an address or a label of stored address
+async fn resolve_address(ctx: &Context, email: &str) -> Result<String, mails::MailError> {
+    if email.contains('@') {
+        return Ok(email.to_string());
+    }
+
+    ctx.storage
+        .find_account_by_label(email)
+        .await?
+        .map(|account| account.email_addr)
+        .ok_or_else(|| mails::MailError::EmailCheckError(email.to_string()))
+}
+
+/// Finds stored email address and the provider that created it.
+/// If address is not stored, it means that user did not run create first
+async fn find_account(
+    ctx: &Context,
+    email: &str,
+) -> Result<(mails::Account, Arc<dyn mails::Provider>), mails::MailError> {
+    let account = ctx.storage.find_account(email).await?;
+    let provider = ctx.provider(&account.provider)?;
+
+    Ok((account, provider))
+}
+
+/// Lists inbox starting from `offset` and caches listed emails
+pub(crate) async fn get_emails_from_provider(
+    ctx: &Context,
+    email: &str,
+    offset: u32,
+) -> Result<Vec<mails::Message>, mails::MailError> {
+    let (account, provider) = find_account(ctx, email).await?;
+
+    let messages = provider.list_messages(&account, offset).await?;
+
+    ctx.storage.cache_messages(&messages).await?;
+
+    Ok(messages)
+}
+
+/// Waits for emails with id greater than `seq`, caches them and announces them with `notifier`
+pub(crate) async fn check_new_emails_from_provider(
+    ctx: &Context,
     email: &str,
     seq: u32,
-) -> Result<Vec<serde_json::Value>, mails::MailError> {
-    // Check if email address is in database
-    // If it is not, it means that user did not run create first
-    let found_obj = find_element_in_db(db, "email_users", "mails.email_addr", email).await?;
-
-    match found_obj {
-        Some(email_obj) => {
-            let name_str = email_obj.get_str("name")?;
-
-            match name_str {
-                "guerrillamail" => {
-                    let guerrilla_user_struct: GuerrillaUser =
-                        bson::from_bson(bson::Bson::Document(email_obj))?;
-
-                    let index = guerrilla_user_struct
-                        .mails
-                        .iter()
-                        .position(|x| x.email_addr == email)
-                        .unwrap();
-
-                    let result_response = if call_function == "get" {
-                        let response = mails::GuerrillaMail::get_email_list(
-                            seq,
-                            &guerrilla_user_struct.mails[index].sid_token,
-                        )
-                        .await?;
-
-                        let value: serde_json::Value = serde_json::from_str(&response)?;
-
-                        // Using unwrap is safe here if Guerrillamail API does not change return type
-                        // Currently returns a vector
-                        let list = value["list"].as_array().unwrap();
-
-                        list.to_vec()
-                    } else {
-                        // Check every 10 seconds if returned list
-                        // from response has data
-                        // Break after 5 minutes (30 ticks) if list is still empty
-                        println!(
-                            "Breaks automatically after 5 minutes if there is not a new email"
-                        );
-
-                        let mut i = tokio::time::interval(Duration::from_secs(10));
-                        let mut counter = 0;
-
-                        loop {
-                            i.tick().await;
-
-                            counter += 1;
-
-                            if counter == 30 {
-                                break vec![];
-                            }
-
-                            let response = mails::GuerrillaMail::check_email(
-                                seq,
-                                &guerrilla_user_struct.mails[index].sid_token,
-                            )
-                            .await?;
-
-                            let value: serde_json::Value = serde_json::from_str(&response)?;
-
-                            // Using unwrap is safe here if Guerrillamail API does not change return type
-                            // Currently returns a vector
-                            let list = value["list"].as_array().unwrap();
-
-                            if list.is_empty() {
-                                println!("Checking for new email...");
-                            } else {
-                                break list.to_vec();
-                            }
-                        }
-                    };
-
-                    Ok(result_response)
-                }
-                _ => panic!("Unexpected email provider"),
-            }
-        }
-        None => Err(mails::MailError::EmailCheckError),
+    notifier: &notify::Notifier,
+) -> Result<Vec<mails::Message>, mails::MailError> {
+    let (account, provider) = find_account(ctx, email).await?;
+
+    let timeout = ctx.pollers.timeout();
+    println!(
+        "Breaks automatically after {} seconds if there is not a new email",
+        timeout.as_secs()
+    );
+
+    // Polls quickly at first and less often while nothing arrives,
+    // sharing the poller with other commands waiting for the same address
+    let mut subscription = ctx.pollers.subscribe(account, provider, seq);
+
+    let messages = tokio::time::timeout(timeout, subscription.next())
+        .await
+        .map_err(|_| mails::MailError::TimeoutError)??;
+
+    ctx.storage.cache_messages(&messages).await?;
+
+    // Email arrived, so failed notification only deserves a warning
+    if let Err(e) = notifier.notify(&messages).await {
+        eprintln!("{}", e.to_string().fg::<BrightYellow>());
     }
+
+    Ok(messages)
 }
 
-async fn fetch_email_from_provider(
-    db: &mongodb::Database,
+/// Fetches email with its body and caches it.
+/// Returns None if inbox has no email with `email_id`
+pub(crate) async fn fetch_email_from_provider(
+    ctx: &Context,
     email: &str,
     email_id: &str,
-) -> Result<serde_json::Value, mails::MailError> {
-    let found_obj = find_element_in_db(db, "email_users", "mails.email_addr", email).await?;
+) -> Result<Option<mails::FetchedMessage>, mails::MailError> {
+    let (account, provider) = find_account(ctx, email).await?;
 
-    match found_obj {
-        Some(email_obj) => {
-            let name_str = email_obj.get_str("name")?;
+    let fetched = provider.fetch_message(&account, email_id).await?;
 
-            match name_str {
-                "guerrillamail" => {
-                    let guerrilla_user_struct: GuerrillaUser =
-                        bson::from_bson(bson::Bson::Document(email_obj))?;
+    if let Some(fetched) = &fetched {
+        ctx.storage
+            .cache_messages(std::slice::from_ref(&fetched.message))
+            .await?;
+    }
 
-                    let index = guerrilla_user_struct
-                        .mails
-                        .iter()
-                        .position(|x| x.email_addr == email)
-                        .unwrap();
+    Ok(fetched)
+}
 
-                    let response = mails::GuerrillaMail::fetch_email(
-                        email_id,
-                        &guerrilla_user_struct.mails[index].sid_token,
-                    )
-                    .await?;
+/// Provider of `email`, if it can send email that `capability` allows
+async fn sending_account(
+    ctx: &Context,
+    email: &str,
+    capability: fn(mails::Capabilities) -> bool,
+    action: &str,
+) -> Result<(mails::Account, Arc<dyn mails::Provider>), mails::MailError> {
+    let (account, provider) = find_account(ctx, email).await?;
+
+    if !capability(provider.capabilities()) {
+        return Err(mails::MailError::UnsupportedError(format!(
+            "provider `{}` cannot {action}",
+            account.provider
+        )));
+    }
 
-                    let value: serde_json::Value = serde_json::from_str(&response)?;
+    Ok((account, provider))
+}
 
-                    Ok(value)
-                }
-                _ => panic!("Unexpected email provider"),
+async fn fetch_original(
+    ctx: &Context,
+    email: &str,
+    email_id: &str,
+) -> Result<mails::Message, mails::MailError> {
+    fetch_email_from_provider(ctx, email, email_id)
+        .await?
+        .map(|fetched| fetched.message)
+        .ok_or_else(|| mails::MailError::MessageNotFoundError(email_id.to_string()))
+}
+
+/// Replies to the sender of email `email_id` with text from `body`,
+/// which is asked for only if the provider can reply
+async fn reply_to_email(
+    ctx: &Context,
+    email: &str,
+    email_id: &str,
+    body: impl FnOnce(&mails::Message) -> Result<String, mails::MailError>,
+) -> Result<mails::OutgoingMessage, mails::MailError> {
+    let (account, provider) = sending_account(
+        ctx,
+        email,
+        |capabilities| capabilities.reply,
+        "reply to emails",
+    )
+    .await?;
+
+    let original = fetch_original(ctx, email, email_id).await?;
+    let reply = mails::OutgoingMessage::reply(&original, &body(&original)?)?;
+
+    provider.send_message(&account, &reply).await?;
+
+    Ok(reply)
+}
+
+async fn forward_email(
+    ctx: &Context,
+    email: &str,
+    email_id: &str,
+    to: &str,
+    note: &str,
+) -> Result<mails::OutgoingMessage, mails::MailError> {
+    let (account, provider) = sending_account(
+        ctx,
+        email,
+        |capabilities| capabilities.forward,
+        "forward emails",
+    )
+    .await?;
+
+    let original = fetch_original(ctx, email, email_id).await?;
+    let forward = mails::OutgoingMessage::forward(&original, to, note)?;
+
+    provider.send_message(&account, &forward).await?;
+
+    Ok(forward)
+}
+
+/// Address given with `--addr` of a server command
+fn listen_addr(sub_args: &ArgMatches) -> Result<std::net::SocketAddr, mails::MailError> {
+    let addr = sub_args.value_of("addr").expect("default");
+
+    addr.parse()
+        .map_err(|_| mails::MailError::ListenAddressError(addr.to_string()))
+}
+
+/// Text of reply from `--body`, piped stdin or `$EDITOR`
+fn reply_body(body: Option<&str>, original: &mails::Message) -> Result<String, mails::MailError> {
+    let body = match body {
+        Some(body) => body.to_string(),
+        None if !std::io::stdin().is_terminal() => {
+            let mut body = String::new();
+            std::io::stdin()
+                .read_to_string(&mut body)
+                .map_err(mails::MailError::StdinError)?;
+            body
+        }
+        None => edit_reply(original)?,
+    };
+
+    if body.trim().is_empty() {
+        return Err(mails::MailError::EmptyBodyError);
+    }
+
+    Ok(body)
+}
+
+/// Reply written in `$VISUAL` or `$EDITOR`, `vi` if neither is set.
+/// Lines starting with `#` are left out, like in git commit messages
+fn edit_reply(original: &mails::Message) -> Result<String, mails::MailError> {
+    let path =
+        std::env::temp_dir().join(format!("disposable_mail_reply_{}.txt", std::process::id()));
+    let template = format!(
+        "\n# Reply to {} about \"{}\".\n# Lines starting with # are removed and the email is quoted below the reply.\n# Empty reply cancels sending.\n",
+        original.mail_from, original.mail_subject
+    );
+    fs::write(&path, template).map_err(mails::MailError::file_error(&path))?;
+
+    let editor = std::env::var("VISUAL")
+        .or_else(|_| std::env::var("EDITOR"))
+        .unwrap_or_else(|_| "vi".to_string());
+    let mut words = editor.split_whitespace();
+    let status = std::process::Command::new(words.next().unwrap_or("vi"))
+        .args(words)
+        .arg(&path)
+        .status();
+
+    let text = fs::read_to_string(&path).map_err(mails::MailError::file_error(&path));
+    fs::remove_file(&path).ok();
+
+    let status =
+        status.map_err(|e| mails::MailError::EditorError(format!("cannot run `{editor}`: {e}")))?;
+    if !status.success() {
+        return Err(mails::MailError::EditorError(format!(
+            "`{editor}` exited with {status}"
+        )));
+    }
+
+    Ok(without_comments(&text?))
+}
+
+fn without_comments(text: &str) -> String {
+    text.lines()
+        .filter(|line| !line.starts_with('#'))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Lists inbox of every unexpired email address and caches
+/// the emails, fetching bodies of those that were not fetched before
+async fn refresh_cached_messages(ctx: &Context) -> Result<(), mails::MailError> {
+    let emails = ctx.storage.list_addresses().await?;
+
+    for email in emails {
+        let list = get_emails_from_provider(ctx, &email, 0).await?;
+
+        for message in list {
+            let cached = ctx.storage.find_message(&email, &message.mail_id).await?;
+
+            if cached.is_some_and(|cached| cached.mail_body.is_some()) {
+                continue;
             }
+
+            fetch_email_from_provider(ctx, &email, &message.mail_id).await?;
         }
-        None => Err(mails::MailError::EmailCheckError),
     }
+
+    Ok(())
 }
 
-async fn find_element_in_db(
-    db: &mongodb::Database,
-    collection: &str,
-    key: &str,
-    value: &str,
-) -> Result<Option<bson::Document>, mails::MailError> {
-    let email_users = db.collection::<bson::Document>(collection);
+/// Fetches emails with their attachments. Every email
+/// in the inbox is fetched if `email_id` is None
+pub(crate) async fn export_emails_from_provider(
+    ctx: &Context,
+    email: &str,
+    email_id: Option<&str>,
+) -> Result<Vec<export::ExportedMessage>, mails::MailError> {
+    let email_ids = match email_id {
+        Some(email_id) => vec![email_id.to_string()],
+        None => {
+            let mut email_ids: Vec<String> = Vec::new();
+
+            loop {
+                let offset = email_ids.len() as u32;
+                let list = get_emails_from_provider(ctx, email, offset).await?;
+
+                let new_ids: Vec<String> = list
+                    .into_iter()
+                    .map(|message| message.mail_id)
+                    .filter(|id| !email_ids.contains(id))
+                    .collect();
+
+                if new_ids.is_empty() {
+                    break email_ids;
+                }
+
+                email_ids.extend(new_ids);
+            }
+        }
+    };
+
+    let (account, provider) = find_account(ctx, email).await?;
 
-    let filter = bson::doc! {key: value};
+    let mut messages = Vec::new();
 
-    let found_obj = email_users.find_one(filter, None).await?;
+    for email_id in email_ids {
+        let fetched = fetch_email_from_provider(ctx, email, &email_id)
+            .await?
+            .ok_or_else(|| mails::MailError::MessageNotFoundError(email_id.clone()))?;
+
+        let mut attachments = Vec::new();
+
+        for part in fetched.attachments {
+            let data = provider
+                .fetch_attachment(&account, &email_id, &part.part_id)
+                .await?;
+
+            attachments.push(mails::Attachment {
+                filename: part.filename,
+                content_type: part.content_type,
+                data,
+            });
+        }
 
-    Ok(found_obj)
+        messages.push((fetched.message, attachments));
+    }
+
+    Ok(messages)
+}
+
+pub(crate) async fn delete_email_from_provider(
+    ctx: &Context,
+    email: &str,
+    email_id: &str,
+) -> Result<(), mails::MailError> {
+    let (account, provider) = find_account(ctx, email).await?;
+
+    provider.delete_message(&account, email_id).await
 }
 
-fn pretty_print_json(json_data: Vec<serde_json::Value>) {
+fn print_email_list(messages: &[mails::Message]) {
     let mut table = Table::new();
 
     table.set_header(vec!["ID", "From", "Subject", "Date"]);
 
-    if json_data.is_empty() {
-        println!("");
+    if messages.is_empty() {
+        println!();
         return;
     }
 
-    for value in json_data {
-        // Using unwrap is safe here, because mail_timestamp always contains digits
-        // and does not gonna exceed u64 soon
-        let timestamp = value["mail_timestamp"]
-            .as_str()
-            .unwrap()
-            .parse::<i64>()
-            .unwrap();
+    for message in messages {
+        table.add_row(vec![
+            &message.mail_id,
+            &message.mail_from,
+            &message.mail_subject,
+            &message.date().to_string(),
+        ]);
+    }
+
+    println!("{table}");
+}
+
+fn print_search_results(messages: &[mails::Message]) {
+    if messages.is_empty() {
+        println!("No emails found");
+        return;
+    }
+
+    let mut table = Table::new();
+
+    table.set_header(vec!["Email", "ID", "From", "Subject", "Date"]);
+
+    for message in messages {
+        table.add_row(vec![
+            &message.email_addr,
+            &message.mail_id,
+            &message.mail_from,
+            &message.mail_subject,
+            &message.date().to_string(),
+        ]);
+    }
+
+    println!("{table}");
+}
 
-        let date: DateTime<Utc> =
-            chrono::DateTime::from_utc(NaiveDateTime::from_timestamp(timestamp, 0), Utc);
+fn print_addresses(addresses: &[storage::StoredAddress]) {
+    if addresses.is_empty() {
+        println!("No addresses found");
+        return;
+    }
 
+    let mut table = Table::new();
+
+    table.set_header(vec!["Email", "Label", "Tags", "Provider", "Created"]);
+
+    for address in addresses {
         table.add_row(vec![
-            &value["mail_id"].to_string(),
-            &value["mail_from"].to_string(),
-            &value["mail_subject"].to_string(),
-            &date.to_string(),
+            &address.email_addr,
+            address.label.as_deref().unwrap_or_default(),
+            &address.tags.join(", "),
+            &address.provider,
+            &address.created_at.to_string(),
         ]);
     }
 
     println!("{table}");
 }
 
-fn print_fetched_email(value: serde_json::Value) {
-    if value == false {
-        println!("Unexpected email id");
+fn print_api_keys(keys: &[api_keys::ApiKey]) {
+    if keys.is_empty() {
+        println!("No API keys");
         return;
     }
 
-    // Using unwrap is safe here
-    // because check for empty email is above
-    println!("From: {}", value["mail_from"].as_str().unwrap());
-    println!("Date: {} UTC", value["mail_date"].as_str().unwrap());
-    println!("Subject: {}", value["mail_subject"].as_str().unwrap());
-    println!("\n{}", value["mail_body"].as_str().unwrap());
+    let mut table = Table::new();
+
+    table.set_header(vec!["ID", "Name", "Scopes", "Profiles", "Created"]);
+
+    for key in keys {
+        let scopes: Vec<String> = key.scopes.iter().map(|scope| scope.to_string()).collect();
+
+        table.add_row(vec![
+            &key.id,
+            &key.name,
+            &scopes.join(", "),
+            &key.profiles.join(", "),
+            &key.created_at.to_string(),
+        ]);
+    }
+
+    println!("{table}");
+}
+
+fn print_fetched_email(message: Option<&mails::Message>) {
+    let message = match message {
+        Some(message) => message,
+        None => {
+            println!("Unexpected email id");
+            return;
+        }
+    };
+
+    println!("From: {}", message.mail_from);
+    println!("Date: {} UTC", message.date().naive_utc());
+    println!("Subject: {}", message.mail_subject);
+    println!("\n{}", message.mail_body.as_deref().unwrap_or_default());
 }
 
 #[cfg(test)]
 mod tests {
+    use crate::extract;
+    use crate::mails::strategies;
     use crate::mails::MailError;
+    use proptest::prelude::*;
 
     use super::*;
 
-    #[test]
-    fn test_list_providers_success() {
-        assert!(list_providers(FILENAME).is_ok());
+    proptest! {
+        #[test]
+        fn test_print_email_list_never_panics(
+            values in prop::collection::vec(strategies::json_value(), 0..4)
+        ) {
+            let messages: Vec<mails::Message> = values
+                .iter()
+                .filter_map(|value| mails::Message::from_guerrilla_json("", value))
+                .collect();
+
+            print_email_list(&messages);
+            for message in &messages {
+                print_fetched_email(Some(message));
+            }
+        }
     }
 
     #[test]
-    fn test_list_providers_err() {
-        match list_providers("somefile") {
-            Err(e) => assert_eq!(e, MailError::FileNotFound),
-            _ => panic!("Unexpected error"),
-        }
+    fn test_list_providers() {
+        let ctx = Context::in_memory(mails::MemoryProvider::default());
+
+        assert_eq!(
+            list_providers(&ctx),
+            "guerrillamail\nlocal\nmemory (default)\n"
+        );
     }
 
     #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
-    #[ignore]
     async fn test_guerrillamail_creation() -> Result<(), mails::MailError> {
-        let email = create_email_from_provider("guerrillamail").await;
-        assert!(!email.is_err());
+        mock::init_test_provider();
+
+        let ctx = Context::in_memory(mails::MemoryProvider::default());
+
+        let email = store_email_from_provider(&ctx, "guerrillamail")
+            .await?
+            .email_addr;
+
+        assert_eq!(
+            ctx.storage.find_account(&email).await?.provider,
+            "guerrillamail"
+        );
+
         Ok(())
     }
 
     #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
-    async fn test_not_found_provider_email_creation() -> Result<(), mails::MailError> {
-        let email = create_email_from_provider("example").await?;
-        match email {
-            mails::MailEnum::NotAvailabe(msg) => {
-                assert_eq!(msg, "Email provider not available".to_string())
-            }
-            _ => panic!("Unexpected message"),
+    async fn test_not_found_provider_email_creation() {
+        let ctx = Context::in_memory(mails::MemoryProvider::default());
+
+        let email = store_email_from_provider(&ctx, "example").await;
+
+        assert!(matches!(
+            email,
+            Err(MailError::ProviderNotAvailableError(provider)) if provider == "example"
+        ));
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_labeled_address() -> Result<(), mails::MailError> {
+        let ctx = Context::in_memory(mails::MemoryProvider::default());
+        let providers = vec!["memory".to_string()];
+        let tags = vec!["admin".to_string(), "signup".to_string()];
+
+        let admin = store_email_with_failover(&ctx, &providers, Some("admin inbox"), &tags).await?;
+        store_email_from_provider(&ctx, "memory").await?;
+
+        assert_eq!(
+            resolve_address(&ctx, "admin inbox").await?,
+            admin.email_addr
+        );
+        assert_eq!(
+            resolve_address(&ctx, &admin.email_addr).await?,
+            admin.email_addr
+        );
+        assert!(matches!(
+            resolve_address(&ctx, "invited user").await,
+            Err(MailError::EmailCheckError(_))
+        ));
+
+        let tagged = ctx.storage.find_addresses(Some("signup")).await?;
+        assert_eq!(tagged.len(), 1);
+        assert_eq!(tagged[0].label.as_deref(), Some("admin inbox"));
+        assert_eq!(ctx.storage.find_addresses(None).await?.len(), 2);
+
+        for label in ["admin inbox", "admin@example.com", " "] {
+            assert!(matches!(
+                store_email_with_failover(&ctx, &providers, Some(label), &[]).await,
+                Err(MailError::LabelError(_))
+            ));
         }
+        assert_eq!(ctx.storage.list_addresses().await?.len(), 2);
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_memory_flow_without_network_and_database() -> Result<(), mails::MailError> {
+        let memory = mails::MemoryProvider::default();
+        let ctx = Context::in_memory(memory.clone());
+
+        let email = store_email_from_provider(&ctx, ctx.default_provider())
+            .await?
+            .email_addr;
+
+        memory.inject(
+            &email,
+            "GitHub <noreply@github.com>",
+            "Verify your email",
+            "<p>Your code is 482913</p>",
+            vec![mails::Attachment {
+                filename: "terms.txt".to_string(),
+                content_type: "text/plain".to_string(),
+                data: b"Terms of service".to_vec(),
+            }],
+        )?;
+
+        let new_emails =
+            check_new_emails_from_provider(&ctx, &email, 1, &notify::Notifier::default()).await?;
+        assert_eq!(new_emails.len(), 1);
+
+        let fetched = fetch_email_from_provider(&ctx, &email, &new_emails[0].mail_id)
+            .await?
+            .unwrap();
+        let body = fetched.message.mail_body.unwrap_or_default();
+        assert_eq!(extract::extract_code(&body), Some("482913".to_string()));
+
+        let exported = export_emails_from_provider(&ctx, &email, None).await?;
+        assert_eq!(exported.len(), 2);
+        assert_eq!(exported[0].1[0].data, b"Terms of service".to_vec());
+        assert!(export::to_mbox(&exported).contains("Subject: Verify your email"));
+
+        let query = search::SearchQuery {
+            text: Some("482913".to_string()),
+            ..search::SearchQuery::default()
+        };
+        assert_eq!(ctx.storage.find_messages(&query).await?.len(), 1);
+
+        delete_email_from_provider(&ctx, &email, &new_emails[0].mail_id).await?;
+        assert_eq!(get_emails_from_provider(&ctx, &email, 0).await?.len(), 1);
+
         Ok(())
     }
 
     #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
-    async fn test_find_element_in_db() -> Result<(), mails::MailError> {
-        let mongodb_client = db::connect(URL, PORT).await?;
-        let db = mongodb_client.database("disposable_mail_db");
+    async fn test_unknown_email_address() {
+        let ctx = Context::in_memory(mails::MemoryProvider::default());
 
-        let found =
-            find_element_in_db(&db, "email_users", "mails.email_addr", "some_value").await?;
+        assert!(matches!(
+            get_emails_from_provider(&ctx, "abc@memory.test", 0).await,
+            Err(MailError::EmailCheckError(_))
+        ));
+    }
 
-        assert_eq!(found, None);
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_reply_and_forward() -> Result<(), mails::MailError> {
+        let memory = mails::MemoryProvider::default();
+        let ctx = Context::in_memory(memory.clone());
+
+        let qa = store_email_from_provider(&ctx, "memory").await?.email_addr;
+        let app = store_email_from_provider(&ctx, "memory").await?.email_addr;
+        let mail_id = memory.inject(
+            &qa,
+            &format!("App <{app}>"),
+            "Confirm signup",
+            "<p>Reply YES to confirm</p>",
+            Vec::new(),
+        )?;
+
+        let reply = reply_to_email(&ctx, &qa, &mail_id, |original| {
+            assert_eq!(original.mail_subject, "Confirm signup");
+            reply_body(Some("YES"), original)
+        })
+        .await?;
+        assert_eq!(reply.to, app);
+
+        let (account, provider) = find_account(&ctx, &app).await?;
+        let received = provider.check_messages(&account, 1).await?;
+        assert_eq!(received[0].mail_subject, "Re: Confirm signup");
+        assert_eq!(received[0].mail_from, qa);
+
+        forward_email(&ctx, &qa, &mail_id, "dev@example.com", "FYI").await?;
+        let outbox = memory.outbox();
+        assert_eq!(outbox.len(), 1);
+        assert_eq!(outbox[0].subject, "Fwd: Confirm signup");
+        assert!(outbox[0].body.starts_with("FYI\n\n"));
+
+        assert!(matches!(
+            reply_to_email(&ctx, &qa, &mail_id, |original| reply_body(
+                Some(" \n"),
+                original
+            ))
+            .await,
+            Err(MailError::EmptyBodyError)
+        ));
+        assert!(matches!(
+            forward_email(&ctx, &qa, "99", "dev@example.com", "").await,
+            Err(MailError::MessageNotFoundError(_))
+        ));
 
         Ok(())
     }
+
+    #[test]
+    fn test_without_comments() {
+        assert_eq!(
+            without_comments("YES\n# Reply to app\n\n  # kept\n"),
+            "YES\n\n  # kept"
+        );
+    }
 }
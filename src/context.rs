@@ -0,0 +1,281 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use crate::config;
+use crate::db;
+use crate::mails;
+use crate::polling;
+use crate::secrets;
+use crate::storage;
+
+const URL: &str = "mongodb://localhost";
+const PORT: &str = "27017";
+
+/// Provider used when neither `--provider` nor config file chooses one
+pub const DEFAULT_PROVIDER: &str = "guerrillamail";
+
+/// Provider name letting `create` choose a provider by failover policy
+pub const AUTO_PROVIDER: &str = "auto";
+
+/// Storage and providers every command works with
+pub struct Context {
+    /// Storage of the active profile
+    pub storage: Arc<dyn storage::Storage>,
+    pub profile: String,
+    pub profiles: Arc<dyn storage::Profiles>,
+    /// API keys of HTTP server
+    pub keys: Arc<dyn storage::KeyStore>,
+    /// Provider receiving email with the built-in SMTP server
+    pub local: mails::LocalProvider,
+    /// Policy of `create auto`
+    pub failover: config::FailoverConfig,
+    /// Pollers shared by everything waiting for email
+    pub pollers: Arc<polling::Pollers>,
+    providers: HashMap<&'static str, Arc<dyn mails::Provider>>,
+    default_provider: String,
+}
+
+impl Context {
+    /// Chooses `provider` and `profile`, or the ones from config file.
+    /// Memory provider keeps everything in memory, so MongoDB is not used with it
+    pub async fn new(
+        config: &config::Config,
+        provider: Option<&str>,
+        profile: Option<&str>,
+    ) -> Result<Self, mails::MailError> {
+        let default_provider = provider
+            .or(config.provider.as_deref())
+            .unwrap_or(DEFAULT_PROVIDER);
+
+        let profile = profile
+            .or(config.profile.as_deref())
+            .unwrap_or(storage::DEFAULT_PROFILE);
+        storage::check_profile_name(profile)?;
+
+        let local = config.provider(mails::LocalProvider::NAME);
+        let domain = local
+            .domain
+            .unwrap_or_else(|| mails::local::DEFAULT_DOMAIN.to_string());
+
+        if default_provider == mails::MemoryProvider::NAME {
+            let mut context =
+                Context::with_memory(mails::MemoryProvider::default(), &domain, profile);
+            context.failover = config.failover.clone();
+            context.pollers = Arc::new(polling::Pollers::new(config.polling.clone()));
+            context.set_local(context.local.clone().with_relay(local.relay));
+
+            return Ok(context);
+        }
+
+        let imap = mails::ImapProvider::from_config(config)?;
+
+        let key = secrets::load_key(config).await?;
+
+        let mongodb_client = db::connect(URL, PORT).await?;
+        let profiles = db::MongoProfiles::new(mongodb_client, key);
+
+        let mut context =
+            Context::with_profiles(Arc::new(profiles), profile, &domain, default_provider);
+        context.failover = config.failover.clone();
+        context.pollers = Arc::new(polling::Pollers::new(config.polling.clone()));
+        context.set_local(context.local.clone().with_relay(local.relay));
+        if let Some(imap) = imap {
+            context
+                .providers
+                .insert(mails::ImapProvider::NAME, Arc::new(imap));
+        }
+
+        Ok(context)
+    }
+
+    /// Context keeping addresses and emails in memory.
+    /// Emails are delivered to addresses with `MemoryProvider::inject`
+    #[cfg(test)]
+    pub fn in_memory(memory: mails::MemoryProvider) -> Self {
+        Context::with_memory(
+            memory,
+            mails::local::DEFAULT_DOMAIN,
+            storage::DEFAULT_PROFILE,
+        )
+    }
+
+    fn with_memory(memory: mails::MemoryProvider, domain: &str, profile: &str) -> Self {
+        let mut context = Context::with_profiles(
+            Arc::new(storage::MemoryProfiles::default()),
+            profile,
+            domain,
+            mails::MemoryProvider::NAME,
+        );
+        context
+            .providers
+            .insert(mails::MemoryProvider::NAME, Arc::new(memory));
+
+        context
+    }
+
+    fn with_profiles<P>(
+        profiles: Arc<P>,
+        profile: &str,
+        domain: &str,
+        default_provider: &str,
+    ) -> Self
+    where
+        P: storage::Profiles + storage::KeyStore + 'static,
+    {
+        let storage = profiles.storage(profile);
+        let local = mails::LocalProvider::new(storage.clone(), domain);
+
+        let mut providers: HashMap<&'static str, Arc<dyn mails::Provider>> = HashMap::new();
+        providers.insert(DEFAULT_PROVIDER, Arc::new(mails::GuerrillaProvider));
+        providers.insert(mails::LocalProvider::NAME, Arc::new(local.clone()));
+
+        Context {
+            storage,
+            profile: profile.to_string(),
+            profiles: profiles.clone(),
+            keys: profiles,
+            local,
+            failover: config::FailoverConfig::default(),
+            pollers: Arc::new(polling::Pollers::default()),
+            providers,
+            default_provider: default_provider.to_string(),
+        }
+    }
+
+    /// Same context working with storage of another profile
+    pub fn with_profile(&self, profile: &str) -> Context {
+        let storage = self.profiles.storage(profile);
+        let local = mails::LocalProvider::new(storage.clone(), self.local.domain())
+            .with_relay(self.local.relay().map(str::to_string));
+
+        let mut providers = self.providers.clone();
+        providers.insert(mails::LocalProvider::NAME, Arc::new(local.clone()));
+
+        Context {
+            storage,
+            profile: profile.to_string(),
+            profiles: self.profiles.clone(),
+            keys: self.keys.clone(),
+            local,
+            failover: self.failover.clone(),
+            pollers: self.pollers.clone(),
+            providers,
+            default_provider: self.default_provider.clone(),
+        }
+    }
+
+    fn set_local(&mut self, local: mails::LocalProvider) {
+        self.providers
+            .insert(mails::LocalProvider::NAME, Arc::new(local.clone()));
+        self.local = local;
+    }
+
+    pub fn default_provider(&self) -> &str {
+        &self.default_provider
+    }
+
+    /// Providers `create` tries in order. `auto` means providers from
+    /// failover policy, or default provider followed by the others by name
+    pub fn failover_order(&self, provider: &str) -> Vec<String> {
+        if provider != AUTO_PROVIDER {
+            return vec![provider.to_string()];
+        }
+
+        if !self.failover.providers.is_empty() {
+            return self.failover.providers.clone();
+        }
+
+        let others = self
+            .provider_names()
+            .into_iter()
+            .filter(|name| *name != self.default_provider);
+
+        let mut providers = vec![self.default_provider.clone()];
+        providers.extend(others.map(str::to_string));
+
+        providers
+    }
+
+    /// Adds provider, replacing the one with the same name
+    #[cfg(test)]
+    pub fn register(&mut self, name: &'static str, provider: Arc<dyn mails::Provider>) {
+        self.providers.insert(name, provider);
+    }
+
+    /// Names of available providers, sorted
+    pub fn provider_names(&self) -> Vec<&'static str> {
+        let mut names: Vec<&'static str> = self.providers.keys().copied().collect();
+        names.sort_unstable();
+
+        names
+    }
+
+    pub fn provider(&self, name: &str) -> Result<Arc<dyn mails::Provider>, mails::MailError> {
+        self.providers
+            .get(name)
+            .cloned()
+            .ok_or_else(|| mails::MailError::ProviderNotAvailableError(name.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_memory_provider_from_config() -> Result<(), mails::MailError> {
+        let config = config::Config::parse(r#"provider = "memory""#)?;
+
+        let context = Context::new(&config, None, None).await?;
+
+        assert_eq!(context.default_provider(), "memory");
+        assert!(context.provider("memory").is_ok());
+        assert_eq!(context.profile, "default");
+
+        let config = config::Config::parse("provider = \"memory\"\nprofile = \"qa\"")?;
+        assert_eq!(Context::new(&config, None, None).await?.profile, "qa");
+        assert_eq!(
+            Context::new(&config, None, Some("alice")).await?.profile,
+            "alice"
+        );
+        assert!(matches!(
+            Context::new(&config, None, Some("qa team")).await,
+            Err(mails::MailError::ConfigError(_))
+        ));
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_local_relay_from_config() -> Result<(), mails::MailError> {
+        let config = config::Config::parse(
+            "provider = \"memory\"\n[providers.local]\nrelay = \"smtp.example.com:25\"",
+        )?;
+
+        let context = Context::new(&config, None, None).await?;
+
+        assert_eq!(context.local.relay(), Some("smtp.example.com:25"));
+        assert_eq!(
+            context.with_profile("qa").local.relay(),
+            Some("smtp.example.com:25")
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_default_providers() -> Result<(), mails::MailError> {
+        let context = Context::new(&config::Config::default(), None, None).await?;
+
+        assert_eq!(context.default_provider(), "guerrillamail");
+        assert!(context.provider("local").is_ok());
+        assert_eq!(context.failover_order("auto"), ["guerrillamail", "local"]);
+        assert_eq!(context.failover_order("local"), ["local"]);
+        assert!(matches!(
+            context.provider("memory"),
+            Err(mails::MailError::ProviderNotAvailableError(name)) if name == "memory"
+        ));
+
+        Ok(())
+    }
+}
@@ -1,8 +1,37 @@
-use bson;
-use mongodb::{options::ClientOptions, options::IndexOptions, Client, Collection, IndexModel};
-use std::time;
+use async_trait::async_trait;
+use chrono::prelude::*;
+use futures::stream::TryStreamExt;
+use mongodb::bson::oid;
+use mongodb::error::{ErrorKind, WriteFailure};
+use mongodb::{
+    options::ClientOptions, options::FindOneAndUpdateOptions, options::FindOptions,
+    options::IndexOptions, options::ReturnDocument, options::UpdateOptions, Client, Collection,
+    IndexModel,
+};
 
+use std::sync::Arc;
+
+use crate::api_keys::ApiKey;
 use crate::mails;
+use crate::search::SearchQuery;
+use crate::secrets::{self, SecretKey};
+use crate::storage::{self, Storage};
+
+/// Database of the default profile, other profiles get the name as suffix
+pub const DATABASE: &str = "disposable_mail_db";
+
+/// Database of data shared by profiles, like API keys
+const ADMIN_DATABASE: &str = "disposable_mail_admin";
+
+/// Code of the error MongoDB returns when a unique index is violated
+const DUPLICATE_KEY: i32 = 11000;
+
+/// Storage backed by a MongoDB database. Session tokens
+/// are encrypted if a key is set
+pub struct MongoStorage {
+    db: mongodb::Database,
+    key: Option<SecretKey>,
+}
 
 pub async fn connect(url: &str, port: &str) -> Result<Client, mongodb::error::Error> {
     let mut client_options = ClientOptions::parse(format!("{url}:{port}")).await?;
@@ -21,7 +50,7 @@ pub async fn create_index(
     // delete document after 60 minutes
     let index_key = bson::doc! { "createdAt": 1 };
     let index_options = IndexOptions::builder()
-        .expire_after(Some(time::Duration::new(3600, 0)))
+        .expire_after(Some(storage::ADDRESS_LIFETIME))
         .build();
     let index_model = IndexModel::builder()
         .keys(index_key)
@@ -30,13 +59,769 @@ pub async fn create_index(
 
     email_users.create_index(index_model, None).await?;
 
+    // Labels are unique, addresses without one are left out of the index
+    let label_index = IndexModel::builder()
+        .keys(bson::doc! { "mails.label": 1 })
+        .options(IndexOptions::builder().unique(true).sparse(true).build())
+        .build();
+
+    email_users.create_index(label_index, None).await?;
+
+    Ok(())
+}
+
+fn is_duplicate_key(error: &mongodb::error::Error) -> bool {
+    matches!(
+        &*error.kind,
+        ErrorKind::Write(WriteFailure::WriteError(e)) if e.code == DUPLICATE_KEY
+    )
+}
+
+/// Saves messages in `messages` collection so they can be searched
+/// after email address expires. Already cached messages are updated,
+/// but a cached body is kept if the new message has none
+pub async fn cache_messages(
+    db: &mongodb::Database,
+    messages: &[mails::Message],
+) -> Result<(), mails::MailError> {
+    let collection = db.collection::<bson::Document>("messages");
+
+    for message in messages {
+        let filter = bson::doc! { "email_addr": &message.email_addr, "mail_id": &message.mail_id };
+        let update = bson::doc! { "$set": bson::to_document(message)? };
+        let options = UpdateOptions::builder().upsert(true).build();
+
+        collection.update_one(filter, update, options).await?;
+    }
+
     Ok(())
 }
 
+/// Returns cached messages matching the filter, newest first
+pub async fn find_messages(
+    db: &mongodb::Database,
+    filter: bson::Document,
+) -> Result<Vec<mails::Message>, mails::MailError> {
+    let collection = db.collection::<mails::Message>("messages");
+
+    let options = FindOptions::builder()
+        .sort(bson::doc! { "mail_timestamp": -1 })
+        .build();
+
+    let messages = collection
+        .find(filter, options)
+        .await?
+        .try_collect()
+        .await?;
+
+    Ok(messages)
+}
+
+async fn find_element_in_db(
+    db: &mongodb::Database,
+    collection: &str,
+    key: &str,
+    value: &str,
+) -> Result<Option<bson::Document>, mails::MailError> {
+    let email_users = db.collection::<bson::Document>(collection);
+
+    let filter = bson::doc! {key: value};
+
+    let found_obj = email_users.find_one(filter, None).await?;
+
+    Ok(found_obj)
+}
+
+/// Accounts are stored in `email_users` collection in the format
+/// of Guerrillamail users, `name` of the user is the provider
+fn user_from_account(account: &mails::Account, key: Option<&SecretKey>) -> mails::GuerrillaUser {
+    let mut user = mails::GuerrillaUser::new(account.created_at);
+
+    user.name = account.provider.clone();
+    user.email(mails::GuerrillaMail {
+        email_addr: account.email_addr.clone(),
+        email_timestamp: account.created_at.timestamp().max(0) as u64,
+        alias: account
+            .email_addr
+            .split('@')
+            .next()
+            .unwrap_or_default()
+            .to_string(),
+        sid_token: match key {
+            Some(key) => key.encrypt(&account.email_addr, &account.sid_token),
+            None => account.sid_token.clone(),
+        },
+        label: account.label.clone(),
+        tags: account.tags.clone(),
+        proxy: account.proxy,
+    });
+
+    user
+}
+
+fn account_from_document(
+    document: bson::Document,
+    email_addr: &str,
+    key: Option<&SecretKey>,
+) -> Result<mails::Account, mails::MailError> {
+    let user: mails::GuerrillaUser = bson::from_document(document)?;
+
+    let mail = user
+        .mails
+        .into_iter()
+        .find(|mail| mail.email_addr == email_addr)
+        .ok_or_else(|| mails::MailError::EmailCheckError(email_addr.to_string()))?;
+
+    let created_at = created_at(&mail)?;
+
+    Ok(mails::Account {
+        provider: user.name,
+        sid_token: secrets::decrypt(key, &mail.email_addr, &mail.sid_token)?,
+        email_addr: mail.email_addr,
+        created_at,
+        label: mail.label,
+        tags: mail.tags,
+        proxy: mail.proxy,
+    })
+}
+
+/// Addresses of `email_users` document, without session tokens
+fn stored_addresses_from_document(
+    document: bson::Document,
+) -> Result<Vec<storage::StoredAddress>, mails::MailError> {
+    let user: mails::GuerrillaUser = bson::from_document(document)?;
+
+    user.mails
+        .into_iter()
+        .map(|mail| {
+            Ok(storage::StoredAddress {
+                created_at: created_at(&mail)?,
+                email_addr: mail.email_addr,
+                provider: user.name.clone(),
+                label: mail.label,
+                tags: mail.tags,
+            })
+        })
+        .collect()
+}
+
+fn created_at(mail: &mails::GuerrillaMail) -> Result<DateTime<Utc>, mails::MailError> {
+    i64::try_from(mail.email_timestamp)
+        .ok()
+        .and_then(|timestamp| NaiveDateTime::from_timestamp_opt(timestamp, 0))
+        .map(|date| DateTime::from_utc(date, Utc))
+        .ok_or_else(|| {
+            mails::MailError::UnexpectedDocumentError(format!(
+                "invalid email_timestamp `{}`",
+                mail.email_timestamp
+            ))
+        })
+}
+
+/// `mails` of `email_users` document with session tokens encrypted with `new_key`.
+/// Returns None if the document has no tokens
+fn rekey_mails(
+    document: &bson::Document,
+    old_key: Option<&SecretKey>,
+    new_key: &SecretKey,
+) -> Result<Option<bson::Array>, mails::MailError> {
+    let mails = match document.get("mails") {
+        Some(mails) => mails.as_array().ok_or_else(|| {
+            mails::MailError::UnexpectedDocumentError("`mails` is not an array".to_string())
+        })?,
+        None => return Ok(None),
+    };
+
+    let mut rekeyed = bson::Array::new();
+
+    for mail in mails {
+        let mut mail = mail
+            .as_document()
+            .ok_or_else(|| {
+                mails::MailError::UnexpectedDocumentError("mail is not a document".to_string())
+            })?
+            .clone();
+
+        let email_addr = mail.get_str("email_addr")?.to_string();
+        let sid_token = secrets::decrypt(old_key, &email_addr, mail.get_str("sid_token")?)?;
+
+        mail.insert("sid_token", new_key.encrypt(&email_addr, &sid_token));
+        rekeyed.push(bson::Bson::Document(mail));
+    }
+
+    Ok(Some(rekeyed))
+}
+
+/// Updates of `email_users` documents of every profile, with session tokens
+/// encrypted with `new_key`. Fails if any token of any profile does not decrypt
+fn rekey_profiles(
+    profiles: &[Vec<bson::Document>],
+    old_key: Option<&SecretKey>,
+    new_key: &SecretKey,
+) -> Result<Vec<Vec<(oid::ObjectId, bson::Array)>>, mails::MailError> {
+    profiles
+        .iter()
+        .map(|documents| {
+            let mut updates = Vec::new();
+            for document in documents {
+                if let Some(mails) = rekey_mails(document, old_key, new_key)? {
+                    updates.push((document.get_object_id("_id")?, mails));
+                }
+            }
+
+            Ok(updates)
+        })
+        .collect()
+}
+
+fn is_expiry_index(index: &IndexModel) -> bool {
+    index.keys.contains_key("createdAt")
+        && index
+            .options
+            .as_ref()
+            .is_some_and(|options| options.expire_after.is_some())
+}
+
+impl MongoStorage {
+    pub fn new(db: mongodb::Database, key: Option<SecretKey>) -> Self {
+        MongoStorage { db, key }
+    }
+}
+
+/// Every profile has its own MongoDB database
+pub struct MongoProfiles {
+    client: Client,
+    key: Option<SecretKey>,
+}
+
+impl MongoProfiles {
+    pub fn new(client: Client, key: Option<SecretKey>) -> Self {
+        MongoProfiles { client, key }
+    }
+
+    fn api_keys(&self) -> Collection<ApiKey> {
+        self.client
+            .database(ADMIN_DATABASE)
+            .collection::<ApiKey>("api_keys")
+    }
+}
+
+fn database_name(profile: &str) -> String {
+    if profile == storage::DEFAULT_PROFILE {
+        DATABASE.to_string()
+    } else {
+        format!("{DATABASE}_{profile}")
+    }
+}
+
+/// Profile stored in database `name`, None if it is not a database of the tool
+fn profile_name(name: &str) -> Option<String> {
+    match name.strip_prefix(DATABASE)? {
+        "" => Some(storage::DEFAULT_PROFILE.to_string()),
+        suffix => suffix.strip_prefix('_').map(str::to_string),
+    }
+}
+
+#[async_trait]
+impl storage::Profiles for MongoProfiles {
+    fn storage(&self, profile: &str) -> Arc<dyn Storage> {
+        let db = self.client.database(&database_name(profile));
+
+        Arc::new(MongoStorage::new(db, self.key.clone()))
+    }
+
+    async fn list(&self) -> Result<Vec<String>, mails::MailError> {
+        let mut profiles: Vec<String> = self
+            .client
+            .list_database_names(None, None)
+            .await?
+            .iter()
+            .filter_map(|name| profile_name(name))
+            .collect();
+        profiles.sort_unstable();
+
+        Ok(profiles)
+    }
+
+    async fn delete(&self, profile: &str) -> Result<(), mails::MailError> {
+        self.client
+            .database(&database_name(profile))
+            .drop(None)
+            .await?;
+
+        Ok(())
+    }
+
+    async fn rekey(&self, new_key: &SecretKey) -> Result<usize, mails::MailError> {
+        let mut collections = Vec::new();
+        let mut documents = Vec::new();
+
+        for profile in self.list().await? {
+            let email_users = self
+                .client
+                .database(&database_name(&profile))
+                .collection::<bson::Document>("email_users");
+
+            documents.push(
+                email_users
+                    .find(bson::doc! {}, None)
+                    .await?
+                    .try_collect()
+                    .await?,
+            );
+            collections.push(email_users);
+        }
+
+        // Every token of every profile is decrypted before anything is written,
+        // so a wrong old key leaves all databases untouched
+        let updates = rekey_profiles(&documents, self.key.as_ref(), new_key)?;
+
+        let mut count = 0;
+        for (email_users, updates) in collections.iter().zip(updates) {
+            for (id, mails) in updates {
+                count += mails.len();
+
+                email_users
+                    .update_one(
+                        bson::doc! { "_id": id },
+                        bson::doc! { "$set": { "mails": mails } },
+                        None,
+                    )
+                    .await?;
+            }
+        }
+
+        Ok(count)
+    }
+}
+
+#[async_trait]
+impl storage::KeyStore for MongoProfiles {
+    async fn save_api_key(&self, key: &ApiKey) -> Result<(), mails::MailError> {
+        self.api_keys().insert_one(key, None).await?;
+
+        Ok(())
+    }
+
+    async fn find_api_key(&self, id: &str) -> Result<Option<ApiKey>, mails::MailError> {
+        Ok(self
+            .api_keys()
+            .find_one(bson::doc! { "id": id }, None)
+            .await?)
+    }
+
+    async fn list_api_keys(&self) -> Result<Vec<ApiKey>, mails::MailError> {
+        let options = FindOptions::builder()
+            .sort(bson::doc! { "created_at": 1 })
+            .build();
+
+        Ok(self
+            .api_keys()
+            .find(bson::doc! {}, options)
+            .await?
+            .try_collect()
+            .await?)
+    }
+
+    async fn revoke_api_key(&self, id: &str) -> Result<bool, mails::MailError> {
+        let result = self
+            .api_keys()
+            .delete_one(bson::doc! { "id": id }, None)
+            .await?;
+
+        Ok(result.deleted_count > 0)
+    }
+}
+
+#[async_trait]
+impl Storage for MongoStorage {
+    async fn save_account(&self, account: &mails::Account) -> Result<(), mails::MailError> {
+        let email_users = self.db.collection::<bson::Document>("email_users");
+
+        create_index(&email_users).await?;
+
+        let document = bson::to_document(&user_from_account(account, self.key.as_ref()))?;
+
+        match email_users.insert_one(document, None).await {
+            Ok(_) => Ok(()),
+            Err(e) if is_duplicate_key(&e) => Err(mails::MailError::LabelError(format!(
+                "`{}` is already used by another address",
+                account.label.as_deref().unwrap_or_default()
+            ))),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn find_account(&self, email_addr: &str) -> Result<mails::Account, mails::MailError> {
+        let document = find_element_in_db(&self.db, "email_users", "mails.email_addr", email_addr)
+            .await?
+            .ok_or_else(|| mails::MailError::EmailCheckError(email_addr.to_string()))?;
+
+        account_from_document(document, email_addr, self.key.as_ref())
+    }
+
+    async fn list_addresses(&self) -> Result<Vec<String>, mails::MailError> {
+        mails::get_unexpired_guerrillamails_from_db(&self.db).await
+    }
+
+    async fn find_account_by_label(
+        &self,
+        label: &str,
+    ) -> Result<Option<mails::Account>, mails::MailError> {
+        let document = find_element_in_db(&self.db, "email_users", "mails.label", label).await?;
+
+        let document = match document {
+            Some(document) => document,
+            None => return Ok(None),
+        };
+
+        let email_addr = stored_addresses_from_document(document.clone())?
+            .into_iter()
+            .find(|address| address.label.as_deref() == Some(label))
+            .map(|address| address.email_addr)
+            .ok_or_else(|| mails::MailError::EmailCheckError(label.to_string()))?;
+
+        account_from_document(document, &email_addr, self.key.as_ref()).map(Some)
+    }
+
+    async fn find_addresses(
+        &self,
+        tag: Option<&str>,
+    ) -> Result<Vec<storage::StoredAddress>, mails::MailError> {
+        let filter = match tag {
+            Some(tag) => bson::doc! { "mails.tags": tag },
+            None => bson::doc! {},
+        };
+
+        let documents: Vec<bson::Document> = self
+            .db
+            .collection::<bson::Document>("email_users")
+            .find(filter, None)
+            .await?
+            .try_collect()
+            .await?;
+
+        let mut addresses = Vec::new();
+        for document in documents {
+            addresses.extend(
+                stored_addresses_from_document(document)?
+                    .into_iter()
+                    .filter(|address| tag.is_none_or(|tag| address.tags.iter().any(|t| t == tag))),
+            );
+        }
+
+        Ok(addresses)
+    }
+
+    async fn cache_messages(&self, messages: &[mails::Message]) -> Result<(), mails::MailError> {
+        cache_messages(&self.db, messages).await
+    }
+
+    async fn find_messages(
+        &self,
+        query: &SearchQuery,
+    ) -> Result<Vec<mails::Message>, mails::MailError> {
+        find_messages(&self.db, query.filter()).await
+    }
+
+    async fn find_message(
+        &self,
+        email_addr: &str,
+        mail_id: &str,
+    ) -> Result<Option<mails::Message>, mails::MailError> {
+        let filter = bson::doc! { "email_addr": email_addr, "mail_id": mail_id };
+
+        Ok(find_messages(&self.db, filter).await?.into_iter().next())
+    }
+
+    async fn save_raw_message(
+        &self,
+        email_addr: &str,
+        mail_id: &str,
+        raw: &[u8],
+    ) -> Result<(), mails::MailError> {
+        let collection = self.db.collection::<bson::Document>("raw_messages");
+
+        let filter = bson::doc! { "email_addr": email_addr, "mail_id": mail_id };
+        let raw = bson::Binary {
+            subtype: bson::spec::BinarySubtype::Generic,
+            bytes: raw.to_vec(),
+        };
+        let update = bson::doc! { "$set": { "raw": raw } };
+        let options = UpdateOptions::builder().upsert(true).build();
+
+        collection.update_one(filter, update, options).await?;
+
+        Ok(())
+    }
+
+    async fn find_raw_message(
+        &self,
+        email_addr: &str,
+        mail_id: &str,
+    ) -> Result<Option<Vec<u8>>, mails::MailError> {
+        let filter = bson::doc! { "email_addr": email_addr, "mail_id": mail_id };
+
+        let document = self
+            .db
+            .collection::<bson::Document>("raw_messages")
+            .find_one(filter, None)
+            .await?;
+
+        match document {
+            Some(document) => Ok(Some(document.get_binary_generic("raw")?.clone())),
+            None => Ok(None),
+        }
+    }
+
+    async fn delete_message(
+        &self,
+        email_addr: &str,
+        mail_id: &str,
+    ) -> Result<(), mails::MailError> {
+        let filter = bson::doc! { "email_addr": email_addr, "mail_id": mail_id };
+
+        for collection in ["messages", "raw_messages"] {
+            self.db
+                .collection::<bson::Document>(collection)
+                .delete_one(filter.clone(), None)
+                .await?;
+        }
+
+        Ok(())
+    }
+
+    async fn next_message_id(&self, email_addr: &str) -> Result<u32, mails::MailError> {
+        // Counter is increased in a single write, so concurrent deliveries get different ids
+        let options = FindOneAndUpdateOptions::builder()
+            .upsert(true)
+            .return_document(ReturnDocument::After)
+            .build();
+
+        let counter = self
+            .db
+            .collection::<bson::Document>("message_ids")
+            .find_one_and_update(
+                bson::doc! { "_id": email_addr },
+                bson::doc! { "$inc": { "last_id": 1 } },
+                options,
+            )
+            .await?
+            .ok_or_else(|| {
+                mails::MailError::UnexpectedDocumentError("message id was not counted".to_string())
+            })?;
+
+        Ok(counter.get_i32("last_id")? as u32)
+    }
+
+    async fn save_health(&self, health: &storage::ProviderHealth) -> Result<(), mails::MailError> {
+        let collection = self.db.collection::<bson::Document>("provider_health");
+
+        let filter = bson::doc! { "provider": &health.provider };
+        let update = bson::doc! { "$set": bson::to_document(health)? };
+        let options = UpdateOptions::builder().upsert(true).build();
+
+        collection.update_one(filter, update, options).await?;
+
+        Ok(())
+    }
+
+    async fn find_health(
+        &self,
+        provider: &str,
+    ) -> Result<Option<storage::ProviderHealth>, mails::MailError> {
+        let health = self
+            .db
+            .collection::<storage::ProviderHealth>("provider_health")
+            .find_one(bson::doc! { "provider": provider }, None)
+            .await?;
+
+        Ok(health)
+    }
+
+    async fn ping(&self) -> Result<(), mails::MailError> {
+        self.db.run_command(bson::doc! { "ping": 1 }, None).await?;
+
+        Ok(())
+    }
+
+    async fn check_expiry(&self) -> Result<(), mails::MailError> {
+        // Index is created together with the collection, when first address is saved
+        let collections = self
+            .db
+            .list_collection_names(bson::doc! { "name": "email_users" })
+            .await?;
+        if collections.is_empty() {
+            return Ok(());
+        }
+
+        let indexes: Vec<IndexModel> = self
+            .db
+            .collection::<bson::Document>("email_users")
+            .list_indexes(None)
+            .await?
+            .try_collect()
+            .await?;
+
+        if indexes.iter().any(is_expiry_index) {
+            Ok(())
+        } else {
+            Err(mails::MailError::UnexpectedDocumentError(
+                "email_users has no TTL index on createdAt, so addresses never expire".to_string(),
+            ))
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_account_document_round_trip() {
+        let account = mails::Account {
+            label: Some("admin".to_string()),
+            tags: vec!["signup".to_string()],
+            proxy: Some(1),
+            ..mails::Account::test("memory", "abc@memory.test")
+        };
+
+        let document = bson::to_document(&user_from_account(&account, None)).unwrap();
+
+        assert_eq!(document.get_str("name").unwrap(), "memory");
+        assert_eq!(
+            account_from_document(document.clone(), "abc@memory.test", None).unwrap(),
+            account
+        );
+        assert!(matches!(
+            account_from_document(document, "other@memory.test", None),
+            Err(mails::MailError::EmailCheckError(_))
+        ));
+    }
+
+    #[test]
+    fn test_encrypted_token_and_rekey() {
+        let account = mails::Account::test("guerrillamail", "abc@guerrillamail.com");
+        let key = SecretKey::generate();
+
+        let document = bson::to_document(&user_from_account(&account, Some(&key))).unwrap();
+        assert!(!document.to_string().contains("\"token\""));
+        assert_eq!(
+            account_from_document(document.clone(), "abc@guerrillamail.com", Some(&key)).unwrap(),
+            account
+        );
+        assert!(matches!(
+            account_from_document(document.clone(), "abc@guerrillamail.com", None),
+            Err(mails::MailError::SecretKeyError(_))
+        ));
+
+        let new_key = SecretKey::generate();
+        assert!(rekey_mails(&document, Some(&new_key), &new_key).is_err());
+
+        let mut rekeyed = document.clone();
+        rekeyed.insert(
+            "mails",
+            rekey_mails(&document, Some(&key), &new_key)
+                .unwrap()
+                .unwrap(),
+        );
+        assert_eq!(
+            account_from_document(rekeyed, "abc@guerrillamail.com", Some(&new_key)).unwrap(),
+            account
+        );
+    }
+
+    #[test]
+    fn test_rekey_checks_every_profile_first() {
+        let key = SecretKey::generate();
+        let new_key = SecretKey::generate();
+
+        let document = |email_addr: &str, key: &SecretKey| {
+            let account = mails::Account::test("guerrillamail", email_addr);
+            let mut document = bson::to_document(&user_from_account(&account, Some(key))).unwrap();
+            document.insert("_id", oid::ObjectId::new());
+
+            document
+        };
+
+        let default = vec![document("a@guerrillamail.com", &key)];
+        let qa = vec![
+            document("b@guerrillamail.com", &key),
+            document("c@guerrillamail.com", &key),
+        ];
+
+        let updates = rekey_profiles(&[default.clone(), qa.clone()], Some(&key), &new_key).unwrap();
+        assert_eq!(updates.len(), 2);
+        assert_eq!(updates[0].len(), 1);
+        assert_eq!(updates[1].len(), 2);
+
+        // Token of the second profile was encrypted with another key
+        let broken = vec![document("d@guerrillamail.com", &new_key)];
+        assert!(matches!(
+            rekey_profiles(&[default, broken], Some(&key), &new_key),
+            Err(mails::MailError::SecretKeyError(_))
+        ));
+    }
+
+    #[test]
+    fn test_profile_databases() {
+        assert_eq!(database_name("default"), "disposable_mail_db");
+        assert_eq!(database_name("qa-team"), "disposable_mail_db_qa-team");
+
+        for profile in ["default", "qa-team"] {
+            assert_eq!(
+                profile_name(&database_name(profile)).as_deref(),
+                Some(profile)
+            );
+        }
+        assert_eq!(profile_name("admin"), None);
+        assert_eq!(profile_name("disposable_mail_dbx"), None);
+    }
+
+    #[test]
+    fn test_duplicate_key() {
+        let write_error = |code: i32| -> mongodb::error::Error {
+            let error =
+                bson::from_document(bson::doc! { "code": code, "errmsg": "E11000" }).unwrap();
+            ErrorKind::Write(WriteFailure::WriteError(error)).into()
+        };
+
+        assert!(is_duplicate_key(&write_error(DUPLICATE_KEY)));
+        assert!(!is_duplicate_key(&write_error(121)));
+    }
+
+    #[test]
+    fn test_expiry_index() {
+        let expiring = IndexModel::builder()
+            .keys(bson::doc! { "createdAt": 1 })
+            .options(
+                IndexOptions::builder()
+                    .expire_after(Some(storage::ADDRESS_LIFETIME))
+                    .build(),
+            )
+            .build();
+        let plain = IndexModel::builder()
+            .keys(bson::doc! { "createdAt": 1 })
+            .build();
+
+        assert!(is_expiry_index(&expiring));
+        assert!(!is_expiry_index(&plain));
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    #[ignore = "needs running MongoDB"]
+    async fn test_find_element_in_db() -> Result<(), mails::MailError> {
+        let mongodb_client = connect("mongodb://localhost", "27017").await?;
+        let db = mongodb_client.database("disposable_mail_db");
+
+        let found =
+            find_element_in_db(&db, "email_users", "mails.email_addr", "some_value").await?;
+
+        assert_eq!(found, None);
+
+        Ok(())
+    }
+
     #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
     async fn test_db_connection() -> Result<(), mongodb::error::Error> {
         let client = connect("mongodb://localhost", "27017").await;
@@ -0,0 +1,309 @@
+use chrono::prelude::*;
+use owo_colors::colors::*;
+use owo_colors::OwoColorize;
+use serde::Serialize;
+
+use std::future::Future;
+use std::time::{Duration, Instant};
+
+use crate::config::Config;
+use crate::context::Context;
+use crate::http;
+use crate::mails::{Account, ErrorCategory, MailError};
+use crate::storage::ProviderHealth;
+
+/// Check fails if it takes longer, so unreachable MongoDB does not hang doctor
+const CHECK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Result of one check
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Check {
+    pub name: String,
+    pub passed: bool,
+    pub latency_ms: u128,
+    pub error: Option<String>,
+    #[serde(skip)]
+    category: Option<ErrorCategory>,
+}
+
+/// Results of every check, in the order they ran
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Report {
+    pub healthy: bool,
+    pub checks: Vec<Check>,
+}
+
+impl Report {
+    fn new(checks: Vec<Check>) -> Self {
+        Report {
+            healthy: checks.iter().all(|check| check.passed),
+            checks,
+        }
+    }
+
+    /// Error with exit code of the first failed check, None if every check passed
+    pub fn error(&self) -> Option<MailError> {
+        let failed: Vec<&Check> = self.checks.iter().filter(|check| !check.passed).collect();
+
+        let category = failed.first()?.category?;
+
+        Some(MailError::HealthCheckError(failed.len(), category))
+    }
+}
+
+/// Checks config file, then storage and providers of context built from it
+pub async fn run(
+    config_path: Option<&str>,
+    provider: Option<&str>,
+    profile: Option<&str>,
+) -> Report {
+    let started = Instant::now();
+
+    let context = match Config::load(config_path) {
+        Ok(config) => {
+            http::init(&config);
+
+            Context::new(&config, provider, profile).await
+        }
+        Err(e) => Err(e),
+    };
+
+    let config_check = finish("config", started, context.as_ref().map(|_| ()));
+
+    match context {
+        Ok(ctx) => {
+            let mut report = check_context(&ctx).await;
+            report.checks.insert(0, config_check);
+
+            Report::new(report.checks)
+        }
+        Err(_) => Report::new(vec![config_check]),
+    }
+}
+
+/// Checks storage connectivity, expiry of stored addresses and every provider.
+/// Results of provider checks are saved as their health
+pub async fn check_context(ctx: &Context) -> Report {
+    let storage = ErrorCategory::StorageUnreachable;
+    let mut checks = vec![timed("storage", storage, ctx.storage.ping()).await];
+
+    let storage_reachable = checks[0].passed;
+    if storage_reachable {
+        checks.push(timed("storage expiry index", storage, ctx.storage.check_expiry()).await);
+    }
+
+    for name in ctx.provider_names() {
+        let check_name = format!("provider {name}");
+        let check = match ctx.provider(name) {
+            Ok(provider) => {
+                let account = if storage_reachable {
+                    stored_account(ctx, name).await
+                } else {
+                    None
+                };
+
+                timed(
+                    &check_name,
+                    ErrorCategory::ProviderDown,
+                    provider.check_health(account.as_ref()),
+                )
+                .await
+            }
+            Err(e) => finish(&check_name, Instant::now(), Err(&e)),
+        };
+
+        if storage_reachable {
+            ctx.storage
+                .save_health(&ProviderHealth {
+                    provider: name.to_string(),
+                    healthy: check.passed,
+                    checked_at: Utc::now(),
+                    error: check.error.clone(),
+                })
+                .await
+                .ok();
+        }
+
+        checks.push(check);
+    }
+
+    Report::new(checks)
+}
+
+/// Newest unexpired address of `provider`, whose inbox is checked instead of creating one
+async fn stored_account(ctx: &Context, provider: &str) -> Option<Account> {
+    let address = ctx
+        .storage
+        .find_addresses(None)
+        .await
+        .ok()?
+        .into_iter()
+        .filter(|address| address.provider == provider)
+        .max_by_key(|address| address.created_at)?;
+
+    ctx.storage.find_account(&address.email_addr).await.ok()
+}
+
+/// Runs `check`, which fails with `timeout_category` if it does not finish in time
+async fn timed<F>(name: &str, timeout_category: ErrorCategory, check: F) -> Check
+where
+    F: Future<Output = Result<(), MailError>>,
+{
+    let started = Instant::now();
+
+    match tokio::time::timeout(CHECK_TIMEOUT, check).await {
+        Ok(result) => finish(name, started, result.as_ref().map(|_| ())),
+        Err(_) => Check {
+            name: name.to_string(),
+            passed: false,
+            latency_ms: started.elapsed().as_millis(),
+            error: Some(format!("no answer in {} s", CHECK_TIMEOUT.as_secs())),
+            category: Some(timeout_category),
+        },
+    }
+}
+
+fn finish(name: &str, started: Instant, result: Result<(), &MailError>) -> Check {
+    Check {
+        name: name.to_string(),
+        passed: result.is_ok(),
+        latency_ms: started.elapsed().as_millis(),
+        error: result.err().map(describe),
+        category: result.err().map(MailError::category),
+    }
+}
+
+/// Error message followed by its causes, which tell what exactly failed
+fn describe(error: &MailError) -> String {
+    let mut description = error.to_string();
+
+    let mut source = std::error::Error::source(error);
+    while let Some(cause) = source {
+        // Some errors already include their causes in the message
+        let cause_message = cause.to_string();
+        if !description.contains(&cause_message) {
+            description.push_str(&format!(": {cause_message}"));
+        }
+        source = cause.source();
+    }
+
+    description
+}
+
+pub fn print_report(report: &Report) {
+    for check in &report.checks {
+        let status = if check.passed {
+            "PASS".fg::<Green>().to_string()
+        } else {
+            "FAIL".fg::<Red>().to_string()
+        };
+
+        match &check.error {
+            Some(error) => println!("{status} {} ({} ms): {error}", check.name, check.latency_ms),
+            None => println!("{status} {} ({} ms)", check.name, check.latency_ms),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::mails::MemoryProvider;
+    use crate::mock;
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_memory_context_is_healthy() -> Result<(), MailError> {
+        mock::init_test_provider();
+
+        let ctx = Context::in_memory(MemoryProvider::default());
+
+        let report = check_context(&ctx).await;
+
+        let names: Vec<&str> = report.checks.iter().map(|c| c.name.as_str()).collect();
+        assert_eq!(
+            names,
+            [
+                "storage",
+                "storage expiry index",
+                "provider guerrillamail",
+                "provider local",
+                "provider memory"
+            ]
+        );
+        assert!(report.healthy, "{report:?}");
+        assert!(report.error().is_none());
+        assert!(ctx.storage.find_health("memory").await?.unwrap().healthy);
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_provider_check_lists_stored_address() -> Result<(), MailError> {
+        mock::init_test_provider();
+
+        let memory = MemoryProvider::default();
+        let ctx = Context::in_memory(memory.clone());
+        let account = ctx.provider("memory")?.create_address().await?;
+        ctx.storage.save_account(&account).await?;
+
+        let check = |report: &Report| {
+            report
+                .checks
+                .iter()
+                .find(|check| check.name == "provider memory")
+                .cloned()
+                .unwrap()
+        };
+        assert!(check(&check_context(&ctx).await).passed);
+
+        // Address the provider does not know fails the check, so its inbox was listed
+        let mut unknown = account.clone();
+        unknown.email_addr = "unknown@memory.test".to_string();
+        unknown.created_at = account.created_at + chrono::Duration::seconds(1);
+        ctx.storage.save_account(&unknown).await?;
+
+        assert!(!check(&check_context(&ctx).await).passed);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_failed_check_sets_exit_code() {
+        let report = Report::new(vec![
+            finish("config", Instant::now(), Ok(())),
+            finish(
+                "storage",
+                Instant::now(),
+                Err(&MailError::UnexpectedDocumentError("mails".to_string())),
+            ),
+        ]);
+
+        assert!(!report.healthy);
+        assert!(matches!(
+            report.error(),
+            Some(MailError::HealthCheckError(
+                1,
+                ErrorCategory::StorageCorrupt
+            ))
+        ));
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_invalid_config_is_reported() {
+        let path = std::env::temp_dir().join("disposable_mail_doctor_test.toml");
+        std::fs::write(&path, "[http]\ntimeout = 5").unwrap();
+
+        let report = run(path.to_str(), Some("memory"), None).await;
+
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(report.checks.len(), 1);
+        assert_eq!(report.checks[0].name, "config");
+        assert!(!report.healthy);
+        assert_eq!(
+            report.error().map(|e| e.category()),
+            Some(ErrorCategory::BadInput)
+        );
+    }
+}
@@ -0,0 +1,115 @@
+use chrono::prelude::*;
+use serde::Serialize;
+
+use crate::mails::{ErrorCategory, MailError, Message};
+use crate::storage::StoredAddress;
+
+/// Version of event schema. Increased when a field is removed or changes its
+/// meaning. New fields and event types do not change it
+pub const SCHEMA_VERSION: u32 = 1;
+
+/// Something that happened to addresses of a profile, serialized as one JSON object
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Event {
+    pub version: u32,
+    pub at: DateTime<Utc>,
+    pub profile: String,
+    #[serde(flatten)]
+    pub kind: EventKind,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum EventKind {
+    AddressCreated(StoredAddress),
+    MessageReceived(Message),
+    AddressExpired {
+        email_addr: String,
+    },
+    /// Provider failed, the address is checked again later
+    ProviderError {
+        provider: String,
+        email_addr: Option<String>,
+        error: String,
+        category: ErrorCategory,
+    },
+}
+
+impl EventKind {
+    /// Value of `type` field
+    pub fn name(&self) -> &'static str {
+        match self {
+            EventKind::AddressCreated(_) => "address_created",
+            EventKind::MessageReceived(_) => "message_received",
+            EventKind::AddressExpired { .. } => "address_expired",
+            EventKind::ProviderError { .. } => "provider_error",
+        }
+    }
+}
+
+impl Event {
+    pub fn new(profile: &str, kind: EventKind) -> Self {
+        Event {
+            version: SCHEMA_VERSION,
+            at: Utc::now(),
+            profile: profile.to_string(),
+            kind,
+        }
+    }
+
+    pub fn provider_error(
+        profile: &str,
+        provider: &str,
+        email_addr: Option<&str>,
+        error: &MailError,
+    ) -> Self {
+        Event::new(
+            profile,
+            EventKind::ProviderError {
+                provider: provider.to_string(),
+                email_addr: email_addr.map(str::to_string),
+                error: error.to_string(),
+                category: error.category(),
+            },
+        )
+    }
+
+    /// Address the event is about, if any
+    pub fn email_addr(&self) -> Option<&str> {
+        match &self.kind {
+            EventKind::AddressCreated(address) => Some(&address.email_addr),
+            EventKind::MessageReceived(message) => Some(&message.email_addr),
+            EventKind::AddressExpired { email_addr } => Some(email_addr),
+            EventKind::ProviderError { email_addr, .. } => email_addr.as_deref(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_event_schema() {
+        let event = Event::provider_error(
+            "qa",
+            "guerrillamail",
+            Some("abc@guerrillamail.com"),
+            &MailError::ResponseError(reqwest::StatusCode::BAD_GATEWAY),
+        );
+
+        let json = serde_json::to_value(&event).unwrap();
+
+        assert_eq!(json["version"], 1);
+        assert_eq!(json["type"], event.kind.name());
+        assert_eq!(json["profile"], "qa");
+        assert_eq!(json["email_addr"], "abc@guerrillamail.com");
+        assert_eq!(json["category"], "provider_down");
+        assert_eq!(event.email_addr(), Some("abc@guerrillamail.com"));
+        assert!(json["at"]
+            .as_str()
+            .unwrap()
+            .parse::<DateTime<Utc>>()
+            .is_ok());
+    }
+}
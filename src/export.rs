@@ -0,0 +1,231 @@
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+
+use crate::mails::{Attachment, MailError, Message};
+
+/// Email together with downloaded attachments
+pub type ExportedMessage = (Message, Vec<Attachment>);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ExportFormat {
+    Eml,
+    Mbox,
+    Json,
+}
+
+impl FromStr for ExportFormat {
+    type Err = MailError;
+
+    fn from_str(format: &str) -> Result<Self, Self::Err> {
+        match format {
+            "eml" => Ok(ExportFormat::Eml),
+            "mbox" => Ok(ExportFormat::Mbox),
+            "json" => Ok(ExportFormat::Json),
+            _ => Err(MailError::ExportFormatError(format.to_string())),
+        }
+    }
+}
+
+/// Writes messages to path. Eml export of a `single` email asked for by id
+/// creates a file, otherwise a directory with one `<id>.eml` file per message,
+/// even if the inbox has one email or none
+pub fn write_messages(
+    format: ExportFormat,
+    messages: &[ExportedMessage],
+    path: &Path,
+    single: bool,
+) -> Result<(), MailError> {
+    match format {
+        ExportFormat::Eml if single => {
+            let (message, attachments) = messages
+                .first()
+                .ok_or_else(|| MailError::MessageNotFoundError(path.display().to_string()))?;
+            fs::write(path, message.to_rfc5322(attachments))
+                .map_err(MailError::file_error(path))?;
+        }
+        ExportFormat::Eml => {
+            fs::create_dir_all(path).map_err(MailError::file_error(path))?;
+
+            for (message, attachments) in messages {
+                let filename = format!("{}.eml", sanitize_filename(&message.mail_id));
+                let path = path.join(filename);
+                fs::write(&path, message.to_rfc5322(attachments))
+                    .map_err(MailError::file_error(&path))?;
+            }
+        }
+        ExportFormat::Mbox => {
+            fs::write(path, to_mbox(messages)).map_err(MailError::file_error(path))?
+        }
+        ExportFormat::Json => {
+            let messages: Vec<&Message> = messages.iter().map(|(message, _)| message).collect();
+            fs::write(path, serde_json::to_string_pretty(&messages)?)
+                .map_err(MailError::file_error(path))?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Creates mbox (mboxrd variant) with LF line endings
+pub fn to_mbox(messages: &[ExportedMessage]) -> String {
+    let mut mbox = String::new();
+
+    for (message, attachments) in messages {
+        mbox.push_str(&format!(
+            "From {} {}\n",
+            envelope_sender(&message.mail_from),
+            message.date().format("%a %b %e %H:%M:%S %Y")
+        ));
+
+        for line in message.to_rfc5322(attachments).lines() {
+            mbox.push_str(&quote_from_line(line));
+            mbox.push('\n');
+        }
+
+        mbox.push('\n');
+    }
+
+    mbox
+}
+
+/// mboxrd quoting, so lines starting with "From " are not read as separators
+fn quote_from_line(line: &str) -> std::borrow::Cow<'_, str> {
+    if line.trim_start_matches('>').starts_with("From ") {
+        format!(">{line}").into()
+    } else {
+        line.into()
+    }
+}
+
+fn envelope_sender(mail_from: &str) -> String {
+    let address = match (mail_from.find('<'), mail_from.rfind('>')) {
+        (Some(start), Some(end)) if start < end => &mail_from[start + 1..end],
+        _ => mail_from,
+    };
+
+    match address.split_whitespace().next() {
+        Some(address) => address.to_string(),
+        None => "MAILER-DAEMON".to_string(),
+    }
+}
+
+fn sanitize_filename(name: &str) -> String {
+    name.chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(mail_id: &str, mail_body: &str) -> Message {
+        Message {
+            email_addr: "abc@guerrillamail.com".to_string(),
+            mail_id: mail_id.to_string(),
+            mail_from: "GitHub <noreply@github.com>".to_string(),
+            mail_subject: "Verify your email".to_string(),
+            mail_excerpt: String::new(),
+            mail_body: Some(mail_body.to_string()),
+            mail_content_type: None,
+            mail_timestamp: 1648372800,
+        }
+    }
+
+    #[test]
+    fn test_export_format_from_str() {
+        assert!(matches!(
+            "mbox".parse::<ExportFormat>(),
+            Ok(ExportFormat::Mbox)
+        ));
+        assert!(matches!(
+            "pdf".parse::<ExportFormat>(),
+            Err(MailError::ExportFormatError(format)) if format == "pdf"
+        ));
+    }
+
+    #[test]
+    fn test_to_mbox() {
+        let messages = vec![
+            (message("1", "first"), Vec::new()),
+            (message("2", "second"), Vec::new()),
+        ];
+
+        let mbox = to_mbox(&messages);
+
+        assert!(mbox.starts_with("From noreply@github.com Sun Mar 27 09:20:00 2022\n"));
+        assert_eq!(mbox.matches("\nFrom noreply@github.com ").count(), 1);
+        assert!(!mbox.contains('\r'));
+    }
+
+    #[test]
+    fn test_mboxrd_quotes_from_lines() {
+        assert_eq!(quote_from_line("From the team"), ">From the team");
+        assert_eq!(quote_from_line(">From the team"), ">>From the team");
+        assert_eq!(quote_from_line("Fromage"), "Fromage");
+        assert_eq!(quote_from_line(" From the team"), " From the team");
+
+        let body = "Thanks for signing up\nFrom the team\n>From quoted reply";
+        let mut plain = message("1", body);
+        plain.mail_content_type = Some("text/plain".to_string());
+        let messages = vec![(plain, Vec::new()), (message("2", "second"), Vec::new())];
+
+        let mbox = to_mbox(&messages);
+
+        // Only the two separators start with "From ", and the body survives
+        assert_eq!(
+            mbox.lines()
+                .filter(|line| line.starts_with("From "))
+                .count(),
+            2
+        );
+        let first = mbox.split("\n\nFrom ").next().unwrap();
+        let encoded: String = first
+            .split("\n\n")
+            .nth(1)
+            .unwrap()
+            .lines()
+            .map(|line| line.trim_start_matches('>'))
+            .collect();
+        assert_eq!(base64::decode(encoded).unwrap(), body.as_bytes());
+    }
+
+    #[test]
+    fn test_envelope_sender() {
+        assert_eq!(envelope_sender("noreply@github.com"), "noreply@github.com");
+        assert_eq!(
+            envelope_sender("GitHub <noreply@github.com>"),
+            "noreply@github.com"
+        );
+        assert_eq!(envelope_sender(""), "MAILER-DAEMON");
+    }
+
+    #[test]
+    fn test_write_eml_directory() -> Result<(), MailError> {
+        let dir =
+            std::env::temp_dir().join(format!("disposable_mail_export_{}", std::process::id()));
+        let messages = vec![
+            (message("1", "first"), Vec::new()),
+            (message("2", "second"), Vec::new()),
+        ];
+
+        write_messages(ExportFormat::Eml, &messages, &dir, false)?;
+
+        assert!(dir.join("1.eml").exists());
+        assert!(dir.join("2.eml").exists());
+
+        fs::remove_dir_all(&dir).map_err(MailError::file_error(&dir))?;
+
+        // Inbox with one email is still a directory, email asked for by id a file
+        write_messages(ExportFormat::Eml, &messages[..1], &dir, false)?;
+        assert!(dir.join("1.eml").is_file());
+        fs::remove_dir_all(&dir).map_err(MailError::file_error(&dir))?;
+
+        write_messages(ExportFormat::Eml, &messages[..1], &dir, true)?;
+        assert!(dir.is_file());
+        fs::remove_file(&dir).map_err(MailError::file_error(&dir))?;
+
+        Ok(())
+    }
+}
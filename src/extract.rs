@@ -0,0 +1,153 @@
+/// Finds verification code in email text. Numbers of 4 to 8 digits are
+/// codes, and the one closest after the word "code" wins if there are more
+pub fn extract_code(text: &str) -> Option<String> {
+    let text = html_to_text(text);
+    let lowercase = text.to_lowercase();
+
+    let mut codes: Vec<(usize, String)> = Vec::new();
+    let mut current = String::new();
+    let mut start = 0;
+
+    for (index, c) in text
+        .char_indices()
+        .chain(std::iter::once((text.len(), ' ')))
+    {
+        if c.is_ascii_digit() {
+            if current.is_empty() {
+                start = index;
+            }
+            current.push(c);
+            continue;
+        }
+
+        let previous_is_word = text[..start]
+            .chars()
+            .next_back()
+            .is_some_and(|c| c.is_alphanumeric());
+
+        if (4..=8).contains(&current.len()) && !previous_is_word && !c.is_alphanumeric() {
+            codes.push((start, current.clone()));
+        }
+
+        current.clear();
+    }
+
+    match lowercase.find("code") {
+        Some(keyword) => codes
+            .iter()
+            .find(|(start, _)| *start > keyword)
+            .or_else(|| codes.first())
+            .map(|(_, code)| code.to_owned()),
+        None => codes.first().map(|(_, code)| code.to_owned()),
+    }
+}
+
+/// Returns http and https links in order of appearance, without duplicates
+pub fn extract_links(text: &str) -> Vec<String> {
+    let mut links: Vec<String> = Vec::new();
+    let mut rest = text;
+
+    while let Some(start) = rest.find("http") {
+        let candidate = &rest[start..];
+
+        if !(candidate.starts_with("http://") || candidate.starts_with("https://")) {
+            rest = &candidate[4..];
+            continue;
+        }
+
+        let end = candidate
+            .find(|c: char| c.is_whitespace() || "\"'<>".contains(c))
+            .unwrap_or(candidate.len());
+
+        let link = candidate[..end]
+            .trim_end_matches(|c| ".,;)".contains(c))
+            .replace("&amp;", "&");
+
+        if !links.contains(&link) {
+            links.push(link);
+        }
+
+        rest = &candidate[end..];
+    }
+
+    links
+}
+
+/// Converts HTML email body to plain text good enough for terminal
+pub fn html_to_text(html: &str) -> String {
+    let mut text = String::with_capacity(html.len());
+    let mut tag = String::new();
+    let mut in_tag = false;
+
+    for c in html.chars() {
+        match c {
+            '<' => {
+                in_tag = true;
+                tag.clear();
+            }
+            '>' if in_tag => {
+                in_tag = false;
+
+                let name = tag
+                    .trim_start_matches('/')
+                    .split(|c: char| c.is_whitespace() || c == '/')
+                    .next()
+                    .unwrap_or("")
+                    .to_lowercase();
+
+                if ["br", "p", "div", "tr", "li", "h1", "h2", "h3"].contains(&name.as_str()) {
+                    text.push('\n');
+                }
+            }
+            _ if in_tag => tag.push(c),
+            _ => text.push(c),
+        }
+    }
+
+    text.replace("&nbsp;", " ")
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&amp;", "&")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_extract_code() {
+        assert_eq!(
+            extract_code("Order 2022 confirmed. Your verification code is 482913."),
+            Some("482913".to_string())
+        );
+        assert_eq!(
+            extract_code("<p>Use <b>7731</b> to sign in</p>"),
+            Some("7731".to_string())
+        );
+        assert_eq!(extract_code("Welcome to example.com, user a12345"), None);
+    }
+
+    #[test]
+    fn test_extract_links() {
+        let html = r#"<a href="https://example.com/verify?id=1&amp;t=2">Verify</a>
+            or visit https://example.com/help. Again: https://example.com/help"#;
+
+        assert_eq!(
+            extract_links(html),
+            vec![
+                "https://example.com/verify?id=1&t=2".to_string(),
+                "https://example.com/help".to_string(),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_html_to_text() {
+        assert_eq!(
+            html_to_text("<p>Hello&nbsp;<b>world</b></p>Bye &amp; thanks"),
+            "\nHello world\nBye & thanks"
+        );
+    }
+}
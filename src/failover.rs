@@ -0,0 +1,230 @@
+use chrono::prelude::*;
+
+use crate::context::Context;
+use crate::mails::{Account, MailError};
+use crate::storage::ProviderHealth;
+
+/// Creates address with the first provider in `providers` that succeeds.
+/// Providers whose last health check failed less than the cooldown ago
+/// are skipped, and tried last if the policy allows it.
+/// Every attempt is saved as health check of the provider
+pub async fn create_address(ctx: &Context, providers: &[String]) -> Result<Account, MailError> {
+    let cooldown = chrono::Duration::seconds(ctx.failover.cooldown_secs as i64);
+
+    let mut healthy = Vec::new();
+    let mut unhealthy = Vec::new();
+
+    for name in providers {
+        let provider = ctx.provider(name)?;
+
+        let failed_recently = ctx
+            .storage
+            .find_health(name)
+            .await?
+            .is_some_and(|health| !health.healthy && Utc::now() - health.checked_at < cooldown);
+
+        if failed_recently {
+            unhealthy.push((name, provider));
+        } else {
+            healthy.push((name, provider));
+        }
+    }
+
+    // Provider chosen by name is tried even if it failed recently
+    if ctx.failover.retry_unhealthy || providers.len() == 1 {
+        healthy.append(&mut unhealthy);
+    }
+
+    let skipped: Vec<&str> = unhealthy.iter().map(|(name, _)| name.as_str()).collect();
+    let mut last_error = MailError::ProvidersUnhealthyError(skipped.join(", "));
+
+    let attempts = healthy.len();
+
+    for (index, (name, provider)) in healthy.into_iter().enumerate() {
+        let result = provider.create_address().await;
+
+        ctx.storage
+            .save_health(&ProviderHealth {
+                provider: name.clone(),
+                healthy: result.is_ok(),
+                checked_at: Utc::now(),
+                error: result.as_ref().err().map(|e| e.to_string()),
+            })
+            .await?;
+
+        match result {
+            Ok(account) => return Ok(account),
+            Err(e) if index + 1 < attempts => {
+                eprintln!("Creating {name} email failed, trying the next provider: {e}");
+                last_error = e;
+            }
+            Err(e) => last_error = e,
+        }
+    }
+
+    Err(last_error)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use async_trait::async_trait;
+
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+
+    use crate::mails::{ErrorCategory, FetchedMessage, MemoryProvider, Message, Provider};
+
+    /// Memory provider which fails to create addresses while it is down
+    struct FlakyProvider {
+        name: &'static str,
+        memory: MemoryProvider,
+        down: AtomicBool,
+    }
+
+    impl FlakyProvider {
+        fn register(ctx: &mut Context, name: &'static str) -> Arc<FlakyProvider> {
+            let provider = Arc::new(FlakyProvider {
+                name,
+                memory: MemoryProvider::default(),
+                down: AtomicBool::new(false),
+            });
+
+            ctx.register(name, provider.clone());
+
+            provider
+        }
+
+        fn set_down(&self, down: bool) {
+            self.down.store(down, Ordering::SeqCst);
+        }
+    }
+
+    #[async_trait]
+    impl Provider for FlakyProvider {
+        async fn create_address(&self) -> Result<Account, MailError> {
+            if self.down.load(Ordering::SeqCst) {
+                return Err(MailError::UnexpectedResponseError(format!(
+                    "{} is down",
+                    self.name
+                )));
+            }
+
+            Ok(Account {
+                provider: self.name.to_string(),
+                ..self.memory.create_address().await?
+            })
+        }
+
+        async fn list_messages(
+            &self,
+            account: &Account,
+            offset: u32,
+        ) -> Result<Vec<Message>, MailError> {
+            self.memory.list_messages(account, offset).await
+        }
+
+        async fn check_messages(
+            &self,
+            account: &Account,
+            seq: u32,
+        ) -> Result<Vec<Message>, MailError> {
+            self.memory.check_messages(account, seq).await
+        }
+
+        async fn fetch_message(
+            &self,
+            account: &Account,
+            mail_id: &str,
+        ) -> Result<Option<FetchedMessage>, MailError> {
+            self.memory.fetch_message(account, mail_id).await
+        }
+
+        async fn delete_message(&self, account: &Account, mail_id: &str) -> Result<(), MailError> {
+            self.memory.delete_message(account, mail_id).await
+        }
+
+        async fn fetch_attachment(
+            &self,
+            account: &Account,
+            mail_id: &str,
+            part_id: &str,
+        ) -> Result<Vec<u8>, MailError> {
+            self.memory
+                .fetch_attachment(account, mail_id, part_id)
+                .await
+        }
+    }
+
+    fn order(names: &[&str]) -> Vec<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_failover_skips_recently_failed_provider() -> Result<(), MailError> {
+        let mut ctx = Context::in_memory(MemoryProvider::default());
+        let first = FlakyProvider::register(&mut ctx, "first");
+        FlakyProvider::register(&mut ctx, "second");
+
+        first.set_down(true);
+        let account = create_address(&ctx, &order(&["first", "second"])).await?;
+        assert_eq!(account.provider, "second");
+
+        let health = ctx.storage.find_health("first").await?.unwrap();
+        assert!(!health.healthy);
+        assert_eq!(
+            health.error.as_deref(),
+            Some("Unexpected response from email provider: first is down")
+        );
+
+        // First provider is up again, but it is skipped until the cooldown ends
+        first.set_down(false);
+        let account = create_address(&ctx, &order(&["first", "second"])).await?;
+        assert_eq!(account.provider, "second");
+
+        ctx.failover.cooldown_secs = 0;
+        let account = create_address(&ctx, &order(&["first", "second"])).await?;
+        assert_eq!(account.provider, "first");
+        assert!(ctx.storage.find_health("first").await?.unwrap().healthy);
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_unhealthy_provider_is_last_resort() -> Result<(), MailError> {
+        let mut ctx = Context::in_memory(MemoryProvider::default());
+        let first = FlakyProvider::register(&mut ctx, "first");
+        let second = FlakyProvider::register(&mut ctx, "second");
+
+        first.set_down(true);
+        create_address(&ctx, &order(&["first", "second"])).await?;
+
+        // Only the skipped provider is up
+        first.set_down(false);
+        second.set_down(true);
+        let account = create_address(&ctx, &order(&["first", "second"])).await?;
+        assert_eq!(account.provider, "first");
+
+        // Without retries, providers that failed recently are not tried at all
+        first.set_down(true);
+        second.set_down(false);
+        create_address(&ctx, &order(&["first", "second"])).await?;
+        ctx.failover.retry_unhealthy = false;
+        first.set_down(false);
+        second.set_down(true);
+        assert!(matches!(
+            create_address(&ctx, &order(&["second", "first"])).await,
+            Err(MailError::UnexpectedResponseError(_))
+        ));
+        let error = create_address(&ctx, &order(&["first", "second"]))
+            .await
+            .unwrap_err();
+        assert!(
+            matches!(error, MailError::ProvidersUnhealthyError(ref skipped) if skipped == "first, second")
+        );
+        assert_eq!(error.category(), ErrorCategory::ProviderDown);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,580 @@
+use rand::Rng;
+use reqwest::{Client, NoProxy, Proxy, RequestBuilder, Response, StatusCode};
+use tokio::time::Instant;
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::config::{Config, HttpConfig, ProviderConfig};
+
+static CLIENTS: Mutex<Option<Clients>> = Mutex::new(None);
+
+struct Clients {
+    config: Config,
+    providers: HashMap<String, Arc<HttpClient>>,
+}
+
+/// Configures clients returned by `client`. Clients created
+/// before the call keep the old configuration
+pub fn init(config: &Config) {
+    let mut clients = CLIENTS.lock().unwrap_or_else(|e| e.into_inner());
+
+    *clients = Some(Clients {
+        config: config.clone(),
+        providers: HashMap::new(),
+    });
+}
+
+/// Returns HTTP client of the provider. Every call for the same
+/// provider returns the same client, so they share the rate limit
+pub fn client(provider: &str) -> Result<Arc<HttpClient>, reqwest::Error> {
+    let mut clients = CLIENTS.lock().unwrap_or_else(|e| e.into_inner());
+
+    let clients = clients.get_or_insert_with(|| Clients {
+        config: Config::default(),
+        providers: HashMap::new(),
+    });
+
+    if let Some(client) = clients.providers.get(provider) {
+        return Ok(client.clone());
+    }
+
+    let client = Arc::new(HttpClient::new(
+        &clients.config.http,
+        provider,
+        &clients.config.provider(provider),
+    )?);
+
+    clients
+        .providers
+        .insert(provider.to_string(), client.clone());
+
+    Ok(client)
+}
+
+/// Minimum time between two requests if config does not set it
+fn default_rate_limit(provider: &str) -> Duration {
+    match provider {
+        "guerrillamail" => Duration::from_millis(500),
+        _ => Duration::ZERO,
+    }
+}
+
+/// HTTP client with timeouts, rate limit and retries
+/// with jittered exponential backoff
+pub struct HttpClient {
+    /// One client per proxy of the pool, or a single client
+    clients: Vec<Client>,
+    next_client: AtomicUsize,
+    base_url: Option<String>,
+    max_retries: u32,
+    initial_backoff: Duration,
+    max_backoff: Duration,
+    rate_limiter: RateLimiter,
+}
+
+impl HttpClient {
+    pub fn new(
+        http: &HttpConfig,
+        provider: &str,
+        provider_config: &ProviderConfig,
+    ) -> Result<Self, reqwest::Error> {
+        let clients = match provider_config.proxy_pool.as_slice() {
+            [] => vec![build_client(http, provider_config.proxy.as_deref())?],
+            pool => pool
+                .iter()
+                .map(|proxy| build_client(http, Some(proxy)))
+                .collect::<Result<_, _>>()?,
+        };
+
+        let rate_limit = match provider_config.rate_limit_ms {
+            Some(rate_limit) => Duration::from_millis(rate_limit),
+            None => default_rate_limit(provider),
+        };
+
+        Ok(HttpClient {
+            clients,
+            next_client: AtomicUsize::new(0),
+            base_url: provider_config
+                .base_url
+                .as_ref()
+                .map(|url| url.trim_end_matches('/').to_string()),
+            max_retries: http.max_retries,
+            initial_backoff: Duration::from_millis(http.initial_backoff_ms),
+            max_backoff: Duration::from_millis(http.max_backoff_ms),
+            rate_limiter: RateLimiter::new(rate_limit),
+        })
+    }
+
+    /// Provider URL from config, or `default` if config does not override it
+    pub fn base_url<'a>(&'a self, default: &'a str) -> &'a str {
+        self.base_url.as_deref().unwrap_or(default)
+    }
+
+    /// Proxy of the pool the next new address is created through
+    pub fn next_proxy(&self) -> u32 {
+        (self.next_client.fetch_add(1, Ordering::Relaxed) % self.clients.len()) as u32
+    }
+
+    /// Sends request through the next proxy of the pool.
+    /// Used for requests not belonging to an address
+    pub async fn send<F>(&self, request: F) -> Result<Response, reqwest::Error>
+    where
+        F: Fn(&Client) -> RequestBuilder,
+    {
+        self.send_through(self.next_proxy(), request).await
+    }
+
+    /// Sends request through proxy `proxy` of the pool, as returned by `next_proxy`
+    pub async fn send_through<F>(&self, proxy: u32, request: F) -> Result<Response, reqwest::Error>
+    where
+        F: Fn(&Client) -> RequestBuilder,
+    {
+        self.send_with(&self.clients[proxy as usize % self.clients.len()], request)
+            .await
+    }
+
+    /// Sends request of an existing address through `proxy` its address was created
+    /// through. Addresses stored without it use a proxy chosen by `session`, so their
+    /// requests also always go through the same proxy of the pool
+    pub async fn send_in_session<F>(
+        &self,
+        session: &str,
+        proxy: Option<u32>,
+        request: F,
+    ) -> Result<Response, reqwest::Error>
+    where
+        F: Fn(&Client) -> RequestBuilder,
+    {
+        self.send_through(self.session_proxy(session, proxy), request)
+            .await
+    }
+
+    /// Sends request of an existing address like `send_in_session`, but only once.
+    /// Used for requests which must not be repeated, like sending an email
+    pub async fn send_once_in_session(
+        &self,
+        session: &str,
+        proxy: Option<u32>,
+        request: impl FnOnce(&Client) -> RequestBuilder,
+    ) -> Result<Response, reqwest::Error> {
+        let proxy = self.session_proxy(session, proxy) as usize;
+
+        self.rate_limiter.wait().await;
+
+        request(&self.clients[proxy % self.clients.len()])
+            .send()
+            .await
+    }
+
+    /// `proxy` of the address, or one chosen by `session` for addresses stored without it
+    fn session_proxy(&self, session: &str, proxy: Option<u32>) -> u32 {
+        proxy.unwrap_or_else(|| (fnv1a(session.as_bytes()) % self.clients.len() as u64) as u32)
+    }
+
+    /// Sends request built by `request`, which is called again for every retry.
+    /// Connection errors, timeouts, 429 and 5xx gateway errors are retried,
+    /// after that the last response or error is returned
+    async fn send_with<F>(&self, client: &Client, request: F) -> Result<Response, reqwest::Error>
+    where
+        F: Fn(&Client) -> RequestBuilder,
+    {
+        let mut attempt = 0;
+
+        loop {
+            self.rate_limiter.wait().await;
+
+            let result = request(client).send().await;
+
+            let retry_after = match &result {
+                Ok(response) if is_retryable_status(response.status()) => retry_after(response),
+                Err(e) if e.is_timeout() || e.is_connect() => None,
+                _ => return result,
+            };
+
+            if attempt >= self.max_retries {
+                return result;
+            }
+
+            let delay = retry_after
+                .map(|delay| delay.min(self.max_backoff))
+                .unwrap_or_else(|| {
+                    backoff_delay(
+                        attempt,
+                        self.initial_backoff,
+                        self.max_backoff,
+                        rand::thread_rng().gen(),
+                    )
+                });
+
+            tokio::time::sleep(delay).await;
+
+            attempt += 1;
+        }
+    }
+}
+
+/// Client going through `proxy`. Without it, proxy from environment
+/// variables like `HTTPS_PROXY` is used. `NO_PROXY` is honored in both cases
+fn build_client(http: &HttpConfig, proxy: Option<&str>) -> Result<Client, reqwest::Error> {
+    let mut builder = Client::builder()
+        .connect_timeout(Duration::from_secs(http.connect_timeout_secs))
+        .timeout(Duration::from_secs(http.timeout_secs))
+        .user_agent(http.user_agent.as_str());
+
+    if let Some(proxy) = proxy {
+        builder = builder.proxy(Proxy::all(proxy)?.no_proxy(NoProxy::from_env()));
+    }
+
+    builder.build()
+}
+
+/// Hash which stays the same across runs, unlike `DefaultHasher`
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ u64::from(*byte)).wrapping_mul(0x100000001b3)
+    })
+}
+
+fn is_retryable_status(status: StatusCode) -> bool {
+    matches!(
+        status,
+        StatusCode::TOO_MANY_REQUESTS
+            | StatusCode::BAD_GATEWAY
+            | StatusCode::SERVICE_UNAVAILABLE
+            | StatusCode::GATEWAY_TIMEOUT
+    )
+}
+
+pub fn retry_after(response: &Response) -> Option<Duration> {
+    let seconds = response
+        .headers()
+        .get(reqwest::header::RETRY_AFTER)?
+        .to_str()
+        .ok()?
+        .parse()
+        .ok()?;
+
+    Some(Duration::from_secs(seconds))
+}
+
+/// Exponential backoff with equal jitter: half of the delay is fixed
+/// and half is random, so clients retrying together spread out.
+/// `jitter` is a number between 0 and 1
+fn backoff_delay(attempt: u32, initial: Duration, max: Duration, jitter: f64) -> Duration {
+    let exponential = initial
+        .checked_mul(2_u32.saturating_pow(attempt))
+        .unwrap_or(max)
+        .min(max);
+
+    exponential / 2 + exponential.mul_f64(jitter.clamp(0.0, 1.0)) / 2
+}
+
+/// Spaces requests at least `interval` apart
+struct RateLimiter {
+    interval: Duration,
+    next: tokio::sync::Mutex<Instant>,
+}
+
+impl RateLimiter {
+    fn new(interval: Duration) -> Self {
+        RateLimiter {
+            interval,
+            next: tokio::sync::Mutex::new(Instant::now()),
+        }
+    }
+
+    async fn wait(&self) {
+        if self.interval.is_zero() {
+            return;
+        }
+
+        let slot = {
+            let mut next = self.next.lock().await;
+            let slot = (*next).max(Instant::now());
+            *next = slot + self.interval;
+            slot
+        };
+
+        tokio::time::sleep_until(slot).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    /// Serves responses with given status codes in order,
+    /// one per connection. Returns server address
+    async fn serve_statuses(statuses: Vec<u16>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            for status in statuses {
+                let (mut socket, _) = listener.accept().await.unwrap();
+
+                let mut buffer = [0; 1024];
+                let _ = socket.read(&mut buffer).await;
+
+                let response = format!(
+                    "HTTP/1.1 {status} Status\r\nContent-Length: 2\r\nConnection: close\r\n\r\nok"
+                );
+                socket.write_all(response.as_bytes()).await.unwrap();
+            }
+        });
+
+        format!("http://{addr}")
+    }
+
+    fn test_client(max_retries: u32) -> HttpClient {
+        let http = HttpConfig {
+            max_retries,
+            initial_backoff_ms: 1,
+            max_backoff_ms: 10,
+            ..Default::default()
+        };
+
+        HttpClient::new(&http, "test", &ProviderConfig::default()).unwrap()
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_send_retries_gateway_errors() {
+        let url = serve_statuses(vec![502, 503, 200]).await;
+
+        let response = test_client(3)
+            .send(|client| client.get(&url))
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_send_gives_up_after_max_retries() {
+        let url = serve_statuses(vec![502, 502, 200]).await;
+
+        let response = test_client(1)
+            .send(|client| client.get(&url))
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::BAD_GATEWAY);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_send_does_not_retry_client_errors() {
+        let url = serve_statuses(vec![404, 200]).await;
+
+        let response = test_client(3)
+            .send(|client| client.get(&url))
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_send_once_does_not_retry() {
+        let url = serve_statuses(vec![503, 200]).await;
+
+        let response = test_client(3)
+            .send_once_in_session("session", None, |client| client.post(&url))
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+    }
+
+    #[test]
+    fn test_backoff_delay() {
+        let initial = Duration::from_millis(500);
+        let max = Duration::from_secs(8);
+
+        assert_eq!(
+            backoff_delay(0, initial, max, 0.0),
+            Duration::from_millis(250)
+        );
+        assert_eq!(
+            backoff_delay(2, initial, max, 1.0),
+            Duration::from_millis(2000)
+        );
+        assert_eq!(backoff_delay(10, initial, max, 1.0), max);
+        assert_eq!(backoff_delay(100, initial, max, 0.0), max / 2);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_rate_limiter_spaces_requests() {
+        let rate_limiter = RateLimiter::new(Duration::from_millis(50));
+        let start = Instant::now();
+
+        for _ in 0..3 {
+            rate_limiter.wait().await;
+        }
+
+        assert!(start.elapsed() >= Duration::from_millis(100));
+    }
+
+    /// Reads request head and answers it with `body`. Returns the head
+    async fn answer_request(socket: &mut tokio::net::TcpStream, body: &str) -> String {
+        let mut head = Vec::new();
+        let mut buffer = [0; 1024];
+
+        while !head.windows(4).any(|window| window == b"\r\n\r\n") {
+            let read = socket.read(&mut buffer).await.unwrap();
+            if read == 0 {
+                break;
+            }
+            head.extend_from_slice(&buffer[..read]);
+        }
+
+        let response = format!(
+            "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+            body.len()
+        );
+        socket.write_all(response.as_bytes()).await.unwrap();
+
+        String::from_utf8_lossy(&head).to_string()
+    }
+
+    /// HTTP proxy answering every request itself with `name`.
+    /// Returns its address and heads of received requests
+    async fn serve_http_proxy(name: &'static str) -> (String, Arc<Mutex<Vec<String>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let heads = Arc::new(Mutex::new(Vec::new()));
+
+        let received = heads.clone();
+        tokio::spawn(async move {
+            loop {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                let head = answer_request(&mut socket, name).await;
+                received.lock().unwrap().push(head);
+            }
+        });
+
+        (addr.to_string(), heads)
+    }
+
+    /// Field of SOCKS5 message prefixed by its length
+    async fn read_field(socket: &mut tokio::net::TcpStream) -> String {
+        let length = socket.read_u8().await.unwrap();
+        let mut value = vec![0; length as usize];
+        socket.read_exact(&mut value).await.unwrap();
+
+        String::from_utf8(value).unwrap()
+    }
+
+    /// SOCKS5 proxy accepting `qa:secret` login, which answers the
+    /// request itself. Returns its address and requested destinations
+    async fn serve_socks5_proxy() -> (String, Arc<Mutex<Vec<String>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let destinations = Arc::new(Mutex::new(Vec::new()));
+
+        let received = destinations.clone();
+        tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+
+            // Greeting, username and password authentication is chosen
+            let mut greeting = [0; 2];
+            socket.read_exact(&mut greeting).await.unwrap();
+            let mut methods = vec![0; greeting[1] as usize];
+            socket.read_exact(&mut methods).await.unwrap();
+            assert!(methods.contains(&2));
+            socket.write_all(&[5, 2]).await.unwrap();
+
+            // RFC 1929 login
+            assert_eq!(socket.read_u8().await.unwrap(), 1);
+            let login = (read_field(&mut socket).await, read_field(&mut socket).await);
+            assert_eq!(login, ("qa".to_string(), "secret".to_string()));
+            socket.write_all(&[1, 0]).await.unwrap();
+
+            // CONNECT to a domain name
+            let mut request = [0; 4];
+            socket.read_exact(&mut request).await.unwrap();
+            assert_eq!(request, [5, 1, 0, 3]);
+            let host = read_field(&mut socket).await;
+            let port = socket.read_u16().await.unwrap();
+            received.lock().unwrap().push(format!("{host}:{port}"));
+            socket
+                .write_all(&[5, 0, 0, 1, 0, 0, 0, 0, 0, 0])
+                .await
+                .unwrap();
+
+            answer_request(&mut socket, "socks").await;
+        });
+
+        (addr.to_string(), destinations)
+    }
+
+    fn proxied_client(provider_config: ProviderConfig) -> HttpClient {
+        HttpClient::new(&HttpConfig::default(), "test", &provider_config).unwrap()
+    }
+
+    async fn body(client: &HttpClient, session: Option<&str>) -> String {
+        let request = |client: &Client| client.get("http://provider.test/ajax.php");
+
+        let response = match session {
+            Some(session) => client.send_in_session(session, None, request).await,
+            None => client.send(request).await,
+        };
+
+        response.unwrap().text().await.unwrap()
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_proxy_pool_rotates_per_new_address() {
+        let (first, first_heads) = serve_http_proxy("first").await;
+        let (second, _) = serve_http_proxy("second").await;
+
+        let client = proxied_client(ProviderConfig {
+            proxy_pool: vec![
+                format!("http://qa:secret@{first}"),
+                format!("http://qa:secret@{second}"),
+            ],
+            ..Default::default()
+        });
+
+        assert_eq!(body(&client, None).await, "first");
+        assert_eq!(body(&client, None).await, "second");
+        assert_eq!(body(&client, None).await, "first");
+
+        let session = body(&client, Some("sid-token")).await;
+        assert_eq!(body(&client, Some("sid-token")).await, session);
+
+        // Session stays on the proxy its address was created through
+        let proxy = client.next_proxy();
+        for _ in 0..3 {
+            let response = client
+                .send_in_session("sid-token", Some(proxy), |client| {
+                    client.get("http://provider.test/ajax.php")
+                })
+                .await
+                .unwrap();
+            assert_eq!(response.text().await.unwrap(), "second");
+        }
+
+        let head = first_heads.lock().unwrap()[0].clone();
+        assert!(head.starts_with("GET http://provider.test/ajax.php HTTP/1.1"));
+        assert!(head.contains("proxy-authorization: Basic cWE6c2VjcmV0"));
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_socks5_proxy_with_login() {
+        let (proxy, destinations) = serve_socks5_proxy().await;
+
+        let client = proxied_client(ProviderConfig {
+            proxy: Some(format!("socks5h://qa:secret@{proxy}")),
+            ..Default::default()
+        });
+
+        assert_eq!(body(&client, Some("sid-token")).await, "socks");
+        assert_eq!(*destinations.lock().unwrap(), ["provider.test:80"]);
+    }
+}
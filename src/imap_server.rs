@@ -0,0 +1,916 @@
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use crate::cli;
+use crate::context::Context;
+use crate::mails::{MailError, Message};
+
+/// Ids of stored emails do not change, so one validity fits every mailbox
+const UID_VALIDITY: u32 = 1;
+
+/// Longest command line, without its literals
+const MAX_LINE_LENGTH: usize = 8 * 1024;
+
+/// Most literal data in one command. Commands of a read-only
+/// server carry names and passwords, never whole emails
+const MAX_LITERAL_SIZE: usize = 64 * 1024;
+
+/// Serves stored addresses as mailboxes until the process is stopped
+pub async fn serve(addr: SocketAddr, ctx: Arc<Context>) -> Result<(), MailError> {
+    let listener = TcpListener::bind(addr)
+        .await
+        .map_err(|e| MailError::ServerError(Box::new(e)))?;
+
+    serve_listener(listener, ctx).await
+}
+
+pub async fn serve_listener(listener: TcpListener, ctx: Arc<Context>) -> Result<(), MailError> {
+    loop {
+        let (stream, peer) = listener
+            .accept()
+            .await
+            .map_err(|e| MailError::ServerError(Box::new(e)))?;
+
+        let ctx = ctx.clone();
+
+        tokio::spawn(async move {
+            if let Err(e) = handle_session(stream, &ctx).await {
+                eprintln!("IMAP session with {peer} failed: {e}");
+            }
+        });
+    }
+}
+
+/// Email of the selected mailbox, numbered by its id
+struct Email {
+    uid: u32,
+    message: Message,
+    raw: String,
+}
+
+struct Session<'a> {
+    ctx: &'a Context,
+    logged_in: bool,
+    selected: Option<Vec<Email>>,
+}
+
+/// Read-only server side of RFC 3501, enough for mail clients to browse
+/// emails. Any username and password is accepted and there is no TLS,
+/// it is meant for local use only
+async fn handle_session(stream: TcpStream, ctx: &Context) -> io::Result<()> {
+    let (reader, mut writer) = stream.into_split();
+    let mut reader = BufReader::new(reader);
+
+    writer
+        .write_all(b"* OK [CAPABILITY IMAP4rev1] disposable-mail-tool read-only IMAP ready\r\n")
+        .await?;
+
+    let mut session = Session {
+        ctx,
+        logged_in: false,
+        selected: None,
+    };
+
+    while let Some(line) = read_command(&mut reader, &mut writer).await? {
+        let (tag, command) = match line.split_once(' ') {
+            Some((tag, command)) => (tag.to_string(), command),
+            None => {
+                writer.write_all(b"* BAD Missing command\r\n").await?;
+                continue;
+            }
+        };
+
+        let arguments = match arguments(command) {
+            Some(arguments) if !arguments.is_empty() => arguments,
+            _ => {
+                writer
+                    .write_all(format!("{tag} BAD Invalid arguments\r\n").as_bytes())
+                    .await?;
+                continue;
+            }
+        };
+
+        let verb = arguments[0].to_uppercase();
+        let (data, status) = session.respond(&verb, &arguments[1..]).await;
+
+        writer
+            .write_all(format!("{data}{tag} {status}\r\n").as_bytes())
+            .await?;
+
+        if verb == "LOGOUT" {
+            return Ok(());
+        }
+    }
+
+    Ok(())
+}
+
+/// Reads one command line. Literals sent by the client are read after
+/// continuation request and put into the line as quoted strings.
+/// Commands with too much literal data get BAD, a too long line ends the session
+async fn read_command(
+    reader: &mut (impl AsyncBufReadExt + Unpin),
+    writer: &mut (impl AsyncWriteExt + Unpin),
+) -> io::Result<Option<String>> {
+    let mut command = String::new();
+    let mut literals = 0;
+    let mut line = Vec::new();
+
+    loop {
+        line.clear();
+        let limit = MAX_LINE_LENGTH as u64 + 1;
+        if (&mut *reader)
+            .take(limit)
+            .read_until(b'\n', &mut line)
+            .await?
+            == 0
+        {
+            return Ok(None);
+        }
+
+        if line.len() > MAX_LINE_LENGTH {
+            writer.write_all(b"* BYE Command line too long\r\n").await?;
+            return Ok(None);
+        }
+
+        let text = String::from_utf8_lossy(&line);
+        let text = text.trim_end_matches(['\r', '\n']);
+
+        match literal_length(text) {
+            Some((before, length)) if literals + length > MAX_LITERAL_SIZE => {
+                let tag = command.split(' ').next().unwrap_or_default();
+                let tag = if tag.is_empty() {
+                    before.split(' ').next().unwrap_or("*")
+                } else {
+                    tag
+                };
+
+                writer
+                    .write_all(format!("{tag} BAD Literal too large\r\n").as_bytes())
+                    .await?;
+
+                command.clear();
+                literals = 0;
+            }
+            Some((before, length)) => {
+                writer.write_all(b"+ Ready for literal data\r\n").await?;
+
+                let mut literal = vec![0; length];
+                reader.read_exact(&mut literal).await?;
+                literals += length;
+
+                command.push_str(before);
+                command.push_str(&quoted(&String::from_utf8_lossy(&literal)));
+            }
+            None => {
+                command.push_str(text);
+                return Ok(Some(command));
+            }
+        }
+    }
+}
+
+/// Line without `{n}` at its end, and `n`
+fn literal_length(line: &str) -> Option<(&str, usize)> {
+    let (before, length) = line.strip_suffix('}')?.rsplit_once('{')?;
+
+    Some((before, length.parse().ok()?))
+}
+
+impl Session<'_> {
+    /// Untagged data and tagged status of response to the command
+    async fn respond(&mut self, verb: &str, arguments: &[String]) -> (String, String) {
+        let response = match verb {
+            "CAPABILITY" => Ok((
+                "* CAPABILITY IMAP4rev1\r\n".to_string(),
+                "OK CAPABILITY completed".to_string(),
+            )),
+            "NOOP" | "CHECK" => Ok((String::new(), format!("OK {verb} completed"))),
+            "LOGOUT" => Ok((
+                "* BYE Logging out\r\n".to_string(),
+                "OK LOGOUT completed".to_string(),
+            )),
+            "LOGIN" if arguments.len() == 2 => {
+                self.logged_in = true;
+                Ok((String::new(), "OK LOGIN completed".to_string()))
+            }
+            _ if !self.logged_in => Ok((String::new(), "NO Log in first".to_string())),
+            "LIST" | "LSUB" if arguments.len() == 2 => self.list(verb, &arguments[1]).await,
+            "STATUS" if arguments.len() == 2 => self.status(&arguments[0], &arguments[1]).await,
+            "SELECT" | "EXAMINE" if arguments.len() == 1 => self.select(verb, &arguments[0]).await,
+            "STORE" | "COPY" | "MOVE" | "EXPUNGE" | "APPEND" | "CREATE" | "DELETE" | "RENAME"
+            | "SUBSCRIBE" | "UNSUBSCRIBE" => Ok((
+                String::new(),
+                "NO [CANNOT] Mailboxes are read-only".to_string(),
+            )),
+            _ if self.selected.is_none() => match verb {
+                "FETCH" | "SEARCH" | "UID" | "CLOSE" | "UNSELECT" => {
+                    Ok((String::new(), "NO Select a mailbox first".to_string()))
+                }
+                _ => Ok((String::new(), "BAD Unknown command".to_string())),
+            },
+            "CLOSE" | "UNSELECT" => {
+                self.selected = None;
+                Ok((String::new(), format!("OK {verb} completed")))
+            }
+            "FETCH" if arguments.len() == 2 => self.fetch(false, &arguments[0], &arguments[1]),
+            "SEARCH" => self.search(false, arguments),
+            "UID" if !arguments.is_empty() => {
+                match (arguments[0].to_uppercase().as_str(), &arguments[1..]) {
+                    ("FETCH", [set, items]) => self.fetch(true, set, items),
+                    ("SEARCH", criteria) => self.search(true, criteria),
+                    ("STORE" | "COPY" | "MOVE" | "EXPUNGE", _) => Ok((
+                        String::new(),
+                        "NO [CANNOT] Mailboxes are read-only".to_string(),
+                    )),
+                    _ => Ok((String::new(), "BAD Unknown UID command".to_string())),
+                }
+            }
+            _ => Ok((String::new(), "BAD Unknown command".to_string())),
+        };
+
+        response.unwrap_or_else(|e| (String::new(), format!("NO {e}")))
+    }
+
+    /// INBOX, which is always empty, and every unexpired address
+    async fn mailboxes(&self) -> Result<Vec<String>, MailError> {
+        let mut mailboxes = vec!["INBOX".to_string()];
+        mailboxes.extend(self.ctx.storage.list_addresses().await?);
+
+        Ok(mailboxes)
+    }
+
+    /// Emails of mailbox, fetched through the provider of the address
+    async fn load(&self, mailbox: &str) -> Result<Vec<Email>, MailError> {
+        if mailbox.eq_ignore_ascii_case("INBOX") {
+            return Ok(Vec::new());
+        }
+
+        if !self.mailboxes().await?.iter().any(|name| name == mailbox) {
+            return Err(MailError::EmailCheckError(mailbox.to_string()));
+        }
+
+        let mut emails: Vec<Email> = cli::export_emails_from_provider(self.ctx, mailbox, None)
+            .await?
+            .into_iter()
+            .filter_map(|(message, attachments)| {
+                Some(Email {
+                    uid: message.mail_id.parse().ok()?,
+                    raw: message.to_rfc5322(&attachments),
+                    message,
+                })
+            })
+            .collect();
+
+        emails.sort_by_key(|email| email.uid);
+
+        Ok(emails)
+    }
+
+    async fn list(&self, verb: &str, pattern: &str) -> Result<(String, String), MailError> {
+        let data = self
+            .mailboxes()
+            .await?
+            .iter()
+            .filter(|name| matches_pattern(&pattern.to_lowercase(), &name.to_lowercase()))
+            .map(|name| format!("* {verb} (\\HasNoChildren) \"/\" {}\r\n", quoted(name)))
+            .collect();
+
+        Ok((data, format!("OK {verb} completed")))
+    }
+
+    async fn status(&self, mailbox: &str, items: &str) -> Result<(String, String), MailError> {
+        let emails = self.load(mailbox).await?;
+
+        let mut values = Vec::new();
+
+        for item in items.trim_matches(['(', ')']).split_whitespace() {
+            let value = match item.to_uppercase().as_str() {
+                "MESSAGES" => emails.len() as u32,
+                "UIDNEXT" => uid_next(&emails),
+                "UIDVALIDITY" => UID_VALIDITY,
+                "RECENT" => 0,
+                "UNSEEN" => emails.len() as u32,
+                _ => return Ok((String::new(), format!("BAD Unknown status item {item}"))),
+            };
+
+            values.push(format!("{} {value}", item.to_uppercase()));
+        }
+
+        Ok((
+            format!("* STATUS {} ({})\r\n", quoted(mailbox), values.join(" ")),
+            "OK STATUS completed".to_string(),
+        ))
+    }
+
+    async fn select(&mut self, verb: &str, mailbox: &str) -> Result<(String, String), MailError> {
+        self.selected = None;
+
+        let emails = self.load(mailbox).await?;
+
+        let data = format!(
+            "* FLAGS ()\r\n\
+             * {} EXISTS\r\n\
+             * 0 RECENT\r\n\
+             * OK [UIDVALIDITY {UID_VALIDITY}] UIDs valid\r\n\
+             * OK [UIDNEXT {}] Predicted next UID\r\n\
+             * OK [PERMANENTFLAGS ()] No permanent flags\r\n",
+            emails.len(),
+            uid_next(&emails)
+        );
+
+        self.selected = Some(emails);
+
+        Ok((data, format!("OK [READ-ONLY] {verb} completed")))
+    }
+
+    fn selected(&self, uid: bool, set: &str) -> Option<Vec<(usize, &Email)>> {
+        let emails = self.selected.as_deref().unwrap_or_default();
+        let max = match uid {
+            true => emails.last().map(|email| email.uid).unwrap_or(0),
+            false => emails.len() as u32,
+        };
+
+        let mut selected = Vec::new();
+
+        for (index, email) in emails.iter().enumerate() {
+            let number = if uid { email.uid } else { index as u32 + 1 };
+
+            if in_set(set, number, max)? {
+                selected.push((index + 1, email));
+            }
+        }
+
+        Some(selected)
+    }
+
+    fn fetch(&self, uid: bool, set: &str, items: &str) -> Result<(String, String), MailError> {
+        let items = match fetch_items(items, uid) {
+            Some(items) => items,
+            None => return Ok((String::new(), "BAD Invalid fetch items".to_string())),
+        };
+
+        let emails = match self.selected(uid, set) {
+            Some(emails) => emails,
+            None => return Ok((String::new(), "BAD Invalid sequence set".to_string())),
+        };
+
+        let mut data = String::new();
+
+        for (number, email) in emails {
+            let mut values = Vec::new();
+
+            for item in &items {
+                match fetch_item(email, item) {
+                    Some(value) => values.push(value),
+                    None => {
+                        return Ok((String::new(), format!("BAD Unsupported fetch item {item}")))
+                    }
+                }
+            }
+
+            data.push_str(&format!("* {number} FETCH ({})\r\n", values.join(" ")));
+        }
+
+        Ok((data, "OK FETCH completed".to_string()))
+    }
+
+    /// Supports ALL, sequence sets and UID criterion, which are all required to match
+    fn search(&self, uid: bool, criteria: &[String]) -> Result<(String, String), MailError> {
+        let mut selected = match self.selected(uid, "1:*") {
+            Some(emails) => emails,
+            None => return Ok((String::new(), "BAD Invalid search".to_string())),
+        };
+
+        let mut criteria = criteria.iter();
+
+        while let Some(criterion) = criteria.next() {
+            let (by_uid, set) = match criterion.to_uppercase().as_str() {
+                "ALL" => continue,
+                "UID" => match criteria.next() {
+                    Some(set) => (true, set.as_str()),
+                    None => return Ok((String::new(), "BAD Missing UID set".to_string())),
+                },
+                _ => (false, criterion.as_str()),
+            };
+
+            let matching = match self.selected(by_uid, set) {
+                Some(emails) => emails,
+                None => {
+                    return Ok((
+                        String::new(),
+                        format!("BAD Unsupported search criterion {criterion}"),
+                    ))
+                }
+            };
+
+            selected.retain(|(number, _)| matching.iter().any(|(other, _)| other == number));
+        }
+
+        let mut data = "* SEARCH".to_string();
+
+        for (number, email) in selected {
+            let number = if uid { email.uid } else { number as u32 };
+            data.push_str(&format!(" {number}"));
+        }
+
+        data.push_str("\r\n");
+
+        Ok((data, "OK SEARCH completed".to_string()))
+    }
+}
+
+fn uid_next(emails: &[Email]) -> u32 {
+    emails.last().map(|email| email.uid + 1).unwrap_or(1)
+}
+
+/// Splits arguments at spaces outside of quoted strings, parenthesized
+/// lists and brackets. Quoted strings are unquoted
+fn arguments(line: &str) -> Option<Vec<String>> {
+    let mut arguments = Vec::new();
+    let mut argument = String::new();
+    let mut depth = 0;
+    let mut in_quotes = false;
+    let mut escaped = false;
+
+    for c in line.chars() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if in_quotes => escaped = true,
+            '"' => in_quotes = !in_quotes,
+            '(' | '[' if !in_quotes => depth += 1,
+            ')' | ']' if !in_quotes => depth -= 1,
+            ' ' if !in_quotes && depth == 0 => {
+                if !argument.is_empty() {
+                    arguments.push(unquoted(&argument));
+                    argument.clear();
+                }
+                continue;
+            }
+            _ => {}
+        }
+
+        if depth < 0 {
+            return None;
+        }
+
+        argument.push(c);
+    }
+
+    if in_quotes || depth != 0 {
+        return None;
+    }
+
+    if !argument.is_empty() {
+        arguments.push(unquoted(&argument));
+    }
+
+    Some(arguments)
+}
+
+fn unquoted(argument: &str) -> String {
+    match argument
+        .strip_prefix('"')
+        .and_then(|argument| argument.strip_suffix('"'))
+    {
+        Some(argument) => argument.replace("\\\"", "\"").replace("\\\\", "\\"),
+        None => argument.to_string(),
+    }
+}
+
+/// String as IMAP quoted string, or as literal if it cannot be quoted
+fn quoted(value: &str) -> String {
+    if value.is_ascii() && !value.contains(['\r', '\n']) {
+        format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+    } else {
+        literal(value)
+    }
+}
+
+fn literal(value: &str) -> String {
+    format!("{{{}}}\r\n{value}", value.len())
+}
+
+/// `*` matches anything, `%` too since mailboxes have no hierarchy
+fn matches_pattern(pattern: &str, name: &str) -> bool {
+    match pattern.chars().next() {
+        None => name.is_empty(),
+        Some('*' | '%') => (0..=name.len())
+            .filter(|index| name.is_char_boundary(*index))
+            .any(|index| matches_pattern(&pattern[1..], &name[index..])),
+        Some(c) => name
+            .strip_prefix(c)
+            .is_some_and(|rest| matches_pattern(&pattern[c.len_utf8()..], rest)),
+    }
+}
+
+/// Whether `number` is in sequence set like `1,3:5,7:*`.
+/// Returns None if the set is invalid
+fn in_set(set: &str, number: u32, max: u32) -> Option<bool> {
+    let value = |value: &str| match value {
+        "*" => Some(max),
+        _ => value.parse::<u32>().ok().filter(|value| *value > 0),
+    };
+
+    let mut found = false;
+
+    for range in set.split(',') {
+        let (start, end) = match range.split_once(':') {
+            Some((start, end)) => (value(start)?, value(end)?),
+            None => (value(range)?, value(range)?),
+        };
+
+        found |= (start.min(end)..=start.max(end)).contains(&number);
+    }
+
+    Some(found)
+}
+
+/// Fetch items with macros expanded. UID is always returned by UID FETCH
+fn fetch_items(items: &str, uid: bool) -> Option<Vec<String>> {
+    let items = match items.strip_prefix('(') {
+        Some(items) => arguments(items.strip_suffix(')')?)?,
+        None => vec![items.to_string()],
+    };
+
+    let mut expanded: Vec<String> = Vec::new();
+
+    if uid {
+        expanded.push("UID".to_string());
+    }
+
+    for item in items {
+        let macro_items: &[&str] = match item.to_uppercase().as_str() {
+            "ALL" => &["FLAGS", "INTERNALDATE", "RFC822.SIZE", "ENVELOPE"],
+            "FAST" => &["FLAGS", "INTERNALDATE", "RFC822.SIZE"],
+            _ => &[],
+        };
+
+        if macro_items.is_empty() {
+            expanded.push(item);
+        } else {
+            expanded.extend(macro_items.iter().map(|item| item.to_string()));
+        }
+    }
+
+    expanded.dedup_by(|a, b| a.eq_ignore_ascii_case(b));
+
+    Some(expanded)
+}
+
+/// Value of fetch item together with its name.
+/// Returns None if the item is not supported
+fn fetch_item(email: &Email, item: &str) -> Option<String> {
+    let (header, text) = match email.raw.find("\r\n\r\n") {
+        Some(index) => email.raw.split_at(index + 4),
+        None => (email.raw.as_str(), ""),
+    };
+
+    let upper = item.to_uppercase();
+
+    let value = match upper.as_str() {
+        "UID" => email.uid.to_string(),
+        "FLAGS" => "()".to_string(),
+        "INTERNALDATE" => format!(
+            "\"{}\"",
+            email.message.date().format("%d-%b-%Y %H:%M:%S +0000")
+        ),
+        "RFC822.SIZE" => email.raw.len().to_string(),
+        "ENVELOPE" => envelope(header),
+        "RFC822" => literal(&email.raw),
+        "RFC822.HEADER" => literal(header),
+        "RFC822.TEXT" => literal(text),
+        _ => return body_section(&email.raw, header, text, &upper),
+    };
+
+    Some(format!("{upper} {value}"))
+}
+
+/// `BODY[section]<partial>` and its `BODY.PEEK` variant
+fn body_section(raw: &str, header: &str, text: &str, item: &str) -> Option<String> {
+    let item = item
+        .strip_prefix("BODY.PEEK[")
+        .or_else(|| item.strip_prefix("BODY["))?;
+    let (section, partial) = item.split_once(']')?;
+
+    let content = match section {
+        "" => raw.to_string(),
+        "HEADER" => header.to_string(),
+        "TEXT" => text.to_string(),
+        _ => {
+            let (not, fields) = match section.strip_prefix("HEADER.FIELDS.NOT ") {
+                Some(fields) => (true, fields),
+                None => (false, section.strip_prefix("HEADER.FIELDS ")?),
+            };
+
+            let fields: Vec<&str> = fields.trim_matches(['(', ')']).split_whitespace().collect();
+
+            let mut content: String = header_fields(header)
+                .into_iter()
+                .filter(|field| {
+                    let name = field.split(':').next().unwrap_or_default().to_uppercase();
+                    fields.contains(&name.as_str()) != not
+                })
+                .collect();
+            content.push_str("\r\n");
+
+            content
+        }
+    };
+
+    if partial.is_empty() {
+        return Some(format!("BODY[{section}] {}", literal(&content)));
+    }
+
+    let (start, length) = partial
+        .strip_prefix('<')?
+        .strip_suffix('>')?
+        .split_once('.')?;
+    let start: usize = start.parse().ok()?;
+    let length: usize = length.parse().ok()?;
+
+    let bytes = content.as_bytes();
+    let part = &bytes[start.min(bytes.len())..start.saturating_add(length).min(bytes.len())];
+
+    Some(format!(
+        "BODY[{section}]<{start}> {}",
+        literal(&String::from_utf8_lossy(part))
+    ))
+}
+
+/// Header fields including their folded lines and line endings
+fn header_fields(header: &str) -> Vec<String> {
+    let mut fields: Vec<String> = Vec::new();
+
+    for line in header.split_inclusive("\r\n") {
+        if line == "\r\n" {
+            break;
+        }
+
+        match fields.last_mut() {
+            Some(field) if line.starts_with([' ', '\t']) => field.push_str(line),
+            _ => fields.push(line.to_string()),
+        }
+    }
+
+    fields
+}
+
+fn header_value(header: &str, name: &str) -> Option<String> {
+    header_fields(header).into_iter().find_map(|field| {
+        let (field_name, value) = field.split_once(':')?;
+
+        field_name
+            .eq_ignore_ascii_case(name)
+            .then(|| value.replace("\r\n", "").trim().to_string())
+    })
+}
+
+/// Envelope structure from header, which is already encoded as ASCII
+fn envelope(header: &str) -> String {
+    let string = |name: &str| {
+        header_value(header, name)
+            .map(|value| quoted(&value))
+            .unwrap_or_else(|| "NIL".to_string())
+    };
+    let addresses = |name: &str| {
+        header_value(header, name)
+            .map(|value| address(&value))
+            .unwrap_or_else(|| "NIL".to_string())
+    };
+
+    let from = addresses("From");
+
+    format!(
+        "({} {} {from} {from} {from} {} NIL NIL NIL {})",
+        string("Date"),
+        string("Subject"),
+        addresses("To"),
+        string("Message-ID")
+    )
+}
+
+/// Address list with one address, from `Name <mailbox@host>` or `mailbox@host`
+fn address(value: &str) -> String {
+    let (name, addr) = match value.rsplit_once('<') {
+        Some((name, addr)) => (name.trim().trim_matches('"'), addr.trim_end_matches('>')),
+        None => ("", value),
+    };
+
+    let (mailbox, host) = addr.trim().rsplit_once('@').unwrap_or((addr.trim(), ""));
+
+    if mailbox.is_empty() {
+        return "NIL".to_string();
+    }
+
+    let nstring = |value: &str| match value {
+        "" => "NIL".to_string(),
+        value => quoted(value),
+    };
+
+    format!(
+        "(({} NIL {} {}))",
+        nstring(name),
+        quoted(mailbox),
+        nstring(host)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::mails::{Attachment, MemoryProvider};
+
+    #[test]
+    fn test_arguments() {
+        assert_eq!(
+            arguments(r#"LOGIN "qa" "pa ss\"word""#),
+            Some(vec![
+                "LOGIN".to_string(),
+                "qa".to_string(),
+                "pa ss\"word".to_string()
+            ])
+        );
+        assert_eq!(
+            arguments("FETCH 1:* (UID BODY.PEEK[HEADER.FIELDS (FROM TO)])"),
+            Some(vec![
+                "FETCH".to_string(),
+                "1:*".to_string(),
+                "(UID BODY.PEEK[HEADER.FIELDS (FROM TO)])".to_string()
+            ])
+        );
+        assert_eq!(arguments("FETCH 1 (UID"), None);
+    }
+
+    #[test]
+    fn test_in_set() {
+        assert_eq!(in_set("1,3:5", 4, 9), Some(true));
+        assert_eq!(in_set("1,3:5", 2, 9), Some(false));
+        assert_eq!(in_set("7:*", 9, 9), Some(true));
+        assert_eq!(in_set("12:*", 9, 9), Some(true));
+        assert_eq!(in_set("0:x", 1, 9), None);
+    }
+
+    #[test]
+    fn test_matches_pattern() {
+        assert!(matches_pattern("*", "abc@memory.test"));
+        assert!(matches_pattern("abc%", "abc@memory.test"));
+        assert!(matches_pattern("inbox", "inbox"));
+        assert!(!matches_pattern("inbox", "abc@memory.test"));
+    }
+
+    #[test]
+    fn test_envelope() {
+        let header = "From: GitHub <noreply@github.com>\r\nTo: abc@memory.test\r\n\
+                      Subject: =?utf-8?b?VmVyaWZ5?=\r\nDate: Tue, 1 Jul 2003 10:52:37 +0000\r\n\r\n";
+
+        assert_eq!(
+            envelope(header),
+            "(\"Tue, 1 Jul 2003 10:52:37 +0000\" \"=?utf-8?b?VmVyaWZ5?=\" \
+             ((\"GitHub\" NIL \"noreply\" \"github.com\")) \
+             ((\"GitHub\" NIL \"noreply\" \"github.com\")) \
+             ((\"GitHub\" NIL \"noreply\" \"github.com\")) \
+             ((NIL NIL \"abc\" \"memory.test\")) NIL NIL NIL NIL)"
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_oversized_commands_are_rejected() {
+        let (client, server) = tokio::io::duplex(1024);
+        let (reader, mut writer) = tokio::io::split(client);
+        let mut reader = BufReader::new(reader);
+
+        // Server echoes every command it reads
+        tokio::spawn(async move {
+            let (server_reader, mut server_writer) = tokio::io::split(server);
+            let mut server_reader = BufReader::new(server_reader);
+
+            while let Some(command) = read_command(&mut server_reader, &mut server_writer)
+                .await
+                .unwrap()
+            {
+                let reply = format!("{command} OK\r\n");
+                server_writer.write_all(reply.as_bytes()).await.unwrap();
+            }
+        });
+
+        let mut response = String::new();
+
+        writer
+            .write_all(b"a LOGIN {99999999999}\r\n")
+            .await
+            .unwrap();
+        reader.read_line(&mut response).await.unwrap();
+        assert_eq!(response, "a BAD Literal too large\r\n");
+
+        response.clear();
+        writer.write_all(b"b NOOP\r\n").await.unwrap();
+        reader.read_line(&mut response).await.unwrap();
+        assert_eq!(response, "b NOOP OK\r\n");
+
+        response.clear();
+        writer
+            .write_all(&vec![b'x'; MAX_LINE_LENGTH + 1])
+            .await
+            .unwrap();
+        reader.read_line(&mut response).await.unwrap();
+        assert_eq!(response, "* BYE Command line too long\r\n");
+    }
+
+    #[test]
+    fn test_body_section_partial_does_not_overflow() {
+        let item = format!("BODY[]<2.{}>", usize::MAX);
+
+        assert_eq!(
+            body_section("Subject: x\r\n\r\nbody", "", "", &item),
+            Some("BODY[]<2> {16}\r\nbject: x\r\n\r\nbody".to_string())
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_mail_client_reads_stored_emails() -> Result<(), MailError> {
+        let memory = MemoryProvider::default();
+        let ctx = Context::in_memory(memory.clone());
+
+        let email = cli::store_email_from_provider(&ctx, "memory")
+            .await?
+            .email_addr;
+        memory.inject(
+            &email,
+            "GitHub <noreply@github.com>",
+            "Verify your email",
+            "<p>Your code is 482913</p>",
+            vec![Attachment {
+                filename: "terms.txt".to_string(),
+                content_type: "text/plain".to_string(),
+                data: b"Terms of service".to_vec(),
+            }],
+        )?;
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve_listener(listener, Arc::new(ctx)));
+
+        let email_addr = email.clone();
+
+        // Mail client is blocking, so it runs outside of the runtime
+        tokio::task::spawn_blocking(move || {
+            let stream = std::net::TcpStream::connect(addr).unwrap();
+            let mut client = imap::Client::new(stream);
+            client.read_greeting().unwrap();
+            let mut session = client.login("qa", "anything").map_err(|(e, _)| e).unwrap();
+
+            let mailboxes = session.list(Some(""), Some("*")).unwrap();
+            let names: Vec<&str> = mailboxes.iter().map(|mailbox| mailbox.name()).collect();
+            assert_eq!(names, ["INBOX", email_addr.as_str()]);
+
+            let mailbox = session.examine("INBOX").unwrap();
+            assert_eq!(mailbox.exists, 0);
+
+            let mailbox = session.select(&email_addr).unwrap();
+            assert_eq!(mailbox.exists, 2);
+            assert_eq!(mailbox.uid_next, Some(3));
+            assert_eq!(mailbox.uid_validity, Some(UID_VALIDITY));
+
+            let fetches = session
+                .uid_fetch("2", "(ENVELOPE RFC822.SIZE BODY.PEEK[])")
+                .unwrap();
+            let fetch = &fetches[0];
+            let raw = String::from_utf8_lossy(fetch.body().unwrap()).to_string();
+
+            assert_eq!(fetch.uid, Some(2));
+            assert_eq!(
+                fetch.envelope().unwrap().subject,
+                Some(&b"Verify your email"[..])
+            );
+            assert_eq!(fetch.size, Some(raw.len() as u32));
+            assert!(raw.contains("filename=\"terms.txt\""));
+
+            let fetches = session
+                .fetch("1:*", "BODY.PEEK[HEADER.FIELDS (SUBJECT)]")
+                .unwrap();
+            assert_eq!(fetches.len(), 2);
+            assert_eq!(
+                fetches[1].header(),
+                Some(&b"Subject: Verify your email\r\n\r\n"[..])
+            );
+
+            assert_eq!(session.uid_search("ALL").unwrap().len(), 2);
+            assert!(matches!(
+                session.uid_store("2", "+FLAGS (\\Deleted)"),
+                Err(imap::Error::No(_))
+            ));
+
+            session.logout().unwrap();
+        })
+        .await
+        .unwrap();
+
+        Ok(())
+    }
+}
@@ -1,75 +1,301 @@
+use serde::Serialize;
 use thiserror::Error;
 
-#[derive(Error, Debug, PartialEq)]
+/// Kind of failure, which decides exit code of the tool
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCategory {
+    Internal,
+    BadInput,
+    ProviderDown,
+    AddressExpired,
+    StorageUnreachable,
+    NoMailBeforeTimeout,
+    FileSystem,
+    Unavailable,
+    StorageCorrupt,
+}
+
+impl ErrorCategory {
+    /// Exit codes are documented in README, do not change them
+    pub fn exit_code(self) -> i32 {
+        match self {
+            ErrorCategory::Internal => 1,
+            ErrorCategory::BadInput => 2,
+            ErrorCategory::ProviderDown => 3,
+            ErrorCategory::AddressExpired => 4,
+            ErrorCategory::StorageUnreachable => 5,
+            ErrorCategory::NoMailBeforeTimeout => 6,
+            ErrorCategory::FileSystem => 7,
+            ErrorCategory::Unavailable => 8,
+            ErrorCategory::StorageCorrupt => 9,
+        }
+    }
+
+    pub fn hint(self) -> &'static str {
+        match self {
+            ErrorCategory::Internal => "This is a bug, please report it.",
+            ErrorCategory::BadInput => "Check command arguments and config file. Run with --help for usage.",
+            ErrorCategory::ProviderDown => {
+                "Email provider is unreachable or changed its API. Try again later or use another provider."
+            }
+            ErrorCategory::AddressExpired => {
+                "Emails expire after 60 minutes. Run `create` for a new address or `guerrillamails` to list stored ones."
+            }
+            ErrorCategory::StorageUnreachable => "Check that MongoDB is running on localhost:27017.",
+            ErrorCategory::NoMailBeforeTimeout => {
+                "No email arrived in time. Run `check` again or make sure the email was sent."
+            }
+            ErrorCategory::FileSystem => "Check that the path exists and is accessible.",
+            ErrorCategory::Unavailable => {
+                "A port, the OS keyring or a desktop service is unavailable. Check that it is running and not used by another process."
+            }
+            ErrorCategory::StorageCorrupt => {
+                "MongoDB holds data this version cannot read. Upgrade the tool, or remove the profile with `delete-profile`."
+            }
+        }
+    }
+}
+
+#[derive(Error, Debug)]
 pub enum MailError {
+    #[error("Request to email provider failed")]
+    RequestError(#[from] reqwest::Error),
     #[error("Query returned status code `{0}`")]
-    ResponseError(String),
+    ResponseError(reqwest::StatusCode),
     #[error("Returned JSON doesn't match struct")]
-    MatchError,
-    #[error("Error: `{0}` when creating email")]
-    CreateEmailError(String),
-    #[error("Email address is not in database.\nYou are passing a wrong email address, you did not call create first or email address expired.")]
-    EmailCheckError,
-    #[error("Provider text file not found")]
-    FileNotFound,
-    #[error("{0}")]
-    MongoDBError(String),
-    #[error("{0}")]
-    BsonError(String),
-    #[error("{0}")]
-    BsonValueAccessError(String),
-    #[error("{0}")]
-    BsonDeserializeError(String),
-    #[error("{0}")]
-    ParseIntError(String),
-    #[error("{0}")]
-    SerdeJsonError(String),
+    MatchError(#[source] reqwest::Error),
+    #[error("Email provider returned invalid JSON")]
+    SerdeJsonError(#[from] serde_json::Error),
+    #[error("Unexpected response from email provider: {0}")]
+    UnexpectedResponseError(String),
+    #[error("IMAP request to mail server failed")]
+    ImapError(#[from] imap::Error),
+    #[error("Email provider `{0}` is not available")]
+    ProviderNotAvailableError(String),
+    #[error("Every provider failed recently and was skipped: {0}")]
+    ProvidersUnhealthyError(String),
+    #[error("Email address `{0}` is not in database")]
+    EmailCheckError(String),
+    #[error("Email with id `{0}` not found in inbox")]
+    MessageNotFoundError(String),
+    #[error("No new email before timeout")]
+    TimeoutError,
+    #[error("Database request failed")]
+    MongoDBError(#[from] mongodb::error::Error),
+    #[error("Cannot convert document to BSON")]
+    BsonError(#[from] bson::ser::Error),
+    #[error("Stored document is missing a field")]
+    BsonValueAccessError(#[from] bson::document::ValueAccessError),
+    #[error("Stored document doesn't match struct")]
+    BsonDeserializeError(#[from] bson::de::Error),
+    #[error("Unexpected document in database: {0}")]
+    UnexpectedDocumentError(String),
+    #[error("Invalid number")]
+    ParseIntError(#[from] std::num::ParseIntError),
+    #[error("Invalid date `{0}`. Expected YYYY-MM-DD or RFC 3339 format")]
+    DateError(String),
+    #[error("Unknown export format `{0}`. Available formats: eml, mbox, json")]
+    ExportFormatError(String),
+    #[error("Invalid config: {0}")]
+    ConfigError(String),
+    #[error("Cannot decrypt stored secrets: {0}")]
+    SecretKeyError(String),
+    #[error("OS keyring failed: {0}")]
+    KeyringError(String),
+    #[error("Cannot access file `{path}`")]
+    FileNotAccessible {
+        path: String,
+        #[source]
+        source: std::io::Error,
+    },
+    #[error("Terminal error")]
+    TerminalError(#[source] std::io::Error),
+    #[error("Cannot read email body from stdin")]
+    StdinError(#[source] std::io::Error),
+    #[error("Editor failed: {0}")]
+    EditorError(String),
+    #[error("Invalid address `{0}` to listen on. Expected IP:PORT like 127.0.0.1:8080")]
+    ListenAddressError(String),
+    #[error("Server error")]
+    ServerError(#[source] Box<dyn std::error::Error + Send + Sync>),
+    #[error("API key error: {0}")]
+    ApiKeyError(String),
+    #[error("Invalid label: {0}")]
+    LabelError(String),
+    #[error("{0} health checks failed")]
+    HealthCheckError(usize, ErrorCategory),
+    #[error("Notification failed: {0}")]
+    NotifyError(String),
+    #[error("Unknown notification `{0}`. Available notifications: desktop, bell, title")]
+    NotifySinkError(String),
+    #[error("Provider is rate limiting requests")]
+    RateLimitError(Option<std::time::Duration>),
+    #[error("Not supported: {0}")]
+    UnsupportedError(String),
+    #[error("Cannot send email: {0}")]
+    SendError(String),
+    #[error("Email body is empty")]
+    EmptyBodyError,
+    #[error("Invalid email address {0:?}")]
+    AddressError(String),
+    /// Error of a poller shared by several commands waiting for the same address
+    #[error(transparent)]
+    SharedError(std::sync::Arc<MailError>),
 }
 
-impl std::convert::From<reqwest::Error> for MailError {
-    fn from(err: reqwest::Error) -> Self {
-        MailError::ResponseError(err.to_string())
+impl MailError {
+    pub fn category(&self) -> ErrorCategory {
+        match self {
+            MailError::RequestError(_)
+            | MailError::ResponseError(_)
+            | MailError::MatchError(_)
+            | MailError::SerdeJsonError(_)
+            | MailError::UnexpectedResponseError(_)
+            | MailError::ImapError(_)
+            | MailError::RateLimitError(_)
+            | MailError::SendError(_)
+            | MailError::ProvidersUnhealthyError(_) => ErrorCategory::ProviderDown,
+            MailError::EmailCheckError(_) => ErrorCategory::AddressExpired,
+            MailError::TimeoutError => ErrorCategory::NoMailBeforeTimeout,
+            MailError::MongoDBError(_) | MailError::BsonError(_) => {
+                ErrorCategory::StorageUnreachable
+            }
+            MailError::BsonValueAccessError(_)
+            | MailError::BsonDeserializeError(_)
+            | MailError::UnexpectedDocumentError(_) => ErrorCategory::StorageCorrupt,
+            MailError::ProviderNotAvailableError(_)
+            | MailError::MessageNotFoundError(_)
+            | MailError::ParseIntError(_)
+            | MailError::DateError(_)
+            | MailError::ExportFormatError(_)
+            | MailError::ConfigError(_)
+            | MailError::SecretKeyError(_)
+            | MailError::LabelError(_)
+            | MailError::ApiKeyError(_)
+            | MailError::NotifySinkError(_)
+            | MailError::UnsupportedError(_)
+            | MailError::EmptyBodyError
+            | MailError::AddressError(_)
+            | MailError::EditorError(_)
+            | MailError::ListenAddressError(_) => ErrorCategory::BadInput,
+            MailError::FileNotAccessible { .. } => ErrorCategory::FileSystem,
+            MailError::ServerError(_)
+            | MailError::NotifyError(_)
+            | MailError::KeyringError(_)
+            | MailError::StdinError(_) => ErrorCategory::Unavailable,
+            MailError::TerminalError(_) => ErrorCategory::Internal,
+            MailError::HealthCheckError(_, category) => *category,
+            MailError::SharedError(error) => error.category(),
+        }
     }
-}
 
-impl std::convert::From<std::io::Error> for MailError {
-    fn from(_err: std::io::Error) -> Self {
-        MailError::FileNotFound
+    pub fn file_error(path: impl AsRef<std::path::Path>) -> impl FnOnce(std::io::Error) -> Self {
+        let path = path.as_ref().display().to_string();
+
+        move |source| MailError::FileNotAccessible { path, source }
     }
-}
 
-impl std::convert::From<mongodb::error::Error> for MailError {
-    fn from(err: mongodb::error::Error) -> Self {
-        MailError::MongoDBError(err.to_string())
+    /// Error message followed by its causes and a hint what to do
+    pub fn report(&self) -> String {
+        let mut report = format!("Error: {self}");
+
+        let mut source = std::error::Error::source(self);
+        while let Some(cause) = source {
+            report.push_str(&format!("\nCaused by: {cause}"));
+            source = cause.source();
+        }
+
+        report.push_str(&format!("\nHint: {}", self.category().hint()));
+
+        report
     }
 }
 
-impl std::convert::From<bson::ser::Error> for MailError {
-    fn from(err: bson::ser::Error) -> Self {
-        MailError::BsonError(err.to_string())
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_exit_codes_are_distinct() {
+        let categories = [
+            ErrorCategory::Internal,
+            ErrorCategory::BadInput,
+            ErrorCategory::ProviderDown,
+            ErrorCategory::AddressExpired,
+            ErrorCategory::StorageUnreachable,
+            ErrorCategory::NoMailBeforeTimeout,
+            ErrorCategory::FileSystem,
+            ErrorCategory::Unavailable,
+            ErrorCategory::StorageCorrupt,
+        ];
+
+        let mut codes: Vec<i32> = categories.iter().map(|c| c.exit_code()).collect();
+        codes.sort();
+        codes.dedup();
+
+        assert_eq!(codes.len(), categories.len());
+        assert!(!codes.contains(&0));
     }
-}
 
-impl std::convert::From<bson::document::ValueAccessError> for MailError {
-    fn from(err: bson::document::ValueAccessError) -> Self {
-        MailError::BsonValueAccessError(err.to_string())
+    #[test]
+    fn test_report_contains_cause_and_hint() {
+        let source = std::io::Error::new(std::io::ErrorKind::NotFound, "No such file");
+        let error = MailError::file_error("providers.txt")(source);
+
+        assert_eq!(error.category(), ErrorCategory::FileSystem);
+        assert_eq!(
+            error.report(),
+            "Error: Cannot access file `providers.txt`\n\
+             Caused by: No such file\n\
+             Hint: Check that the path exists and is accessible."
+        );
     }
-}
 
-impl std::convert::From<bson::de::Error> for MailError {
-    fn from(err: bson::de::Error) -> Self {
-        MailError::BsonDeserializeError(err.to_string())
+    #[test]
+    fn test_user_input_and_io_failures_differ() {
+        let wrong_key = MailError::SecretKeyError("encrypted with another key".to_string());
+        let keyring = MailError::KeyringError("no secret service".to_string());
+        let port_in_use = MailError::ServerError(Box::new(std::io::Error::from(
+            std::io::ErrorKind::AddrInUse,
+        )));
+
+        assert_eq!(wrong_key.category(), ErrorCategory::BadInput);
+        assert_eq!(keyring.category(), ErrorCategory::Unavailable);
+        assert_eq!(port_in_use.category(), ErrorCategory::Unavailable);
+        assert_eq!(
+            MailError::ListenAddressError("localhost".to_string()).category(),
+            ErrorCategory::BadInput
+        );
+        assert_eq!(
+            MailError::EditorError("`vi` exited with 1".to_string()).category(),
+            ErrorCategory::BadInput
+        );
+        assert_eq!(
+            MailError::NotifySinkError("email".to_string()).category(),
+            ErrorCategory::BadInput
+        );
+        assert_eq!(
+            MailError::NotifyError("D-Bus".to_string()).category(),
+            ErrorCategory::Unavailable
+        );
     }
-}
 
-impl std::convert::From<std::num::ParseIntError> for MailError {
-    fn from(err: std::num::ParseIntError) -> Self {
-        MailError::ParseIntError(err.to_string())
+    #[test]
+    fn test_corrupt_document_is_not_unreachable_storage() {
+        let error = MailError::UnexpectedDocumentError("message_ids".to_string());
+
+        assert_eq!(error.category(), ErrorCategory::StorageCorrupt);
+        assert_ne!(
+            error.category().exit_code(),
+            ErrorCategory::StorageUnreachable.exit_code()
+        );
     }
-}
 
-impl std::convert::From<serde_json::Error> for MailError {
-    fn from(err: serde_json::Error) -> Self {
-        MailError::SerdeJsonError(err.to_string())
+    #[test]
+    fn test_expired_email_category() {
+        let error = MailError::EmailCheckError("abc@guerrillamail.com".to_string());
+
+        assert_eq!(error.category().exit_code(), 4);
     }
 }
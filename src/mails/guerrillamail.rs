@@ -1,11 +1,14 @@
-use crate::mails::MailError;
-use bson;
+use crate::http;
+use crate::mails::provider::{Account, AttachmentPart, Capabilities, FetchedMessage, Provider};
+use crate::mails::{MailError, Message, OutgoingMessage};
+use async_trait::async_trait;
 use chrono::prelude::*;
 use futures::stream::TryStreamExt;
 use mongodb::bson::oid;
 use serde::{Deserialize, Serialize};
 
-use reqwest::Client;
+const PROVIDER: &str = "guerrillamail";
+const BASE_URL: &str = "https://www.guerrillamail.com";
 
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct GuerrillaMail {
@@ -13,6 +16,13 @@ pub struct GuerrillaMail {
     pub email_timestamp: u64,
     pub alias: String,
     pub sid_token: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub label: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
+    /// Proxy of the pool the address was created through
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub proxy: Option<u32>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
@@ -31,65 +41,224 @@ pub struct GuerrillaUser {
 }
 
 impl GuerrillaMail {
+    /// Session of the address, for other requests of the address
+    #[cfg(test)]
+    pub fn session(&self) -> Session<'_> {
+        Session {
+            sid_token: &self.sid_token,
+            proxy: self.proxy,
+        }
+    }
+
+    /// Creates address through the next proxy of the pool, which is kept in `proxy`
     pub async fn create_new_email() -> Result<Self, MailError> {
-        let response = match reqwest::get(
-            "https://www.guerrillamail.com/ajax.php?f=get_email_address&ip=127.0.0.1&agent=Mozilla",
-        )
-        .await
+        let client = http::client(PROVIDER)?;
+        let base_url = client.base_url(BASE_URL);
+        let proxy = client.next_proxy();
+
+        let response = match client
+            .send_through(proxy, |client| {
+                client.get(format!("{base_url}/ajax.php")).query(&[
+                    ("f", "get_email_address"),
+                    ("ip", "127.0.0.1"),
+                    ("agent", "Mozilla"),
+                ])
+            })
+            .await
         {
             Ok(response) => response,
-            Err(e) => return Err(MailError::CreateEmailError(e.to_string())),
+            Err(e) => return Err(MailError::RequestError(e)),
         };
 
         match response.status() {
             reqwest::StatusCode::OK => match response.json::<GuerrillaMail>().await {
-                Ok(mail) => Ok(mail),
-                Err(_) => Err(MailError::MatchError),
+                Ok(mail) => Ok(GuerrillaMail {
+                    proxy: Some(proxy),
+                    ..mail
+                }),
+                Err(e) => Err(MailError::MatchError(e)),
             },
-            error => Err(MailError::ResponseError(error.to_string())),
+            error => Err(MailError::ResponseError(error)),
         }
     }
 
-    pub async fn check_email(seq: u32, sid_token: &String) -> Result<String, reqwest::Error> {
-        let client = Client::builder().build()?;
+    /// Requests new emails without a session, which Guerrilla Mail answers
+    /// with a JSON object. No address is created
+    pub async fn ping() -> Result<(), MailError> {
+        let client = http::client(PROVIDER)?;
+        let base_url = client.base_url(BASE_URL);
+
         let response = client
-            .get(format!(
-            "https://www.guerrillamail.com/ajax.php?f=check_email&seq={seq}&sid_token={sid_token}"
-        ))
-            .header("Cookie", format!("PHPSESSID={sid_token}"))
-            .send()
+            .send(|client| {
+                client
+                    .get(format!("{base_url}/ajax.php"))
+                    .query(&[("f", "check_email"), ("seq", "0")])
+            })
             .await?;
 
-        Ok(response.text().await?)
+        if response.status() != reqwest::StatusCode::OK {
+            return Err(MailError::ResponseError(response.status()));
+        }
+
+        match response.json::<serde_json::Value>().await {
+            Ok(serde_json::Value::Object(_)) => Ok(()),
+            Ok(value) => Err(MailError::UnexpectedResponseError(format!(
+                "expected JSON object, got `{value}`"
+            ))),
+            Err(e) => Err(MailError::MatchError(e)),
+        }
     }
 
-    pub async fn get_email_list(seq: u32, sid_token: &String) -> Result<String, reqwest::Error> {
-        let client = Client::builder().build()?;
-        let response = client
-            .get(format!(
-            "https://www.guerrillamail.com/ajax.php?f=get_email_list&offset={seq}&sid_token={sid_token}&seq=1"
-        ))
-            .header("Cookie", format!("PHPSESSID={sid_token}"))
-            .send()
-            .await?;
+    /// Fails with `RateLimitError` if Guerrilla Mail is still rate limiting
+    /// after retries, so pollers can wait as long as it asks
+    pub async fn check_email(seq: u32, session: Session<'_>) -> Result<String, MailError> {
+        let seq = seq.to_string();
+        let query = [("f", "check_email"), ("seq", seq.as_str())];
+
+        let response = api_request("/ajax.php", &query, session).await?;
+
+        if response.status() == reqwest::StatusCode::TOO_MANY_REQUESTS {
+            return Err(MailError::RateLimitError(http::retry_after(&response)));
+        }
 
         Ok(response.text().await?)
     }
 
-    pub async fn fetch_email(email_id: &str, sid_token: &String) -> Result<String, reqwest::Error> {
-        let client = Client::builder().build()?;
+    pub async fn get_email_list(
+        offset: u32,
+        session: Session<'_>,
+    ) -> Result<String, reqwest::Error> {
+        let offset = offset.to_string();
+        let query = [
+            ("f", "get_email_list"),
+            ("offset", offset.as_str()),
+            ("seq", "1"),
+        ];
+
+        api_request("/ajax.php", &query, session)
+            .await?
+            .text()
+            .await
+    }
+
+    pub async fn fetch_email(
+        email_id: &str,
+        session: Session<'_>,
+    ) -> Result<String, reqwest::Error> {
+        let query = [("f", "fetch_email"), ("email_id", email_id)];
+
+        api_request("/ajax.php", &query, session)
+            .await?
+            .text()
+            .await
+    }
+
+    pub async fn delete_email(
+        email_id: &str,
+        session: Session<'_>,
+    ) -> Result<String, reqwest::Error> {
+        let query = [("f", "del_email"), ("email_ids[]", email_id)];
+
+        api_request("/ajax.php", &query, session)
+            .await?
+            .text()
+            .await
+    }
+
+    pub async fn fetch_attachment(
+        email_id: &str,
+        part_id: &str,
+        session: Session<'_>,
+    ) -> Result<Vec<u8>, reqwest::Error> {
+        let query = [
+            ("get_att", ""),
+            ("email_id", email_id),
+            ("part_id", part_id),
+        ];
+
+        let response = api_request("/inbox", &query, session).await?;
+
+        Ok(response.bytes().await?.to_vec())
+    }
+
+    /// Sends plain text email from the address of the session
+    pub async fn send_email(
+        to: &str,
+        subject: &str,
+        body: &str,
+        session: Session<'_>,
+    ) -> Result<(), MailError> {
+        let client = http::client(PROVIDER)?;
+        let url = format!("{}/ajax.php", client.base_url(BASE_URL));
+        let sid_token = session.sid_token;
+        let form = [("to", to), ("subject", subject), ("body", body)];
+
+        // Sent once, a retry after a lost response could send the email again
         let response = client
-            .get(format!(
-            "https://www.guerrillamail.com/ajax.php?f=fetch_email&email_id={email_id}&sid_token={sid_token}"
-        ))
-            .header("Cookie", format!("PHPSESSID={sid_token}"))
-            .send()
+            .send_once_in_session(sid_token, session.proxy, |client| {
+                client
+                    .post(&url)
+                    .query(&[("f", "send_email")])
+                    .form(&form)
+                    .header(reqwest::header::COOKIE, format!("PHPSESSID={sid_token}"))
+            })
             .await?;
 
-        Ok(response.text().await?)
+        if !response.status().is_success() {
+            return Err(MailError::ResponseError(response.status()));
+        }
+
+        let value: serde_json::Value = response.json().await.map_err(MailError::MatchError)?;
+
+        match value.get("error") {
+            Some(error) => Err(MailError::SendError(
+                error
+                    .as_str()
+                    .unwrap_or("rejected by Guerrilla Mail")
+                    .to_string(),
+            )),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Session token of an address and proxy of the pool the address was created through
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Session<'a> {
+    pub sid_token: &'a str,
+    pub proxy: Option<u32>,
+}
+
+impl<'a> From<&'a Account> for Session<'a> {
+    fn from(account: &'a Account) -> Self {
+        Session {
+            sid_token: &account.sid_token,
+            proxy: account.proxy,
+        }
     }
 }
 
+/// Sends request of a session to Guerrilla Mail. Parameters are URL-encoded
+/// and the session goes only in the cookie, so it does not end up in logs
+async fn api_request(
+    path: &str,
+    query: &[(&str, &str)],
+    session: Session<'_>,
+) -> Result<reqwest::Response, reqwest::Error> {
+    let client = http::client(PROVIDER)?;
+    let url = format!("{}{path}", client.base_url(BASE_URL));
+    let sid_token = session.sid_token;
+
+    client
+        .send_in_session(sid_token, session.proxy, |client| {
+            client
+                .get(&url)
+                .query(query)
+                .header(reqwest::header::COOKIE, format!("PHPSESSID={sid_token}"))
+        })
+        .await
+}
+
 impl GuerrillaUser {
     pub fn new(mail_creation_date: chrono::DateTime<Utc>) -> Self {
         GuerrillaUser {
@@ -105,6 +274,103 @@ impl GuerrillaUser {
     }
 }
 
+/// Provider backed by Guerrilla Mail API
+pub struct GuerrillaProvider;
+
+#[async_trait]
+impl Provider for GuerrillaProvider {
+    async fn create_address(&self) -> Result<Account, MailError> {
+        let guerrilla_email = GuerrillaMail::create_new_email().await?;
+
+        let created_at = i64::try_from(guerrilla_email.email_timestamp)
+            .ok()
+            .and_then(|timestamp| NaiveDateTime::from_timestamp_opt(timestamp, 0))
+            .map(|date| DateTime::from_utc(date, Utc))
+            .ok_or_else(|| {
+                MailError::UnexpectedResponseError(format!(
+                    "invalid email_timestamp `{}`",
+                    guerrilla_email.email_timestamp
+                ))
+            })?;
+
+        Ok(Account {
+            provider: PROVIDER.to_string(),
+            email_addr: guerrilla_email.email_addr,
+            sid_token: guerrilla_email.sid_token,
+            created_at,
+            label: None,
+            tags: Vec::new(),
+            proxy: guerrilla_email.proxy,
+        })
+    }
+
+    async fn list_messages(
+        &self,
+        account: &Account,
+        offset: u32,
+    ) -> Result<Vec<Message>, MailError> {
+        let response = GuerrillaMail::get_email_list(offset, account.into()).await?;
+
+        messages_from_list(&account.email_addr, &response)
+    }
+
+    async fn check_messages(&self, account: &Account, seq: u32) -> Result<Vec<Message>, MailError> {
+        let response = GuerrillaMail::check_email(seq, account.into()).await?;
+
+        messages_from_list(&account.email_addr, &response)
+    }
+
+    async fn fetch_message(
+        &self,
+        account: &Account,
+        mail_id: &str,
+    ) -> Result<Option<FetchedMessage>, MailError> {
+        let response = GuerrillaMail::fetch_email(mail_id, account.into()).await?;
+
+        parse_fetched_email(&account.email_addr, &response)
+    }
+
+    async fn delete_message(&self, account: &Account, mail_id: &str) -> Result<(), MailError> {
+        GuerrillaMail::delete_email(mail_id, account.into()).await?;
+
+        Ok(())
+    }
+
+    async fn fetch_attachment(
+        &self,
+        account: &Account,
+        mail_id: &str,
+        part_id: &str,
+    ) -> Result<Vec<u8>, MailError> {
+        Ok(GuerrillaMail::fetch_attachment(mail_id, part_id, account.into()).await?)
+    }
+
+    /// Without an address, only the API is probed, as creating one
+    /// would use up the rate limit
+    async fn check_health(&self, account: Option<&Account>) -> Result<(), MailError> {
+        match account {
+            Some(account) => self.list_messages(account, 0).await.map(|_| ()),
+            None => GuerrillaMail::ping().await,
+        }
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            reply: true,
+            forward: true,
+        }
+    }
+
+    async fn send_message(
+        &self,
+        account: &Account,
+        message: &OutgoingMessage,
+    ) -> Result<(), MailError> {
+        GuerrillaMail::send_email(&message.to, &message.subject, &message.body, account.into())
+            .await
+    }
+}
+
 fn date_default_value() -> chrono::DateTime<Utc> {
     chrono::Utc::now()
 }
@@ -126,45 +392,206 @@ pub async fn get_unexpired_guerrillamails_from_db(
         emails.push(email);
     }
 
-    if emails.is_empty() {
-        return Ok(Vec::new());
-    }
+    email_addrs_from_documents(&emails)
+}
 
+/// Collects `mails.email_addr` values from `email_users` documents.
+/// Documents without `mails` field are skipped
+pub fn email_addrs_from_documents(emails: &[bson::Document]) -> Result<Vec<String>, MailError> {
     let mut email_addrs: Vec<String> = Vec::new();
 
     for email in emails {
-        // Using unwrap is safe, because mongodb fields are known
-        if email.get("mails").is_some() {
-            let emails_bson = email.get("mails").unwrap().as_array().unwrap();
-            for email in emails_bson {
-                let email_addr = email
-                    .as_document()
-                    .unwrap()
-                    .get("email_addr")
-                    .unwrap()
-                    .as_str()
-                    .unwrap();
-
-                email_addrs.push(email_addr.to_owned());
-            }
-        } else {
-            return Ok(Vec::new());
+        let emails_bson = match email.get("mails") {
+            Some(mails) => mails.as_array().ok_or_else(|| {
+                MailError::UnexpectedDocumentError("`mails` is not an array".to_string())
+            })?,
+            None => continue,
         };
+
+        for email in emails_bson {
+            let email_addr = email
+                .as_document()
+                .and_then(|email| email.get_str("email_addr").ok())
+                .ok_or_else(|| {
+                    MailError::UnexpectedDocumentError(
+                        "`mails.email_addr` is not a string".to_string(),
+                    )
+                })?;
+
+            email_addrs.push(email_addr.to_owned());
+        }
     }
 
     Ok(email_addrs)
 }
 
+/// Returns `list` array from `get_email_list` or `check_email` response
+pub fn parse_email_list(response: &str) -> Result<Vec<serde_json::Value>, MailError> {
+    let mut value: serde_json::Value = serde_json::from_str(response)?;
+
+    match value.get_mut("list").map(serde_json::Value::take) {
+        Some(serde_json::Value::Array(list)) => Ok(list),
+        _ => Err(MailError::UnexpectedResponseError(
+            "`list` is not an array".to_string(),
+        )),
+    }
+}
+
+fn messages_from_list(email_addr: &str, response: &str) -> Result<Vec<Message>, MailError> {
+    Ok(parse_email_list(response)?
+        .iter()
+        .filter_map(|value| Message::from_guerrilla_json(email_addr, value))
+        .collect())
+}
+
+/// Parses `fetch_email` response, which is `false` for unknown email id
+pub fn parse_fetched_email(
+    email_addr: &str,
+    response: &str,
+) -> Result<Option<FetchedMessage>, MailError> {
+    let value: serde_json::Value = serde_json::from_str(response)?;
+
+    if value == false {
+        return Ok(None);
+    }
+
+    let message = Message::from_guerrilla_json(email_addr, &value).ok_or_else(|| {
+        MailError::UnexpectedResponseError(
+            "email without `mail_id` or `mail_timestamp`".to_string(),
+        )
+    })?;
+
+    let attachments = value["att_info"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|attachment| {
+            let part_id = attachment["p"].as_str()?;
+
+            Some(AttachmentPart {
+                part_id: part_id.to_string(),
+                filename: attachment["f"].as_str().unwrap_or(part_id).to_string(),
+                content_type: attachment["t"]
+                    .as_str()
+                    .unwrap_or("application/octet-stream")
+                    .to_string(),
+            })
+        })
+        .collect();
+
+    Ok(Some(FetchedMessage {
+        message,
+        attachments,
+    }))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    const TEST_SESSION: Session = Session {
+        sid_token: "test",
+        proxy: None,
+    };
+
+    use crate::mails::strategies;
+    use crate::mock;
+    use proptest::prelude::*;
+
+    proptest! {
+        #[test]
+        fn test_parse_email_list_never_panics(response in "\\PC*") {
+            let _ = parse_email_list(&response);
+        }
+
+        #[test]
+        fn test_parse_email_list_json_never_panics(value in strategies::json_value()) {
+            let _ = parse_email_list(&value.to_string());
+        }
+
+        #[test]
+        fn test_parse_fetched_email_never_panics(value in strategies::json_value()) {
+            let _ = parse_fetched_email("a@guerrillamail.com", &value.to_string());
+        }
+
+        #[test]
+        fn test_email_addrs_from_documents_never_panics(
+            documents in prop::collection::vec(strategies::bson_document(), 0..4)
+        ) {
+            let _ = email_addrs_from_documents(&documents);
+        }
+
+        #[test]
+        fn test_guerrilla_user_from_bson_never_panics(document in strategies::bson_document()) {
+            let _ = bson::from_document::<GuerrillaUser>(document);
+        }
+    }
+
+    #[test]
+    fn test_parse_email_list() {
+        let list = parse_email_list(r#"{"list": [{"mail_id": "1"}], "count": "1"}"#).unwrap();
+
+        assert_eq!(list, vec![serde_json::json!({"mail_id": "1"})]);
+        assert!(matches!(
+            parse_email_list(r#"{"list": null}"#),
+            Err(MailError::UnexpectedResponseError(_))
+        ));
+    }
+
+    #[test]
+    fn test_parse_fetched_email() {
+        assert_eq!(
+            parse_fetched_email("a@guerrillamail.com", "false").unwrap(),
+            None
+        );
+
+        let fetched = parse_fetched_email(
+            "a@guerrillamail.com",
+            r#"{"mail_id": "2", "mail_timestamp": "1646092800", "mail_body": "Hi",
+                "att_info": [{"f": "terms.txt", "t": "text/plain", "p": "1"}, {"f": "broken"}]}"#,
+        )
+        .unwrap()
+        .unwrap();
+
+        assert_eq!(fetched.message.mail_body, Some("Hi".to_string()));
+        assert_eq!(
+            fetched.attachments,
+            vec![AttachmentPart {
+                part_id: "1".to_string(),
+                filename: "terms.txt".to_string(),
+                content_type: "text/plain".to_string(),
+            }]
+        );
+        assert!(matches!(
+            parse_fetched_email("a@guerrillamail.com", "{}"),
+            Err(MailError::UnexpectedResponseError(_))
+        ));
+    }
+
+    #[test]
+    fn test_email_addrs_from_documents() {
+        let documents = vec![
+            bson::doc! { "mails": [{ "email_addr": "a@guerrillamail.com" }] },
+            bson::doc! {},
+        ];
+
+        assert_eq!(
+            email_addrs_from_documents(&documents).unwrap(),
+            vec!["a@guerrillamail.com".to_string()]
+        );
+        assert!(matches!(
+            email_addrs_from_documents(&[bson::doc! { "mails": [{ "email_addr": 1 }] }]),
+            Err(MailError::UnexpectedDocumentError(_))
+        ));
+    }
+
     #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
-    #[ignore]
     async fn test_check_email() -> Result<(), MailError> {
+        mock::init_test_provider();
+
         let guerrillamail = GuerrillaMail::create_new_email().await?;
 
-        let response = GuerrillaMail::check_email(1, &guerrillamail.sid_token).await?;
+        let response = GuerrillaMail::check_email(1, guerrillamail.session()).await?;
 
         let value: serde_json::Value = serde_json::from_str(&response)?;
 
@@ -174,9 +601,10 @@ mod tests {
     }
 
     #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
-    #[ignore]
     async fn test_check_email_with_wrong_values() -> Result<(), MailError> {
-        let response = GuerrillaMail::check_email(1, &"test".to_string()).await?;
+        mock::init_test_provider();
+
+        let response = GuerrillaMail::check_email(1, TEST_SESSION).await?;
 
         let value: serde_json::Value = serde_json::from_str(&response)?;
 
@@ -189,9 +617,10 @@ mod tests {
     }
 
     #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
-    #[ignore]
     async fn test_get_email_list_with_wrong_values() -> Result<(), MailError> {
-        let response = GuerrillaMail::get_email_list(1, &"test".to_string()).await?;
+        mock::init_test_provider();
+
+        let response = GuerrillaMail::get_email_list(1, TEST_SESSION).await?;
 
         let value: serde_json::Value = serde_json::from_str(&response)?;
 
@@ -201,11 +630,12 @@ mod tests {
     }
 
     #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
-    #[ignore]
     async fn test_get_email_list() -> Result<(), MailError> {
+        mock::init_test_provider();
+
         let guerrillamail = GuerrillaMail::create_new_email().await?;
 
-        let response = GuerrillaMail::get_email_list(1, &guerrillamail.sid_token).await?;
+        let response = GuerrillaMail::get_email_list(1, guerrillamail.session()).await?;
 
         let value: serde_json::Value = serde_json::from_str(&response)?;
 
@@ -216,10 +646,137 @@ mod tests {
 
     #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
     async fn test_fetch_email_with_wrong_values() -> Result<(), MailError> {
-        let response = GuerrillaMail::fetch_email("111", &"test".to_string()).await?;
+        mock::init_test_provider();
+
+        let response = GuerrillaMail::fetch_email("111", TEST_SESSION).await?;
 
         assert_eq!(response, "false".to_string());
 
         Ok(())
     }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_create_check_fetch_delete_flow() -> Result<(), MailError> {
+        mock::init_test_provider();
+
+        let guerrillamail = GuerrillaMail::create_new_email().await?;
+        let session = guerrillamail.session();
+
+        let list = parse_email_list(&GuerrillaMail::get_email_list(0, session).await?)?;
+        assert_eq!(list.len(), 1);
+        assert_eq!(list[0]["mail_subject"], "Welcome to Guerrilla Mail");
+
+        // Verification email arrives 2 seconds after creation
+        let mut new_emails = Vec::new();
+        for _ in 0..10 {
+            new_emails = parse_email_list(&GuerrillaMail::check_email(1, session).await?)?;
+            if !new_emails.is_empty() {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(500)).await;
+        }
+        assert_eq!(new_emails.len(), 1);
+        assert_eq!(new_emails[0]["mail_id"], "2");
+
+        let email: serde_json::Value =
+            serde_json::from_str(&GuerrillaMail::fetch_email("2", session).await?)?;
+        assert_eq!(email["mail_body"], "<p>Your code is 482913</p>");
+        assert_eq!(email["att_info"][0]["f"], "terms.txt");
+
+        let attachment = GuerrillaMail::fetch_attachment("2", "1", session).await?;
+        assert_eq!(attachment, b"Terms of service".to_vec());
+
+        let deleted: serde_json::Value =
+            serde_json::from_str(&GuerrillaMail::delete_email("2", session).await?)?;
+        assert_eq!(deleted["deleted_ids"], serde_json::json!(["2"]));
+
+        let response = GuerrillaMail::fetch_email("2", session).await?;
+        assert_eq!(response, "false");
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_session_token_is_sent_only_in_cookie() -> Result<(), MailError> {
+        mock::init_test_provider();
+
+        let guerrillamail = GuerrillaMail::create_new_email().await?;
+        let sid_token = &guerrillamail.sid_token;
+        let session = guerrillamail.session();
+
+        GuerrillaMail::check_email(1, session).await?;
+        GuerrillaMail::get_email_list(0, session).await?;
+        // `&` in id must not start another parameter
+        let response = GuerrillaMail::fetch_email("1&f=del_email", session).await?;
+        assert_eq!(response, "false");
+        GuerrillaMail::delete_email("1 2", session).await?;
+        GuerrillaMail::fetch_attachment("2", "1", session).await?;
+
+        let requests = mock::recorded_requests(&format!("PHPSESSID={sid_token}"));
+        assert_eq!(requests.len(), 5);
+
+        for request in &requests {
+            assert!(!request.uri.contains(sid_token.as_str()), "{}", request.uri);
+            assert!(!request.uri.contains("sid_token"), "{}", request.uri);
+        }
+
+        let query = |uri: &str| -> Vec<(String, String)> {
+            reqwest::Url::parse(&format!("http://localhost{uri}"))
+                .unwrap()
+                .query_pairs()
+                .into_owned()
+                .collect()
+        };
+        let pairs = |pairs: &[(&str, &str)]| -> Vec<(String, String)> {
+            pairs
+                .iter()
+                .map(|(key, value)| (key.to_string(), value.to_string()))
+                .collect()
+        };
+
+        assert_eq!(
+            query(&requests[2].uri),
+            pairs(&[("f", "fetch_email"), ("email_id", "1&f=del_email")])
+        );
+        assert_eq!(
+            query(&requests[3].uri),
+            pairs(&[("f", "del_email"), ("email_ids[]", "1 2")])
+        );
+        assert!(requests[4].uri.starts_with("/inbox?"));
+
+        // Welcome email was not deleted by any of the requests
+        let list = parse_email_list(&GuerrillaMail::get_email_list(0, session).await?)?;
+        assert_eq!(list.len(), 1);
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_send_email() -> Result<(), MailError> {
+        mock::init_test_provider();
+
+        let guerrillamail = GuerrillaMail::create_new_email().await?;
+        let sid_token = &guerrillamail.sid_token;
+        let session = guerrillamail.session();
+
+        GuerrillaMail::send_email(
+            "dev@example.com",
+            "Fwd: Verify",
+            "Code & more",
+            guerrillamail.session(),
+        )
+        .await?;
+        assert!(matches!(
+            GuerrillaMail::send_email("nobody", "Re: Verify", "YES", session).await,
+            Err(MailError::SendError(_))
+        ));
+
+        // Email is sent in the body, so it stays out of proxy and server logs
+        let requests = mock::recorded_requests(&format!("PHPSESSID={sid_token}"));
+        assert_eq!(requests.len(), 2);
+        assert_eq!(requests[0].method, "POST");
+        assert_eq!(requests[0].uri, "/ajax.php?f=send_email");
+
+        Ok(())
+    }
 }
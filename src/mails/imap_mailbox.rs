@@ -0,0 +1,576 @@
+use async_trait::async_trait;
+use chrono::prelude::*;
+use rand::Rng;
+
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::time::Duration;
+
+use crate::config::Config;
+use crate::mails::message::{attachments_from_rfc5322, is_addressed_to};
+use crate::mails::provider::{Account, AttachmentPart, FetchedMessage, Provider};
+use crate::mails::{MailError, Message};
+
+const PAGE_SIZE: usize = 20;
+
+/// Connection of IMAP session, plain TCP or TLS
+trait Stream: Read + Write + Send {}
+
+impl<T: Read + Write + Send> Stream for T {}
+
+type Session = ::imap::Session<Box<dyn Stream>>;
+
+/// Mail server with catch-all mailbox of a domain
+#[derive(Debug, Clone, PartialEq)]
+struct ImapSettings {
+    host: String,
+    port: u16,
+    username: String,
+    password: String,
+    tls: bool,
+    mailbox: String,
+    timeout: Duration,
+}
+
+/// Provider for a catch-all domain hosted on a regular mail server.
+/// Addresses are random local parts on the domain, and their emails are
+/// read over IMAP from the catch-all mailbox, filtered by `To` header
+#[derive(Debug, Clone)]
+pub struct ImapProvider {
+    domain: String,
+    settings: ImapSettings,
+}
+
+/// Email as it was fetched from the server
+struct RawEmail {
+    uid: u32,
+    received: Option<DateTime<Utc>>,
+    raw: Vec<u8>,
+}
+
+impl ImapProvider {
+    pub const NAME: &'static str = "imap";
+
+    /// Provider from `[providers.imap]` section of config file.
+    /// Returns None if the section is missing
+    pub fn from_config(config: &Config) -> Result<Option<Self>, MailError> {
+        if !config.providers.contains_key(ImapProvider::NAME) {
+            return Ok(None);
+        }
+
+        let provider = config.provider(ImapProvider::NAME);
+
+        let required = |value: Option<String>, field: &str| {
+            value.ok_or_else(|| {
+                MailError::ConfigError(format!(
+                    "`{field}` is required in [providers.{}] section",
+                    ImapProvider::NAME
+                ))
+            })
+        };
+
+        let tls = provider.tls.unwrap_or(true);
+
+        Ok(Some(ImapProvider {
+            domain: required(provider.domain, "domain")?.to_lowercase(),
+            settings: ImapSettings {
+                host: required(provider.host, "host")?,
+                port: provider.port.unwrap_or(if tls { 993 } else { 143 }),
+                username: required(provider.username, "username")?,
+                password: required(provider.password, "password")?,
+                tls,
+                mailbox: provider.mailbox.unwrap_or_else(|| "INBOX".to_string()),
+                timeout: Duration::from_secs(config.http.timeout_secs),
+            },
+        }))
+    }
+
+    /// Runs `f` in a new IMAP session with the catch-all mailbox selected.
+    /// IMAP client is blocking, so the session lives on a blocking thread
+    async fn run<T, F>(&self, f: F) -> Result<T, MailError>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut Session) -> Result<T, MailError> + Send + 'static,
+    {
+        let settings = self.settings.clone();
+
+        tokio::task::spawn_blocking(move || {
+            let mut session = connect(&settings)?;
+
+            session.select(&settings.mailbox)?;
+
+            let result = f(&mut session);
+
+            let _ = session.logout();
+
+            result
+        })
+        .await
+        .map_err(|e| MailError::UnexpectedResponseError(e.to_string()))?
+    }
+
+    /// Emails of `account` among uids picked by `select` from the uids found by
+    /// search, newest first. Search and fetch share one session
+    async fn find_emails<F>(&self, account: &Account, select: F) -> Result<Vec<RawEmail>, MailError>
+    where
+        F: FnOnce(Vec<u32>) -> Vec<u32> + Send + 'static,
+    {
+        let email_addr = account.email_addr.clone();
+
+        self.run(move |session| {
+            let mut uids: Vec<u32> = session
+                .uid_search(format!("TO {}", quote(&email_addr)))?
+                .into_iter()
+                .collect();
+            uids.sort_unstable_by(|a, b| b.cmp(a));
+
+            fetch_addressed(session, &select(uids), &email_addr)
+        })
+        .await
+    }
+
+    async fn fetch_email(
+        &self,
+        account: &Account,
+        mail_id: &str,
+    ) -> Result<Option<RawEmail>, MailError> {
+        let uid: u32 = match mail_id.parse() {
+            Ok(uid) => uid,
+            Err(_) => return Ok(None),
+        };
+
+        let email_addr = account.email_addr.clone();
+
+        Ok(self
+            .run(move |session| fetch_addressed(session, &[uid], &email_addr))
+            .await?
+            .pop())
+    }
+}
+
+fn connect(settings: &ImapSettings) -> Result<Session, MailError> {
+    let tcp =
+        TcpStream::connect((settings.host.as_str(), settings.port)).map_err(::imap::Error::Io)?;
+    tcp.set_read_timeout(Some(settings.timeout))
+        .and_then(|_| tcp.set_write_timeout(Some(settings.timeout)))
+        .map_err(::imap::Error::Io)?;
+
+    let stream: Box<dyn Stream> = if settings.tls {
+        let connector = native_tls::TlsConnector::new().map_err(::imap::Error::Tls)?;
+
+        Box::new(
+            connector
+                .connect(&settings.host, tcp)
+                .map_err(::imap::Error::TlsHandshake)?,
+        )
+    } else {
+        Box::new(tcp)
+    };
+
+    let mut client = ::imap::Client::new(stream);
+    client.read_greeting()?;
+
+    let session = client
+        .login(&settings.username, &settings.password)
+        .map_err(|(e, _)| e)?;
+
+    Ok(session)
+}
+
+/// Emails with uid in `uids` addressed to `email_addr`, newest first
+fn fetch_addressed(
+    session: &mut Session,
+    uids: &[u32],
+    email_addr: &str,
+) -> Result<Vec<RawEmail>, MailError> {
+    if uids.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let mut emails = fetch_raw(session, uids)?
+        .into_iter()
+        .filter(|email| is_addressed_to(&email.raw, email_addr))
+        .collect::<Vec<RawEmail>>();
+
+    emails.sort_by_key(|email| std::cmp::Reverse(email.uid));
+
+    Ok(emails)
+}
+
+fn fetch_raw(session: &mut Session, uids: &[u32]) -> Result<Vec<RawEmail>, MailError> {
+    let uid_set = uids
+        .iter()
+        .map(u32::to_string)
+        .collect::<Vec<String>>()
+        .join(",");
+
+    let fetches = session.uid_fetch(uid_set, "(UID INTERNALDATE BODY.PEEK[])")?;
+
+    Ok(fetches
+        .iter()
+        .filter_map(|fetch| {
+            Some(RawEmail {
+                uid: fetch.uid?,
+                received: fetch.internal_date().map(|date| date.with_timezone(&Utc)),
+                raw: fetch.body()?.to_vec(),
+            })
+        })
+        .collect())
+}
+
+/// IMAP quoted string
+fn quote(value: &str) -> String {
+    format!("\"{}\"", value.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+fn to_message(account: &Account, email: &RawEmail) -> Option<Message> {
+    let received = email.received.unwrap_or_else(Utc::now);
+
+    Message::from_rfc5322(
+        &account.email_addr,
+        &email.uid.to_string(),
+        received.timestamp(),
+        &email.raw,
+    )
+}
+
+#[async_trait]
+impl Provider for ImapProvider {
+    async fn create_address(&self) -> Result<Account, MailError> {
+        let mut rng = rand::thread_rng();
+
+        let alias: String = (0..10)
+            .map(|_| char::from_digit(rng.gen_range(0..36), 36).unwrap_or('a'))
+            .collect();
+
+        Ok(Account {
+            provider: ImapProvider::NAME.to_string(),
+            email_addr: format!("{alias}@{}", self.domain),
+            sid_token: String::new(),
+            created_at: Utc::now(),
+            label: None,
+            tags: Vec::new(),
+            proxy: None,
+        })
+    }
+
+    /// Addresses are created without the server, so log in instead
+    async fn check_health(&self, _account: Option<&Account>) -> Result<(), MailError> {
+        self.run(|_| Ok(())).await
+    }
+
+    async fn list_messages(
+        &self,
+        account: &Account,
+        offset: u32,
+    ) -> Result<Vec<Message>, MailError> {
+        let select = move |uids: Vec<u32>| {
+            uids.into_iter()
+                .skip(offset as usize)
+                .take(PAGE_SIZE)
+                .collect()
+        };
+
+        Ok(self
+            .find_emails(account, select)
+            .await?
+            .iter()
+            .filter_map(|email| to_message(account, email))
+            .collect())
+    }
+
+    async fn check_messages(&self, account: &Account, seq: u32) -> Result<Vec<Message>, MailError> {
+        let select = move |uids: Vec<u32>| uids.into_iter().filter(|uid| *uid > seq).collect();
+
+        Ok(self
+            .find_emails(account, select)
+            .await?
+            .iter()
+            .filter_map(|email| to_message(account, email))
+            .collect())
+    }
+
+    async fn fetch_message(
+        &self,
+        account: &Account,
+        mail_id: &str,
+    ) -> Result<Option<FetchedMessage>, MailError> {
+        let email = match self.fetch_email(account, mail_id).await? {
+            Some(email) => email,
+            None => return Ok(None),
+        };
+
+        Ok(to_message(account, &email).map(|message| FetchedMessage {
+            message,
+            attachments: AttachmentPart::numbered(&attachments_from_rfc5322(&email.raw)),
+        }))
+    }
+
+    async fn delete_message(&self, account: &Account, mail_id: &str) -> Result<(), MailError> {
+        let not_found = || MailError::MessageNotFoundError(mail_id.to_string());
+        let uid: u32 = mail_id.parse().map_err(|_| not_found())?;
+        let email_addr = account.email_addr.clone();
+
+        // Other addresses share the mailbox, so only their own emails are deleted,
+        // and only this one is expunged even if other clients flagged more
+        let deleted = self
+            .run(move |session| {
+                if fetch_addressed(session, &[uid], &email_addr)?.is_empty() {
+                    return Ok(false);
+                }
+
+                session.uid_store(uid.to_string(), "+FLAGS (\\Deleted)")?;
+                session.uid_expunge(uid.to_string())?;
+
+                Ok(true)
+            })
+            .await?;
+
+        if deleted {
+            Ok(())
+        } else {
+            Err(not_found())
+        }
+    }
+
+    async fn fetch_attachment(
+        &self,
+        account: &Account,
+        mail_id: &str,
+        part_id: &str,
+    ) -> Result<Vec<u8>, MailError> {
+        let index: usize = part_id.parse()?;
+
+        self.fetch_email(account, mail_id)
+            .await?
+            .and_then(|email| attachments_from_rfc5322(&email.raw).into_iter().nth(index))
+            .map(|attachment| attachment.data)
+            .ok_or_else(|| MailError::MessageNotFoundError(mail_id.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::{BufRead, BufReader};
+    use std::net::TcpListener;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{Arc, Mutex};
+
+    /// Emails in the fake catch-all mailbox: uid, raw email and deleted flag
+    type Mailbox = Arc<Mutex<Vec<(u32, String, bool)>>>;
+
+    /// In-process IMAP server answering the commands the provider sends.
+    /// Like real servers, its search also matches addresses in other headers.
+    /// Returns its port and the number of sessions opened
+    fn fake_server(mailbox: Mailbox) -> (u16, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let sessions = Arc::new(AtomicUsize::new(0));
+
+        let counter = sessions.clone();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                counter.fetch_add(1, Ordering::SeqCst);
+                let mailbox = mailbox.clone();
+                std::thread::spawn(move || handle(stream.unwrap(), &mailbox));
+            }
+        });
+
+        (port, sessions)
+    }
+
+    fn handle(stream: TcpStream, mailbox: &Mailbox) {
+        let mut writer = stream.try_clone().unwrap();
+        let mut reader = BufReader::new(stream);
+
+        writer.write_all(b"* OK fake IMAP ready\r\n").unwrap();
+
+        let mut line = String::new();
+        while reader.read_line(&mut line).unwrap() > 0 {
+            let command = line.trim_end().to_string();
+            line.clear();
+
+            let (tag, command) = command.split_once(' ').unwrap();
+            let mut response = String::new();
+            let mut mailbox = mailbox.lock().unwrap();
+
+            let status = match command.split(' ').collect::<Vec<&str>>().as_slice() {
+                ["LOGIN", "\"catch-all@qa.example.com\"", "\"secret\""] => "OK LOGIN completed",
+                ["LOGIN", ..] => "NO [AUTHENTICATIONFAILED] Invalid credentials",
+                ["SELECT", ..] => {
+                    response.push_str(&format!("* {} EXISTS\r\n", mailbox.len()));
+                    "OK [READ-WRITE] SELECT completed"
+                }
+                ["UID", "SEARCH", "TO", address] => {
+                    let address = address.trim_matches('"');
+                    let uids: Vec<String> = mailbox
+                        .iter()
+                        .filter(|(_, raw, _)| raw.to_lowercase().contains(address))
+                        .map(|(uid, _, _)| uid.to_string())
+                        .collect();
+
+                    response.push_str(&format!("* SEARCH {}\r\n", uids.join(" ")));
+                    "OK SEARCH completed"
+                }
+                ["UID", "FETCH", uids, ..] => {
+                    for uid in uids.split(',') {
+                        let uid: u32 = uid.parse().unwrap();
+
+                        if let Some(index) = mailbox.iter().position(|(id, _, _)| *id == uid) {
+                            let raw = &mailbox[index].1;
+                            response.push_str(&format!(
+                                "* {} FETCH (UID {uid} INTERNALDATE \"17-Jul-2024 02:44:25 +0000\" BODY[] {{{}}}\r\n{raw})\r\n",
+                                index + 1,
+                                raw.len()
+                            ));
+                        }
+                    }
+                    "OK FETCH completed"
+                }
+                ["UID", "STORE", uid, "+FLAGS", "(\\Deleted)"] => {
+                    let uid: u32 = uid.parse().unwrap();
+                    for email in mailbox.iter_mut().filter(|(id, _, _)| *id == uid) {
+                        email.2 = true;
+                    }
+                    "OK STORE completed"
+                }
+                ["EXPUNGE"] => {
+                    mailbox.retain(|(_, _, deleted)| !deleted);
+                    "OK EXPUNGE completed"
+                }
+                ["UID", "EXPUNGE", uid] => {
+                    let uid: u32 = uid.parse().unwrap();
+                    mailbox.retain(|(id, _, deleted)| *id != uid || !deleted);
+                    "OK EXPUNGE completed"
+                }
+                ["LOGOUT"] => {
+                    response.push_str("* BYE\r\n");
+                    "OK LOGOUT completed"
+                }
+                _ => "BAD unknown command",
+            };
+
+            writer
+                .write_all(format!("{response}{tag} {status}\r\n").as_bytes())
+                .unwrap();
+        }
+    }
+
+    fn provider(port: u16, password: &str) -> ImapProvider {
+        let config = Config::parse(&format!(
+            r#"
+            [providers.imap]
+            domain = "QA.example.com"
+            host = "127.0.0.1"
+            port = {port}
+            username = "catch-all@qa.example.com"
+            password = "{password}"
+            tls = false
+            "#
+        ))
+        .unwrap();
+
+        ImapProvider::from_config(&config).unwrap().unwrap()
+    }
+
+    #[test]
+    fn test_missing_section_or_field() {
+        assert!(ImapProvider::from_config(&Config::default())
+            .unwrap()
+            .is_none());
+
+        let config = Config::parse(
+            r#"
+            [providers.imap]
+            domain = "qa.example.com"
+            host = "mail.example.com"
+            "#,
+        )
+        .unwrap();
+
+        assert!(matches!(
+            ImapProvider::from_config(&config),
+            Err(MailError::ConfigError(message)) if message.contains("username")
+        ));
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_read_catch_all_mailbox() -> Result<(), MailError> {
+        let mailbox: Mailbox = Arc::new(Mutex::new(vec![
+            (
+                3,
+                "From: app@staging.test\r\nTo: qa@qa.example.com\r\nSubject: Verify\r\n\r\nYour code is 482913\r\n".to_string(),
+                false,
+            ),
+            (
+                5,
+                "From: app@staging.test\r\nTo: dev@qa.example.com\r\nCc: qa@qa.example.com\r\nSubject: Copy\r\n\r\nNot for qa\r\n".to_string(),
+                // Flagged by another client, which expunges it later
+                true,
+            ),
+            (
+                7,
+                "From: app@staging.test\r\nTo: QA@qa.example.com\r\nSubject: Reset\r\n\r\nReset token 5521\r\n".to_string(),
+                false,
+            ),
+        ]));
+        let (port, sessions) = fake_server(mailbox.clone());
+        let provider = provider(port, "secret");
+
+        let created = provider.create_address().await?;
+        assert!(created.email_addr.ends_with("@qa.example.com"));
+
+        let account = Account::test(ImapProvider::NAME, "qa@qa.example.com");
+
+        let emails = provider.list_messages(&account, 0).await?;
+        assert_eq!(sessions.load(Ordering::SeqCst), 1);
+        assert_eq!(
+            emails
+                .iter()
+                .map(|message| message.mail_id.as_str())
+                .collect::<Vec<&str>>(),
+            ["7", "3"]
+        );
+        assert_eq!(emails[1].mail_subject, "Verify");
+
+        let new_emails = provider.check_messages(&account, 3).await?;
+        assert_eq!(sessions.load(Ordering::SeqCst), 2);
+        assert_eq!(new_emails.len(), 1);
+        assert_eq!(new_emails[0].mail_subject, "Reset");
+
+        assert_eq!(provider.fetch_message(&account, "5").await?, None);
+        assert!(matches!(
+            provider.delete_message(&account, "5").await,
+            Err(MailError::MessageNotFoundError(_))
+        ));
+
+        provider.delete_message(&account, "7").await?;
+        assert_eq!(
+            mailbox
+                .lock()
+                .unwrap()
+                .iter()
+                .map(|(uid, _, _)| *uid)
+                .collect::<Vec<u32>>(),
+            [3, 5]
+        );
+        assert_eq!(provider.list_messages(&account, 0).await?.len(), 1);
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_wrong_password() {
+        let (port, _) = fake_server(Arc::new(Mutex::new(Vec::new())));
+        let provider = provider(port, "wrong");
+
+        let error = provider
+            .list_messages(&Account::test(ImapProvider::NAME, "qa@qa.example.com"), 0)
+            .await
+            .unwrap_err();
+
+        assert!(matches!(error, MailError::ImapError(::imap::Error::No(_))));
+    }
+}
@@ -0,0 +1,327 @@
+use async_trait::async_trait;
+use chrono::prelude::*;
+use rand::Rng;
+
+use std::sync::Arc;
+
+use crate::mails::message::attachments_from_rfc5322;
+use crate::mails::provider::{Account, AttachmentPart, Capabilities, FetchedMessage, Provider};
+use crate::mails::{Attachment, MailError, Message, OutgoingMessage};
+use crate::search::SearchQuery;
+use crate::smtp;
+use crate::storage::Storage;
+
+/// Domain of local addresses when config file does not set one
+pub const DEFAULT_DOMAIN: &str = "disposable.local";
+const PAGE_SIZE: usize = 20;
+
+/// Provider whose inboxes are filled by the built-in SMTP server.
+/// Emails are kept in storage, so they are read without network
+#[derive(Clone)]
+pub struct LocalProvider {
+    storage: Arc<dyn Storage>,
+    domain: String,
+    /// SMTP server sending email to other domains
+    relay: Option<String>,
+    // Addresses receiving their first email are created one at a time
+    delivery: Arc<tokio::sync::Mutex<()>>,
+}
+
+impl LocalProvider {
+    pub const NAME: &'static str = "local";
+
+    pub fn new(storage: Arc<dyn Storage>, domain: &str) -> Self {
+        LocalProvider {
+            storage,
+            domain: domain.to_lowercase(),
+            relay: None,
+            delivery: Arc::new(tokio::sync::Mutex::new(())),
+        }
+    }
+
+    /// Sends email to addresses outside the domain through SMTP server `relay`
+    pub fn with_relay(mut self, relay: Option<String>) -> Self {
+        self.relay = relay;
+        self
+    }
+
+    pub fn domain(&self) -> &str {
+        &self.domain
+    }
+
+    pub fn relay(&self) -> Option<&str> {
+        self.relay.as_deref()
+    }
+
+    /// Whether the SMTP server takes email for `email_addr`
+    pub fn accepts(&self, email_addr: &str) -> bool {
+        email_addr.rsplit_once('@').is_some_and(|(alias, domain)| {
+            !alias.is_empty() && domain.eq_ignore_ascii_case(&self.domain)
+        })
+    }
+
+    /// Stores email received over SMTP in inbox of `email_addr`.
+    /// Address does not have to be created before, any address on the domain gets email.
+    /// Returns id of the delivered email
+    pub async fn deliver(&self, email_addr: &str, raw: &[u8]) -> Result<String, MailError> {
+        let email_addr = email_addr.to_lowercase();
+
+        let _delivery = self.delivery.lock().await;
+
+        if let Err(MailError::EmailCheckError(_)) = self.storage.find_account(&email_addr).await {
+            self.storage
+                .save_account(&Account {
+                    provider: LocalProvider::NAME.to_string(),
+                    email_addr: email_addr.clone(),
+                    sid_token: random_token(),
+                    created_at: Utc::now(),
+                    label: None,
+                    tags: Vec::new(),
+                    proxy: None,
+                })
+                .await?;
+        }
+
+        let mail_id = self.storage.next_message_id(&email_addr).await?.to_string();
+
+        let message = Message::from_rfc5322(&email_addr, &mail_id, Utc::now().timestamp(), raw)
+            .ok_or_else(|| {
+                MailError::UnexpectedResponseError("email is not in RFC 5322 format".to_string())
+            })?;
+
+        self.storage
+            .save_raw_message(&email_addr, &mail_id, raw)
+            .await?;
+        self.storage.cache_messages(&[message]).await?;
+
+        Ok(mail_id)
+    }
+
+    async fn inbox(&self, email_addr: &str) -> Result<Vec<Message>, MailError> {
+        let query = SearchQuery {
+            email_addr: Some(email_addr.to_string()),
+            ..Default::default()
+        };
+
+        self.storage.find_messages(&query).await
+    }
+
+    async fn attachments(
+        &self,
+        account: &Account,
+        mail_id: &str,
+    ) -> Result<Vec<Attachment>, MailError> {
+        let raw = self
+            .storage
+            .find_raw_message(&account.email_addr, mail_id)
+            .await?
+            .unwrap_or_default();
+
+        Ok(attachments_from_rfc5322(&raw))
+    }
+}
+
+fn random_token() -> String {
+    let mut rng = rand::thread_rng();
+
+    (0..26)
+        .map(|_| char::from_digit(rng.gen_range(0..36), 36).unwrap_or('0'))
+        .collect()
+}
+
+#[async_trait]
+impl Provider for LocalProvider {
+    async fn create_address(&self) -> Result<Account, MailError> {
+        let mut rng = rand::thread_rng();
+
+        let alias: String = (0..8)
+            .map(|_| char::from_digit(rng.gen_range(10..36), 36).unwrap_or('a'))
+            .collect();
+
+        Ok(Account {
+            provider: LocalProvider::NAME.to_string(),
+            email_addr: format!("{alias}@{}", self.domain),
+            sid_token: random_token(),
+            created_at: Utc::now(),
+            label: None,
+            tags: Vec::new(),
+            proxy: None,
+        })
+    }
+
+    async fn list_messages(
+        &self,
+        account: &Account,
+        offset: u32,
+    ) -> Result<Vec<Message>, MailError> {
+        Ok(self
+            .inbox(&account.email_addr)
+            .await?
+            .into_iter()
+            .skip(offset as usize)
+            .take(PAGE_SIZE)
+            .collect())
+    }
+
+    async fn check_messages(&self, account: &Account, seq: u32) -> Result<Vec<Message>, MailError> {
+        Ok(self
+            .inbox(&account.email_addr)
+            .await?
+            .into_iter()
+            .filter(|message| message.mail_id.parse().is_ok_and(|id: u32| id > seq))
+            .collect())
+    }
+
+    async fn fetch_message(
+        &self,
+        account: &Account,
+        mail_id: &str,
+    ) -> Result<Option<FetchedMessage>, MailError> {
+        let message = match self
+            .storage
+            .find_message(&account.email_addr, mail_id)
+            .await?
+        {
+            Some(message) => message,
+            None => return Ok(None),
+        };
+
+        let attachments = self.attachments(account, mail_id).await?;
+
+        Ok(Some(FetchedMessage {
+            message,
+            attachments: AttachmentPart::numbered(&attachments),
+        }))
+    }
+
+    async fn delete_message(&self, account: &Account, mail_id: &str) -> Result<(), MailError> {
+        self.storage
+            .delete_message(&account.email_addr, mail_id)
+            .await
+    }
+
+    async fn fetch_attachment(
+        &self,
+        account: &Account,
+        mail_id: &str,
+        part_id: &str,
+    ) -> Result<Vec<u8>, MailError> {
+        let index: usize = part_id.parse()?;
+
+        self.attachments(account, mail_id)
+            .await?
+            .into_iter()
+            .nth(index)
+            .map(|attachment| attachment.data)
+            .ok_or_else(|| MailError::MessageNotFoundError(mail_id.to_string()))
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            reply: true,
+            forward: true,
+        }
+    }
+
+    /// Email to the own domain is delivered directly, other email goes through the relay
+    async fn send_message(
+        &self,
+        account: &Account,
+        message: &OutgoingMessage,
+    ) -> Result<(), MailError> {
+        let raw = message.to_rfc5322(&account.email_addr);
+
+        if self.accepts(&message.to) {
+            return self.deliver(&message.to, raw.as_bytes()).await.map(|_| ());
+        }
+
+        match &self.relay {
+            Some(relay) => smtp::send(relay, &account.email_addr, &message.to, &raw).await,
+            None => Err(MailError::UnsupportedError(format!(
+                "`{}` is not on {}. Set relay in [providers.local] section of config file to send email to other domains",
+                message.to, self.domain
+            ))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::storage::MemoryStorage;
+
+    #[test]
+    fn test_accepts_only_configured_domain() {
+        let provider = LocalProvider::new(Arc::new(MemoryStorage::default()), "Staging.Test");
+
+        assert!(provider.accepts("qa@staging.test"));
+        assert!(provider.accepts("QA@STAGING.TEST"));
+        assert!(!provider.accepts("qa@example.com"));
+        assert!(!provider.accepts("@staging.test"));
+        assert!(!provider.accepts("staging.test"));
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_deliver_to_address_that_was_not_created() -> Result<(), MailError> {
+        let storage = Arc::new(MemoryStorage::default());
+        let provider = LocalProvider::new(storage.clone(), DEFAULT_DOMAIN);
+
+        let raw =
+            "From: App <app@staging.test>\r\nSubject: Reset password\r\n\r\nToken 91827364\r\n";
+
+        assert_eq!(
+            provider
+                .deliver("qa@disposable.local", raw.as_bytes())
+                .await?,
+            "1"
+        );
+        assert_eq!(
+            provider
+                .deliver("qa@disposable.local", raw.as_bytes())
+                .await?,
+            "2"
+        );
+
+        let account = storage.find_account("qa@disposable.local").await?;
+        assert_eq!(account.provider, "local");
+
+        let new_emails = provider.check_messages(&account, 1).await?;
+        assert_eq!(new_emails.len(), 1);
+        assert_eq!(new_emails[0].mail_from, "App <app@staging.test>");
+        assert_eq!(new_emails[0].mail_subject, "Reset password");
+
+        provider.delete_message(&account, "2").await?;
+        assert_eq!(provider.fetch_message(&account, "2").await?, None);
+        assert_eq!(provider.list_messages(&account, 0).await?.len(), 1);
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn test_delivered_ids_are_never_reused() -> Result<(), MailError> {
+        let storage = Arc::new(MemoryStorage::default());
+        let raw = b"From: app@staging.test\r\nSubject: Code\r\n\r\n482913\r\n";
+
+        // Separate providers, like the SMTP server and a command running next to it
+        let deliveries = (0..4).map(|_| {
+            let provider = LocalProvider::new(storage.clone(), DEFAULT_DOMAIN);
+            tokio::spawn(async move { provider.deliver("qa@disposable.local", raw).await })
+        });
+
+        let mut ids = Vec::new();
+        for delivery in deliveries.collect::<Vec<_>>() {
+            ids.push(delivery.await.unwrap()?);
+        }
+        ids.sort();
+        assert_eq!(ids, ["1", "2", "3", "4"]);
+
+        let provider = LocalProvider::new(storage.clone(), DEFAULT_DOMAIN);
+        let account = storage.find_account("qa@disposable.local").await?;
+        provider.delete_message(&account, "4").await?;
+
+        assert_eq!(provider.deliver("qa@disposable.local", raw).await?, "5");
+
+        Ok(())
+    }
+}
@@ -0,0 +1,322 @@
+use async_trait::async_trait;
+use chrono::prelude::*;
+use rand::Rng;
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use crate::extract;
+use crate::mails::provider::{Account, AttachmentPart, Capabilities, FetchedMessage, Provider};
+use crate::mails::{Attachment, MailError, Message, OutgoingMessage};
+
+const DOMAIN: &str = "memory.test";
+const PAGE_SIZE: usize = 20;
+
+/// Provider keeping inboxes in memory, for tests and demos.
+/// Clones share inboxes, so test code keeps a clone to inject emails.
+/// Like Guerrillamail, every new inbox starts with a welcome email with id 1.
+/// Sent email reaches inboxes of this provider, email to other addresses stays in outbox
+#[derive(Debug, Clone, Default)]
+pub struct MemoryProvider {
+    inboxes: Arc<Mutex<HashMap<String, Inbox>>>,
+    outbox: Arc<Mutex<Vec<OutgoingMessage>>>,
+}
+
+#[derive(Debug, Default)]
+struct Inbox {
+    sid_token: String,
+    last_id: u32,
+    messages: Vec<(Message, Vec<Attachment>)>,
+}
+
+impl MemoryProvider {
+    pub const NAME: &'static str = "memory";
+
+    /// Delivers email to an address created by this provider.
+    /// Returns id of the delivered email
+    pub fn inject(
+        &self,
+        email_addr: &str,
+        mail_from: &str,
+        mail_subject: &str,
+        mail_body: &str,
+        attachments: Vec<Attachment>,
+    ) -> Result<String, MailError> {
+        let mut inboxes = self.inboxes.lock().unwrap_or_else(|e| e.into_inner());
+
+        let inbox = inboxes
+            .get_mut(email_addr)
+            .ok_or_else(|| MailError::EmailCheckError(email_addr.to_string()))?;
+
+        inbox.last_id += 1;
+
+        let message = Message {
+            email_addr: email_addr.to_string(),
+            mail_id: inbox.last_id.to_string(),
+            mail_from: mail_from.to_string(),
+            mail_subject: mail_subject.to_string(),
+            mail_excerpt: extract::html_to_text(mail_body)
+                .trim()
+                .chars()
+                .take(50)
+                .collect(),
+            mail_body: Some(mail_body.to_string()),
+            mail_content_type: None,
+            mail_timestamp: Utc::now().timestamp(),
+        };
+
+        inbox.messages.push((message, attachments));
+
+        Ok(inbox.last_id.to_string())
+    }
+
+    /// Email sent to addresses outside this provider
+    #[cfg(test)]
+    pub fn outbox(&self) -> Vec<OutgoingMessage> {
+        self.outbox
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+    }
+
+    fn with_inbox<T>(
+        &self,
+        account: &Account,
+        f: impl FnOnce(&mut Inbox) -> T,
+    ) -> Result<T, MailError> {
+        let mut inboxes = self.inboxes.lock().unwrap_or_else(|e| e.into_inner());
+
+        match inboxes.get_mut(&account.email_addr) {
+            Some(inbox) if inbox.sid_token == account.sid_token => Ok(f(inbox)),
+            _ => Err(MailError::EmailCheckError(account.email_addr.clone())),
+        }
+    }
+}
+
+impl Inbox {
+    fn find(&self, mail_id: &str) -> Option<&(Message, Vec<Attachment>)> {
+        self.messages
+            .iter()
+            .find(|(message, _)| message.mail_id == mail_id)
+    }
+}
+
+/// List elements carry an excerpt, like list of a real provider
+fn without_body(message: &Message) -> Message {
+    Message {
+        mail_body: None,
+        ..message.clone()
+    }
+}
+
+#[async_trait]
+impl Provider for MemoryProvider {
+    async fn create_address(&self) -> Result<Account, MailError> {
+        let mut rng = rand::thread_rng();
+
+        let sid_token: String = (0..26)
+            .map(|_| char::from_digit(rng.gen_range(0..36), 36).unwrap_or('0'))
+            .collect();
+        let alias: String = (0..8)
+            .map(|_| char::from_digit(rng.gen_range(10..36), 36).unwrap_or('a'))
+            .collect();
+
+        let account = Account {
+            provider: MemoryProvider::NAME.to_string(),
+            email_addr: format!("{alias}@{DOMAIN}"),
+            sid_token: sid_token.clone(),
+            created_at: Utc::now(),
+            label: None,
+            tags: Vec::new(),
+            proxy: None,
+        };
+
+        self.inboxes
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .insert(
+                account.email_addr.clone(),
+                Inbox {
+                    sid_token,
+                    ..Default::default()
+                },
+            );
+
+        self.inject(
+            &account.email_addr,
+            &format!("no-reply@{DOMAIN}"),
+            "Welcome to memory inbox",
+            "<p>Emails delivered with MemoryProvider::inject show up here</p>",
+            Vec::new(),
+        )?;
+
+        Ok(account)
+    }
+
+    async fn list_messages(
+        &self,
+        account: &Account,
+        offset: u32,
+    ) -> Result<Vec<Message>, MailError> {
+        self.with_inbox(account, |inbox| {
+            inbox
+                .messages
+                .iter()
+                .rev()
+                .skip(offset as usize)
+                .take(PAGE_SIZE)
+                .map(|(message, _)| without_body(message))
+                .collect()
+        })
+    }
+
+    async fn check_messages(&self, account: &Account, seq: u32) -> Result<Vec<Message>, MailError> {
+        self.with_inbox(account, |inbox| {
+            inbox
+                .messages
+                .iter()
+                .rev()
+                .filter(|(message, _)| message.mail_id.parse().is_ok_and(|id: u32| id > seq))
+                .map(|(message, _)| without_body(message))
+                .collect()
+        })
+    }
+
+    async fn fetch_message(
+        &self,
+        account: &Account,
+        mail_id: &str,
+    ) -> Result<Option<FetchedMessage>, MailError> {
+        self.with_inbox(account, |inbox| {
+            inbox
+                .find(mail_id)
+                .map(|(message, attachments)| FetchedMessage {
+                    message: message.clone(),
+                    attachments: AttachmentPart::numbered(attachments),
+                })
+        })
+    }
+
+    async fn delete_message(&self, account: &Account, mail_id: &str) -> Result<(), MailError> {
+        self.with_inbox(account, |inbox| {
+            inbox
+                .messages
+                .retain(|(message, _)| message.mail_id != mail_id)
+        })
+    }
+
+    async fn fetch_attachment(
+        &self,
+        account: &Account,
+        mail_id: &str,
+        part_id: &str,
+    ) -> Result<Vec<u8>, MailError> {
+        self.with_inbox(account, |inbox| {
+            inbox
+                .find(mail_id)
+                .and_then(|(_, attachments)| attachments.get(part_id.parse::<usize>().ok()?))
+                .map(|attachment| attachment.data.clone())
+        })?
+        .ok_or_else(|| MailError::MessageNotFoundError(mail_id.to_string()))
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            reply: true,
+            forward: true,
+        }
+    }
+
+    async fn send_message(
+        &self,
+        account: &Account,
+        message: &OutgoingMessage,
+    ) -> Result<(), MailError> {
+        self.with_inbox(account, |_| ())?;
+
+        match self.inject(
+            &message.to,
+            &account.email_addr,
+            &message.subject,
+            &message.body,
+            Vec::new(),
+        ) {
+            Ok(_) => Ok(()),
+            Err(MailError::EmailCheckError(_)) => {
+                self.outbox
+                    .lock()
+                    .unwrap_or_else(|e| e.into_inner())
+                    .push(message.clone());
+
+                Ok(())
+            }
+            Err(e) => Err(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_inject_check_fetch_delete() -> Result<(), MailError> {
+        let provider = MemoryProvider::default();
+        let account = provider.create_address().await?;
+
+        assert!(account.email_addr.ends_with("@memory.test"));
+        assert_eq!(provider.list_messages(&account, 0).await?.len(), 1);
+        assert!(provider.check_messages(&account, 1).await?.is_empty());
+
+        let attachment = Attachment {
+            filename: "terms.txt".to_string(),
+            content_type: "text/plain".to_string(),
+            data: b"Terms of service".to_vec(),
+        };
+        provider.inject(
+            &account.email_addr,
+            "a@b.c",
+            "First",
+            "<p>One</p>",
+            Vec::new(),
+        )?;
+        let mail_id = provider.inject(
+            &account.email_addr,
+            "a@b.c",
+            "Second",
+            "<p>Two</p>",
+            vec![attachment],
+        )?;
+
+        let new_emails = provider.check_messages(&account, 2).await?;
+        assert_eq!(new_emails.len(), 1);
+        assert_eq!(new_emails[0].mail_subject, "Second");
+        assert_eq!(new_emails[0].mail_excerpt, "Two");
+        assert_eq!(new_emails[0].mail_body, None);
+
+        let fetched = provider.fetch_message(&account, &mail_id).await?.unwrap();
+        assert_eq!(fetched.message.mail_body, Some("<p>Two</p>".to_string()));
+        assert_eq!(fetched.attachments[0].filename, "terms.txt");
+
+        let data = provider
+            .fetch_attachment(&account, &mail_id, &fetched.attachments[0].part_id)
+            .await?;
+        assert_eq!(data, b"Terms of service".to_vec());
+
+        provider.delete_message(&account, &mail_id).await?;
+        assert_eq!(provider.fetch_message(&account, &mail_id).await?, None);
+        assert_eq!(provider.list_messages(&account, 0).await?.len(), 2);
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_unknown_address() {
+        let provider = MemoryProvider::default();
+
+        assert!(matches!(
+            provider.inject("abc@memory.test", "a@b.c", "Hi", "Hi", Vec::new()),
+            Err(MailError::EmailCheckError(_))
+        ));
+    }
+}
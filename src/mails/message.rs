@@ -0,0 +1,506 @@
+use chrono::prelude::*;
+use mail_parser::{MessageParser, MimeHeaders, PartType};
+use serde::{Deserialize, Serialize};
+
+use crate::extract;
+
+pub(super) const LINE_ENDING: &str = "\r\n";
+
+/// Email received in a disposable inbox, as it is cached in database
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Message {
+    pub email_addr: String,
+    pub mail_id: String,
+    pub mail_from: String,
+    pub mail_subject: String,
+    #[serde(default)]
+    pub mail_excerpt: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mail_body: Option<String>,
+    /// `text/html` or `text/plain`, if the provider tells what the body is
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mail_content_type: Option<String>,
+    pub mail_timestamp: i64,
+}
+
+impl Message {
+    /// Creates message from an element returned by Guerrillamail
+    /// `get_email_list`, `check_email` or `fetch_email` functions.
+    /// Returns None if value does not look like an email
+    pub fn from_guerrilla_json(email_addr: &str, value: &serde_json::Value) -> Option<Self> {
+        Some(Message {
+            email_addr: email_addr.to_string(),
+            mail_id: json_to_string(&value["mail_id"])?,
+            mail_from: json_to_string(&value["mail_from"]).unwrap_or_default(),
+            mail_subject: json_to_string(&value["mail_subject"]).unwrap_or_default(),
+            mail_excerpt: json_to_string(&value["mail_excerpt"]).unwrap_or_default(),
+            mail_body: json_to_string(&value["mail_body"]),
+            mail_content_type: json_to_string(&value["content_type"])
+                .and_then(|content_type| text_content_type(&content_type)),
+            mail_timestamp: json_to_string(&value["mail_timestamp"])?.parse().ok()?,
+        })
+    }
+
+    /// Creates message from email in RFC 5322 format, as received over SMTP.
+    /// Returns None if `raw` cannot be parsed
+    pub fn from_rfc5322(
+        email_addr: &str,
+        mail_id: &str,
+        mail_timestamp: i64,
+        raw: &[u8],
+    ) -> Option<Self> {
+        let parsed = MessageParser::default().parse(raw)?;
+
+        let mail_from = match parsed.from().and_then(|from| from.first()) {
+            Some(from) => match (from.name(), from.address()) {
+                (Some(name), Some(address)) => format!("{name} <{address}>"),
+                (name, address) => address.or(name).unwrap_or_default().to_string(),
+            },
+            None => String::new(),
+        };
+
+        // Plain text emails are kept as they are, not converted to HTML
+        let (mail_body, mail_content_type) = match parsed.html_part(0).map(|part| &part.body) {
+            Some(PartType::Html(html)) => (html.to_string(), "text/html"),
+            _ => (
+                parsed
+                    .body_text(0)
+                    .map(|body| body.into_owned())
+                    .unwrap_or_default(),
+                "text/plain",
+            ),
+        };
+
+        Some(Message {
+            email_addr: email_addr.to_string(),
+            mail_id: mail_id.to_string(),
+            mail_from,
+            mail_subject: parsed.subject().unwrap_or_default().to_string(),
+            mail_excerpt: extract::html_to_text(&mail_body)
+                .trim()
+                .chars()
+                .take(80)
+                .collect(),
+            mail_body: Some(mail_body),
+            mail_content_type: Some(mail_content_type.to_string()),
+            mail_timestamp,
+        })
+    }
+
+    /// Date the email was received. Timestamps out of range fall back to Unix epoch
+    pub fn date(&self) -> DateTime<Utc> {
+        let date = NaiveDateTime::from_timestamp_opt(self.mail_timestamp, 0)
+            .unwrap_or_else(|| NaiveDateTime::from_timestamp(0, 0));
+
+        DateTime::from_utc(date, Utc)
+    }
+
+    /// Reconstructs email in RFC 5322 format with CRLF line endings.
+    /// Body and attachments are base64 encoded, so lines never exceed 78 characters
+    pub fn to_rfc5322(&self, attachments: &[Attachment]) -> String {
+        let mut headers = vec![
+            format!("From: {}", encode_mailbox(&self.mail_from)),
+            format!("To: {}", self.email_addr),
+            format!("Subject: {}", encode_header(&self.mail_subject)),
+            format!("Date: {}", self.date().to_rfc2822()),
+            format!("Message-ID: <{}.{}>", self.mail_id, self.email_addr),
+            "MIME-Version: 1.0".to_string(),
+        ];
+
+        let body = self.mail_body.as_ref().unwrap_or(&self.mail_excerpt);
+        // Body is guessed to be HTML only when the provider did not tell
+        let content_type = match self.mail_content_type.as_deref() {
+            Some("text/html") => "text/html; charset=utf-8",
+            Some(_) => "text/plain; charset=utf-8",
+            None if body.contains('<') => "text/html; charset=utf-8",
+            None => "text/plain; charset=utf-8",
+        };
+
+        let mut lines: Vec<String> = Vec::new();
+
+        if attachments.is_empty() {
+            headers.push(format!("Content-Type: {content_type}"));
+            headers.push("Content-Transfer-Encoding: base64".to_string());
+            lines.extend(headers);
+            lines.push(String::new());
+            lines.extend(encode_base64_lines(body.as_bytes()));
+        } else {
+            let boundary = format!("----=_disposable_mail_{}", self.mail_id);
+
+            headers.push(format!(
+                "Content-Type: multipart/mixed; boundary=\"{boundary}\""
+            ));
+            lines.extend(headers);
+            lines.push(String::new());
+
+            lines.push(format!("--{boundary}"));
+            lines.push(format!("Content-Type: {content_type}"));
+            lines.push("Content-Transfer-Encoding: base64".to_string());
+            lines.push(String::new());
+            lines.extend(encode_base64_lines(body.as_bytes()));
+
+            for attachment in attachments {
+                let filename = attachment.filename.replace('"', "");
+
+                lines.push(format!("--{boundary}"));
+                lines.push(format!(
+                    "Content-Type: {}; name=\"{}\"",
+                    content_type_header(&attachment.content_type),
+                    encode_header(&filename)
+                ));
+                lines.push("Content-Transfer-Encoding: base64".to_string());
+                lines.push(format!(
+                    "Content-Disposition: attachment; filename=\"{}\"",
+                    encode_header(&filename)
+                ));
+                lines.push(String::new());
+                lines.extend(encode_base64_lines(&attachment.data));
+            }
+
+            lines.push(format!("--{boundary}--"));
+        }
+
+        let mut email = lines.join(LINE_ENDING);
+        email.push_str(LINE_ENDING);
+
+        email
+    }
+}
+
+/// Whether `email_addr` is among `To` addresses of email in RFC 5322 format
+pub fn is_addressed_to(raw: &[u8], email_addr: &str) -> bool {
+    MessageParser::default()
+        .parse(raw)
+        .and_then(|parsed| {
+            parsed.to().map(|to| {
+                to.iter().any(|addr| {
+                    addr.address()
+                        .is_some_and(|address| address.eq_ignore_ascii_case(email_addr))
+                })
+            })
+        })
+        .unwrap_or(false)
+}
+
+/// Attachments of email in RFC 5322 format
+pub fn attachments_from_rfc5322(raw: &[u8]) -> Vec<Attachment> {
+    let parsed = match MessageParser::default().parse(raw) {
+        Some(parsed) => parsed,
+        None => return Vec::new(),
+    };
+
+    parsed
+        .attachments()
+        .enumerate()
+        .map(|(index, part)| Attachment {
+            filename: part
+                .attachment_name()
+                .map(str::to_string)
+                .unwrap_or_else(|| format!("attachment-{}", index + 1)),
+            content_type: part
+                .content_type()
+                .map(|content_type| match content_type.subtype() {
+                    Some(subtype) => format!("{}/{}", content_type.ctype(), subtype),
+                    None => content_type.ctype().to_string(),
+                })
+                .unwrap_or_else(|| "application/octet-stream".to_string()),
+            data: part.contents().to_vec(),
+        })
+        .collect()
+}
+
+/// File attached to an email
+#[derive(Debug, Clone, PartialEq)]
+pub struct Attachment {
+    pub filename: String,
+    pub content_type: String,
+    pub data: Vec<u8>,
+}
+
+/// Non ASCII header values are written as RFC 2047 encoded words
+pub(super) fn encode_header(value: &str) -> String {
+    // Line breaks in header value would start a new header
+    let value = value.replace(['\r', '\n'], " ");
+
+    if value.is_ascii() {
+        value
+    } else {
+        format!("=?UTF-8?B?{}?=", base64::encode(value))
+    }
+}
+
+/// `Name <address>` with only the name encoded, as encoded words
+/// are not allowed in the address. Other values are encoded whole
+fn encode_mailbox(mailbox: &str) -> String {
+    match mailbox.trim().rsplit_once('<') {
+        Some((name, address)) if address.ends_with('>') && !name.trim().is_empty() => {
+            let name = name.trim();
+            let name = if name.is_ascii() && name.contains(|c| "()<>[]:;@\\,.\"".contains(c)) {
+                format!("\"{}\"", name.replace(['\\', '"'], ""))
+            } else {
+                name.to_string()
+            };
+
+            format!(
+                "{} <{}",
+                encode_header(&name),
+                address.replace(['\r', '\n'], "")
+            )
+        }
+        _ => encode_header(mailbox),
+    }
+}
+
+/// `content_type` if it is a plain `type/subtype`. Anything else, like
+/// a value with line breaks from a received email, is replaced
+fn content_type_header(content_type: &str) -> &str {
+    let is_token = |part: &str| {
+        !part.is_empty()
+            && part
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || "!#$&^_.+-".contains(c))
+    };
+
+    match content_type.split_once('/') {
+        Some((ctype, subtype)) if is_token(ctype) && is_token(subtype) => content_type,
+        _ => "application/octet-stream",
+    }
+}
+
+pub(super) fn encode_base64_lines(data: &[u8]) -> Vec<String> {
+    base64::encode(data)
+        .as_bytes()
+        .chunks(76)
+        .map(|chunk| String::from_utf8_lossy(chunk).into_owned())
+        .collect()
+}
+
+/// `text/html` or `text/plain` from content type given by a provider, like
+/// `text/html; charset=utf-8` or just `text`. None if it is something else
+fn text_content_type(content_type: &str) -> Option<String> {
+    let content_type = content_type.trim().to_ascii_lowercase();
+
+    if content_type.starts_with("text/html") || content_type == "html" {
+        Some("text/html".to_string())
+    } else if content_type.starts_with("text/plain") || content_type == "text" {
+        Some("text/plain".to_string())
+    } else {
+        None
+    }
+}
+
+/// Guerrillamail returns some numeric fields as strings and some as numbers
+fn json_to_string(value: &serde_json::Value) -> Option<String> {
+    match value {
+        serde_json::Value::String(s) => Some(s.to_owned()),
+        serde_json::Value::Number(n) => Some(n.to_string()),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::mails::strategies;
+    use proptest::prelude::*;
+
+    proptest! {
+        #[test]
+        fn test_message_from_arbitrary_json_never_panics(value in strategies::json_value()) {
+            if let Some(message) = Message::from_guerrilla_json("abc@guerrillamail.com", &value) {
+                let _ = message.date();
+                let _ = message.to_rfc5322(&[]);
+            }
+        }
+    }
+
+    #[test]
+    fn test_message_from_guerrilla_list_element() {
+        let value = serde_json::json!({
+            "mail_id": "42",
+            "mail_from": "noreply@github.com",
+            "mail_subject": "Verify your email",
+            "mail_excerpt": "Your code is 123456",
+            "mail_timestamp": "1648372800",
+        });
+
+        let message = Message::from_guerrilla_json("abc@guerrillamail.com", &value).unwrap();
+
+        assert_eq!(message.mail_id, "42");
+        assert_eq!(message.mail_body, None);
+        assert_eq!(message.mail_timestamp, 1648372800);
+    }
+
+    #[test]
+    fn test_message_from_guerrilla_numeric_fields() {
+        let value = serde_json::json!({
+            "mail_id": 42,
+            "mail_from": "noreply@github.com",
+            "mail_subject": "Verify your email",
+            "mail_body": "<p>Your code is 123456</p>",
+            "mail_timestamp": 1648372800,
+        });
+
+        let message = Message::from_guerrilla_json("abc@guerrillamail.com", &value).unwrap();
+
+        assert_eq!(message.mail_id, "42");
+        assert_eq!(
+            message.mail_body,
+            Some("<p>Your code is 123456</p>".to_string())
+        );
+    }
+
+    #[test]
+    fn test_message_to_rfc5322() {
+        let message = Message {
+            email_addr: "abc@guerrillamail.com".to_string(),
+            mail_id: "42".to_string(),
+            mail_from: "noreply@github.com".to_string(),
+            mail_subject: "Verify your email".to_string(),
+            mail_excerpt: String::new(),
+            mail_body: Some("Your code is 123456".to_string()),
+            mail_content_type: None,
+            mail_timestamp: 1648372800,
+        };
+
+        assert_eq!(
+            message.to_rfc5322(&[]),
+            "From: noreply@github.com\r\n\
+             To: abc@guerrillamail.com\r\n\
+             Subject: Verify your email\r\n\
+             Date: Sun, 27 Mar 2022 09:20:00 +0000\r\n\
+             Message-ID: <42.abc@guerrillamail.com>\r\n\
+             MIME-Version: 1.0\r\n\
+             Content-Type: text/plain; charset=utf-8\r\n\
+             Content-Transfer-Encoding: base64\r\n\
+             \r\n\
+             WW91ciBjb2RlIGlzIDEyMzQ1Ng==\r\n"
+        );
+    }
+
+    #[test]
+    fn test_content_type_from_provider() {
+        let value = serde_json::json!({
+            "mail_id": "42",
+            "mail_body": "Use <code> tags",
+            "mail_timestamp": 1648372800,
+            "content_type": "text",
+        });
+
+        let message = Message::from_guerrilla_json("abc@guerrillamail.com", &value).unwrap();
+        assert_eq!(message.mail_content_type, Some("text/plain".to_string()));
+        assert!(message
+            .to_rfc5322(&[])
+            .contains("Content-Type: text/plain; charset=utf-8\r\n"));
+
+        let raw = b"From: app@staging.test\r\nTo: qa@staging.test\r\nSubject: Code\r\n\r\nWrap it in <pre>\r\n";
+        let message = Message::from_rfc5322("qa@staging.test", "1", 1648372800, raw).unwrap();
+        assert_eq!(message.mail_content_type, Some("text/plain".to_string()));
+        assert!(message
+            .to_rfc5322(&[])
+            .contains("Content-Type: text/plain; charset=utf-8\r\n"));
+    }
+
+    #[test]
+    fn test_message_with_attachment_to_rfc5322() {
+        let message = Message {
+            email_addr: "abc@guerrillamail.com".to_string(),
+            mail_id: "42".to_string(),
+            mail_from: "noreply@github.com".to_string(),
+            mail_subject: "Überprüfung".to_string(),
+            mail_excerpt: String::new(),
+            mail_body: Some("<p>Invoice attached</p>".to_string()),
+            mail_content_type: None,
+            mail_timestamp: 1648372800,
+        };
+        let attachment = Attachment {
+            filename: "invoice.txt".to_string(),
+            content_type: "text/plain".to_string(),
+            data: b"total: 10".to_vec(),
+        };
+
+        let email = message.to_rfc5322(&[attachment]);
+
+        assert!(email.contains("Subject: =?UTF-8?B?w5xiZXJwcsO8ZnVuZw==?=\r\n"));
+        assert!(email
+            .contains("Content-Type: multipart/mixed; boundary=\"----=_disposable_mail_42\"\r\n"));
+        assert!(email.contains("Content-Type: text/html; charset=utf-8\r\n"));
+        assert!(email.contains("Content-Disposition: attachment; filename=\"invoice.txt\"\r\n"));
+        assert!(email.contains("dG90YWw6IDEw\r\n"));
+        assert!(email.ends_with("------=_disposable_mail_42--\r\n"));
+    }
+
+    #[test]
+    fn test_rfc5322_headers_from_received_values() {
+        let message = Message {
+            email_addr: "abc@disposable.local".to_string(),
+            mail_id: "4".to_string(),
+            mail_from: "Zoë Müller <zoe@example.com>".to_string(),
+            mail_subject: "Invoice".to_string(),
+            mail_excerpt: String::new(),
+            mail_body: Some("Invoice attached".to_string()),
+            mail_content_type: None,
+            mail_timestamp: 1648372800,
+        };
+        let attachment = Attachment {
+            filename: "invoice.txt".to_string(),
+            content_type: "text/plain\r\nBcc: spy@evil.test".to_string(),
+            data: b"total: 10".to_vec(),
+        };
+
+        let raw = message.to_rfc5322(&[attachment]);
+
+        assert!(raw.starts_with("From: =?UTF-8?B?"));
+        assert!(raw.contains("?= <zoe@example.com>\r\n"));
+        assert!(!raw.contains("Bcc"));
+        assert!(raw.contains("Content-Type: application/octet-stream; name=\"invoice.txt\"\r\n"));
+        assert_eq!(
+            Message::from_rfc5322("abc@disposable.local", "4", 1648372800, raw.as_bytes())
+                .unwrap()
+                .mail_from,
+            message.mail_from
+        );
+
+        assert_eq!(
+            encode_mailbox("Doe, Jane <jane@example.com>"),
+            "\"Doe, Jane\" <jane@example.com>"
+        );
+        assert_eq!(encode_mailbox("jane@example.com"), "jane@example.com");
+    }
+
+    #[test]
+    fn test_rfc5322_round_trip() {
+        let message = Message {
+            email_addr: "abc@disposable.local".to_string(),
+            mail_id: "3".to_string(),
+            mail_from: "noreply@github.com".to_string(),
+            mail_subject: "Überprüfung".to_string(),
+            mail_excerpt: "Your code is 123456".to_string(),
+            mail_body: Some("<p>Your code is 123456</p>".to_string()),
+            mail_content_type: Some("text/html".to_string()),
+            mail_timestamp: 1648372800,
+        };
+        let attachment = Attachment {
+            filename: "terms.txt".to_string(),
+            content_type: "text/plain".to_string(),
+            data: b"Terms of service".to_vec(),
+        };
+
+        let raw = message.to_rfc5322(std::slice::from_ref(&attachment));
+
+        assert_eq!(
+            Message::from_rfc5322("abc@disposable.local", "3", 1648372800, raw.as_bytes()),
+            Some(message)
+        );
+        assert_eq!(attachments_from_rfc5322(raw.as_bytes()), vec![attachment]);
+    }
+
+    #[test]
+    fn test_message_from_unexpected_json() {
+        assert_eq!(
+            Message::from_guerrilla_json("abc@guerrillamail.com", &serde_json::Value::Bool(false)),
+            None
+        );
+    }
+}
@@ -1,8 +1,23 @@
 mod guerrillamail;
 pub use guerrillamail::GuerrillaMail;
+pub use guerrillamail::GuerrillaProvider;
 pub use guerrillamail::GuerrillaUser;
 mod error;
+pub use error::ErrorCategory;
 pub use error::MailError;
-mod mail_enum;
 pub use guerrillamail::get_unexpired_guerrillamails_from_db;
-pub use mail_enum::MailEnum;
+mod imap_mailbox;
+pub use imap_mailbox::ImapProvider;
+pub mod local;
+pub use local::LocalProvider;
+mod memory;
+pub use memory::MemoryProvider;
+mod message;
+mod outgoing;
+mod provider;
+#[cfg(test)]
+pub mod strategies;
+pub use message::Attachment;
+pub use message::Message;
+pub use outgoing::{check_address, OutgoingMessage};
+pub use provider::{Account, Capabilities, FetchedMessage, Provider};
@@ -0,0 +1,263 @@
+use chrono::prelude::*;
+use rand::Rng;
+
+use crate::extract;
+use crate::mails::message::{encode_base64_lines, encode_header, LINE_ENDING};
+use crate::mails::{MailError, Message};
+
+const FORWARDED: &str = "---------- Forwarded message ----------";
+
+/// Plain text email sent from a disposable address
+#[derive(Debug, Clone, PartialEq)]
+pub struct OutgoingMessage {
+    pub to: String,
+    pub subject: String,
+    pub body: String,
+}
+
+impl OutgoingMessage {
+    /// Reply to the sender of `original`, with its text quoted below `body`.
+    /// Sender comes from received email, so its address is checked
+    pub fn reply(original: &Message, body: &str) -> Result<Self, MailError> {
+        let quoted: Vec<String> = text(original)
+            .lines()
+            .map(|line| format!("> {line}").trim_end().to_string())
+            .collect();
+
+        let to = address(&original.mail_from);
+        check_address(to)?;
+
+        Ok(OutgoingMessage {
+            to: to.to_string(),
+            subject: prefixed("Re:", &original.mail_subject),
+            body: format!(
+                "{}\n\nOn {}, {} wrote:\n{}\n",
+                body.trim_end(),
+                original.date().to_rfc2822(),
+                original.mail_from,
+                quoted.join("\n")
+            ),
+        })
+    }
+
+    /// `original` sent on to `to`, below an optional note
+    pub fn forward(original: &Message, to: &str, note: &str) -> Result<Self, MailError> {
+        check_address(to)?;
+
+        let mut body = String::new();
+        if !note.trim().is_empty() {
+            body.push_str(note.trim_end());
+            body.push_str("\n\n");
+        }
+
+        body.push_str(&format!(
+            "{FORWARDED}\nFrom: {}\nDate: {}\nSubject: {}\nTo: {}\n\n{}\n",
+            original.mail_from,
+            original.date().to_rfc2822(),
+            original.mail_subject,
+            original.email_addr,
+            text(original).trim_end()
+        ));
+
+        Ok(OutgoingMessage {
+            to: to.to_string(),
+            subject: prefixed("Fwd:", &original.mail_subject),
+            body,
+        })
+    }
+
+    /// Email sent from `from` in RFC 5322 format with CRLF line endings.
+    /// Body is base64 encoded, so lines never exceed 78 characters
+    pub fn to_rfc5322(&self, from: &str) -> String {
+        let domain = from
+            .rsplit_once('@')
+            .map_or("localhost", |(_, domain)| domain);
+        let id: u64 = rand::thread_rng().gen();
+
+        let mut lines = vec![
+            format!("From: {from}"),
+            format!("To: {}", self.to),
+            format!("Subject: {}", encode_header(&self.subject)),
+            format!("Date: {}", Utc::now().to_rfc2822()),
+            format!("Message-ID: <{id:016x}@{domain}>"),
+            "MIME-Version: 1.0".to_string(),
+            "Content-Type: text/plain; charset=utf-8".to_string(),
+            "Content-Transfer-Encoding: base64".to_string(),
+            String::new(),
+        ];
+
+        let body = self.body.lines().collect::<Vec<_>>().join(LINE_ENDING);
+        lines.extend(encode_base64_lines(body.as_bytes()));
+
+        let mut email = lines.join(LINE_ENDING);
+        email.push_str(LINE_ENDING);
+
+        email
+    }
+}
+
+/// Address of `Name <address>` sender, or the whole value without angle brackets
+pub fn address(mail_from: &str) -> &str {
+    match mail_from.rsplit_once('<') {
+        Some((_, rest)) => rest.split('>').next().unwrap_or(rest).trim(),
+        None => mail_from.trim(),
+    }
+}
+
+/// Checks that `address` is a single address which can be put into
+/// headers and SMTP commands without starting another line or address
+pub fn check_address(address: &str) -> Result<(), MailError> {
+    let valid = match address.split_once('@') {
+        Some((local, domain)) => {
+            !local.is_empty()
+                && !domain.is_empty()
+                && !domain.contains('@')
+                && !address
+                    .chars()
+                    .any(|c| c.is_whitespace() || c.is_control() || c == '<' || c == '>')
+        }
+        None => false,
+    };
+
+    if valid {
+        Ok(())
+    } else {
+        Err(MailError::AddressError(address.to_string()))
+    }
+}
+
+/// Subject starting with `prefix`, which is not repeated in long threads
+fn prefixed(prefix: &str, subject: &str) -> String {
+    let has_prefix = subject
+        .get(..prefix.len())
+        .is_some_and(|start| start.eq_ignore_ascii_case(prefix));
+
+    if has_prefix {
+        subject.to_string()
+    } else {
+        format!("{prefix} {subject}")
+    }
+}
+
+fn text(message: &Message) -> String {
+    let body = message.mail_body.as_ref().unwrap_or(&message.mail_excerpt);
+
+    extract::html_to_text(body).trim().to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn original() -> Message {
+        Message {
+            email_addr: "qa@disposable.local".to_string(),
+            mail_id: "2".to_string(),
+            mail_from: "App <app@staging.test>".to_string(),
+            mail_subject: "Verify your address".to_string(),
+            mail_excerpt: "Reply YES to confirm".to_string(),
+            mail_body: Some("<p>Reply YES to confirm</p>".to_string()),
+            mail_content_type: None,
+            mail_timestamp: 1_700_000_000,
+        }
+    }
+
+    #[test]
+    fn test_address() {
+        assert_eq!(address("App <app@staging.test>"), "app@staging.test");
+        assert_eq!(address("app@staging.test"), "app@staging.test");
+        assert_eq!(address("<app@staging.test>"), "app@staging.test");
+    }
+
+    #[test]
+    fn test_check_address() {
+        assert!(check_address("app@staging.test").is_ok());
+
+        for address in [
+            "",
+            "app",
+            "@staging.test",
+            "app@",
+            "app@staging@test",
+            "app @staging.test",
+            "app@staging.test>",
+            "<app@staging.test",
+            "app@staging.test\r\nBcc: spy@evil.test",
+        ] {
+            assert!(
+                matches!(check_address(address), Err(MailError::AddressError(_))),
+                "{address:?} is accepted"
+            );
+        }
+    }
+
+    #[test]
+    fn test_reply_rejects_injected_sender() {
+        let injected = Message {
+            mail_from: "app@staging.test\r\nBcc: spy@evil.test".to_string(),
+            ..original()
+        };
+
+        let error = OutgoingMessage::reply(&injected, "YES").unwrap_err();
+        assert_eq!(error.category(), crate::mails::ErrorCategory::BadInput);
+
+        assert!(matches!(
+            OutgoingMessage::forward(
+                &original(),
+                "dev@example.com>\r\nRCPT TO:<spy@evil.test",
+                ""
+            ),
+            Err(MailError::AddressError(_))
+        ));
+    }
+
+    #[test]
+    fn test_reply_quotes_original() {
+        let reply = OutgoingMessage::reply(&original(), "YES\n").unwrap();
+
+        assert_eq!(reply.to, "app@staging.test");
+        assert_eq!(reply.subject, "Re: Verify your address");
+        assert!(reply.body.starts_with("YES\n\nOn "));
+        assert!(reply
+            .body
+            .ends_with("App <app@staging.test> wrote:\n> Reply YES to confirm\n"));
+
+        let again = OutgoingMessage::reply(
+            &Message {
+                mail_subject: reply.subject,
+                ..original()
+            },
+            "YES",
+        )
+        .unwrap();
+        assert_eq!(again.subject, "Re: Verify your address");
+    }
+
+    #[test]
+    fn test_forward_keeps_original_headers() {
+        let forward = OutgoingMessage::forward(&original(), "dev@example.com", "").unwrap();
+
+        assert_eq!(forward.to, "dev@example.com");
+        assert_eq!(forward.subject, "Fwd: Verify your address");
+        assert!(forward.body.starts_with(FORWARDED));
+        assert!(forward
+            .body
+            .contains("\nTo: qa@disposable.local\n\nReply YES to confirm\n"));
+    }
+
+    #[test]
+    fn test_to_rfc5322_round_trip() {
+        let reply = OutgoingMessage::reply(&original(), "Zürich: YES").unwrap();
+        let raw = reply.to_rfc5322("qa@disposable.local");
+
+        assert!(raw.lines().all(|line| line.len() <= 78));
+
+        let parsed = Message::from_rfc5322("app@staging.test", "1", 0, raw.as_bytes()).unwrap();
+        assert_eq!(parsed.mail_from, "qa@disposable.local");
+        assert_eq!(parsed.mail_subject, "Re: Verify your address");
+        assert!(parsed
+            .mail_body
+            .unwrap()
+            .starts_with("Zürich: YES\r\n\r\nOn "));
+    }
+}
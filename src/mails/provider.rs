@@ -0,0 +1,137 @@
+use async_trait::async_trait;
+use chrono::prelude::*;
+use serde::Serialize;
+
+use crate::mails::{Attachment, MailError, Message, OutgoingMessage};
+
+/// Email address created by a provider, together with
+/// the session token needed to read its inbox
+#[derive(Debug, Clone, PartialEq)]
+pub struct Account {
+    pub provider: String,
+    pub email_addr: String,
+    pub sid_token: String,
+    pub created_at: DateTime<Utc>,
+    /// Unique name given by user, usable instead of the address
+    pub label: Option<String>,
+    pub tags: Vec<String>,
+    /// Proxy of the pool the address was created through, for providers
+    /// that tie sessions to the IP address
+    pub proxy: Option<u32>,
+}
+
+#[cfg(test)]
+impl Account {
+    /// Account created now, in whole seconds like stored dates,
+    /// without label, tags or proxy
+    pub fn test(provider: &str, email_addr: &str) -> Account {
+        Account {
+            provider: provider.to_string(),
+            email_addr: email_addr.to_string(),
+            sid_token: "token".to_string(),
+            created_at: Utc.timestamp_opt(Utc::now().timestamp(), 0).unwrap(),
+            label: None,
+            tags: Vec::new(),
+            proxy: None,
+        }
+    }
+}
+
+/// Attachment of a fetched email, its content is downloaded separately
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct AttachmentPart {
+    pub part_id: String,
+    pub filename: String,
+    pub content_type: String,
+}
+
+impl AttachmentPart {
+    /// Parts of attachments that are numbered by their position
+    pub fn numbered(attachments: &[Attachment]) -> Vec<AttachmentPart> {
+        attachments
+            .iter()
+            .enumerate()
+            .map(|(index, attachment)| AttachmentPart {
+                part_id: index.to_string(),
+                filename: attachment.filename.clone(),
+                content_type: attachment.content_type.clone(),
+            })
+            .collect()
+    }
+}
+
+/// Email with its body and list of attachments
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct FetchedMessage {
+    pub message: Message,
+    pub attachments: Vec<AttachmentPart>,
+}
+
+/// What a provider can do besides receiving email
+#[derive(Serialize, Debug, Clone, Copy, Default, PartialEq)]
+pub struct Capabilities {
+    /// Sends email to the sender of a received one
+    pub reply: bool,
+    /// Sends received email on to any address
+    pub forward: bool,
+}
+
+/// Operations every email provider supports
+#[async_trait]
+pub trait Provider: Send + Sync {
+    async fn create_address(&self) -> Result<Account, MailError>;
+
+    /// Fails if provider is unreachable or its responses do not match
+    /// the expected format. Nothing is created: inbox of `account`, an unexpired
+    /// address of the provider, is listed. Without one there is nothing to check,
+    /// unless the provider overrides it
+    async fn check_health(&self, account: Option<&Account>) -> Result<(), MailError> {
+        match account {
+            Some(account) => self.list_messages(account, 0).await.map(|_| ()),
+            None => Ok(()),
+        }
+    }
+
+    /// Emails in inbox, newest first, skipping first `offset` of them
+    async fn list_messages(
+        &self,
+        account: &Account,
+        offset: u32,
+    ) -> Result<Vec<Message>, MailError>;
+
+    /// Emails with id greater than `seq`
+    async fn check_messages(&self, account: &Account, seq: u32) -> Result<Vec<Message>, MailError>;
+
+    /// Returns None if inbox has no email with `mail_id`
+    async fn fetch_message(
+        &self,
+        account: &Account,
+        mail_id: &str,
+    ) -> Result<Option<FetchedMessage>, MailError>;
+
+    async fn delete_message(&self, account: &Account, mail_id: &str) -> Result<(), MailError>;
+
+    async fn fetch_attachment(
+        &self,
+        account: &Account,
+        mail_id: &str,
+        part_id: &str,
+    ) -> Result<Vec<u8>, MailError>;
+
+    /// Nothing besides receiving email, unless the provider says otherwise
+    fn capabilities(&self) -> Capabilities {
+        Capabilities::default()
+    }
+
+    /// Sends email from the address of `account`
+    async fn send_message(
+        &self,
+        account: &Account,
+        _message: &OutgoingMessage,
+    ) -> Result<(), MailError> {
+        Err(MailError::UnsupportedError(format!(
+            "provider `{}` cannot send email",
+            account.provider
+        )))
+    }
+}
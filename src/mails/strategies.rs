@@ -0,0 +1,67 @@
+//! Proptest strategies for arbitrary provider responses and stored documents
+
+use proptest::prelude::*;
+
+/// Keys the parsers look for, so generated values often reach deeper parsing paths
+const KEYS: [&str; 10] = [
+    "list",
+    "mail_id",
+    "mail_from",
+    "mail_subject",
+    "mail_excerpt",
+    "mail_body",
+    "mail_timestamp",
+    "mails",
+    "email_addr",
+    "name",
+];
+
+fn key() -> impl Strategy<Value = String> {
+    prop_oneof![
+        prop::sample::select(KEYS.to_vec()).prop_map(str::to_string),
+        "[a-z_]{1,8}",
+    ]
+}
+
+pub fn json_value() -> impl Strategy<Value = serde_json::Value> {
+    let leaf = prop_oneof![
+        Just(serde_json::Value::Null),
+        any::<bool>().prop_map(serde_json::Value::Bool),
+        any::<i64>().prop_map(serde_json::Value::from),
+        any::<f64>().prop_map(serde_json::Value::from),
+        any::<i64>().prop_map(|n| serde_json::Value::String(n.to_string())),
+        "\\PC{0,12}".prop_map(serde_json::Value::String),
+    ];
+
+    leaf.prop_recursive(4, 64, 8, |inner| {
+        prop_oneof![
+            prop::collection::vec(inner.clone(), 0..8).prop_map(serde_json::Value::Array),
+            prop::collection::hash_map(key(), inner, 0..8)
+                .prop_map(|map| serde_json::Value::Object(map.into_iter().collect())),
+        ]
+    })
+}
+
+pub fn bson_value() -> impl Strategy<Value = bson::Bson> {
+    let leaf = prop_oneof![
+        Just(bson::Bson::Null),
+        any::<bool>().prop_map(bson::Bson::Boolean),
+        any::<i32>().prop_map(bson::Bson::Int32),
+        any::<i64>().prop_map(bson::Bson::Int64),
+        any::<f64>().prop_map(bson::Bson::Double),
+        any::<i64>().prop_map(|n| bson::Bson::DateTime(bson::DateTime::from_millis(n))),
+        "\\PC{0,12}".prop_map(bson::Bson::String),
+    ];
+
+    leaf.prop_recursive(4, 64, 8, |inner| {
+        prop_oneof![
+            prop::collection::vec(inner.clone(), 0..8).prop_map(bson::Bson::Array),
+            prop::collection::hash_map(key(), inner, 0..8)
+                .prop_map(|map| bson::Bson::Document(map.into_iter().collect())),
+        ]
+    })
+}
+
+pub fn bson_document() -> impl Strategy<Value = bson::Document> {
+    prop::collection::hash_map(key(), bson_value(), 0..8).prop_map(|map| map.into_iter().collect())
+}
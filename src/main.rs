@@ -1,9 +1,30 @@
 use owo_colors::OwoColorize;
-use tokio;
 
+mod api_keys;
+mod bulk;
 mod cli;
+mod config;
+mod context;
 mod db;
+mod doctor;
+mod events;
+mod export;
+mod extract;
+mod failover;
+mod http;
+mod imap_server;
 mod mails;
+mod mock;
+mod notify;
+mod polling;
+mod push;
+mod search;
+mod secrets;
+mod server;
+mod smtp;
+mod storage;
+mod stream;
+mod tui;
 
 const BANNER: &str = r#"
  _____  _           _____                              _       _     _     _             _             
@@ -15,8 +36,12 @@ const BANNER: &str = r#"
 "#;
 
 #[tokio::main]
-async fn main() -> Result<(), mails::MailError> {
-    println!("{}", BANNER.fg_rgb::<0x2E, 0x31, 0x92>());
+async fn main() {
+    // Banner goes to stderr to keep stdout clean for JSON output
+    eprintln!("{}", BANNER.fg_rgb::<0x2E, 0x31, 0x92>());
 
-    cli::menu().await
+    if let Err(e) = cli::menu().await {
+        eprintln!("{}", e.report());
+        std::process::exit(e.category().exit_code());
+    }
 }
@@ -0,0 +1,501 @@
+use axum::extract::{Form, Query, State};
+use axum::http::{header, HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::routing::get;
+use axum::{Json, Router};
+use rand::Rng;
+use serde::Deserialize;
+
+use std::collections::HashMap;
+use std::fs;
+use std::net::{SocketAddr, TcpListener};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use crate::extract;
+use crate::mails::MailError;
+
+const MOCK_DOMAIN: &str = "guerrillamailblock.com";
+
+/// Email delivered to every address created on the mock server
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct ScriptedMessage {
+    pub mail_from: String,
+    pub mail_subject: String,
+    pub mail_body: String,
+    /// Seconds after address creation when the email arrives
+    #[serde(default)]
+    pub delay_secs: i64,
+    #[serde(default)]
+    pub attachments: Vec<ScriptedAttachment>,
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct ScriptedAttachment {
+    pub filename: String,
+    #[serde(default = "default_content_type")]
+    pub content_type: String,
+    pub content: String,
+}
+
+fn default_content_type() -> String {
+    "application/octet-stream".to_string()
+}
+
+struct Session {
+    email_addr: String,
+    created_at: i64,
+    deleted: Vec<String>,
+}
+
+struct MockState {
+    inbox: Vec<ScriptedMessage>,
+    sessions: HashMap<String, Session>,
+}
+
+type SharedState = Arc<Mutex<MockState>>;
+
+/// Email Guerrilla Mail puts into every new inbox
+pub fn welcome_message() -> ScriptedMessage {
+    ScriptedMessage {
+        mail_from: "no-reply@guerrillamail.com".to_string(),
+        mail_subject: "Welcome to Guerrilla Mail".to_string(),
+        mail_body: "<p>Dear Random User,</p><p>Thank you for using Guerrilla Mail.</p>".to_string(),
+        delay_secs: 0,
+        attachments: Vec::new(),
+    }
+}
+
+/// Reads scripted inbox, a JSON array of emails
+pub fn load_inbox(path: &Path) -> Result<Vec<ScriptedMessage>, MailError> {
+    let content = fs::read_to_string(path).map_err(MailError::file_error(path))?;
+
+    Ok(serde_json::from_str(&content)?)
+}
+
+/// Router emulating Guerrilla Mail `ajax.php` API. Every created address
+/// gets the welcome email followed by `inbox`
+pub fn router(inbox: Vec<ScriptedMessage>) -> Router {
+    let mut messages = vec![welcome_message()];
+    messages.extend(inbox);
+
+    let state = Arc::new(Mutex::new(MockState {
+        inbox: messages,
+        sessions: HashMap::new(),
+    }));
+
+    Router::new()
+        .route("/ajax.php", get(ajax).post(ajax_form))
+        .route("/inbox", get(attachment))
+        .with_state(state)
+}
+
+/// Serves mock provider until the process is stopped
+pub async fn serve(addr: SocketAddr, inbox: Vec<ScriptedMessage>) -> Result<(), MailError> {
+    let listener = TcpListener::bind(addr).map_err(|e| MailError::ServerError(Box::new(e)))?;
+
+    serve_listener(listener, router(inbox)).await
+}
+
+async fn serve_listener(listener: TcpListener, router: Router) -> Result<(), MailError> {
+    listener
+        .set_nonblocking(true)
+        .map_err(|e| MailError::ServerError(Box::new(e)))?;
+
+    axum::Server::from_tcp(listener)
+        .map_err(|e| MailError::ServerError(Box::new(e)))?
+        .serve(router.into_make_service())
+        .await
+        .map_err(|e| MailError::ServerError(Box::new(e)))
+}
+
+async fn ajax(
+    State(state): State<SharedState>,
+    Query(params): Query<Vec<(String, String)>>,
+    headers: HeaderMap,
+) -> Response {
+    if params
+        .iter()
+        .any(|(key, value)| key == "f" && value == "send_email")
+    {
+        return (StatusCode::METHOD_NOT_ALLOWED, "Email is sent with POST").into_response();
+    }
+
+    respond(&state, &params, &headers)
+}
+
+/// Form fields are read like query parameters, as PHP does with `$_REQUEST`
+async fn ajax_form(
+    State(state): State<SharedState>,
+    Query(mut params): Query<Vec<(String, String)>>,
+    headers: HeaderMap,
+    Form(form): Form<Vec<(String, String)>>,
+) -> Response {
+    params.extend(form);
+
+    respond(&state, &params, &headers)
+}
+
+fn respond(state: &SharedState, params: &[(String, String)], headers: &HeaderMap) -> Response {
+    let param = |name: &str| {
+        params
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+    };
+
+    let mut state = state.lock().unwrap_or_else(|e| e.into_inner());
+
+    if param("f") == Some("get_email_address") {
+        return create_session(&mut state).into_response();
+    }
+
+    let sid_token = session_token(param("sid_token"), headers);
+    let now = chrono::Utc::now().timestamp();
+
+    let MockState { inbox, sessions } = &mut *state;
+    let session = match sid_token.and_then(|sid_token| sessions.get_mut(&sid_token)) {
+        Some(session) => session,
+        None if param("f") == Some("fetch_email") => return Json(false).into_response(),
+        None => {
+            return Json(serde_json::json!({
+                "error": "Please call get_email_address or set_email_user first"
+            }))
+            .into_response()
+        }
+    };
+
+    let arrived: Vec<(String, &ScriptedMessage)> = inbox
+        .iter()
+        .enumerate()
+        .map(|(index, message)| ((index + 1).to_string(), message))
+        .filter(|(id, message)| {
+            session.created_at + message.delay_secs <= now && !session.deleted.contains(id)
+        })
+        .collect();
+
+    match param("f") {
+        Some("check_email") => {
+            let seq: usize = param("seq").and_then(|seq| seq.parse().ok()).unwrap_or(0);
+
+            let list: Vec<serde_json::Value> = arrived
+                .iter()
+                .filter(|(id, _)| id.parse::<usize>().unwrap_or(0) > seq)
+                .map(|(id, message)| message_json(id, message, session.created_at, false))
+                .collect();
+
+            Json(serde_json::json!({
+                "list": list,
+                "count": list.len().to_string(),
+                "email": session.email_addr,
+                "ts": now,
+            }))
+            .into_response()
+        }
+        Some("get_email_list") => {
+            let offset: usize = param("offset")
+                .and_then(|offset| offset.parse().ok())
+                .unwrap_or(0);
+
+            // Newest email comes first, like in Guerrilla Mail
+            let list: Vec<serde_json::Value> = arrived
+                .iter()
+                .rev()
+                .skip(offset)
+                .take(20)
+                .map(|(id, message)| message_json(id, message, session.created_at, false))
+                .collect();
+
+            Json(serde_json::json!({
+                "list": list,
+                "count": arrived.len().to_string(),
+                "email": session.email_addr,
+                "ts": now,
+            }))
+            .into_response()
+        }
+        Some("fetch_email") => {
+            match arrived
+                .iter()
+                .find(|(id, _)| Some(id.as_str()) == param("email_id"))
+            {
+                Some((id, message)) => {
+                    Json(message_json(id, message, session.created_at, true)).into_response()
+                }
+                None => Json(false).into_response(),
+            }
+        }
+        Some("del_email") => {
+            let deleted: Vec<String> = params
+                .iter()
+                .filter(|(key, _)| key == "email_ids[]")
+                .map(|(_, id)| id.to_owned())
+                .filter(|id| arrived.iter().any(|(arrived_id, _)| arrived_id == id))
+                .collect();
+
+            session.deleted.extend(deleted.iter().cloned());
+
+            Json(serde_json::json!({ "deleted_ids": deleted })).into_response()
+        }
+        // Sent email goes nowhere, tests look at recorded requests
+        Some("send_email") => match param("to") {
+            Some(to) if to.contains('@') => {
+                Json(serde_json::json!({ "sent": true })).into_response()
+            }
+            _ => Json(serde_json::json!({ "error": "Invalid recipient" })).into_response(),
+        },
+        _ => (StatusCode::BAD_REQUEST, "Unknown function").into_response(),
+    }
+}
+
+async fn attachment(
+    State(state): State<SharedState>,
+    Query(params): Query<HashMap<String, String>>,
+    headers: HeaderMap,
+) -> Response {
+    let state = state.lock().unwrap_or_else(|e| e.into_inner());
+
+    let sid_token = session_token(params.get("sid_token").map(String::as_str), &headers);
+    if !sid_token.is_some_and(|sid_token| state.sessions.contains_key(&sid_token)) {
+        return StatusCode::FORBIDDEN.into_response();
+    }
+
+    let index =
+        |name: &str| -> Option<usize> { params.get(name)?.parse::<usize>().ok()?.checked_sub(1) };
+
+    match index("email_id")
+        .and_then(|email| state.inbox.get(email))
+        .and_then(|message| message.attachments.get(index("part_id")?))
+    {
+        Some(attachment) => (
+            [(header::CONTENT_TYPE, attachment.content_type.clone())],
+            attachment.content.clone(),
+        )
+            .into_response(),
+        None => StatusCode::NOT_FOUND.into_response(),
+    }
+}
+
+fn create_session(state: &mut MockState) -> Response {
+    let mut rng = rand::thread_rng();
+
+    let sid_token: String = (0..26)
+        .map(|_| char::from_digit(rng.gen_range(0..36), 36).unwrap_or('0'))
+        .collect();
+    let alias: String = (0..8)
+        .map(|_| char::from_digit(rng.gen_range(10..36), 36).unwrap_or('a'))
+        .collect();
+
+    let email_addr = format!("{alias}@{MOCK_DOMAIN}");
+    let created_at = chrono::Utc::now().timestamp();
+
+    state.sessions.insert(
+        sid_token.clone(),
+        Session {
+            email_addr: email_addr.clone(),
+            created_at,
+            deleted: Vec::new(),
+        },
+    );
+
+    (
+        [(header::SET_COOKIE, format!("PHPSESSID={sid_token}"))],
+        Json(serde_json::json!({
+            "email_addr": email_addr,
+            "email_timestamp": created_at,
+            "alias": alias,
+            "sid_token": sid_token,
+        })),
+    )
+        .into_response()
+}
+
+/// Session is taken from `sid_token` parameter or `PHPSESSID` cookie
+fn session_token(sid_token: Option<&str>, headers: &HeaderMap) -> Option<String> {
+    if let Some(sid_token) = sid_token {
+        return Some(sid_token.to_string());
+    }
+
+    headers
+        .get_all(header::COOKIE)
+        .iter()
+        .filter_map(|cookie| cookie.to_str().ok())
+        .flat_map(|cookie| cookie.split(';'))
+        .find_map(|cookie| cookie.trim().strip_prefix("PHPSESSID="))
+        .map(str::to_string)
+}
+
+fn message_json(
+    id: &str,
+    message: &ScriptedMessage,
+    created_at: i64,
+    with_body: bool,
+) -> serde_json::Value {
+    let timestamp = created_at + message.delay_secs;
+    let excerpt: String = extract::html_to_text(&message.mail_body)
+        .trim()
+        .chars()
+        .take(80)
+        .collect();
+
+    let mut value = serde_json::json!({
+        "mail_id": id,
+        "mail_from": message.mail_from,
+        "mail_subject": message.mail_subject,
+        "mail_excerpt": excerpt,
+        "mail_timestamp": timestamp.to_string(),
+        "mail_read": "0",
+        "att": message.attachments.len().to_string(),
+    });
+
+    if with_body {
+        value["mail_body"] = serde_json::Value::String(message.mail_body.clone());
+        value["att_info"] = message
+            .attachments
+            .iter()
+            .enumerate()
+            .map(|(index, attachment)| {
+                serde_json::json!({
+                    "f": attachment.filename,
+                    "t": attachment.content_type,
+                    "p": (index + 1).to_string(),
+                })
+            })
+            .collect();
+    }
+
+    value
+}
+
+/// Starts mock provider shared by all tests of the test binary
+/// and points Guerrilla Mail client at it. Scripted inbox has
+/// the welcome email and a verification email arriving after 2 seconds
+#[cfg(test)]
+pub fn init_test_provider() -> &'static str {
+    static BASE_URL: std::sync::OnceLock<String> = std::sync::OnceLock::new();
+
+    BASE_URL.get_or_init(|| {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("free local port");
+        let base_url = format!("http://{}", listener.local_addr().expect("local address"));
+
+        let inbox = vec![ScriptedMessage {
+            mail_from: "noreply@example.com".to_string(),
+            mail_subject: "Verify your email".to_string(),
+            mail_body: "<p>Your code is 482913</p>".to_string(),
+            delay_secs: 2,
+            attachments: vec![ScriptedAttachment {
+                filename: "terms.txt".to_string(),
+                content_type: "text/plain".to_string(),
+                content: "Terms of service".to_string(),
+            }],
+        }];
+
+        let router = router(inbox).layer(axum::middleware::from_fn(record_request));
+
+        // Server lives in its own runtime, because runtime
+        // of every tokio test is dropped when the test ends
+        std::thread::spawn(move || {
+            tokio::runtime::Runtime::new()
+                .expect("tokio runtime")
+                .block_on(serve_listener(listener, router))
+        });
+
+        let mut config = crate::config::Config::default();
+        config.providers.insert(
+            "guerrillamail".to_string(),
+            crate::config::ProviderConfig {
+                rate_limit_ms: Some(0),
+                base_url: Some(base_url.clone()),
+                ..Default::default()
+            },
+        );
+        crate::http::init(&config);
+
+        base_url
+    })
+}
+
+/// Request received by the test provider
+#[cfg(test)]
+#[derive(Debug, Clone)]
+pub struct RecordedRequest {
+    pub method: String,
+    /// Path and query, as sent
+    pub uri: String,
+    pub cookie: Option<String>,
+}
+
+#[cfg(test)]
+static RECORDED_REQUESTS: Mutex<Vec<RecordedRequest>> = Mutex::new(Vec::new());
+
+#[cfg(test)]
+async fn record_request<B>(
+    request: axum::http::Request<B>,
+    next: axum::middleware::Next<B>,
+) -> Response {
+    RECORDED_REQUESTS
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .push(RecordedRequest {
+            method: request.method().to_string(),
+            uri: request.uri().to_string(),
+            cookie: request
+                .headers()
+                .get(header::COOKIE)
+                .and_then(|cookie| cookie.to_str().ok())
+                .map(str::to_string),
+        });
+
+    next.run(request).await
+}
+
+/// Requests the test provider received with `cookie`. Tests share
+/// the provider, so each test finds its own requests by session cookie
+#[cfg(test)]
+pub fn recorded_requests(cookie: &str) -> Vec<RecordedRequest> {
+    RECORDED_REQUESTS
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .iter()
+        .filter(|request| request.cookie.as_deref() == Some(cookie))
+        .cloned()
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_load_inbox_with_defaults() {
+        let path = std::env::temp_dir().join(format!("mock_inbox_{}.json", std::process::id()));
+        fs::write(
+            &path,
+            r#"[{"mail_from": "a@example.com", "mail_subject": "Hi", "mail_body": "Hello",
+                 "attachments": [{"filename": "a.bin", "content": "x"}]}]"#,
+        )
+        .unwrap();
+
+        let inbox = load_inbox(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(inbox[0].delay_secs, 0);
+        assert_eq!(
+            inbox[0].attachments[0].content_type,
+            "application/octet-stream"
+        );
+    }
+
+    #[test]
+    fn test_session_token_from_cookie() {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::COOKIE, "lang=en; PHPSESSID=abc".parse().unwrap());
+
+        assert_eq!(session_token(None, &headers), Some("abc".to_string()));
+        assert_eq!(
+            session_token(Some("def"), &headers),
+            Some("def".to_string())
+        );
+        assert_eq!(session_token(None, &HeaderMap::new()), None);
+    }
+}
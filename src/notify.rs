@@ -0,0 +1,297 @@
+use tokio::io::AsyncWriteExt;
+
+use std::io::IsTerminal;
+use std::process::Stdio;
+
+use crate::config::NotifyConfig;
+use crate::mails::{MailError, Message};
+
+/// Where new email is announced
+#[derive(Debug, Clone, PartialEq)]
+pub enum Sink {
+    /// Freedesktop notification sent through D-Bus
+    Desktop,
+    /// Terminal bell
+    Bell,
+    /// Terminal title
+    Title,
+    /// Shell command receiving each message as JSON on stdin
+    Command(String),
+}
+
+impl std::str::FromStr for Sink {
+    type Err = MailError;
+
+    fn from_str(sink: &str) -> Result<Self, Self::Err> {
+        match sink {
+            "desktop" => Ok(Sink::Desktop),
+            "bell" => Ok(Sink::Bell),
+            "title" => Ok(Sink::Title),
+            _ => Err(MailError::NotifySinkError(sink.to_string())),
+        }
+    }
+}
+
+/// Announces new email to every sink
+#[derive(Debug, Clone, Default)]
+pub struct Notifier {
+    sinks: Vec<Sink>,
+}
+
+impl Notifier {
+    pub fn from_config(config: &NotifyConfig) -> Self {
+        let mut notifier = Notifier::default();
+
+        if config.desktop {
+            notifier.add(Sink::Desktop);
+        }
+        if config.bell {
+            notifier.add(Sink::Bell);
+        }
+        if config.title {
+            notifier.add(Sink::Title);
+        }
+        if let Some(command) = &config.command {
+            notifier.add(Sink::Command(command.clone()));
+        }
+
+        notifier
+    }
+
+    /// Adds sink unless it is already used
+    pub fn add(&mut self, sink: Sink) {
+        if !self.sinks.contains(&sink) {
+            self.sinks.push(sink);
+        }
+    }
+
+    /// Announces `messages` to every sink, even if some of them fail.
+    /// Returns the first failure
+    pub async fn notify(&self, messages: &[Message]) -> Result<(), MailError> {
+        if messages.is_empty() {
+            return Ok(());
+        }
+
+        let summary = summary(messages);
+        let mut result = Ok(());
+
+        for sink in &self.sinks {
+            let sent = match sink {
+                Sink::Desktop => desktop(&summary, messages).await,
+                Sink::Bell => {
+                    terminal("\x07");
+                    Ok(())
+                }
+                Sink::Title => {
+                    terminal(&format!("\x1b]0;{summary}\x07"));
+                    Ok(())
+                }
+                Sink::Command(command) => run_command(command, messages).await,
+            };
+
+            if result.is_ok() {
+                result = sent;
+            }
+        }
+
+        result
+    }
+}
+
+/// Summary without control characters, as the sender could
+/// otherwise end the title escape sequence and start its own
+fn summary(messages: &[Message]) -> String {
+    match messages {
+        [message] => format!("New email from {}", printable(&message.mail_from)),
+        _ => format!("{} new emails", messages.len()),
+    }
+}
+
+/// `text` without control characters, so email fields cannot move the cursor
+/// or change the terminal with escape sequences
+pub(crate) fn printable(text: &str) -> String {
+    text.chars().filter(|c| !c.is_control()).collect()
+}
+
+/// Writes escape sequence to stderr, so it does not end up in piped output
+fn terminal(sequence: &str) {
+    let mut stderr = std::io::stderr();
+
+    if stderr.is_terminal() {
+        use std::io::Write;
+
+        write!(stderr, "{sequence}").ok();
+        stderr.flush().ok();
+    }
+}
+
+#[cfg(target_os = "linux")]
+async fn desktop(summary: &str, messages: &[Message]) -> Result<(), MailError> {
+    use std::collections::HashMap;
+
+    let body: Vec<String> = messages
+        .iter()
+        .map(|m| printable(&m.mail_subject))
+        .collect();
+    let error = |e: zbus::Error| MailError::NotifyError(format!("D-Bus: {e}"));
+
+    let connection = zbus::Connection::session().await.map_err(error)?;
+    connection
+        .call_method(
+            Some("org.freedesktop.Notifications"),
+            "/org/freedesktop/Notifications",
+            Some("org.freedesktop.Notifications"),
+            "Notify",
+            &(
+                env!("CARGO_PKG_NAME"),
+                0u32,
+                "mail-message-new",
+                summary,
+                body.join("\n"),
+                Vec::<&str>::new(),
+                HashMap::<&str, zbus::zvariant::Value>::new(),
+                -1i32,
+            ),
+        )
+        .await
+        .map_err(error)?;
+
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+async fn desktop(_summary: &str, _messages: &[Message]) -> Result<(), MailError> {
+    Err(MailError::NotifyError(
+        "desktop notifications need D-Bus, which is available on Linux".to_string(),
+    ))
+}
+
+/// Runs `command` once per message, with the message as JSON on stdin
+async fn run_command(command: &str, messages: &[Message]) -> Result<(), MailError> {
+    for message in messages {
+        let json = serde_json::to_vec(message)?;
+
+        let mut child = shell(command)
+            .stdin(Stdio::piped())
+            .spawn()
+            .map_err(|e| MailError::NotifyError(format!("cannot run `{command}`: {e}")))?;
+
+        if let Some(mut stdin) = child.stdin.take() {
+            // Command may exit without reading its input
+            stdin.write_all(&json).await.ok();
+        }
+
+        let status = child
+            .wait()
+            .await
+            .map_err(|e| MailError::NotifyError(format!("cannot run `{command}`: {e}")))?;
+
+        if !status.success() {
+            return Err(MailError::NotifyError(format!(
+                "`{command}` exited with {status}"
+            )));
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(windows)]
+fn shell(command: &str) -> tokio::process::Command {
+    let mut shell = tokio::process::Command::new("cmd");
+    shell.arg("/C").arg(command);
+
+    shell
+}
+
+#[cfg(not(windows))]
+fn shell(command: &str) -> tokio::process::Command {
+    let mut shell = tokio::process::Command::new("sh");
+    shell.arg("-c").arg(command);
+
+    shell
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(id: &str, subject: &str) -> Message {
+        Message {
+            email_addr: "qa@example.com".to_string(),
+            mail_id: id.to_string(),
+            mail_from: "noreply@github.com".to_string(),
+            mail_subject: subject.to_string(),
+            mail_excerpt: String::new(),
+            mail_body: None,
+            mail_content_type: None,
+            mail_timestamp: 1_650_000_000,
+        }
+    }
+
+    #[cfg(unix)]
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_command_receives_messages() -> Result<(), MailError> {
+        let path = std::env::temp_dir().join("disposable_mail_notify_test.ndjson");
+        std::fs::remove_file(&path).ok();
+
+        let config = NotifyConfig {
+            command: Some(format!("cat >> {} && echo >> {0}", path.display())),
+            ..NotifyConfig::default()
+        };
+        let messages = vec![message("1", "Verify your email"), message("2", "Welcome")];
+
+        Notifier::from_config(&config).notify(&messages).await?;
+
+        let content = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let received: Vec<Message> = content
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(received, messages);
+
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_failed_command() {
+        let mut notifier = Notifier::default();
+        notifier.add(Sink::Command("exit 3".to_string()));
+        notifier.add(Sink::Bell);
+
+        assert!(notifier.notify(&[]).await.is_ok());
+        assert!(matches!(
+            notifier.notify(&[message("1", "Welcome")]).await,
+            Err(MailError::NotifyError(e)) if e.contains("exit status: 3")
+        ));
+    }
+
+    #[test]
+    fn test_parse_sink() {
+        assert_eq!("bell".parse::<Sink>().unwrap(), Sink::Bell);
+        assert!(matches!(
+            "email".parse::<Sink>(),
+            Err(MailError::NotifySinkError(_))
+        ));
+        assert_eq!(
+            summary(&[message("1", "Welcome")]),
+            "New email from noreply@github.com"
+        );
+    }
+
+    #[test]
+    fn test_summary_strips_control_characters() {
+        let message = Message {
+            mail_from: "evil@example.com\x07\x1b]0;rm -rf ~\x07\r\n".to_string(),
+            ..message("1", "Welcome")
+        };
+
+        assert_eq!(
+            summary(&[message]),
+            "New email from evil@example.com]0;rm -rf ~"
+        );
+    }
+}
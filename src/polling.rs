@@ -0,0 +1,505 @@
+use chrono::Utc;
+use tokio::sync::{broadcast, Notify};
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::config::{PollingConfig, MAX_BACKOFF_FACTOR, MIN_POLLING_INTERVAL_MS};
+use crate::mails::{Account, MailError, Message, Provider};
+use crate::storage::ADDRESS_LIFETIME;
+
+/// Updates kept for consumers that are slow to receive them
+const UPDATES: usize = 16;
+
+/// Delays between checks of an address. They grow while the address gets
+/// no email and drop back to the initial one when email is expected
+#[derive(Debug, Clone)]
+pub struct Schedule {
+    initial: Duration,
+    max: Duration,
+    factor: f64,
+    interval: Duration,
+}
+
+impl Schedule {
+    pub fn new(config: &PollingConfig) -> Self {
+        let initial =
+            Duration::from_millis(config.initial_interval_ms.max(MIN_POLLING_INTERVAL_MS));
+        let factor = if config.backoff_factor.is_finite() {
+            config.backoff_factor.clamp(1.0, MAX_BACKOFF_FACTOR)
+        } else {
+            1.0
+        };
+
+        Schedule {
+            initial,
+            max: Duration::from_millis(config.max_interval_ms).max(initial),
+            factor,
+            interval: initial,
+        }
+    }
+
+    /// Polls quickly again, for when email is expected soon
+    pub fn trigger(&mut self) {
+        self.interval = self.initial;
+    }
+
+    /// Delay before the next check after a check ended with `result`
+    pub fn next(&mut self, result: &Result<Vec<Message>, MailError>) -> Duration {
+        match result {
+            // Emails often come in bursts, like a confirmation followed by a welcome
+            Ok(messages) if !messages.is_empty() => self.trigger(),
+            // Provider may ask for a longer wait than the schedule would do
+            Err(MailError::RateLimitError(retry_after)) => {
+                self.grow();
+                return retry_after.map_or(self.interval, |delay| delay.max(self.interval));
+            }
+            _ => self.grow(),
+        }
+
+        self.interval
+    }
+
+    fn grow(&mut self) {
+        // Grown delay past the maximum may not fit in a Duration
+        self.interval = Duration::try_from_secs_f64(self.interval.as_secs_f64() * self.factor)
+            .map_or(self.max, |interval| interval.min(self.max));
+    }
+}
+
+/// What the poller of an address found
+#[derive(Debug, Clone)]
+enum Update {
+    /// Emails found by a check. Consumers keep those newer than ones they already got
+    Messages(Arc<Vec<Message>>),
+    /// Check failed, the address is checked again later
+    Failed(Arc<MailError>),
+    /// Address expired and its poller stopped
+    Expired,
+}
+
+/// Id of the newest email of a poller, and older id asked for
+/// by a consumer that joined later
+#[derive(Debug, Default)]
+struct Cursor {
+    seq: u32,
+    requested: Option<u32>,
+}
+
+/// Checks one address in the background for every consumer waiting for its email
+struct Poller {
+    updates: broadcast::Sender<Update>,
+    cursor: Mutex<Cursor>,
+    wake: Notify,
+}
+
+/// Shares one poller per address among every `check` and event stream
+/// waiting for its email. Pollers stop when nobody waits for them
+#[derive(Default)]
+pub struct Pollers {
+    config: PollingConfig,
+    pollers: Mutex<HashMap<String, Arc<Poller>>>,
+}
+
+impl Pollers {
+    pub fn new(config: PollingConfig) -> Self {
+        Pollers {
+            config,
+            pollers: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// How long `check` waits for new email
+    pub fn timeout(&self) -> Duration {
+        Duration::from_secs(self.config.timeout_secs)
+    }
+
+    /// Waits for emails of `account` with id greater than `seq`. Joining consumer
+    /// makes the poller check right away and poll quickly for a while
+    pub fn subscribe(
+        self: &Arc<Self>,
+        account: Account,
+        provider: Arc<dyn Provider>,
+        seq: u32,
+    ) -> Subscription {
+        let mut pollers = self.pollers.lock().unwrap_or_else(|e| e.into_inner());
+
+        let email_addr = account.email_addr.clone();
+        let poller = pollers
+            .entry(email_addr.clone())
+            .or_insert_with(|| {
+                let poller = Arc::new(Poller {
+                    updates: broadcast::channel(UPDATES).0,
+                    cursor: Mutex::new(Cursor {
+                        seq,
+                        requested: None,
+                    }),
+                    wake: Notify::new(),
+                });
+                tokio::spawn(self.clone().poll(poller.clone(), account, provider));
+
+                poller
+            })
+            .clone();
+
+        let mut cursor = poller.cursor.lock().unwrap_or_else(|e| e.into_inner());
+        if seq < cursor.seq {
+            cursor.requested = Some(cursor.requested.map_or(seq, |requested| requested.min(seq)));
+        }
+        poller.wake.notify_one();
+
+        Subscription {
+            email_addr,
+            seq,
+            updates: poller.updates.subscribe(),
+        }
+    }
+
+    /// Pollers checking every few milliseconds, so tests do not wait
+    #[cfg(test)]
+    pub fn fast() -> Self {
+        Pollers::new(PollingConfig {
+            initial_interval_ms: 20,
+            max_interval_ms: 80,
+            backoff_factor: 2.0,
+            timeout_secs: 5,
+        })
+    }
+
+    /// Number of addresses being polled
+    #[cfg(test)]
+    fn len(&self) -> usize {
+        self.pollers.lock().unwrap_or_else(|e| e.into_inner()).len()
+    }
+
+    async fn poll(
+        self: Arc<Self>,
+        poller: Arc<Poller>,
+        account: Account,
+        provider: Arc<dyn Provider>,
+    ) {
+        let mut schedule = Schedule::new(&self.config);
+        let mut delay = Duration::ZERO;
+        let mut rate_limited_until = None;
+        let expires_at = account.created_at
+            + chrono::Duration::from_std(ADDRESS_LIFETIME)
+                .unwrap_or_else(|_| chrono::Duration::zero());
+
+        loop {
+            tokio::select! {
+                _ = tokio::time::sleep(delay) => {}
+                _ = poller.wake.notified() => schedule.trigger(),
+            }
+
+            // Joining consumers do not cut short the wait the provider asked for
+            if let Some(until) = rate_limited_until.take() {
+                tokio::time::sleep_until(until).await;
+            }
+
+            {
+                // Consumers subscribe with the lock held, so none joins a stopping poller
+                let mut pollers = self.pollers.lock().unwrap_or_else(|e| e.into_inner());
+
+                let expired = Utc::now() >= expires_at;
+                if expired || poller.updates.receiver_count() == 0 {
+                    if expired {
+                        poller.updates.send(Update::Expired).ok();
+                    }
+                    pollers.remove(&account.email_addr);
+
+                    return;
+                }
+            }
+
+            let seq = {
+                let mut cursor = poller.cursor.lock().unwrap_or_else(|e| e.into_inner());
+                cursor
+                    .requested
+                    .take()
+                    .map_or(cursor.seq, |r| r.min(cursor.seq))
+            };
+
+            let result = provider.check_messages(&account, seq).await;
+            delay = schedule.next(&result);
+
+            if matches!(result, Err(MailError::RateLimitError(_))) {
+                rate_limited_until = Some(tokio::time::Instant::now() + delay);
+            }
+
+            let update = match result {
+                Ok(messages) if messages.is_empty() => continue,
+                Ok(messages) => {
+                    let newest = messages.iter().filter_map(|m| m.mail_id.parse().ok()).max();
+                    let mut cursor = poller.cursor.lock().unwrap_or_else(|e| e.into_inner());
+                    cursor.seq = cursor.seq.max(newest.unwrap_or(0));
+
+                    Update::Messages(Arc::new(messages))
+                }
+                // Consumers keep waiting while the poller waits as asked
+                Err(MailError::RateLimitError(_)) => continue,
+                Err(e) => Update::Failed(Arc::new(e)),
+            };
+
+            poller.updates.send(update).ok();
+        }
+    }
+}
+
+/// Consumer of the poller of an address
+pub struct Subscription {
+    email_addr: String,
+    seq: u32,
+    updates: broadcast::Receiver<Update>,
+}
+
+impl Subscription {
+    /// Waits for emails newer than those this consumer already got
+    pub async fn next(&mut self) -> Result<Vec<Message>, MailError> {
+        loop {
+            let update = match self.updates.recv().await {
+                Ok(update) => update,
+                Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                Err(broadcast::error::RecvError::Closed) => Update::Expired,
+            };
+
+            if let Some(result) = self.receive(update) {
+                return result;
+            }
+        }
+    }
+
+    /// Like `next`, but returns None instead of waiting
+    pub fn try_next(&mut self) -> Option<Result<Vec<Message>, MailError>> {
+        loop {
+            let update = match self.updates.try_recv() {
+                Ok(update) => update,
+                Err(broadcast::error::TryRecvError::Lagged(_)) => continue,
+                Err(broadcast::error::TryRecvError::Empty) => return None,
+                Err(broadcast::error::TryRecvError::Closed) => Update::Expired,
+            };
+
+            if let Some(result) = self.receive(update) {
+                return Some(result);
+            }
+        }
+    }
+
+    /// Result for the consumer, None if the update has nothing new for it
+    fn receive(&mut self, update: Update) -> Option<Result<Vec<Message>, MailError>> {
+        match update {
+            Update::Messages(messages) => {
+                let seq = self.seq;
+                let new: Vec<Message> = messages
+                    .iter()
+                    .filter(|m| m.mail_id.parse().is_ok_and(|id: u32| id > seq))
+                    .cloned()
+                    .collect();
+
+                self.seq = new
+                    .iter()
+                    .filter_map(|m| m.mail_id.parse().ok())
+                    .fold(seq, u32::max);
+
+                (!new.is_empty()).then_some(Ok(new))
+            }
+            Update::Failed(e) => Some(Err(MailError::SharedError(e))),
+            Update::Expired => Some(Err(MailError::EmailCheckError(self.email_addr.clone()))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use async_trait::async_trait;
+
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use crate::mails::{FetchedMessage, MemoryProvider};
+
+    /// Memory provider counting checks, rate limiting the first `rate_limited` of them
+    #[derive(Default)]
+    struct CountingProvider {
+        memory: MemoryProvider,
+        checks: AtomicUsize,
+        rate_limited: usize,
+    }
+
+    #[async_trait]
+    impl Provider for CountingProvider {
+        async fn create_address(&self) -> Result<Account, MailError> {
+            self.memory.create_address().await
+        }
+
+        async fn list_messages(
+            &self,
+            account: &Account,
+            offset: u32,
+        ) -> Result<Vec<Message>, MailError> {
+            self.memory.list_messages(account, offset).await
+        }
+
+        async fn check_messages(
+            &self,
+            account: &Account,
+            seq: u32,
+        ) -> Result<Vec<Message>, MailError> {
+            if self.checks.fetch_add(1, Ordering::SeqCst) < self.rate_limited {
+                return Err(MailError::RateLimitError(Some(Duration::from_millis(300))));
+            }
+
+            self.memory.check_messages(account, seq).await
+        }
+
+        async fn fetch_message(
+            &self,
+            account: &Account,
+            mail_id: &str,
+        ) -> Result<Option<FetchedMessage>, MailError> {
+            self.memory.fetch_message(account, mail_id).await
+        }
+
+        async fn delete_message(&self, account: &Account, mail_id: &str) -> Result<(), MailError> {
+            self.memory.delete_message(account, mail_id).await
+        }
+
+        async fn fetch_attachment(
+            &self,
+            account: &Account,
+            mail_id: &str,
+            part_id: &str,
+        ) -> Result<Vec<u8>, MailError> {
+            self.memory
+                .fetch_attachment(account, mail_id, part_id)
+                .await
+        }
+    }
+
+    #[test]
+    fn test_schedule_backs_off_when_idle() {
+        let mut schedule = Schedule::new(&Pollers::fast().config);
+        let ms = |millis| Duration::from_millis(millis);
+        let message = Message {
+            email_addr: "a@memory.test".to_string(),
+            mail_id: "1".to_string(),
+            mail_from: String::new(),
+            mail_subject: String::new(),
+            mail_excerpt: String::new(),
+            mail_body: None,
+            mail_content_type: None,
+            mail_timestamp: 0,
+        };
+
+        assert_eq!(schedule.next(&Ok(Vec::new())), ms(40));
+        assert_eq!(schedule.next(&Ok(Vec::new())), ms(80));
+        assert_eq!(schedule.next(&Ok(Vec::new())), ms(80));
+        assert_eq!(schedule.next(&Ok(vec![message])), ms(20));
+        assert_eq!(
+            schedule.next(&Err(MailError::RateLimitError(Some(ms(1000))))),
+            ms(1000)
+        );
+        assert_eq!(schedule.next(&Err(MailError::RateLimitError(None))), ms(80));
+
+        schedule.trigger();
+        assert_eq!(schedule.next(&Err(MailError::TimeoutError)), ms(40));
+    }
+
+    #[test]
+    fn test_schedule_clamps_config() {
+        let mut schedule = Schedule::new(&PollingConfig {
+            initial_interval_ms: 0,
+            max_interval_ms: u64::MAX,
+            backoff_factor: 1e30,
+            timeout_secs: 5,
+        });
+
+        assert_eq!(schedule.next(&Ok(Vec::new())), Duration::from_millis(100));
+        for _ in 0..100 {
+            schedule.next(&Ok(Vec::new()));
+        }
+        assert_eq!(
+            schedule.next(&Ok(Vec::new())),
+            Duration::from_millis(u64::MAX)
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_consumers_share_poller() -> Result<(), MailError> {
+        let provider = Arc::new(CountingProvider::default());
+        let account = provider.create_address().await?;
+        let pollers = Arc::new(Pollers::fast());
+
+        let mut first = pollers.subscribe(account.clone(), provider.clone(), 1);
+        let mut second = pollers.subscribe(account.clone(), provider.clone(), 0);
+        assert_eq!(pollers.len(), 1);
+
+        // Second consumer asked for the welcome email the first one already had
+        let welcome = tokio::time::timeout(pollers.timeout(), second.next())
+            .await
+            .unwrap()?;
+        assert_eq!(welcome[0].mail_id, "1");
+
+        provider
+            .memory
+            .inject(&account.email_addr, "a@example.com", "Code", "", Vec::new())?;
+
+        for subscription in [&mut first, &mut second] {
+            let messages = tokio::time::timeout(pollers.timeout(), subscription.next())
+                .await
+                .unwrap()?;
+            let subjects: Vec<&str> = messages.iter().map(|m| m.mail_subject.as_str()).collect();
+            assert_eq!(subjects, ["Code"]);
+        }
+        assert!(first.try_next().is_none());
+
+        drop(first);
+        drop(second);
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert_eq!(pollers.len(), 0);
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_rate_limit_delays_polling() -> Result<(), MailError> {
+        let provider = Arc::new(CountingProvider {
+            rate_limited: 1,
+            ..CountingProvider::default()
+        });
+        let account = provider.create_address().await?;
+        let pollers = Arc::new(Pollers::fast());
+
+        let mut subscription = pollers.subscribe(account, provider.clone(), 0);
+
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert_eq!(provider.checks.load(Ordering::SeqCst), 1);
+        assert!(subscription.try_next().is_none());
+
+        let messages = tokio::time::timeout(pollers.timeout(), subscription.next())
+            .await
+            .unwrap()?;
+        assert_eq!(messages.len(), 1);
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_expired_address_stops_poller() -> Result<(), MailError> {
+        let provider = Arc::new(CountingProvider::default());
+        let mut account = provider.create_address().await?;
+        account.created_at = Utc::now() - chrono::Duration::hours(2);
+        let pollers = Arc::new(Pollers::fast());
+
+        let mut subscription = pollers.subscribe(account, provider, 0);
+
+        assert!(matches!(
+            subscription.next().await,
+            Err(MailError::EmailCheckError(_))
+        ));
+        assert_eq!(pollers.len(), 0);
+
+        Ok(())
+    }
+}
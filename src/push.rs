@@ -0,0 +1,182 @@
+use tokio::sync::broadcast;
+
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::context::Context;
+use crate::events::Event;
+use crate::stream::Stream;
+
+/// Events kept per profile for clients that reconnect
+pub const RECENT_EVENTS: usize = 100;
+
+/// Event with its id, which increases by one with every event of the profile
+#[derive(Debug, Clone)]
+pub struct Published {
+    pub id: u64,
+    pub event: Arc<Event>,
+}
+
+/// Subscribers and recent events of a profile
+struct Channel {
+    sender: broadcast::Sender<Published>,
+    recent: VecDeque<Published>,
+    last_id: u64,
+}
+
+impl Channel {
+    fn new() -> Self {
+        Channel {
+            sender: broadcast::channel(RECENT_EVENTS).0,
+            recent: VecDeque::new(),
+            last_id: 0,
+        }
+    }
+}
+
+/// Pushes events found by background pollers, one per profile, to subscribers
+pub struct Hub {
+    interval: Duration,
+    channels: Mutex<HashMap<String, Channel>>,
+}
+
+impl Hub {
+    /// Hub whose pollers check addresses every `interval`
+    pub fn new(interval: Duration) -> Arc<Self> {
+        Arc::new(Hub {
+            interval,
+            channels: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Recent events published after `last_id` and receiver of the next ones.
+    /// The first subscription to the profile of `ctx` starts its poller
+    pub fn subscribe(
+        self: &Arc<Self>,
+        ctx: Context,
+        last_id: Option<u64>,
+    ) -> (Vec<Published>, broadcast::Receiver<Published>) {
+        let mut channels = self.channels.lock().unwrap_or_else(|e| e.into_inner());
+
+        let channel = channels.entry(ctx.profile.clone()).or_insert_with(|| {
+            tokio::spawn(self.clone().poll(ctx));
+
+            Channel::new()
+        });
+
+        let replayed = match last_id {
+            Some(last_id) if last_id < channel.last_id => channel
+                .recent
+                .iter()
+                .filter(|published| published.id > last_id)
+                .cloned()
+                .collect(),
+            // Ids of a restarted server start again from 1, so the client gets every event
+            Some(last_id) if last_id > channel.last_id => channel.recent.iter().cloned().collect(),
+            _ => Vec::new(),
+        };
+
+        (replayed, channel.sender.subscribe())
+    }
+
+    /// Numbers event and sends it to subscribers of its profile
+    pub fn publish(&self, event: Event) -> Published {
+        let mut channels = self.channels.lock().unwrap_or_else(|e| e.into_inner());
+
+        let channel = channels
+            .entry(event.profile.clone())
+            .or_insert_with(Channel::new);
+
+        channel.last_id += 1;
+        let published = Published {
+            id: channel.last_id,
+            event: Arc::new(event),
+        };
+
+        if channel.recent.len() == RECENT_EVENTS {
+            channel.recent.pop_front();
+        }
+        channel.recent.push_back(published.clone());
+
+        // Nobody may be listening, the event is still replayed later
+        channel.sender.send(published.clone()).ok();
+
+        published
+    }
+
+    /// Publishes events of every address of the profile until the server stops
+    async fn poll(self: Arc<Self>, ctx: Context) {
+        let mut stream = Stream::new(&ctx, Vec::new());
+        let mut ticks = tokio::time::interval(self.interval);
+
+        loop {
+            ticks.tick().await;
+
+            match stream.poll().await {
+                Ok(events) => {
+                    for event in events {
+                        self.publish(event);
+                    }
+                }
+                Err(e) => eprintln!("Cannot poll profile `{}`: {}", ctx.profile, e.report()),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::events::EventKind;
+    use crate::mails::{MailError, MemoryProvider};
+    use crate::polling::Pollers;
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_poller_publishes_and_replays() -> Result<(), MailError> {
+        let memory = MemoryProvider::default();
+        let mut ctx = Context::in_memory(memory.clone());
+        ctx.pollers = Arc::new(Pollers::fast());
+        let account = ctx.provider("memory")?.create_address().await?;
+        ctx.storage.save_account(&account).await?;
+
+        let hub = Hub::new(Duration::from_millis(20));
+        let (replayed, mut receiver) = hub.subscribe(ctx.with_profile("default"), None);
+        assert!(replayed.is_empty());
+
+        // Let the poller skip emails the address already had
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        memory.inject(&account.email_addr, "a@example.com", "Code", "", Vec::new())?;
+
+        let published = tokio::time::timeout(Duration::from_secs(5), receiver.recv())
+            .await
+            .expect("event before timeout")
+            .expect("open channel");
+        assert_eq!(published.id, 1);
+        assert!(matches!(
+            &published.event.kind,
+            EventKind::MessageReceived(message) if message.mail_subject == "Code"
+        ));
+
+        hub.publish(Event::new(
+            "default",
+            EventKind::AddressExpired {
+                email_addr: account.email_addr.clone(),
+            },
+        ));
+
+        let ids = |events: Vec<Published>| -> Vec<u64> { events.iter().map(|p| p.id).collect() };
+        assert_eq!(
+            ids(hub.subscribe(ctx.with_profile("default"), Some(1)).0),
+            [2]
+        );
+        assert!(ids(hub.subscribe(ctx.with_profile("default"), Some(2)).0).is_empty());
+        assert_eq!(
+            ids(hub.subscribe(ctx.with_profile("default"), Some(7)).0),
+            [1, 2]
+        );
+
+        Ok(())
+    }
+}
@@ -0,0 +1,245 @@
+use chrono::prelude::*;
+
+use crate::mails::{MailError, Message};
+
+/// Query over cached messages. Every set field must match,
+/// text fields are matched case insensitive as substrings
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct SearchQuery {
+    /// Only emails received by this address
+    pub email_addr: Option<String>,
+    /// Matches sender, subject or body
+    pub text: Option<String>,
+    pub from: Option<String>,
+    pub subject: Option<String>,
+    pub body: Option<String>,
+    pub since: Option<DateTime<Utc>>,
+    pub until: Option<DateTime<Utc>>,
+}
+
+impl SearchQuery {
+    /// Builds MongoDB filter for `messages` collection
+    pub fn filter(&self) -> bson::Document {
+        let mut conditions: Vec<bson::Document> = Vec::new();
+
+        if let Some(email_addr) = &self.email_addr {
+            conditions.push(bson::doc! { "email_addr": email_addr });
+        }
+
+        if let Some(text) = &self.text {
+            let regex = contains_regex(text);
+            conditions.push(bson::doc! {
+                "$or": [
+                    { "mail_from": regex.clone() },
+                    { "mail_subject": regex.clone() },
+                    { "mail_excerpt": regex.clone() },
+                    { "mail_body": regex },
+                ]
+            });
+        }
+
+        if let Some(from) = &self.from {
+            conditions.push(bson::doc! { "mail_from": contains_regex(from) });
+        }
+
+        if let Some(subject) = &self.subject {
+            conditions.push(bson::doc! { "mail_subject": contains_regex(subject) });
+        }
+
+        if let Some(body) = &self.body {
+            let regex = contains_regex(body);
+            conditions.push(bson::doc! {
+                "$or": [{ "mail_excerpt": regex.clone() }, { "mail_body": regex }]
+            });
+        }
+
+        let mut timestamp = bson::Document::new();
+
+        if let Some(since) = self.since {
+            timestamp.insert("$gte", since.timestamp());
+        }
+
+        if let Some(until) = self.until {
+            timestamp.insert("$lte", until.timestamp());
+        }
+
+        if !timestamp.is_empty() {
+            conditions.push(bson::doc! { "mail_timestamp": timestamp });
+        }
+
+        match conditions.len() {
+            0 => bson::Document::new(),
+            1 => conditions.remove(0),
+            _ => bson::doc! { "$and": conditions },
+        }
+    }
+}
+
+impl SearchQuery {
+    /// Same as `filter`, for messages that are not stored in MongoDB
+    pub fn matches(&self, message: &Message) -> bool {
+        let body = message.mail_body.as_deref().unwrap_or_default();
+        let contains =
+            |field: &str, text: &str| field.to_lowercase().contains(&text.to_lowercase());
+
+        self.email_addr
+            .as_ref()
+            .is_none_or(|email_addr| message.email_addr == *email_addr)
+            && self.text.as_ref().is_none_or(|text| {
+                contains(&message.mail_from, text)
+                    || contains(&message.mail_subject, text)
+                    || contains(&message.mail_excerpt, text)
+                    || contains(body, text)
+            })
+            && self
+                .from
+                .as_ref()
+                .is_none_or(|from| contains(&message.mail_from, from))
+            && self
+                .subject
+                .as_ref()
+                .is_none_or(|subject| contains(&message.mail_subject, subject))
+            && self
+                .body
+                .as_ref()
+                .is_none_or(|text| contains(&message.mail_excerpt, text) || contains(body, text))
+            && self
+                .since
+                .is_none_or(|since| message.mail_timestamp >= since.timestamp())
+            && self
+                .until
+                .is_none_or(|until| message.mail_timestamp <= until.timestamp())
+    }
+}
+
+/// Parses date passed to `--since` and `--until`.
+/// Date without time is the start of the day, or the end of it if `end_of_day` is set
+pub fn parse_date(date: &str, end_of_day: bool) -> Result<DateTime<Utc>, MailError> {
+    if let Ok(date_time) = DateTime::parse_from_rfc3339(date) {
+        return Ok(date_time.with_timezone(&Utc));
+    }
+
+    let day = NaiveDate::parse_from_str(date, "%Y-%m-%d")
+        .map_err(|_| MailError::DateError(date.to_string()))?;
+
+    let time = if end_of_day {
+        day.and_hms(23, 59, 59)
+    } else {
+        day.and_hms(0, 0, 0)
+    };
+
+    Ok(DateTime::from_utc(time, Utc))
+}
+
+fn contains_regex(text: &str) -> bson::Bson {
+    bson::Bson::RegularExpression(bson::Regex {
+        pattern: escape_regex(text),
+        options: "i".to_string(),
+    })
+}
+
+fn escape_regex(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+
+    for c in text.chars() {
+        if "\\.+*?()|[]{}^$".contains(c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_empty_query_matches_everything() {
+        assert_eq!(SearchQuery::default().filter(), bson::Document::new());
+    }
+
+    #[test]
+    fn test_query_with_single_field() {
+        let query = SearchQuery {
+            from: Some("github".to_string()),
+            ..Default::default()
+        };
+
+        let filter = query.filter();
+        let regex = filter.get("mail_from").unwrap();
+
+        assert_eq!(
+            regex,
+            &bson::Bson::RegularExpression(bson::Regex {
+                pattern: "github".to_string(),
+                options: "i".to_string(),
+            })
+        );
+    }
+
+    #[test]
+    fn test_query_with_date_range() {
+        let query = SearchQuery {
+            subject: Some("verify".to_string()),
+            since: Some(parse_date("2022-03-01", false).unwrap()),
+            until: Some(parse_date("2022-03-01", true).unwrap()),
+            ..Default::default()
+        };
+
+        let filter = query.filter();
+        let conditions = filter.get_array("$and").unwrap();
+
+        assert_eq!(conditions.len(), 2);
+        assert_eq!(
+            conditions[1].as_document().unwrap(),
+            &bson::doc! { "mail_timestamp": { "$gte": 1646092800_i64, "$lte": 1646179199_i64 } }
+        );
+    }
+
+    #[test]
+    fn test_query_matches_message() {
+        let message = Message {
+            email_addr: "abc@memory.test".to_string(),
+            mail_id: "1".to_string(),
+            mail_from: "noreply@GitHub.com".to_string(),
+            mail_subject: "Verify your email".to_string(),
+            mail_excerpt: String::new(),
+            mail_body: Some("Your code is 123456".to_string()),
+            mail_content_type: None,
+            mail_timestamp: 1646092800,
+        };
+        let query = SearchQuery {
+            text: Some("CODE".to_string()),
+            from: Some("github".to_string()),
+            since: Some(parse_date("2022-03-01", false).unwrap()),
+            ..Default::default()
+        };
+
+        assert!(SearchQuery::default().matches(&message));
+        assert!(query.matches(&message));
+        assert!(!SearchQuery {
+            until: Some(parse_date("2022-02-28", true).unwrap()),
+            ..query
+        }
+        .matches(&message));
+    }
+
+    #[test]
+    fn test_parse_date() {
+        assert_eq!(
+            parse_date("2022-03-01T10:00:00+02:00", false).unwrap(),
+            Utc.ymd(2022, 3, 1).and_hms(8, 0, 0)
+        );
+        assert!(matches!(
+            parse_date("somedate", false),
+            Err(MailError::DateError(date)) if date == "somedate"
+        ));
+    }
+
+    #[test]
+    fn test_escape_regex() {
+        assert_eq!(escape_regex("a.b+c"), "a\\.b\\+c");
+    }
+}
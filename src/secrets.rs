@@ -0,0 +1,216 @@
+use chacha20poly1305::aead::{Aead, KeyInit, OsRng, Payload};
+use chacha20poly1305::{AeadCore, ChaCha20Poly1305, Key, Nonce};
+
+use crate::config::Config;
+use crate::mails::MailError;
+
+/// Environment variable with the key, it takes precedence over config file
+pub const KEY_ENV: &str = "DISPOSABLE_MAIL_KEY";
+
+/// Service and user of the key in OS keyring
+const KEYRING_SERVICE: &str = "disposable-mail-tool";
+const KEYRING_USER: &str = "storage-key";
+
+/// Prefix of encrypted values, values without it are plaintext from older versions
+const ENCRYPTED_PREFIX: &str = "enc:v1:";
+const NONCE_LENGTH: usize = 12;
+
+/// Key encrypting provider secrets, like session tokens, in storage
+#[derive(Clone, PartialEq)]
+pub struct SecretKey([u8; 32]);
+
+impl std::fmt::Debug for SecretKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("SecretKey(..)")
+    }
+}
+
+impl SecretKey {
+    pub fn generate() -> Self {
+        SecretKey(ChaCha20Poly1305::generate_key(&mut OsRng).into())
+    }
+
+    /// Key from 32 bytes encoded in base64
+    pub fn from_base64(encoded: &str) -> Result<Self, MailError> {
+        let invalid =
+            || MailError::SecretKeyError("key must be 32 bytes encoded in base64".to_string());
+
+        let bytes = base64::decode(encoded.trim()).map_err(|_| invalid())?;
+
+        Ok(SecretKey(bytes.try_into().map_err(|_| invalid())?))
+    }
+
+    pub fn to_base64(&self) -> String {
+        base64::encode(self.0)
+    }
+
+    /// Encrypts `secret` of `email_addr`. The address is authenticated too,
+    /// so encrypted secret cannot be moved to another address
+    pub fn encrypt(&self, email_addr: &str, secret: &str) -> String {
+        let cipher = ChaCha20Poly1305::new(Key::from_slice(&self.0));
+        let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+
+        let payload = Payload {
+            msg: secret.as_bytes(),
+            aad: email_addr.as_bytes(),
+        };
+        let ciphertext = cipher
+            .encrypt(&nonce, payload)
+            .expect("encryption of in-memory buffer does not fail");
+
+        let mut sealed = nonce.to_vec();
+        sealed.extend(ciphertext);
+
+        format!("{ENCRYPTED_PREFIX}{}", base64::encode(sealed))
+    }
+}
+
+/// Decrypts secret of `email_addr`. Plaintext secrets stored before
+/// encryption was configured are returned as they are
+pub fn decrypt(
+    key: Option<&SecretKey>,
+    email_addr: &str,
+    value: &str,
+) -> Result<String, MailError> {
+    let encoded = match value.strip_prefix(ENCRYPTED_PREFIX) {
+        Some(encoded) => encoded,
+        None => return Ok(value.to_string()),
+    };
+
+    let key = key.ok_or_else(|| {
+        MailError::SecretKeyError(format!(
+            "session token of `{email_addr}` is encrypted, but no key is configured. \
+             Set {KEY_ENV}, `key` or `keyring` in [secrets] section of config file"
+        ))
+    })?;
+
+    let wrong_key = || {
+        MailError::SecretKeyError(format!(
+            "session token of `{email_addr}` was encrypted with another key"
+        ))
+    };
+
+    let sealed = base64::decode(encoded).map_err(|_| wrong_key())?;
+    if sealed.len() < NONCE_LENGTH {
+        return Err(wrong_key());
+    }
+    let (nonce, ciphertext) = sealed.split_at(NONCE_LENGTH);
+
+    let payload = Payload {
+        msg: ciphertext,
+        aad: email_addr.as_bytes(),
+    };
+    let secret = ChaCha20Poly1305::new(Key::from_slice(&key.0))
+        .decrypt(Nonce::from_slice(nonce), payload)
+        .map_err(|_| wrong_key())?;
+
+    String::from_utf8(secret).map_err(|_| wrong_key())
+}
+
+/// Key from `DISPOSABLE_MAIL_KEY`, config file or OS keyring, in this order.
+/// Returns None if none of them is set
+pub async fn load_key(config: &Config) -> Result<Option<SecretKey>, MailError> {
+    if let Ok(key) = std::env::var(KEY_ENV) {
+        return SecretKey::from_base64(&key).map(Some);
+    }
+
+    if let Some(key) = &config.secrets.key {
+        return SecretKey::from_base64(key).map(Some);
+    }
+
+    if !uses_keyring(config) {
+        return Ok(None);
+    }
+
+    let key = keyring_blocking(|entry| match entry.get_password() {
+        Ok(key) => Ok(Some(key)),
+        Err(keyring::Error::NoEntry) => Ok(None),
+        Err(e) => Err(e),
+    })
+    .await?;
+
+    key.map(|key| SecretKey::from_base64(&key)).transpose()
+}
+
+/// Whether the key comes from OS keyring, not from environment or config file
+pub fn uses_keyring(config: &Config) -> bool {
+    config.secrets.keyring && config.secrets.key.is_none() && std::env::var(KEY_ENV).is_err()
+}
+
+/// Replaces key in OS keyring
+pub async fn save_key_to_keyring(key: &SecretKey) -> Result<(), MailError> {
+    let key = key.to_base64();
+
+    keyring_blocking(move |entry| entry.set_password(&key)).await
+}
+
+/// Keyring clients block, so they run outside of the async runtime
+async fn keyring_blocking<T, F>(f: F) -> Result<T, MailError>
+where
+    T: Send + 'static,
+    F: FnOnce(&keyring::Entry) -> Result<T, keyring::Error> + Send + 'static,
+{
+    let keyring_error = |e: keyring::Error| MailError::KeyringError(e.to_string());
+
+    tokio::task::spawn_blocking(move || {
+        let entry = keyring::Entry::new(KEYRING_SERVICE, KEYRING_USER).map_err(keyring_error)?;
+
+        f(&entry).map_err(keyring_error)
+    })
+    .await
+    .map_err(|e| MailError::KeyringError(e.to_string()))?
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encrypt_and_decrypt() -> Result<(), MailError> {
+        let key = SecretKey::generate();
+
+        let encrypted = key.encrypt("abc@guerrillamail.com", "sid-token");
+
+        assert!(encrypted.starts_with(ENCRYPTED_PREFIX));
+        assert!(!encrypted.contains("sid-token"));
+        assert_eq!(
+            decrypt(Some(&key), "abc@guerrillamail.com", &encrypted)?,
+            "sid-token"
+        );
+        assert_eq!(
+            decrypt(None, "abc@guerrillamail.com", "plaintext-token")?,
+            "plaintext-token"
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_missing_or_wrong_key() {
+        let key = SecretKey::generate();
+        let encrypted = key.encrypt("abc@guerrillamail.com", "sid-token");
+
+        let missing = decrypt(None, "abc@guerrillamail.com", &encrypted).unwrap_err();
+        assert!(missing.to_string().contains("no key is configured"));
+
+        let wrong = decrypt(
+            Some(&SecretKey::generate()),
+            "abc@guerrillamail.com",
+            &encrypted,
+        )
+        .unwrap_err();
+        assert!(wrong.to_string().contains("another key"));
+
+        // Token copied to another address does not decrypt
+        assert!(decrypt(Some(&key), "xyz@guerrillamail.com", &encrypted).is_err());
+    }
+
+    #[test]
+    fn test_key_from_base64() {
+        let key = SecretKey::generate();
+
+        assert_eq!(SecretKey::from_base64(&key.to_base64()).unwrap(), key);
+        assert!(SecretKey::from_base64("c2hvcnQ=").is_err());
+        assert!(SecretKey::from_base64("not base64!").is_err());
+    }
+}
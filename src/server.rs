@@ -0,0 +1,733 @@
+use axum::extract::{Path, Query, State};
+use axum::http::{header, HeaderMap, StatusCode};
+use axum::response::sse::{self, KeepAlive, Sse};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use futures::{Stream, StreamExt};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast::error::RecvError;
+
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::net::{SocketAddr, TcpListener};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::api_keys::{self, Scope};
+use crate::cli;
+use crate::context::Context;
+use crate::doctor;
+use crate::mails::{ErrorCategory, MailError};
+use crate::push::{Hub, Published};
+use crate::storage::{self, StoredAddress};
+
+/// `/health` needs no API key, so its checks run at most once in this time
+const HEALTH_TTL: Duration = Duration::from_secs(60);
+
+/// Stream tokens are for opening an event stream right after they are issued
+const STREAM_TOKEN_TTL: Duration = Duration::from_secs(60);
+
+#[derive(Clone)]
+struct ServerState {
+    ctx: Arc<Context>,
+    /// Whether requests need an API key
+    auth: bool,
+    hub: Arc<Hub>,
+    /// Last health report and when it was made
+    health: Arc<tokio::sync::Mutex<Option<(Instant, doctor::Report)>>>,
+    /// Unused stream tokens with their profile and when they were issued
+    stream_tokens: Arc<Mutex<HashMap<String, (String, Instant)>>>,
+}
+
+/// Error response with JSON body `{"error": message}`
+struct ApiError {
+    status: StatusCode,
+    message: String,
+}
+
+impl ApiError {
+    fn new(status: StatusCode, message: &str) -> Self {
+        ApiError {
+            status,
+            message: message.to_string(),
+        }
+    }
+}
+
+impl From<MailError> for ApiError {
+    fn from(error: MailError) -> Self {
+        let status = match (&error, error.category()) {
+            (MailError::MessageNotFoundError(_), _) => StatusCode::NOT_FOUND,
+            (_, ErrorCategory::AddressExpired) => StatusCode::NOT_FOUND,
+            (_, ErrorCategory::BadInput) => StatusCode::BAD_REQUEST,
+            (_, ErrorCategory::ProviderDown) => StatusCode::BAD_GATEWAY,
+            (_, ErrorCategory::StorageUnreachable | ErrorCategory::Unavailable) => {
+                StatusCode::SERVICE_UNAVAILABLE
+            }
+            (_, ErrorCategory::NoMailBeforeTimeout) => StatusCode::GATEWAY_TIMEOUT,
+            (
+                _,
+                ErrorCategory::FileSystem | ErrorCategory::Internal | ErrorCategory::StorageCorrupt,
+            ) => StatusCode::INTERNAL_SERVER_ERROR,
+        };
+
+        ApiError {
+            status,
+            message: error.to_string(),
+        }
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let body = serde_json::json!({ "error": self.message });
+
+        (self.status, Json(body)).into_response()
+    }
+}
+
+#[derive(Deserialize)]
+struct AddressQuery {
+    tag: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct NewAddress {
+    /// Default provider if omitted, `auto` fails over
+    provider: Option<String>,
+    label: Option<String>,
+    #[serde(default)]
+    tags: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct StreamToken {
+    stream_token: String,
+    expires_in: u64,
+}
+
+#[derive(Deserialize)]
+struct EventsQuery {
+    /// Single-use token from `stream-tokens`, as browsers
+    /// cannot set headers of event streams
+    stream_token: Option<String>,
+    /// Replays events after this one, like `Last-Event-ID` header
+    last_event_id: Option<u64>,
+}
+
+#[derive(Deserialize)]
+struct MessagesQuery {
+    #[serde(default)]
+    offset: u32,
+}
+
+/// HTTP API of the tool. Every endpoint except `/health` needs
+/// an API key allowed to access the profile, unless `auth` is false.
+/// Event streams check addresses of their profile every `poll_interval`
+pub fn router(ctx: Arc<Context>, auth: bool, poll_interval: Duration) -> Router {
+    Router::new()
+        .route("/health", get(health))
+        .route("/profiles/:profile/events", get(profile_events))
+        .route(
+            "/profiles/:profile/stream-tokens",
+            post(create_stream_token),
+        )
+        .route(
+            "/profiles/:profile/addresses",
+            get(list_addresses).post(create_address),
+        )
+        .route(
+            "/profiles/:profile/addresses/:email/events",
+            get(address_events),
+        )
+        .route(
+            "/profiles/:profile/addresses/:email/messages",
+            get(list_messages),
+        )
+        .route(
+            "/profiles/:profile/addresses/:email/messages/:id",
+            get(fetch_message).delete(delete_message),
+        )
+        .with_state(ServerState {
+            ctx,
+            auth,
+            hub: Hub::new(poll_interval),
+            health: Arc::default(),
+            stream_tokens: Arc::default(),
+        })
+}
+
+/// Serves HTTP API until the process is stopped
+pub async fn serve(
+    addr: SocketAddr,
+    ctx: Arc<Context>,
+    auth: bool,
+    poll_interval: Duration,
+) -> Result<(), MailError> {
+    let listener = TcpListener::bind(addr).map_err(|e| MailError::ServerError(Box::new(e)))?;
+
+    serve_listener(listener, ctx, auth, poll_interval).await
+}
+
+pub async fn serve_listener(
+    listener: TcpListener,
+    ctx: Arc<Context>,
+    auth: bool,
+    poll_interval: Duration,
+) -> Result<(), MailError> {
+    listener
+        .set_nonblocking(true)
+        .map_err(|e| MailError::ServerError(Box::new(e)))?;
+
+    axum::Server::from_tcp(listener)
+        .map_err(|e| MailError::ServerError(Box::new(e)))?
+        .serve(router(ctx, auth, poll_interval).into_make_service())
+        .await
+        .map_err(|e| MailError::ServerError(Box::new(e)))
+}
+
+/// Context of `profile` if the request has a key allowed to do `scope` in it
+async fn authorize(
+    state: &ServerState,
+    headers: &HeaderMap,
+    profile: &str,
+    scope: Scope,
+) -> Result<Context, ApiError> {
+    authorize_token(state, bearer(headers), profile, scope).await
+}
+
+fn bearer(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+}
+
+async fn authorize_token(
+    state: &ServerState,
+    token: Option<&str>,
+    profile: &str,
+    scope: Scope,
+) -> Result<Context, ApiError> {
+    storage::check_profile_name(profile)?;
+
+    if state.auth {
+        let token =
+            token.ok_or_else(|| ApiError::new(StatusCode::UNAUTHORIZED, "API key is required"))?;
+
+        let key = api_keys::authenticate(state.ctx.keys.as_ref(), token.trim())
+            .await?
+            .ok_or_else(|| ApiError::new(StatusCode::UNAUTHORIZED, "invalid API key"))?;
+
+        if !key.allows(scope, profile) {
+            return Err(ApiError {
+                status: StatusCode::FORBIDDEN,
+                message: format!("API key cannot {scope} in profile `{profile}`"),
+            });
+        }
+    }
+
+    Ok(state.ctx.with_profile(profile))
+}
+
+/// Runs the checks of `doctor`, or reuses their report for `HEALTH_TTL`.
+/// Responds with 503 if any of them failed
+async fn health(State(state): State<ServerState>) -> (StatusCode, Json<doctor::Report>) {
+    // Concurrent requests wait for the same checks
+    let mut cached = state.health.lock().await;
+
+    let report = match &*cached {
+        Some((checked_at, report)) if checked_at.elapsed() < HEALTH_TTL => report.clone(),
+        _ => {
+            let report = doctor::check_context(&state.ctx).await;
+            *cached = Some((Instant::now(), report.clone()));
+
+            report
+        }
+    };
+
+    let status = if report.healthy {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+
+    (status, Json(report))
+}
+
+/// Issues a token opening one event stream of the profile, so the
+/// API key stays out of URLs that end up in logs and browser history
+async fn create_stream_token(
+    State(state): State<ServerState>,
+    Path(profile): Path<String>,
+    headers: HeaderMap,
+) -> Result<(StatusCode, Json<StreamToken>), ApiError> {
+    authorize(&state, &headers, &profile, Scope::Read).await?;
+
+    let stream_token = base64::encode_config(
+        rand::thread_rng().gen::<[u8; 32]>(),
+        base64::URL_SAFE_NO_PAD,
+    );
+
+    let mut stream_tokens = state
+        .stream_tokens
+        .lock()
+        .unwrap_or_else(|e| e.into_inner());
+    stream_tokens.retain(|_, (_, issued_at)| issued_at.elapsed() < STREAM_TOKEN_TTL);
+    stream_tokens.insert(stream_token.clone(), (profile, Instant::now()));
+
+    Ok((
+        StatusCode::CREATED,
+        Json(StreamToken {
+            stream_token,
+            expires_in: STREAM_TOKEN_TTL.as_secs(),
+        }),
+    ))
+}
+
+/// Removes the token, so it opens only one stream
+fn redeem_stream_token(
+    state: &ServerState,
+    stream_token: &str,
+    profile: &str,
+) -> Result<(), ApiError> {
+    let issued = state
+        .stream_tokens
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .remove(stream_token);
+
+    match issued {
+        Some((issued_for, issued_at))
+            if issued_for == profile && issued_at.elapsed() < STREAM_TOKEN_TTL =>
+        {
+            Ok(())
+        }
+        _ => Err(ApiError::new(
+            StatusCode::UNAUTHORIZED,
+            "invalid or expired stream token",
+        )),
+    }
+}
+
+async fn profile_events(
+    State(state): State<ServerState>,
+    Path(profile): Path<String>,
+    Query(query): Query<EventsQuery>,
+    headers: HeaderMap,
+) -> Result<Sse<impl Stream<Item = Result<sse::Event, Infallible>>>, ApiError> {
+    events(state, &headers, query, &profile, None).await
+}
+
+async fn address_events(
+    State(state): State<ServerState>,
+    Path((profile, email)): Path<(String, String)>,
+    Query(query): Query<EventsQuery>,
+    headers: HeaderMap,
+) -> Result<Sse<impl Stream<Item = Result<sse::Event, Infallible>>>, ApiError> {
+    events(state, &headers, query, &profile, Some(email)).await
+}
+
+/// Server-Sent Events of the profile, only those of `email` if it is set.
+/// Events after the last one the client got are replayed first
+async fn events(
+    state: ServerState,
+    headers: &HeaderMap,
+    query: EventsQuery,
+    profile: &str,
+    email: Option<String>,
+) -> Result<Sse<impl Stream<Item = Result<sse::Event, Infallible>>>, ApiError> {
+    let ctx = match (bearer(headers), query.stream_token.as_deref()) {
+        (None, Some(stream_token)) if state.auth => {
+            storage::check_profile_name(profile)?;
+            redeem_stream_token(&state, stream_token, profile)?;
+
+            state.ctx.with_profile(profile)
+        }
+        (token, _) => authorize_token(&state, token, profile, Scope::Read).await?,
+    };
+
+    let last_id = headers
+        .get("last-event-id")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse().ok())
+        .or(query.last_event_id);
+
+    let (replayed, receiver) = state.hub.subscribe(ctx, last_id);
+
+    let live = futures::stream::unfold(receiver, |mut receiver| async move {
+        match receiver.recv().await {
+            Ok(published) => Some((published, receiver)),
+            // Slow client missed events. Ending the stream makes it reconnect
+            // with its last id, so the missed events are replayed
+            Err(RecvError::Lagged(_) | RecvError::Closed) => None,
+        }
+    });
+
+    let events = futures::stream::iter(replayed)
+        .chain(live)
+        .filter(move |published| {
+            let wanted = email
+                .as_deref()
+                .is_none_or(|email| published.event.email_addr() == Some(email));
+
+            futures::future::ready(wanted)
+        })
+        .map(|published| Ok(sse_event(&published)));
+
+    Ok(Sse::new(events).keep_alive(KeepAlive::default()))
+}
+
+fn sse_event(published: &Published) -> sse::Event {
+    sse::Event::default()
+        .id(published.id.to_string())
+        .event(published.event.kind.name())
+        .json_data(published.event.as_ref())
+        .unwrap_or_else(|e| sse::Event::default().comment(format!("cannot serialize event: {e}")))
+}
+
+async fn list_addresses(
+    State(state): State<ServerState>,
+    Path(profile): Path<String>,
+    Query(query): Query<AddressQuery>,
+    headers: HeaderMap,
+) -> Result<Json<Vec<StoredAddress>>, ApiError> {
+    let ctx = authorize(&state, &headers, &profile, Scope::Read).await?;
+
+    Ok(Json(
+        ctx.storage.find_addresses(query.tag.as_deref()).await?,
+    ))
+}
+
+async fn create_address(
+    State(state): State<ServerState>,
+    Path(profile): Path<String>,
+    headers: HeaderMap,
+    Json(address): Json<NewAddress>,
+) -> Result<(StatusCode, Json<StoredAddress>), ApiError> {
+    let ctx = authorize(&state, &headers, &profile, Scope::Create).await?;
+
+    let provider = address
+        .provider
+        .unwrap_or_else(|| ctx.default_provider().to_string());
+
+    let account = cli::store_email_with_failover(
+        &ctx,
+        &ctx.failover_order(&provider),
+        address.label.as_deref(),
+        &address.tags,
+    )
+    .await?;
+
+    Ok((StatusCode::CREATED, Json(StoredAddress::from(account))))
+}
+
+async fn list_messages(
+    State(state): State<ServerState>,
+    Path((profile, email)): Path<(String, String)>,
+    Query(query): Query<MessagesQuery>,
+    headers: HeaderMap,
+) -> Result<Response, ApiError> {
+    let ctx = authorize(&state, &headers, &profile, Scope::Read).await?;
+
+    let messages = cli::get_emails_from_provider(&ctx, &email, query.offset).await?;
+
+    Ok(Json(messages).into_response())
+}
+
+async fn fetch_message(
+    State(state): State<ServerState>,
+    Path((profile, email, id)): Path<(String, String, String)>,
+    headers: HeaderMap,
+) -> Result<Response, ApiError> {
+    let ctx = authorize(&state, &headers, &profile, Scope::Read).await?;
+
+    let fetched = cli::fetch_email_from_provider(&ctx, &email, &id)
+        .await?
+        .ok_or(MailError::MessageNotFoundError(id))?;
+
+    Ok(Json(fetched).into_response())
+}
+
+async fn delete_message(
+    State(state): State<ServerState>,
+    Path((profile, email, id)): Path<(String, String, String)>,
+    headers: HeaderMap,
+) -> Result<StatusCode, ApiError> {
+    let ctx = authorize(&state, &headers, &profile, Scope::Delete).await?;
+
+    cli::delete_email_from_provider(&ctx, &email, &id).await?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::api_keys::ApiKey;
+    use crate::mails::MemoryProvider;
+    use crate::mock;
+    use crate::polling::Pollers;
+
+    fn start(ctx: Arc<Context>, auth: bool) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("free local port");
+        let addr = listener.local_addr().expect("local address");
+
+        tokio::spawn(serve_listener(
+            listener,
+            ctx,
+            auth,
+            Duration::from_millis(50),
+        ));
+
+        format!("http://{addr}")
+    }
+
+    async fn key(ctx: &Context, scopes: Vec<Scope>, profile: &str) -> Result<String, MailError> {
+        let (key, token) = ApiKey::generate("test", scopes, vec![profile.to_string()]);
+        ctx.keys.save_api_key(&key).await?;
+
+        Ok(token)
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_health_endpoint() -> Result<(), MailError> {
+        mock::init_test_provider();
+
+        let ctx = Arc::new(Context::in_memory(MemoryProvider::default()));
+        let base_url = start(ctx.clone(), true);
+
+        let response = reqwest::get(format!("{base_url}/health")).await?;
+        assert_eq!(response.status(), reqwest::StatusCode::OK);
+
+        let report: serde_json::Value = response.json().await?;
+        assert_eq!(report["healthy"], true);
+        assert_eq!(report["checks"][0]["name"], "storage");
+        assert!(report["checks"][0]["latency_ms"].is_u64());
+
+        // Second request gets the same report without checking providers again
+        let checked_at = ctx.storage.find_health("memory").await?.unwrap().checked_at;
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        reqwest::get(format!("{base_url}/health")).await?;
+        assert_eq!(
+            ctx.storage.find_health("memory").await?.unwrap().checked_at,
+            checked_at
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_api_keys_limit_scopes_and_profiles() -> Result<(), MailError> {
+        let memory = MemoryProvider::default();
+        let ctx = Arc::new(Context::in_memory(memory.clone()));
+        let base_url = start(ctx.clone(), true);
+        let client = reqwest::Client::new();
+        let addresses = format!("{base_url}/profiles/qa/addresses");
+
+        let reader = key(&ctx, vec![Scope::Read], "qa").await?;
+        let writer = key(&ctx, vec![Scope::Create, Scope::Read, Scope::Delete], "qa").await?;
+        let other = key(&ctx, vec![Scope::Read], "dev").await?;
+
+        let status = |response: reqwest::Response| response.status().as_u16();
+
+        assert_eq!(status(client.get(&addresses).send().await?), 401);
+        assert_eq!(
+            status(client.get(&addresses).bearer_auth("dmk_x_y").send().await?),
+            401
+        );
+        assert_eq!(
+            status(client.get(&addresses).bearer_auth(&other).send().await?),
+            403
+        );
+
+        let body = serde_json::json!({ "label": "admin", "tags": ["signup"] });
+        assert_eq!(
+            status(
+                client
+                    .post(&addresses)
+                    .bearer_auth(&reader)
+                    .json(&body)
+                    .send()
+                    .await?
+            ),
+            403
+        );
+
+        let response = client
+            .post(&addresses)
+            .bearer_auth(&writer)
+            .json(&body)
+            .send()
+            .await?;
+        assert_eq!(response.status().as_u16(), 201);
+        let created: serde_json::Value = response.json().await?;
+        let email = created["email_addr"]
+            .as_str()
+            .unwrap_or_default()
+            .to_string();
+        assert_eq!(created["label"], "admin");
+
+        // Address is stored in its profile only
+        assert!(ctx.storage.list_addresses().await?.is_empty());
+        let listed: Vec<serde_json::Value> = client
+            .get(format!("{addresses}?tag=signup"))
+            .bearer_auth(&reader)
+            .send()
+            .await?
+            .json()
+            .await?;
+        assert_eq!(listed.len(), 1);
+
+        let id = memory.inject(&email, "App <app@example.com>", "Hi", "<p>Hi</p>", vec![])?;
+        let messages = format!("{addresses}/{email}/messages");
+
+        let listed: Vec<serde_json::Value> = client
+            .get(&messages)
+            .bearer_auth(&reader)
+            .send()
+            .await?
+            .json()
+            .await?;
+        assert_eq!(listed[0]["mail_subject"], "Hi");
+
+        let message = format!("{messages}/{id}");
+        let fetched: serde_json::Value = client
+            .get(&message)
+            .bearer_auth(&reader)
+            .send()
+            .await?
+            .json()
+            .await?;
+        assert_eq!(fetched["message"]["mail_from"], "App <app@example.com>");
+
+        assert_eq!(
+            status(client.delete(&message).bearer_auth(&reader).send().await?),
+            403
+        );
+        assert_eq!(
+            status(client.delete(&message).bearer_auth(&writer).send().await?),
+            204
+        );
+        assert_eq!(
+            status(client.get(&message).bearer_auth(&reader).send().await?),
+            404
+        );
+
+        Ok(())
+    }
+
+    async fn issue_stream_token(stream_tokens: &str, key: &str) -> Result<String, MailError> {
+        let issued: serde_json::Value = reqwest::Client::new()
+            .post(stream_tokens)
+            .bearer_auth(key)
+            .send()
+            .await?
+            .json()
+            .await?;
+
+        Ok(issued["stream_token"]
+            .as_str()
+            .unwrap_or_default()
+            .to_string())
+    }
+
+    /// Reads event stream until it has `count` events
+    async fn read_events(mut response: reqwest::Response, count: usize) -> String {
+        let mut body = String::new();
+
+        while body.matches("\n\n").count() < count {
+            let chunk = tokio::time::timeout(Duration::from_secs(5), response.chunk())
+                .await
+                .expect("event before timeout")
+                .expect("readable stream")
+                .expect("open stream");
+            body.push_str(&String::from_utf8_lossy(&chunk));
+        }
+
+        body
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_events_are_pushed_and_replayed() -> Result<(), MailError> {
+        let memory = MemoryProvider::default();
+        let mut ctx = Context::in_memory(memory.clone());
+        ctx.pollers = Arc::new(Pollers::fast());
+        let ctx = Arc::new(ctx);
+        let base_url = start(ctx.clone(), true);
+        let reader = key(&ctx, vec![Scope::Read], "default").await?;
+
+        let account = ctx.provider("memory")?.create_address().await?;
+        ctx.storage.save_account(&account).await?;
+        let other = ctx.provider("memory")?.create_address().await?;
+        ctx.storage.save_account(&other).await?;
+
+        let events = format!(
+            "{base_url}/profiles/default/addresses/{}/events",
+            account.email_addr
+        );
+        let response = reqwest::get(&events).await?;
+        assert_eq!(response.status().as_u16(), 401);
+
+        // API key is not taken from the URL
+        let response = reqwest::get(format!("{events}?access_token={reader}")).await?;
+        assert_eq!(response.status().as_u16(), 401);
+
+        let stream_tokens = format!("{base_url}/profiles/default/stream-tokens");
+        let response = reqwest::Client::new().post(&stream_tokens).send().await?;
+        assert_eq!(response.status().as_u16(), 401);
+
+        // Token of another profile does not open this one
+        let stream_token = issue_stream_token(&stream_tokens, &reader).await?;
+        let wrong = format!("{base_url}/profiles/other/events?stream_token={stream_token}");
+        assert_eq!(reqwest::get(wrong).await?.status().as_u16(), 401);
+
+        let stream_token = issue_stream_token(&stream_tokens, &reader).await?;
+        let response = reqwest::get(format!("{events}?stream_token={stream_token}")).await?;
+        assert_eq!(response.status().as_u16(), 200);
+
+        let reused = reqwest::get(format!("{events}?stream_token={stream_token}")).await?;
+        assert_eq!(reused.status().as_u16(), 401);
+
+        // Let the poller skip emails the addresses already had
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        memory.inject(&other.email_addr, "a@example.com", "Other", "", vec![])?;
+        memory.inject(&account.email_addr, "a@example.com", "Code", "", vec![])?;
+
+        let body = read_events(response, 1).await;
+        assert!(body.contains("event:message_received"), "{body}");
+        assert!(body.contains("\"mail_subject\":\"Code\""), "{body}");
+        assert!(!body.contains("Other"), "{body}");
+
+        let replayed = reqwest::Client::new()
+            .get(format!("{base_url}/profiles/default/events"))
+            .bearer_auth(&reader)
+            .header("Last-Event-ID", "0")
+            .send()
+            .await?;
+        let body = read_events(replayed, 2).await;
+        assert!(body.contains("id:1\n"), "{body}");
+        assert!(body.contains("Other") && body.contains("Code"), "{body}");
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_server_without_auth() -> Result<(), MailError> {
+        let base_url = start(
+            Arc::new(Context::in_memory(MemoryProvider::default())),
+            false,
+        );
+
+        let response = reqwest::get(format!("{base_url}/profiles/default/addresses")).await?;
+        assert_eq!(response.status().as_u16(), 200);
+
+        let response = reqwest::get(format!("{base_url}/profiles/a.b/addresses")).await?;
+        assert_eq!(response.status().as_u16(), 400);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,610 @@
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::hash::{Hash, Hasher};
+use std::io;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::mails::{check_address, LocalProvider, MailError};
+
+/// Larger emails are rejected
+const MAX_MESSAGE_SIZE: usize = 10 * 1024 * 1024;
+
+/// Longest command line. RFC 5321 allows 512 octets, extensions add some more
+const MAX_COMMAND_LENGTH: usize = 4 * 1024;
+
+/// Longest line of an email. Far above the 1000 octets of RFC 5321,
+/// as some senders do not wrap their lines
+const MAX_DATA_LINE_LENGTH: usize = 64 * 1024;
+
+/// How long recipients an email was delivered to are remembered after a
+/// temporary failure, so the retry of the client skips them
+const DELIVERED_TTL: Duration = Duration::from_secs(24 * 3600);
+
+/// Recipients an email was already delivered to, by digest of the email
+type Delivered = Arc<Mutex<HashMap<(u64, String), Instant>>>;
+
+/// Receives email for local provider until the process is stopped
+pub async fn serve(addr: SocketAddr, provider: LocalProvider) -> Result<(), MailError> {
+    let listener = TcpListener::bind(addr)
+        .await
+        .map_err(|e| MailError::ServerError(Box::new(e)))?;
+
+    serve_listener(listener, provider).await
+}
+
+pub async fn serve_listener(
+    listener: TcpListener,
+    provider: LocalProvider,
+) -> Result<(), MailError> {
+    let delivered = Delivered::default();
+
+    loop {
+        let (stream, peer) = listener
+            .accept()
+            .await
+            .map_err(|e| MailError::ServerError(Box::new(e)))?;
+
+        let provider = provider.clone();
+        let delivered = delivered.clone();
+
+        tokio::spawn(async move {
+            if let Err(e) = handle_session(stream, &provider, &delivered).await {
+                eprintln!("SMTP session with {peer} failed: {e}");
+            }
+        });
+    }
+}
+
+/// Minimal SMTP server side of RFC 5321. There is no authentication
+/// or TLS, it is meant for staging networks only
+async fn handle_session(
+    stream: TcpStream,
+    provider: &LocalProvider,
+    delivered: &Delivered,
+) -> io::Result<()> {
+    let (reader, mut writer) = stream.into_split();
+    let mut reader = BufReader::new(reader);
+
+    let domain = provider.domain().to_string();
+
+    reply(
+        &mut writer,
+        &format!("220 {domain} ESMTP disposable-mail-tool"),
+    )
+    .await?;
+
+    let mut sender: Option<String> = None;
+    let mut recipients: Vec<String> = Vec::new();
+    let mut line = Vec::new();
+
+    loop {
+        if read_line(&mut reader, &mut line, MAX_COMMAND_LENGTH).await? == 0 {
+            return Ok(());
+        }
+
+        // Rest of the line would be read as another command
+        if line.len() > MAX_COMMAND_LENGTH {
+            reply(&mut writer, "500 Line too long").await?;
+            return Ok(());
+        }
+
+        let command = String::from_utf8_lossy(&line);
+        let command = command.trim_end();
+        let (verb, argument) = command.split_once(' ').unwrap_or((command, ""));
+
+        match verb.to_uppercase().as_str() {
+            "HELO" => reply(&mut writer, &format!("250 {domain}")).await?,
+            "EHLO" => {
+                reply(
+                    &mut writer,
+                    &format!("250-{domain}\r\n250-SIZE {MAX_MESSAGE_SIZE}\r\n250 8BITMIME"),
+                )
+                .await?
+            }
+            "MAIL" => match path(argument, "FROM:") {
+                Some(path) => {
+                    sender = Some(path);
+                    recipients.clear();
+                    reply(&mut writer, "250 OK").await?;
+                }
+                None => reply(&mut writer, "501 Syntax: MAIL FROM:<address>").await?,
+            },
+            "RCPT" => match path(argument, "TO:") {
+                _ if sender.is_none() => reply(&mut writer, "503 Need MAIL command").await?,
+                Some(path) if provider.accepts(&path) => {
+                    recipients.push(path);
+                    reply(&mut writer, "250 OK").await?;
+                }
+                Some(_) => reply(&mut writer, &format!("550 Mailbox is not on {domain}")).await?,
+                None => reply(&mut writer, "501 Syntax: RCPT TO:<address>").await?,
+            },
+            "DATA" if recipients.is_empty() => reply(&mut writer, "503 Need RCPT command").await?,
+            "DATA" => {
+                reply(&mut writer, "354 End data with <CR><LF>.<CR><LF>").await?;
+
+                let response = match read_data(&mut reader).await? {
+                    Data::Email(raw) => {
+                        let deliver_to = |recipient: String| {
+                            let raw = &raw;
+                            async move { provider.deliver(&recipient, raw).await }
+                        };
+
+                        deliver(&recipients, &raw, delivered, deliver_to).await
+                    }
+                    Data::TooLarge => "552 Message exceeds fixed maximum message size".to_string(),
+                    Data::LineTooLong => {
+                        reply(&mut writer, "552 Line too long").await?;
+                        return Ok(());
+                    }
+                };
+
+                sender = None;
+                recipients.clear();
+                reply(&mut writer, &response).await?;
+            }
+            "RSET" => {
+                sender = None;
+                recipients.clear();
+                reply(&mut writer, "250 OK").await?;
+            }
+            "NOOP" => reply(&mut writer, "250 OK").await?,
+            "VRFY" => reply(&mut writer, "252 Cannot verify user").await?,
+            "QUIT" => {
+                reply(&mut writer, &format!("221 {domain} closing connection")).await?;
+                return Ok(());
+            }
+            _ => reply(&mut writer, "502 Command not implemented").await?,
+        }
+    }
+}
+
+async fn reply(writer: &mut (impl AsyncWriteExt + Unpin), response: &str) -> io::Result<()> {
+    writer.write_all(format!("{response}\r\n").as_bytes()).await
+}
+
+/// Address from `FROM:<address>` or `TO:<address>` argument.
+/// Parameters after the address are ignored
+fn path(argument: &str, prefix: &str) -> Option<String> {
+    let argument = argument.trim();
+
+    if !argument
+        .get(..prefix.len())
+        .is_some_and(|start| start.eq_ignore_ascii_case(prefix))
+    {
+        return None;
+    }
+
+    let rest = argument[prefix.len()..].trim_start();
+
+    let path = match rest.strip_prefix('<') {
+        Some(rest) => rest.split_once('>')?.0,
+        None => rest.split_whitespace().next().unwrap_or_default(),
+    };
+
+    Some(path.to_string())
+}
+
+/// Reads a line of at most `max` bytes into `line`. A longer line is cut after
+/// `max + 1` bytes, so the caller sees it is too long. Returns bytes read
+async fn read_line(
+    reader: &mut (impl AsyncBufReadExt + Unpin),
+    line: &mut Vec<u8>,
+    max: usize,
+) -> io::Result<usize> {
+    line.clear();
+
+    (&mut *reader)
+        .take(max as u64 + 1)
+        .read_until(b'\n', line)
+        .await
+}
+
+enum Data {
+    Email(Vec<u8>),
+    TooLarge,
+    /// Rest of the email cannot be told apart from commands, so the session ends
+    LineTooLong,
+}
+
+/// Reads email until the line with a single dot and removes dot stuffing
+async fn read_data(reader: &mut (impl AsyncBufReadExt + Unpin)) -> io::Result<Data> {
+    let mut raw = Vec::new();
+    let mut line = Vec::new();
+    let mut too_large = false;
+
+    loop {
+        if read_line(reader, &mut line, MAX_DATA_LINE_LENGTH).await? == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "connection closed during DATA",
+            ));
+        }
+
+        if line.len() > MAX_DATA_LINE_LENGTH {
+            return Ok(Data::LineTooLong);
+        }
+
+        if line == b".\r\n" || line == b".\n" {
+            return Ok(if too_large {
+                Data::TooLarge
+            } else {
+                Data::Email(raw)
+            });
+        }
+
+        let line = line.strip_prefix(b".").unwrap_or(&line);
+
+        if raw.len() + line.len() > MAX_MESSAGE_SIZE {
+            too_large = true;
+        }
+
+        if !too_large {
+            raw.extend_from_slice(line);
+        }
+    }
+}
+
+/// Delivers `raw` to every recipient with `deliver_to`. If some recipients fail
+/// temporarily, the others are remembered, so the retry does not deliver twice
+async fn deliver<F, Fut>(
+    recipients: &[String],
+    raw: &[u8],
+    delivered: &Delivered,
+    deliver_to: F,
+) -> String
+where
+    F: Fn(String) -> Fut,
+    Fut: Future<Output = Result<String, MailError>>,
+{
+    let mut hasher = std::collections::hash_map::DefaultHasher::new();
+    raw.hash(&mut hasher);
+    let digest = hasher.finish();
+
+    let mut done = Vec::new();
+    let mut failure = None;
+
+    for recipient in recipients {
+        let key = (digest, recipient.clone());
+        if delivered
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .remove(&key)
+            .is_some_and(|at| at.elapsed() < DELIVERED_TTL)
+        {
+            done.push(key);
+            continue;
+        }
+
+        match deliver_to(recipient.clone()).await {
+            Ok(_) => done.push(key),
+            Err(e @ MailError::UnexpectedResponseError(_)) => {
+                return format!("554 Message rejected: {e}")
+            }
+            Err(e) => failure = Some(e),
+        }
+    }
+
+    match failure {
+        Some(e) => {
+            let mut delivered = delivered.lock().unwrap_or_else(|e| e.into_inner());
+            delivered.retain(|_, at| at.elapsed() < DELIVERED_TTL);
+            delivered.extend(done.into_iter().map(|key| (key, Instant::now())));
+
+            format!("451 Cannot store message: {e}")
+        }
+        None => "250 OK".to_string(),
+    }
+}
+
+/// Sends email to `to` through SMTP server `relay` like `smtp.example.com:25`.
+/// Like the server, it uses neither authentication nor TLS
+pub async fn send(relay: &str, from: &str, to: &str, raw: &str) -> Result<(), MailError> {
+    // Addresses go into SMTP commands, which must stay one line each
+    check_address(from)?;
+    check_address(to)?;
+
+    let failed = |e: io::Error| MailError::SendError(format!("{relay}: {e}"));
+
+    let (reader, mut writer) = TcpStream::connect(relay)
+        .await
+        .map_err(failed)?
+        .into_split();
+    let mut reader = BufReader::new(reader);
+
+    let domain = from
+        .rsplit_once('@')
+        .map_or("localhost", |(_, domain)| domain);
+
+    // Lines starting with a dot are stuffed, a single dot ends the email
+    let mut data = String::with_capacity(raw.len() + 3);
+    for line in raw.lines() {
+        if line.starts_with('.') {
+            data.push('.');
+        }
+        data.push_str(line);
+        data.push_str("\r\n");
+    }
+    data.push('.');
+
+    let commands = [
+        (None, "220"),
+        (Some(format!("EHLO {domain}")), "250"),
+        (Some(format!("MAIL FROM:<{from}>")), "250"),
+        (Some(format!("RCPT TO:<{to}>")), "250"),
+        (Some("DATA".to_string()), "354"),
+        (Some(data), "250"),
+    ];
+
+    for (command, expected) in commands {
+        if let Some(command) = &command {
+            reply(&mut writer, command).await.map_err(failed)?;
+        }
+
+        let response = response(&mut reader).await.map_err(failed)?;
+        if !response.starts_with(expected) {
+            return Err(MailError::SendError(format!(
+                "{relay} answered `{response}`"
+            )));
+        }
+    }
+
+    // Email is already accepted, a failed goodbye does not matter
+    reply(&mut writer, "QUIT").await.ok();
+
+    Ok(())
+}
+
+/// Last line of possibly multiline response
+async fn response(reader: &mut (impl AsyncBufReadExt + Unpin)) -> io::Result<String> {
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line).await? == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "connection closed",
+            ));
+        }
+
+        if line.as_bytes().get(3) != Some(&b'-') {
+            return Ok(line.trim_end().to_string());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::mails::Provider;
+    use crate::storage::{MemoryStorage, Storage};
+
+    struct Client {
+        reader: BufReader<tokio::net::tcp::OwnedReadHalf>,
+        writer: tokio::net::tcp::OwnedWriteHalf,
+    }
+
+    impl Client {
+        async fn connect(addr: SocketAddr) -> Client {
+            let (reader, writer) = TcpStream::connect(addr).await.unwrap().into_split();
+            let mut client = Client {
+                reader: BufReader::new(reader),
+                writer,
+            };
+
+            assert!(client.response().await.starts_with("220"));
+
+            client
+        }
+
+        /// Last line of possibly multiline response
+        async fn response(&mut self) -> String {
+            loop {
+                let mut line = String::new();
+                self.reader.read_line(&mut line).await.unwrap();
+
+                if line.as_bytes().get(3) != Some(&b'-') {
+                    return line.trim_end().to_string();
+                }
+            }
+        }
+
+        async fn send(&mut self, line: &str) -> String {
+            self.writer
+                .write_all(format!("{line}\r\n").as_bytes())
+                .await
+                .unwrap();
+
+            self.response().await
+        }
+    }
+
+    #[test]
+    fn test_path() {
+        assert_eq!(
+            path("FROM:<app@staging.test> SIZE=100", "FROM:"),
+            Some("app@staging.test".to_string())
+        );
+        assert_eq!(
+            path("to: qa@disposable.local", "TO:"),
+            Some("qa@disposable.local".to_string())
+        );
+        assert_eq!(path("FROM:<>", "FROM:"), Some(String::new()));
+        assert_eq!(path("qa@disposable.local", "TO:"), None);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_receive_email() -> Result<(), MailError> {
+        let storage = Arc::new(MemoryStorage::default());
+        let provider = LocalProvider::new(storage.clone(), "disposable.local");
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve_listener(listener, provider.clone()));
+
+        let mut client = Client::connect(addr).await;
+
+        assert_eq!(client.send("EHLO staging.test").await, "250 8BITMIME");
+        assert!(client.send("DATA").await.starts_with("503"));
+        assert_eq!(client.send("MAIL FROM:<app@staging.test>").await, "250 OK");
+        assert!(client
+            .send("RCPT TO:<qa@example.com>")
+            .await
+            .starts_with("550"));
+        assert_eq!(client.send("RCPT TO:<qa@disposable.local>").await, "250 OK");
+        assert!(client.send("DATA").await.starts_with("354"));
+
+        client
+            .writer
+            .write_all(
+                b"From: app@staging.test\r\nSubject: Verify\r\n\r\nYour code is 482913\r\n..done\r\n",
+            )
+            .await
+            .unwrap();
+        assert_eq!(client.send(".").await, "250 OK");
+        assert!(client.send("QUIT").await.starts_with("221"));
+
+        let account = storage.find_account("qa@disposable.local").await?;
+        let fetched = provider.fetch_message(&account, "1").await?.unwrap();
+
+        assert_eq!(fetched.message.mail_subject, "Verify");
+        assert_eq!(
+            fetched.message.mail_body,
+            Some("Your code is 482913\r\n.done\r\n".to_string())
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_retry_skips_delivered_recipients() {
+        let recipients = vec![
+            "a@disposable.local".to_string(),
+            "b@disposable.local".to_string(),
+        ];
+        let delivered = Delivered::default();
+        let attempts = Mutex::new(Vec::new());
+
+        // Second recipient fails once, like storage that is briefly unreachable
+        let deliver_to = |recipient: String| {
+            let mut attempts = attempts.lock().unwrap();
+            let failed = recipient.starts_with('b') && !attempts.contains(&recipient);
+            attempts.push(recipient);
+
+            async move {
+                if failed {
+                    Err(MailError::TimeoutError)
+                } else {
+                    Ok("1".to_string())
+                }
+            }
+        };
+
+        let response = deliver(&recipients, b"email", &delivered, deliver_to).await;
+        assert!(response.starts_with("451"), "{response}");
+
+        let response = deliver(&recipients, b"email", &delivered, deliver_to).await;
+        assert_eq!(response, "250 OK");
+        assert_eq!(
+            *attempts.lock().unwrap(),
+            [
+                "a@disposable.local",
+                "b@disposable.local",
+                "b@disposable.local"
+            ]
+        );
+        assert!(delivered.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_long_lines_are_rejected() {
+        let provider = LocalProvider::new(Arc::new(MemoryStorage::default()), "disposable.local");
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve_listener(listener, provider));
+
+        let mut client = Client::connect(addr).await;
+        let long_command = "x".repeat(MAX_COMMAND_LENGTH + 1);
+        assert_eq!(client.send(&long_command).await, "500 Line too long");
+
+        let mut client = Client::connect(addr).await;
+        client.send("MAIL FROM:<app@staging.test>").await;
+        client.send("RCPT TO:<qa@disposable.local>").await;
+        client.send("DATA").await;
+        let long_line = "x".repeat(MAX_DATA_LINE_LENGTH + 1);
+        assert_eq!(client.send(&long_line).await, "552 Line too long");
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_local_provider_sends_through_relay() -> Result<(), MailError> {
+        let remote_storage = Arc::new(MemoryStorage::default());
+        let remote = LocalProvider::new(remote_storage.clone(), "disposable.local");
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let relay = listener.local_addr().unwrap().to_string();
+        tokio::spawn(serve_listener(listener, remote.clone()));
+
+        let storage = Arc::new(MemoryStorage::default());
+        let provider = LocalProvider::new(storage.clone(), "staging.test");
+        let account = provider.create_address().await?;
+
+        let message = crate::mails::OutgoingMessage {
+            to: "dev@disposable.local".to_string(),
+            subject: "Fwd: Verify".to_string(),
+            body: "Your code is 482913\n.done".to_string(),
+        };
+
+        assert!(matches!(
+            provider.send_message(&account, &message).await,
+            Err(MailError::UnsupportedError(_))
+        ));
+
+        let provider = provider.with_relay(Some(relay.clone()));
+        provider.send_message(&account, &message).await?;
+
+        let received = remote_storage.find_account("dev@disposable.local").await?;
+        let fetched = remote.fetch_message(&received, "1").await?.unwrap();
+        assert_eq!(fetched.message.mail_from, account.email_addr);
+        assert_eq!(fetched.message.mail_subject, "Fwd: Verify");
+        assert_eq!(
+            fetched.message.mail_body,
+            Some("Your code is 482913\r\n.done".to_string())
+        );
+
+        // Relay does not take email for other domains
+        let rejected = send(&relay, &account.email_addr, "dev@example.com", "\r\n").await;
+        assert!(matches!(rejected, Err(MailError::SendError(e)) if e.contains("550")));
+
+        // Recipient cannot add SMTP commands
+        let injected = "dev@disposable.local>\r\nRCPT TO:<spy@evil.test";
+        let rejected = send(&relay, &account.email_addr, injected, "\r\n").await;
+        assert!(matches!(rejected, Err(MailError::AddressError(_))));
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_local_provider_delivers_to_own_domain() -> Result<(), MailError> {
+        let storage = Arc::new(MemoryStorage::default());
+        let provider = LocalProvider::new(storage.clone(), "disposable.local");
+        let account = provider.create_address().await?;
+
+        let message = crate::mails::OutgoingMessage {
+            to: "qa@disposable.local".to_string(),
+            subject: "Re: Verify".to_string(),
+            body: "YES".to_string(),
+        };
+        provider.send_message(&account, &message).await?;
+
+        let received = storage.find_account("qa@disposable.local").await?;
+        let messages = provider.check_messages(&received, 0).await?;
+        assert_eq!(messages[0].mail_subject, "Re: Verify");
+
+        Ok(())
+    }
+}
@@ -0,0 +1,625 @@
+use async_trait::async_trait;
+use chrono::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::api_keys::ApiKey;
+use crate::mails::{Account, MailError, Message};
+use crate::search::SearchQuery;
+use crate::secrets::SecretKey;
+
+/// Email addresses are forgotten after this time, like they expire at providers
+pub const ADDRESS_LIFETIME: Duration = Duration::from_secs(3600);
+
+/// Profile used when neither `--profile` nor config file chooses one
+pub const DEFAULT_PROFILE: &str = "default";
+
+/// Result of the latest health check of a provider
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ProviderHealth {
+    pub provider: String,
+    pub healthy: bool,
+    #[serde(with = "bson::serde_helpers::chrono_datetime_as_bson_datetime")]
+    pub checked_at: DateTime<Utc>,
+    pub error: Option<String>,
+}
+
+/// Unexpired address with its label and tags, without session token
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct StoredAddress {
+    pub email_addr: String,
+    pub provider: String,
+    pub label: Option<String>,
+    pub tags: Vec<String>,
+    pub created_at: DateTime<Utc>,
+}
+
+impl From<Account> for StoredAddress {
+    fn from(account: Account) -> Self {
+        StoredAddress {
+            email_addr: account.email_addr,
+            provider: account.provider,
+            label: account.label,
+            tags: account.tags,
+            created_at: account.created_at,
+        }
+    }
+}
+
+/// Where created email addresses and received emails are kept
+#[async_trait]
+pub trait Storage: Send + Sync {
+    async fn save_account(&self, account: &Account) -> Result<(), MailError>;
+
+    /// Fails with `EmailCheckError` if address was never created or expired
+    async fn find_account(&self, email_addr: &str) -> Result<Account, MailError>;
+
+    /// Addresses that did not expire yet
+    async fn list_addresses(&self) -> Result<Vec<String>, MailError>;
+
+    /// Unexpired account with `label`
+    async fn find_account_by_label(&self, label: &str) -> Result<Option<Account>, MailError>;
+
+    /// Unexpired addresses, only those tagged with `tag` if it is set
+    async fn find_addresses(&self, tag: Option<&str>) -> Result<Vec<StoredAddress>, MailError>;
+
+    /// Saves messages so they can be searched after email address expires.
+    /// Already cached messages are updated, but a cached body is kept
+    /// if the new message has none
+    async fn cache_messages(&self, messages: &[Message]) -> Result<(), MailError>;
+
+    /// Cached messages matching the query, newest first
+    async fn find_messages(&self, query: &SearchQuery) -> Result<Vec<Message>, MailError>;
+
+    async fn find_message(
+        &self,
+        email_addr: &str,
+        mail_id: &str,
+    ) -> Result<Option<Message>, MailError>;
+
+    /// Keeps email in RFC 5322 format, for providers without own inbox
+    async fn save_raw_message(
+        &self,
+        email_addr: &str,
+        mail_id: &str,
+        raw: &[u8],
+    ) -> Result<(), MailError>;
+
+    async fn find_raw_message(
+        &self,
+        email_addr: &str,
+        mail_id: &str,
+    ) -> Result<Option<Vec<u8>>, MailError>;
+
+    /// Removes cached message together with its raw email
+    async fn delete_message(&self, email_addr: &str, mail_id: &str) -> Result<(), MailError>;
+
+    /// Id for the next email delivered to `email_addr`, counting from 1.
+    /// Ids are never handed out twice, even after emails are deleted
+    async fn next_message_id(&self, email_addr: &str) -> Result<u32, MailError>;
+
+    /// Replaces the previous health check of the provider
+    async fn save_health(&self, health: &ProviderHealth) -> Result<(), MailError>;
+
+    async fn find_health(&self, provider: &str) -> Result<Option<ProviderHealth>, MailError>;
+
+    /// Fails if storage does not answer
+    async fn ping(&self) -> Result<(), MailError>;
+
+    /// Fails if stored addresses would never expire
+    async fn check_expiry(&self) -> Result<(), MailError>;
+}
+
+/// Separate storages of profiles, so addresses and emails
+/// of one profile are not listed or searched in another
+#[async_trait]
+pub trait Profiles: Send + Sync {
+    /// Storage of `profile`, created when it is first used
+    fn storage(&self, profile: &str) -> Arc<dyn Storage>;
+
+    /// Profiles that have any data
+    async fn list(&self) -> Result<Vec<String>, MailError>;
+
+    /// Deletes every address, email and health check of `profile`
+    async fn delete(&self, profile: &str) -> Result<(), MailError>;
+
+    /// Encrypts session tokens of every profile with `new_key`, decrypting them
+    /// with the current key first. Nothing is written unless every token decrypts.
+    /// Returns number of re-encrypted tokens
+    async fn rekey(&self, new_key: &SecretKey) -> Result<usize, MailError>;
+}
+
+/// API keys of HTTP server, shared by every profile
+#[async_trait]
+pub trait KeyStore: Send + Sync {
+    async fn save_api_key(&self, key: &ApiKey) -> Result<(), MailError>;
+
+    async fn find_api_key(&self, id: &str) -> Result<Option<ApiKey>, MailError>;
+
+    /// Keys sorted by creation
+    async fn list_api_keys(&self) -> Result<Vec<ApiKey>, MailError>;
+
+    /// Returns false if there is no key with `id`
+    async fn revoke_api_key(&self, id: &str) -> Result<bool, MailError>;
+}
+
+/// Fails if `profile` cannot name a profile. Profile names become
+/// part of database names, so only letters, digits, `-` and `_` are allowed
+pub fn check_profile_name(profile: &str) -> Result<(), MailError> {
+    let valid = !profile.is_empty()
+        && profile.len() <= 32
+        && profile
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+
+    if valid {
+        Ok(())
+    } else {
+        Err(MailError::ConfigError(format!(
+            "invalid profile `{profile}`. Use at most 32 letters, digits, `-` or `_`"
+        )))
+    }
+}
+
+/// Memory storages of profiles, together with API keys
+#[derive(Default)]
+pub struct MemoryProfiles {
+    storages: Mutex<HashMap<String, Arc<MemoryStorage>>>,
+    api_keys: Mutex<Vec<ApiKey>>,
+}
+
+#[async_trait]
+impl Profiles for MemoryProfiles {
+    fn storage(&self, profile: &str) -> Arc<dyn Storage> {
+        self.storages
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .entry(profile.to_string())
+            .or_default()
+            .clone()
+    }
+
+    async fn list(&self) -> Result<Vec<String>, MailError> {
+        let mut profiles: Vec<String> = self
+            .storages
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .iter()
+            .filter(|(_, storage)| !storage.is_empty())
+            .map(|(profile, _)| profile.clone())
+            .collect();
+        profiles.sort_unstable();
+
+        Ok(profiles)
+    }
+
+    async fn delete(&self, profile: &str) -> Result<(), MailError> {
+        if let Some(storage) = self
+            .storages
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .get(profile)
+        {
+            storage.clear();
+        }
+
+        Ok(())
+    }
+
+    /// Nothing is stored at rest, so there is nothing to encrypt
+    async fn rekey(&self, _new_key: &SecretKey) -> Result<usize, MailError> {
+        Ok(0)
+    }
+}
+
+#[async_trait]
+impl KeyStore for MemoryProfiles {
+    async fn save_api_key(&self, key: &ApiKey) -> Result<(), MailError> {
+        self.api_keys
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .push(key.clone());
+
+        Ok(())
+    }
+
+    async fn find_api_key(&self, id: &str) -> Result<Option<ApiKey>, MailError> {
+        Ok(self
+            .api_keys
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .iter()
+            .find(|key| key.id == id)
+            .cloned())
+    }
+
+    async fn list_api_keys(&self) -> Result<Vec<ApiKey>, MailError> {
+        Ok(self
+            .api_keys
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone())
+    }
+
+    async fn revoke_api_key(&self, id: &str) -> Result<bool, MailError> {
+        let mut keys = self.api_keys.lock().unwrap_or_else(|e| e.into_inner());
+        let count = keys.len();
+
+        keys.retain(|key| key.id != id);
+
+        Ok(keys.len() < count)
+    }
+}
+
+/// Storage that lives as long as the process, used together with memory provider
+#[derive(Debug, Default)]
+pub struct MemoryStorage {
+    accounts: Mutex<Vec<Account>>,
+    messages: Mutex<Vec<Message>>,
+    raw_messages: Mutex<HashMap<(String, String), Vec<u8>>>,
+    message_ids: Mutex<HashMap<String, u32>>,
+    health: Mutex<HashMap<String, ProviderHealth>>,
+}
+
+impl MemoryStorage {
+    fn is_empty(&self) -> bool {
+        self.accounts
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .is_empty()
+            && self
+                .messages
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .is_empty()
+    }
+
+    fn clear(&self) {
+        self.accounts
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clear();
+        self.messages
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clear();
+        self.raw_messages
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clear();
+        self.message_ids
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clear();
+        self.health
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .clear();
+    }
+
+    fn unexpired_accounts(&self) -> Vec<Account> {
+        let oldest = Utc::now() - chrono::Duration::seconds(ADDRESS_LIFETIME.as_secs() as i64);
+
+        self.accounts
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .iter()
+            .filter(|account| account.created_at > oldest)
+            .cloned()
+            .collect()
+    }
+}
+
+#[async_trait]
+impl Storage for MemoryStorage {
+    async fn save_account(&self, account: &Account) -> Result<(), MailError> {
+        let oldest = Utc::now() - chrono::Duration::seconds(ADDRESS_LIFETIME.as_secs() as i64);
+        let mut accounts = self.accounts.lock().unwrap_or_else(|e| e.into_inner());
+
+        // Checked under the same lock as the insert, like the unique index in MongoDB
+        if let Some(label) = &account.label {
+            if let Some(taken) = accounts
+                .iter()
+                .find(|stored| stored.created_at > oldest && stored.label.as_ref() == Some(label))
+            {
+                return Err(MailError::LabelError(format!(
+                    "`{label}` is already used by {}",
+                    taken.email_addr
+                )));
+            }
+        }
+
+        accounts.push(account.clone());
+
+        Ok(())
+    }
+
+    async fn find_account(&self, email_addr: &str) -> Result<Account, MailError> {
+        self.unexpired_accounts()
+            .into_iter()
+            .find(|account| account.email_addr == email_addr)
+            .ok_or_else(|| MailError::EmailCheckError(email_addr.to_string()))
+    }
+
+    async fn list_addresses(&self) -> Result<Vec<String>, MailError> {
+        Ok(self
+            .unexpired_accounts()
+            .into_iter()
+            .map(|account| account.email_addr)
+            .collect())
+    }
+
+    async fn find_account_by_label(&self, label: &str) -> Result<Option<Account>, MailError> {
+        Ok(self
+            .unexpired_accounts()
+            .into_iter()
+            .find(|account| account.label.as_deref() == Some(label)))
+    }
+
+    async fn find_addresses(&self, tag: Option<&str>) -> Result<Vec<StoredAddress>, MailError> {
+        Ok(self
+            .unexpired_accounts()
+            .into_iter()
+            .filter(|account| tag.is_none_or(|tag| account.tags.iter().any(|t| t == tag)))
+            .map(StoredAddress::from)
+            .collect())
+    }
+
+    async fn cache_messages(&self, messages: &[Message]) -> Result<(), MailError> {
+        let mut cached = self.messages.lock().unwrap_or_else(|e| e.into_inner());
+
+        for message in messages {
+            match cached.iter_mut().find(|cached| {
+                cached.email_addr == message.email_addr && cached.mail_id == message.mail_id
+            }) {
+                Some(cached) => {
+                    let mail_body = message.mail_body.clone().or(cached.mail_body.take());
+
+                    *cached = Message {
+                        mail_body,
+                        ..message.clone()
+                    };
+                }
+                None => cached.push(message.clone()),
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn find_messages(&self, query: &SearchQuery) -> Result<Vec<Message>, MailError> {
+        let mut messages: Vec<Message> = self
+            .messages
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .iter()
+            .filter(|message| query.matches(message))
+            .cloned()
+            .collect();
+
+        messages.sort_by_key(|message| std::cmp::Reverse(message.mail_timestamp));
+
+        Ok(messages)
+    }
+
+    async fn find_message(
+        &self,
+        email_addr: &str,
+        mail_id: &str,
+    ) -> Result<Option<Message>, MailError> {
+        Ok(self
+            .messages
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .iter()
+            .find(|message| message.email_addr == email_addr && message.mail_id == mail_id)
+            .cloned())
+    }
+
+    async fn save_raw_message(
+        &self,
+        email_addr: &str,
+        mail_id: &str,
+        raw: &[u8],
+    ) -> Result<(), MailError> {
+        self.raw_messages
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .insert((email_addr.to_string(), mail_id.to_string()), raw.to_vec());
+
+        Ok(())
+    }
+
+    async fn find_raw_message(
+        &self,
+        email_addr: &str,
+        mail_id: &str,
+    ) -> Result<Option<Vec<u8>>, MailError> {
+        Ok(self
+            .raw_messages
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .get(&(email_addr.to_string(), mail_id.to_string()))
+            .cloned())
+    }
+
+    async fn delete_message(&self, email_addr: &str, mail_id: &str) -> Result<(), MailError> {
+        self.messages
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .retain(|message| !(message.email_addr == email_addr && message.mail_id == mail_id));
+        self.raw_messages
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .remove(&(email_addr.to_string(), mail_id.to_string()));
+
+        Ok(())
+    }
+
+    async fn next_message_id(&self, email_addr: &str) -> Result<u32, MailError> {
+        let mut message_ids = self.message_ids.lock().unwrap_or_else(|e| e.into_inner());
+        let last_id = message_ids.entry(email_addr.to_string()).or_insert(0);
+        *last_id += 1;
+
+        Ok(*last_id)
+    }
+
+    async fn save_health(&self, health: &ProviderHealth) -> Result<(), MailError> {
+        self.health
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .insert(health.provider.clone(), health.clone());
+
+        Ok(())
+    }
+
+    async fn find_health(&self, provider: &str) -> Result<Option<ProviderHealth>, MailError> {
+        Ok(self
+            .health
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .get(provider)
+            .cloned())
+    }
+
+    async fn ping(&self) -> Result<(), MailError> {
+        Ok(())
+    }
+
+    /// Expired addresses are filtered out when they are read
+    async fn check_expiry(&self) -> Result<(), MailError> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(mail_id: &str, mail_body: Option<&str>, mail_timestamp: i64) -> Message {
+        Message {
+            email_addr: "abc@memory.test".to_string(),
+            mail_id: mail_id.to_string(),
+            mail_from: "noreply@github.com".to_string(),
+            mail_subject: "Verify your email".to_string(),
+            mail_excerpt: String::new(),
+            mail_body: mail_body.map(str::to_string),
+            mail_content_type: None,
+            mail_timestamp,
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_cache_keeps_body_and_sorts_newest_first() -> Result<(), MailError> {
+        let storage = MemoryStorage::default();
+
+        storage
+            .cache_messages(&[message("1", Some("Your code is 123456"), 10)])
+            .await?;
+        storage
+            .cache_messages(&[message("1", None, 10), message("2", None, 20)])
+            .await?;
+
+        let messages = storage.find_messages(&SearchQuery::default()).await?;
+
+        assert_eq!(messages.len(), 2);
+        assert_eq!(messages[0].mail_id, "2");
+        assert_eq!(
+            messages[1].mail_body,
+            Some("Your code is 123456".to_string())
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_expired_account_is_not_found() -> Result<(), MailError> {
+        let storage = MemoryStorage::default();
+        let account = Account {
+            created_at: Utc::now() - chrono::Duration::minutes(61),
+            ..Account::test("memory", "abc@memory.test")
+        };
+
+        storage.save_account(&account).await?;
+
+        assert!(storage.list_addresses().await?.is_empty());
+        assert!(matches!(
+            storage.find_account("abc@memory.test").await,
+            Err(MailError::EmailCheckError(_))
+        ));
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_label_is_saved_once() -> Result<(), MailError> {
+        let storage = MemoryStorage::default();
+        let account = |email_addr: &str, label: Option<&str>, age: i64| Account {
+            created_at: Utc::now() - chrono::Duration::minutes(age),
+            label: label.map(str::to_string),
+            ..Account::test("memory", email_addr)
+        };
+
+        // Label of an expired address can be used again
+        storage
+            .save_account(&account("old@memory.test", Some("admin"), 61))
+            .await?;
+        storage
+            .save_account(&account("abc@memory.test", Some("admin"), 0))
+            .await?;
+        assert!(matches!(
+            storage
+                .save_account(&account("def@memory.test", Some("admin"), 0))
+                .await,
+            Err(MailError::LabelError(_))
+        ));
+
+        storage
+            .save_account(&account("ghi@memory.test", None, 0))
+            .await?;
+        storage
+            .save_account(&account("jkl@memory.test", None, 0))
+            .await?;
+        assert_eq!(storage.list_addresses().await?.len(), 3);
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_profiles_are_separate() -> Result<(), MailError> {
+        let profiles = MemoryProfiles::default();
+        let account = Account::test("memory", "abc@memory.test");
+
+        profiles.storage("qa").save_account(&account).await?;
+        profiles
+            .storage("default")
+            .cache_messages(&[message("1", None, 1648372800)])
+            .await?;
+
+        assert!(profiles.storage("dev").list_addresses().await?.is_empty());
+        assert_eq!(profiles.storage("qa").list_addresses().await?.len(), 1);
+        assert_eq!(profiles.list().await?, ["default", "qa"]);
+
+        profiles.delete("qa").await?;
+        assert!(profiles.storage("qa").list_addresses().await?.is_empty());
+        assert_eq!(profiles.list().await?, ["default"]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_profile_names() {
+        for profile in ["default", "qa-team", "alice_2"] {
+            assert!(check_profile_name(profile).is_ok());
+        }
+        for profile in ["", "qa team", "../admin", "a.b", &"x".repeat(33)] {
+            assert!(matches!(
+                check_profile_name(profile),
+                Err(MailError::ConfigError(_))
+            ));
+        }
+    }
+}
@@ -0,0 +1,361 @@
+use std::collections::{BTreeMap, HashSet};
+use std::io::Write;
+use std::time::Duration;
+
+use crate::context::Context;
+use crate::events::{Event, EventKind};
+use crate::mails::{Account, MailError, Message};
+use crate::polling::Subscription;
+
+/// Followed address with id of its newest email.
+/// Id is None until the emails the address had when streaming started are skipped,
+/// then its emails come from the shared poller of the address
+struct Watched {
+    account: Account,
+    seq: Option<u32>,
+    subscription: Option<Subscription>,
+}
+
+/// Turns changes of addresses into events, one `poll` at a time
+pub struct Stream<'a> {
+    ctx: &'a Context,
+    /// Followed addresses, every stored address of the profile if None
+    emails: Option<Vec<String>>,
+    watched: BTreeMap<String, Watched>,
+    expired: HashSet<String>,
+    started: bool,
+}
+
+impl<'a> Stream<'a> {
+    /// Follows `emails`, or every stored address of the profile if it is empty
+    pub fn new(ctx: &'a Context, emails: Vec<String>) -> Self {
+        Stream {
+            ctx,
+            emails: (!emails.is_empty()).then_some(emails),
+            watched: BTreeMap::new(),
+            expired: HashSet::new(),
+            started: false,
+        }
+    }
+
+    /// Follows address created by the tool, with every email it receives
+    pub fn created(&mut self, account: Account) -> Event {
+        if let Some(emails) = &mut self.emails {
+            emails.push(account.email_addr.clone());
+        }
+
+        self.watched.insert(
+            account.email_addr.clone(),
+            Watched {
+                account: account.clone(),
+                seq: Some(0),
+                subscription: None,
+            },
+        );
+
+        self.event(EventKind::AddressCreated(account.into()))
+    }
+
+    /// Whether every followed address expired
+    pub fn is_done(&self) -> bool {
+        self.emails
+            .as_ref()
+            .is_some_and(|emails| emails.iter().all(|email| self.expired.contains(email)))
+    }
+
+    /// Events since the previous poll. Emails received before the first poll are skipped
+    pub async fn poll(&mut self) -> Result<Vec<Event>, MailError> {
+        let mut events = Vec::new();
+
+        let addresses = match &self.emails {
+            Some(emails) => emails.clone(),
+            None => self.ctx.storage.list_addresses().await?,
+        };
+
+        for email in addresses {
+            if self.watched.contains_key(&email) || self.expired.contains(&email) {
+                continue;
+            }
+
+            let account = match self.ctx.storage.find_account(&email).await {
+                Ok(account) => account,
+                Err(MailError::EmailCheckError(_)) => {
+                    self.expired.insert(email.clone());
+                    events.push(self.event(EventKind::AddressExpired { email_addr: email }));
+                    continue;
+                }
+                Err(e) => return Err(e),
+            };
+
+            // Address created by someone else while streaming
+            let seq = if self.started {
+                events.push(self.event(EventKind::AddressCreated(account.clone().into())));
+                Some(0)
+            } else {
+                None
+            };
+
+            self.watched.insert(
+                email,
+                Watched {
+                    account,
+                    seq,
+                    subscription: None,
+                },
+            );
+        }
+
+        let emails: Vec<String> = self.watched.keys().cloned().collect();
+        for email in emails {
+            match self.ctx.storage.find_account(&email).await {
+                Ok(_) => events.extend(self.check(&email).await?),
+                Err(MailError::EmailCheckError(_)) => {
+                    self.watched.remove(&email);
+                    self.expired.insert(email.clone());
+                    events.push(self.event(EventKind::AddressExpired { email_addr: email }));
+                }
+                Err(e) => return Err(e),
+            }
+        }
+
+        self.started = true;
+
+        Ok(events)
+    }
+
+    /// Emails its poller found for `email` since the previous poll,
+    /// and provider errors as events
+    async fn check(&mut self, email: &str) -> Result<Vec<Event>, MailError> {
+        let watched = &self.watched[email];
+        let provider_name = watched.account.provider.clone();
+
+        if watched.subscription.is_none() {
+            let subscription = match self.subscribe(email).await {
+                Ok(subscription) => subscription,
+                Err(e) => {
+                    return Ok(vec![Event::provider_error(
+                        &self.ctx.profile,
+                        &provider_name,
+                        Some(email),
+                        &e,
+                    )])
+                }
+            };
+
+            let watched = self.watched.get_mut(email).expect("watched");
+            watched.subscription = Some(subscription);
+        }
+
+        let mut messages = Vec::new();
+        let mut errors = Vec::new();
+
+        let watched = self.watched.get_mut(email).expect("watched");
+        let subscription = watched.subscription.as_mut().expect("subscribed");
+        while let Some(result) = subscription.try_next() {
+            match result {
+                Ok(received) => messages.extend(received),
+                // Expiry is reported when storage no longer has the address
+                Err(MailError::EmailCheckError(_)) => {}
+                Err(e) => errors.push(e),
+            }
+        }
+        watched.seq = watched.seq.max(newest(&messages));
+
+        if !messages.is_empty() {
+            self.ctx.storage.cache_messages(&messages).await?;
+        }
+
+        let errors = errors
+            .iter()
+            .map(|e| Event::provider_error(&self.ctx.profile, &provider_name, Some(email), e));
+        let received = messages
+            .into_iter()
+            .map(|message: Message| self.event(EventKind::MessageReceived(message)));
+
+        Ok(errors.chain(received).collect())
+    }
+
+    /// Subscribes to the poller of `email`, skipping emails it had when streaming started
+    async fn subscribe(&self, email: &str) -> Result<Subscription, MailError> {
+        let watched = &self.watched[email];
+        let provider = self.ctx.provider(&watched.account.provider)?;
+
+        let seq = match watched.seq {
+            Some(seq) => seq,
+            None => newest(&provider.check_messages(&watched.account, 0).await?).unwrap_or(0),
+        };
+
+        Ok(self
+            .ctx
+            .pollers
+            .subscribe(watched.account.clone(), provider, seq))
+    }
+
+    fn event(&self, kind: EventKind) -> Event {
+        Event::new(&self.ctx.profile, kind)
+    }
+}
+
+fn newest(messages: &[Message]) -> Option<u32> {
+    messages.iter().filter_map(|m| m.mail_id.parse().ok()).max()
+}
+
+/// Writes events as NDJSON every `interval` until followed addresses expire
+/// or the reader goes away
+pub async fn run(
+    stream: &mut Stream<'_>,
+    interval: Duration,
+    out: &mut impl Write,
+) -> Result<(), MailError> {
+    let mut ticks = tokio::time::interval(interval);
+
+    loop {
+        ticks.tick().await;
+
+        for event in stream.poll().await? {
+            match write(out, &event) {
+                Err(MailError::TerminalError(e)) if e.kind() == std::io::ErrorKind::BrokenPipe => {
+                    return Ok(())
+                }
+                result => result?,
+            }
+        }
+
+        if stream.is_done() {
+            return Ok(());
+        }
+    }
+}
+
+/// Writes event as a single line and flushes it, so readers get it immediately
+pub fn write(out: &mut impl Write, event: &Event) -> Result<(), MailError> {
+    let line = serde_json::to_string(event)?;
+
+    writeln!(out, "{line}")
+        .and_then(|_| out.flush())
+        .map_err(MailError::TerminalError)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use chrono::Utc;
+
+    use std::sync::Arc;
+
+    use crate::mails::MemoryProvider;
+    use crate::polling::Pollers;
+    use crate::storage::ADDRESS_LIFETIME;
+
+    fn context(memory: &MemoryProvider) -> Context {
+        let mut ctx = Context::in_memory(memory.clone());
+        ctx.pollers = Arc::new(Pollers::fast());
+
+        ctx
+    }
+
+    /// Polls until there are `count` events, as pollers find emails in the background
+    async fn poll_events(stream: &mut Stream<'_>, count: usize) -> Result<Vec<Event>, MailError> {
+        let mut events = Vec::new();
+
+        for _ in 0..100 {
+            events.extend(stream.poll().await?);
+            if events.len() >= count {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+
+        Ok(events)
+    }
+
+    fn kinds(events: &[Event]) -> Vec<serde_json::Value> {
+        events
+            .iter()
+            .map(|event| serde_json::to_value(event).unwrap()["type"].clone())
+            .collect()
+    }
+
+    fn subjects(events: &[Event]) -> Vec<&str> {
+        let mut subjects: Vec<&str> = events
+            .iter()
+            .filter_map(|event| match &event.kind {
+                EventKind::MessageReceived(message) => Some(message.mail_subject.as_str()),
+                _ => None,
+            })
+            .collect();
+        subjects.sort_unstable();
+
+        subjects
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_stream_every_address() -> Result<(), MailError> {
+        let memory = MemoryProvider::default();
+        let ctx = context(&memory);
+        let provider = ctx.provider("memory")?;
+
+        let old = provider.create_address().await?;
+        ctx.storage.save_account(&old).await?;
+        memory.inject(&old.email_addr, "a@example.com", "Before", "", Vec::new())?;
+
+        let mut stream = Stream::new(&ctx, Vec::new());
+        assert!(stream.poll().await?.is_empty());
+
+        memory.inject(&old.email_addr, "a@example.com", "After", "", Vec::new())?;
+        let new = provider.create_address().await?;
+        ctx.storage.save_account(&new).await?;
+
+        let events = poll_events(&mut stream, 3).await?;
+        assert_eq!(kinds(&events)[0], "address_created");
+        assert_eq!(subjects(&events), ["After", "Welcome to memory inbox"]);
+
+        assert!(stream.poll().await?.is_empty());
+        assert!(!stream.is_done());
+
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn test_stream_ends_when_addresses_expire() -> Result<(), MailError> {
+        let memory = MemoryProvider::default();
+        let ctx = context(&memory);
+
+        let mut expiring = ctx.provider("memory")?.create_address().await?;
+        expiring.created_at = Utc::now() - chrono::Duration::from_std(ADDRESS_LIFETIME).unwrap()
+            + chrono::Duration::milliseconds(500);
+        ctx.storage.save_account(&expiring).await?;
+
+        let mut stream = Stream::new(&ctx, vec![expiring.email_addr.clone()]);
+        let created = ctx.provider("memory")?.create_address().await?;
+        ctx.storage.save_account(&created).await?;
+        memory.inject(&created.email_addr, "a@example.com", "Hi", "", Vec::new())?;
+
+        let event = stream.created(created);
+        assert_eq!(kinds(&[event]), ["address_created"]);
+        assert_eq!(
+            subjects(&poll_events(&mut stream, 2).await?),
+            ["Hi", "Welcome to memory inbox"]
+        );
+
+        tokio::time::sleep(Duration::from_millis(600)).await;
+
+        let mut out = Vec::new();
+        let events = stream.poll().await?;
+        for event in &events {
+            write(&mut out, event)?;
+        }
+        assert!(!stream.is_done());
+
+        let line: serde_json::Value = serde_json::from_slice(&out).unwrap();
+        assert_eq!(line["type"], "address_expired");
+        assert_eq!(
+            line["email_addr"].as_str(),
+            Some(expiring.email_addr.as_str())
+        );
+        assert_eq!(line["version"], 1);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,406 @@
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEventKind};
+use ratatui::layout::{Constraint, Direction, Layout};
+use ratatui::style::{Color, Modifier, Style};
+use ratatui::text::Line;
+use ratatui::widgets::{Block, Borders, List, ListItem, ListState, Paragraph, Wrap};
+use ratatui::{DefaultTerminal, Frame};
+
+use std::io::Write;
+use std::time::{Duration, Instant};
+
+use crate::cli;
+use crate::context::Context;
+use crate::extract;
+use crate::mails;
+use crate::notify::printable;
+
+const REFRESH_INTERVAL: Duration = Duration::from_secs(10);
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+const HELP: &str =
+    "q quit | tab switch pane | enter open | n new | r refresh | d delete | y copy address | c copy code | o open link";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Pane {
+    Addresses,
+    Inbox,
+    Message,
+}
+
+struct App {
+    addresses: Vec<String>,
+    address_state: ListState,
+    messages: Vec<mails::Message>,
+    message_state: ListState,
+    opened: Option<mails::Message>,
+    focus: Pane,
+    status: String,
+    last_refresh: Instant,
+}
+
+/// Runs interactive inbox browser until user quits
+pub async fn run(ctx: &Context) -> Result<(), mails::MailError> {
+    let mut terminal = ratatui::init();
+
+    let result = event_loop(&mut terminal, ctx).await;
+
+    ratatui::restore();
+
+    result
+}
+
+async fn event_loop(terminal: &mut DefaultTerminal, ctx: &Context) -> Result<(), mails::MailError> {
+    let mut app = App {
+        addresses: Vec::new(),
+        address_state: ListState::default(),
+        messages: Vec::new(),
+        message_state: ListState::default(),
+        opened: None,
+        focus: Pane::Addresses,
+        status: HELP.to_string(),
+        last_refresh: Instant::now(),
+    };
+
+    app.load_addresses(ctx).await;
+    app.refresh_inbox(ctx).await;
+
+    loop {
+        terminal
+            .draw(|frame| draw(frame, &mut app))
+            .map_err(mails::MailError::TerminalError)?;
+
+        if app.last_refresh.elapsed() >= REFRESH_INTERVAL {
+            app.refresh_inbox(ctx).await;
+        }
+
+        if !event::poll(POLL_INTERVAL).map_err(mails::MailError::TerminalError)? {
+            continue;
+        }
+
+        let key = match event::read().map_err(mails::MailError::TerminalError)? {
+            Event::Key(key) if key.kind == KeyEventKind::Press => key,
+            _ => continue,
+        };
+
+        match key.code {
+            KeyCode::Char('q') | KeyCode::Esc => break,
+            KeyCode::Tab | KeyCode::Right => app.focus = app.focus.next(),
+            KeyCode::BackTab | KeyCode::Left => app.focus = app.focus.previous(),
+            KeyCode::Down | KeyCode::Char('j') => app.select(1, ctx).await,
+            KeyCode::Up | KeyCode::Char('k') => app.select(-1, ctx).await,
+            KeyCode::Enter => match app.focus {
+                Pane::Addresses => {
+                    app.focus = Pane::Inbox;
+                    app.refresh_inbox(ctx).await;
+                }
+                Pane::Inbox | Pane::Message => app.open_message(ctx).await,
+            },
+            KeyCode::Char('n') => app.create_address(ctx).await,
+            KeyCode::Char('r') => app.refresh_inbox(ctx).await,
+            KeyCode::Char('d') => app.delete_message(ctx).await,
+            KeyCode::Char('y') => match app.selected_address() {
+                Some(address) => app.copy(&address),
+                None => app.status = "No email address selected".to_string(),
+            },
+            KeyCode::Char('c') => match app.current_message().and_then(message_code) {
+                Some(code) => app.copy(&code),
+                None => app.status = "No code found in email".to_string(),
+            },
+            KeyCode::Char('o') => app.open_link(),
+            _ => {}
+        }
+    }
+
+    Ok(())
+}
+
+impl Pane {
+    fn next(self) -> Self {
+        match self {
+            Pane::Addresses => Pane::Inbox,
+            Pane::Inbox => Pane::Message,
+            Pane::Message => Pane::Addresses,
+        }
+    }
+
+    fn previous(self) -> Self {
+        match self {
+            Pane::Addresses => Pane::Message,
+            Pane::Inbox => Pane::Addresses,
+            Pane::Message => Pane::Inbox,
+        }
+    }
+}
+
+impl App {
+    fn selected_address(&self) -> Option<String> {
+        self.address_state
+            .selected()
+            .and_then(|index| self.addresses.get(index))
+            .cloned()
+    }
+
+    /// Opened email, or the one selected in inbox if none is opened
+    fn current_message(&self) -> Option<&mails::Message> {
+        self.opened.as_ref().or_else(|| {
+            self.message_state
+                .selected()
+                .and_then(|index| self.messages.get(index))
+        })
+    }
+
+    async fn load_addresses(&mut self, ctx: &Context) {
+        match ctx.storage.list_addresses().await {
+            Ok(addresses) => {
+                self.addresses = addresses;
+
+                let selected = match self.address_state.selected() {
+                    Some(index) => Some(index.min(self.addresses.len().saturating_sub(1))),
+                    None => Some(0),
+                };
+                self.address_state
+                    .select(selected.filter(|_| !self.addresses.is_empty()));
+            }
+            Err(e) => self.status = e.to_string(),
+        }
+    }
+
+    async fn refresh_inbox(&mut self, ctx: &Context) {
+        self.last_refresh = Instant::now();
+
+        let address = match self.selected_address() {
+            Some(address) => address,
+            None => {
+                self.messages.clear();
+                return;
+            }
+        };
+
+        self.messages = match cli::get_emails_from_provider(ctx, &address, 0).await {
+            Ok(messages) => messages,
+            Err(e) => {
+                self.status = e.to_string();
+                return;
+            }
+        };
+
+        let selected = self
+            .message_state
+            .selected()
+            .map(|index| index.min(self.messages.len().saturating_sub(1)))
+            .or(Some(0))
+            .filter(|_| !self.messages.is_empty());
+        self.message_state.select(selected);
+    }
+
+    async fn select(&mut self, step: i32, ctx: &Context) {
+        let (state, len) = match self.focus {
+            Pane::Addresses => (&mut self.address_state, self.addresses.len()),
+            Pane::Inbox => (&mut self.message_state, self.messages.len()),
+            Pane::Message => return,
+        };
+
+        if len == 0 {
+            return;
+        }
+
+        let index = state.selected().unwrap_or(0) as i32 + step;
+        state.select(Some(index.clamp(0, len as i32 - 1) as usize));
+
+        if self.focus == Pane::Addresses {
+            self.message_state.select(None);
+            self.opened = None;
+            self.refresh_inbox(ctx).await;
+        }
+    }
+
+    async fn open_message(&mut self, ctx: &Context) {
+        let (address, mail_id) = match (self.selected_address(), self.message_state.selected()) {
+            (Some(address), Some(index)) if index < self.messages.len() => {
+                (address, self.messages[index].mail_id.clone())
+            }
+            _ => return,
+        };
+
+        match cli::fetch_email_from_provider(ctx, &address, &mail_id).await {
+            Ok(Some(fetched)) => {
+                self.opened = Some(fetched.message);
+                self.focus = Pane::Message;
+            }
+            Ok(None) => self.status = "Unexpected email id".to_string(),
+            Err(e) => self.status = e.to_string(),
+        }
+    }
+
+    async fn create_address(&mut self, ctx: &Context) {
+        match cli::store_email_from_provider(ctx, ctx.default_provider()).await {
+            Ok(account) => {
+                let address = account.email_addr;
+                self.load_addresses(ctx).await;
+
+                let index = self.addresses.iter().position(|x| *x == address);
+                self.address_state.select(index);
+                self.opened = None;
+                self.refresh_inbox(ctx).await;
+
+                self.status = format!("Created {address}");
+            }
+            Err(e) => self.status = e.to_string(),
+        }
+    }
+
+    async fn delete_message(&mut self, ctx: &Context) {
+        let (address, mail_id) = match (self.selected_address(), self.current_message()) {
+            (Some(address), Some(message)) => (address, message.mail_id.clone()),
+            _ => return,
+        };
+
+        match cli::delete_email_from_provider(ctx, &address, &mail_id).await {
+            Ok(()) => {
+                self.opened = None;
+                self.refresh_inbox(ctx).await;
+                self.status = format!("Deleted email {mail_id}");
+            }
+            Err(e) => self.status = e.to_string(),
+        }
+    }
+
+    /// Copies text using OSC 52 terminal escape sequence,
+    /// which also works over SSH
+    fn copy(&mut self, text: &str) {
+        let mut stdout = std::io::stdout();
+
+        let result =
+            write!(stdout, "\x1b]52;c;{}\x07", base64::encode(text)).and_then(|_| stdout.flush());
+
+        self.status = match result {
+            Ok(()) => format!("Copied {text}"),
+            Err(e) => e.to_string(),
+        };
+    }
+
+    fn open_link(&mut self) {
+        let link = match self
+            .opened
+            .as_ref()
+            .and_then(|message| message.mail_body.as_deref())
+            .and_then(|body| extract::extract_links(body).into_iter().next())
+        {
+            Some(link) => link,
+            None => {
+                self.status = "No link found in opened email".to_string();
+                return;
+            }
+        };
+
+        let opener = if cfg!(target_os = "macos") {
+            "open"
+        } else if cfg!(target_os = "windows") {
+            "explorer"
+        } else {
+            "xdg-open"
+        };
+
+        self.status = match std::process::Command::new(opener)
+            .arg(&link)
+            .stdout(std::process::Stdio::null())
+            .stderr(std::process::Stdio::null())
+            .spawn()
+        {
+            Ok(_) => format!("Opened {link}"),
+            Err(e) => format!("Could not open {link}: {e}"),
+        };
+    }
+}
+
+fn message_code(message: &mails::Message) -> Option<String> {
+    let text = message.mail_body.as_ref().unwrap_or(&message.mail_excerpt);
+
+    extract::extract_code(&format!("{} {}", message.mail_subject, text))
+}
+
+fn draw(frame: &mut Frame, app: &mut App) {
+    let rows = Layout::default()
+        .direction(Direction::Vertical)
+        .constraints([Constraint::Min(0), Constraint::Length(1)])
+        .split(frame.area());
+
+    let columns = Layout::default()
+        .direction(Direction::Horizontal)
+        .constraints([Constraint::Percentage(30), Constraint::Percentage(70)])
+        .split(rows[0]);
+
+    let right = Layout::default()
+        .direction(Direction::Vertical)
+        .constraints([Constraint::Percentage(40), Constraint::Percentage(60)])
+        .split(columns[1]);
+
+    let highlight = Style::default()
+        .fg(Color::Black)
+        .bg(Color::Cyan)
+        .add_modifier(Modifier::BOLD);
+
+    let addresses: Vec<ListItem> = app
+        .addresses
+        .iter()
+        .map(|address| ListItem::new(printable(address)))
+        .collect();
+    let addresses = List::new(addresses)
+        .block(pane_block("Addresses", app.focus == Pane::Addresses))
+        .highlight_style(highlight);
+    frame.render_stateful_widget(addresses, columns[0], &mut app.address_state);
+
+    let messages: Vec<ListItem> = app
+        .messages
+        .iter()
+        .map(|message| {
+            ListItem::new(format!(
+                "{}  {}  {}",
+                message.date().format("%H:%M"),
+                printable(&message.mail_from),
+                printable(&message.mail_subject)
+            ))
+        })
+        .collect();
+    let messages = List::new(messages)
+        .block(pane_block("Inbox", app.focus == Pane::Inbox))
+        .highlight_style(highlight);
+    frame.render_stateful_widget(messages, right[0], &mut app.message_state);
+
+    let lines: Vec<Line> = match &app.opened {
+        Some(message) => {
+            let body = message.mail_body.as_ref().unwrap_or(&message.mail_excerpt);
+            let mut lines = vec![
+                Line::from(format!("From: {}", printable(&message.mail_from))),
+                Line::from(format!("Date: {} UTC", message.date().naive_utc())),
+                Line::from(format!("Subject: {}", printable(&message.mail_subject))),
+                Line::from(""),
+            ];
+            lines.extend(
+                extract::html_to_text(body)
+                    .lines()
+                    .map(|line| Line::from(printable(&line.replace('\t', "    ")))),
+            );
+            lines
+        }
+        None => vec![Line::from("Press enter on an email to read it")],
+    };
+    let message = Paragraph::new(lines)
+        .block(pane_block("Message", app.focus == Pane::Message))
+        .wrap(Wrap { trim: false });
+    frame.render_widget(message, right[1]);
+
+    frame.render_widget(Paragraph::new(printable(&app.status)), rows[1]);
+}
+
+fn pane_block(title: &str, focused: bool) -> Block<'_> {
+    let style = if focused {
+        Style::default().fg(Color::Cyan)
+    } else {
+        Style::default()
+    };
+
+    Block::default()
+        .title(title)
+        .borders(Borders::ALL)
+        .border_style(style)
+}